@@ -0,0 +1,374 @@
+use std::sync::Arc;
+
+use serenity::{
+    client::Context,
+    http::Http,
+    model::{channel::Message, id::ChannelId},
+    prelude::TypeMap,
+};
+use tokio::sync::RwLock;
+
+use crate::{
+    irc_discord::IrcQuery,
+    locale::Locale,
+    permissions::{Caller, PermissionMatrix},
+    ChannelMappingKey, IgnoresKey, IrcQueryKey, LatencyKey, LocaleKey, PermissionsKey, SendersKey,
+    ShardManagerKey,
+};
+
+const USAGE: &str = "Usage: !dircord status | reload | reconnect-discord | join <#channel> | part <#channel> | map <#channel> <discord channel id> | ignore <nick|mask|discord id> | unignore <nick|mask|discord id> | ignore-pattern <regex> | unignore-pattern <regex>";
+
+/// The `!dircord` commands for running the bridge, the same from Discord and IRC.
+pub enum AdminCommand {
+    Status,
+    Reload,
+    /// Restarts every shard, for when Discord stops sending events without disconnecting
+    ReconnectDiscord,
+    Join(String),
+    Part(String),
+    /// Bridges an IRC channel to a Discord channel until the next restart or reload
+    Map(String, u64),
+    /// A nick, hostmask or Discord user ID
+    Ignore(String),
+    Unignore(String),
+    IgnorePattern(String),
+    UnignorePattern(String),
+}
+
+impl AdminCommand {
+    /// `None` if `text` isn't a `!dircord` command at all, otherwise the command or how to use it.
+    pub fn parse(text: &str, locale: &Locale) -> Option<Result<Self, String>> {
+        let mut words = text.split_whitespace();
+        if words.next()? != "!dircord" {
+            return None;
+        }
+
+        // patterns can have spaces in them, so they're the rest of the line
+        if let Some(sub @ ("ignore-pattern" | "unignore-pattern")) = words.clone().next() {
+            let pattern = text.trim_start()["!dircord".len()..].trim_start()[sub.len()..].trim();
+            if pattern.is_empty() {
+                return Some(Err(locale.text("admin_usage", USAGE, &[])));
+            }
+            return Some(Ok(if sub == "ignore-pattern" {
+                Self::IgnorePattern(pattern.to_owned())
+            } else {
+                Self::UnignorePattern(pattern.to_owned())
+            }));
+        }
+
+        let command = match (words.next(), words.next(), words.next()) {
+            (Some("status"), None, None) => Self::Status,
+            (Some("reload"), None, None) => Self::Reload,
+            (Some("reconnect-discord"), None, None) => Self::ReconnectDiscord,
+            (Some("join"), Some(channel), None) => Self::Join(channel.to_owned()),
+            (Some("part"), Some(channel), None) => Self::Part(channel.to_owned()),
+            (Some("map"), Some(channel), Some(id)) => match id.parse() {
+                Ok(id) => Self::Map(channel.to_owned(), id),
+                Err(_) => {
+                    return Some(Err(locale.text(
+                        "admin_not_channel_id",
+                        "{id} isn't a Discord channel ID",
+                        &[("id", &id)],
+                    )))
+                }
+            },
+            (Some("ignore"), Some(nick), None) => Self::Ignore(nick.to_owned()),
+            (Some("unignore"), Some(nick), None) => Self::Unignore(nick.to_owned()),
+            _ => return Some(Err(locale.text("admin_usage", USAGE, &[]))),
+        };
+        if words.next().is_some() {
+            return Some(Err(locale.text("admin_usage", USAGE, &[])));
+        }
+        Some(Ok(command))
+    }
+}
+
+/// Whoever's in the `[permissions.dircord]` entry, or in `discord_admins` or `irc_admins`.
+pub fn allowed(permissions: &PermissionMatrix, caller: &Caller<'_>) -> bool {
+    permissions.check("dircord", caller).unwrap_or(false)
+}
+
+/// Answers `!dircord` in a Discord message from an admin. Returns whether it was one, other
+/// messages get relayed like always.
+pub async fn handle_discord(ctx: &Context, msg: &Message) -> bool {
+    let locale = ctx.data.read().await.get::<LocaleKey>().unwrap().clone();
+    let Some(parsed) = AdminCommand::parse(&msg.content, &locale) else {
+        return false;
+    };
+
+    let caller = Caller::Discord {
+        user_id: msg.author.id,
+        roles: msg.member.as_ref().map_or(&[][..], |m| m.roles.as_slice()),
+    };
+    let permissions = ctx
+        .data
+        .read()
+        .await
+        .get::<PermissionsKey>()
+        .unwrap()
+        .clone();
+    if !allowed(&permissions, &caller) {
+        return false;
+    }
+
+    let reply = match parsed {
+        Ok(command) => run(&ctx.http, &ctx.data, command)
+            .await
+            .unwrap_or_else(|e| e),
+        Err(usage) => usage,
+    };
+    if let Err(e) = msg.reply(ctx, reply).await {
+        eprintln!("Failed to answer !dircord: {e}");
+    }
+    true
+}
+
+pub async fn run(
+    http: &Arc<Http>,
+    data: &RwLock<TypeMap>,
+    command: AdminCommand,
+) -> Result<String, String> {
+    match command {
+        AdminCommand::Status => Ok(status(&*data.read().await)),
+        AdminCommand::Reload => {
+            let locale = data.read().await.get::<LocaleKey>().unwrap().clone();
+            let reloaded = crate::reload_config(http, data).await.map_err(|e| {
+                locale.text(
+                    "reload_failed",
+                    "Couldn't reload the config: {error}",
+                    &[("error", &format!("{e:#}"))],
+                )
+            })?;
+            Ok(reloaded.to_string())
+        }
+        AdminCommand::ReconnectDiscord => {
+            let shard_manager = data.read().await.get::<ShardManagerKey>().unwrap().clone();
+            let shards = shard_manager
+                .runners
+                .lock()
+                .await
+                .keys()
+                .copied()
+                .collect::<Vec<_>>();
+            for &shard_id in &shards {
+                shard_manager.restart(shard_id).await;
+            }
+            Ok(data.read().await.get::<LocaleKey>().unwrap().text(
+                "admin_reconnecting",
+                "Reconnecting {count} Discord shard(s)",
+                &[("count", &shards.len())],
+            ))
+        }
+        AdminCommand::Join(channel) => {
+            let data = data.read().await;
+            data.get::<SendersKey>()
+                .unwrap()
+                .get(&channel)
+                .send_join(&channel)
+                .map_err(|e| {
+                    data.get::<LocaleKey>().unwrap().text(
+                        "admin_join_failed",
+                        "Couldn't join {channel}: {error}",
+                        &[("channel", &channel), ("error", &e)],
+                    )
+                })?;
+            Ok(data.get::<LocaleKey>().unwrap().text(
+                "admin_joining",
+                "Joining {channel}",
+                &[("channel", &channel)],
+            ))
+        }
+        AdminCommand::Part(channel) => {
+            let mut data = data.write().await;
+            // bridged channels stop being bridged, otherwise the next reload joins them again
+            if data
+                .get_mut::<ChannelMappingKey>()
+                .unwrap()
+                .remove(&channel)
+                .is_some()
+            {
+                let network = data.get::<SendersKey>().unwrap().network(&channel);
+                data.get_mut::<SendersKey>()
+                    .unwrap()
+                    .remove_channel(&channel);
+                // the IRC loop parts it when it sees it's gone
+                reload_loop(&data, network);
+            } else {
+                data.get::<SendersKey>()
+                    .unwrap()
+                    .get(&channel)
+                    .send_part(&channel)
+                    .map_err(|e| {
+                        data.get::<LocaleKey>().unwrap().text(
+                            "admin_part_failed",
+                            "Couldn't part {channel}: {error}",
+                            &[("channel", &channel), ("error", &e)],
+                        )
+                    })?;
+            }
+            Ok(data.get::<LocaleKey>().unwrap().text(
+                "admin_parting",
+                "Leaving {channel}",
+                &[("channel", &channel)],
+            ))
+        }
+        AdminCommand::Map(channel, id) => {
+            if http.get_channel(ChannelId::from(id)).await.is_err() {
+                return Err(data.read().await.get::<LocaleKey>().unwrap().text(
+                    "admin_no_channel",
+                    "The bot can't see a Discord channel {id}",
+                    &[("id", &id)],
+                ));
+            }
+
+            let mut data = data.write().await;
+            let senders = data.get_mut::<SendersKey>().unwrap();
+            let network = senders.network(&channel);
+            senders
+                .add_channel(channel.clone(), network)
+                .map_err(|e| e.to_string())?;
+            data.get_mut::<ChannelMappingKey>()
+                .unwrap()
+                .insert(channel.clone(), id);
+            reload_loop(&data, network);
+
+            Ok(data.get::<LocaleKey>().unwrap().text(
+                "admin_mapped",
+                "Bridging {channel} to <#{id}> until the next restart or reload",
+                &[("channel", &channel), ("id", &id)],
+            ))
+        }
+        AdminCommand::Ignore(nick) => {
+            let data = data.read().await;
+            let newly = data
+                .get::<IgnoresKey>()
+                .unwrap()
+                .lock()
+                .unwrap()
+                .ignore(&nick);
+            Ok(if newly {
+                data.get::<LocaleKey>().unwrap().text(
+                    "admin_ignored",
+                    "Not relaying {nick} anymore",
+                    &[("nick", &nick)],
+                )
+            } else {
+                data.get::<LocaleKey>().unwrap().text(
+                    "admin_already_ignored",
+                    "{nick} is already ignored",
+                    &[("nick", &nick)],
+                )
+            })
+        }
+        AdminCommand::Unignore(nick) => {
+            let data = data.read().await;
+            let was = data
+                .get::<IgnoresKey>()
+                .unwrap()
+                .lock()
+                .unwrap()
+                .unignore(&nick);
+            Ok(if was {
+                data.get::<LocaleKey>().unwrap().text(
+                    "admin_unignored",
+                    "Relaying {nick} again",
+                    &[("nick", &nick)],
+                )
+            } else {
+                data.get::<LocaleKey>().unwrap().text(
+                    "admin_not_ignored",
+                    "{nick} isn't ignored",
+                    &[("nick", &nick)],
+                )
+            })
+        }
+        AdminCommand::IgnorePattern(pattern) => {
+            let data = data.read().await;
+            let newly = data
+                .get::<IgnoresKey>()
+                .unwrap()
+                .lock()
+                .unwrap()
+                .ignore_pattern(&pattern)?;
+            Ok(if newly {
+                data.get::<LocaleKey>().unwrap().text(
+                    "admin_ignored_pattern",
+                    "Not relaying messages matching {pattern} anymore",
+                    &[("pattern", &pattern)],
+                )
+            } else {
+                data.get::<LocaleKey>().unwrap().text(
+                    "admin_already_ignored_pattern",
+                    "{pattern} is already ignored",
+                    &[("pattern", &pattern)],
+                )
+            })
+        }
+        AdminCommand::UnignorePattern(pattern) => {
+            let data = data.read().await;
+            let was = data
+                .get::<IgnoresKey>()
+                .unwrap()
+                .lock()
+                .unwrap()
+                .unignore_pattern(&pattern);
+            Ok(if was {
+                data.get::<LocaleKey>().unwrap().text(
+                    "admin_unignored_pattern",
+                    "Relaying messages matching {pattern} again",
+                    &[("pattern", &pattern)],
+                )
+            } else {
+                data.get::<LocaleKey>().unwrap().text(
+                    "admin_not_ignored_pattern",
+                    "{pattern} isn't ignored",
+                    &[("pattern", &pattern)],
+                )
+            })
+        }
+    }
+}
+
+/// Lets the IRC loop of `network` pick up changed channels, it joins and parts the difference.
+fn reload_loop(data: &TypeMap, network: usize) {
+    if let Some(queries) = data.get::<IrcQueryKey>().unwrap().get(&network) {
+        let _ = queries.send(IrcQuery::Reload);
+    }
+}
+
+fn status(data: &TypeMap) -> String {
+    let senders = data.get::<SendersKey>().unwrap();
+    let mapping = data.get::<ChannelMappingKey>().unwrap();
+    let latencies = data.get::<LatencyKey>().unwrap();
+    let locale = data.get::<LocaleKey>().unwrap();
+
+    let mut lines = Vec::new();
+    for network in 0..senders.count() {
+        let mut channels = mapping
+            .keys()
+            .filter(|channel| senders.network(channel) == network)
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        channels.sort_unstable();
+        lines.push(locale.text(
+            "admin_status",
+            "{network}: bridging {channels}",
+            &[
+                ("network", &senders.name(network)),
+                ("channels", &channels.join(", ")),
+            ],
+        ));
+        lines.push(latencies[network].lock().unwrap().summary(locale));
+    }
+
+    let ignored = data.get::<IgnoresKey>().unwrap().lock().unwrap().entries();
+    if !ignored.is_empty() {
+        lines.push(locale.text(
+            "admin_status_ignoring",
+            "Ignoring {entries}",
+            &[("entries", &ignored.join(", "))],
+        ));
+    }
+
+    lines.join("\n")
+}
@@ -0,0 +1,134 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use serde::Serialize;
+
+use crate::unix_now;
+
+/// One relayed message, as written to the audit log.
+#[derive(Serialize)]
+pub struct AuditEntry<'a> {
+    pub direction: &'a str,
+    pub source: &'a str,
+    pub destination: &'a str,
+    pub author: Option<&'a str>,
+    pub content: &'a str,
+    /// `None` if the message was delivered, otherwise why it wasn't
+    pub error: Option<String>,
+}
+
+/// When to move the log aside and start a new one.
+pub struct Rotation {
+    /// In bytes, `None` leaves rotating to something like logrotate
+    pub max_size: Option<u64>,
+    /// How many old logs to keep, as `relay.jsonl.1` (newest) up to `relay.jsonl.N`
+    pub keep: usize,
+}
+
+struct Output {
+    file: File,
+    size: u64,
+}
+
+/// Append-only JSON lines log of everything the bridge relays.
+pub struct AuditLog {
+    path: PathBuf,
+    rotation: Rotation,
+    output: Mutex<Output>,
+}
+
+fn open_append(path: &Path) -> io::Result<Output> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    Ok(Output { file, size })
+}
+
+impl AuditLog {
+    pub fn open(path: impl AsRef<Path>, rotation: Rotation) -> io::Result<Self> {
+        let path = path.as_ref().to_owned();
+        let output = open_append(&path)?;
+        Ok(Self {
+            path,
+            rotation,
+            output: Mutex::new(output),
+        })
+    }
+
+    /// Starts writing to whatever is at the path now, after logrotate moved the old file away.
+    pub fn reopen(&self) {
+        let mut output = self.output.lock().unwrap_or_else(|e| e.into_inner());
+        match open_append(&self.path) {
+            Ok(reopened) => *output = reopened,
+            Err(e) => eprintln!("Failed to reopen audit log {}: {e}", self.path.display()),
+        }
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{n}"));
+        path.into()
+    }
+
+    fn rotate(&self, output: &mut Output) -> io::Result<()> {
+        if self.rotation.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            // the oldest one falls off the end
+            let _ = fs::remove_file(self.rotated(self.rotation.keep));
+            for n in (1..self.rotation.keep).rev() {
+                let from = self.rotated(n);
+                if from.exists() {
+                    fs::rename(from, self.rotated(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+
+        *output = open_append(&self.path)?;
+        Ok(())
+    }
+
+    pub fn record(&self, entry: &AuditEntry<'_>) {
+        #[derive(Serialize)]
+        struct Line<'a> {
+            timestamp: u64,
+            #[serde(flatten)]
+            entry: &'a AuditEntry<'a>,
+        }
+
+        let line = Line {
+            timestamp: unix_now(),
+            entry,
+        };
+
+        let mut line = match serde_json::to_string(&line) {
+            Ok(line) => line,
+            Err(e) => {
+                eprintln!("Failed to serialize audit log entry: {e}");
+                return;
+            }
+        };
+        line.push('\n');
+
+        // a poisoned lock just means another write panicked halfway, the file is still usable
+        let mut output = self.output.lock().unwrap_or_else(|e| e.into_inner());
+
+        let full = self.rotation.max_size.map_or(false, |max| {
+            output.size > 0 && output.size + line.len() as u64 > max
+        });
+        if full {
+            if let Err(e) = self.rotate(&mut output) {
+                eprintln!("Failed to rotate audit log {}: {e}", self.path.display());
+            }
+        }
+
+        match output.file.write_all(line.as_bytes()) {
+            Ok(()) => output.size += line.len() as u64,
+            Err(e) => eprintln!("Failed to write audit log entry: {e}"),
+        }
+    }
+}
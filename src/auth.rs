@@ -0,0 +1,157 @@
+use irc::proto::Command;
+
+/// Servers cut AUTHENTICATE payloads into pieces this long.
+const CHUNK_LEN: usize = 400;
+
+/// How the bridge identifies with a network's services.
+#[derive(Clone, Default)]
+pub struct Login {
+    /// Account and password for SASL PLAIN, done while registering
+    sasl: Option<(String, String)>,
+    /// Sent to NickServ once connected, when SASL isn't set up or didn't log us in
+    nickserv_password: Option<String>,
+}
+
+impl Login {
+    pub fn new(
+        sasl_username: Option<String>,
+        sasl_password: Option<String>,
+        nickserv_password: Option<String>,
+    ) -> anyhow::Result<Self> {
+        let sasl = match (sasl_username, sasl_password) {
+            (Some(username), Some(password)) => Some((username, password)),
+            (None, Some(_)) => anyhow::bail!("sasl_password needs a sasl_username or nickname"),
+            _ => None,
+        };
+
+        Ok(Self {
+            sasl,
+            nickserv_password,
+        })
+    }
+
+    pub fn uses_sasl(&self) -> bool {
+        self.sasl.is_some()
+    }
+
+    pub fn nickserv_password(&self) -> Option<&str> {
+        self.nickserv_password.as_deref()
+    }
+
+    /// The answer to the server's `AUTHENTICATE +`, in as many messages as it takes.
+    pub fn sasl_plain(&self) -> Vec<Command> {
+        let Some((ref username, ref password)) = self.sasl else {
+            return Vec::new();
+        };
+
+        let payload = base64(format!("{username}\0{username}\0{password}").as_bytes());
+        let mut messages: Vec<Command> = payload
+            .as_bytes()
+            .chunks(CHUNK_LEN)
+            .map(|chunk| Command::AUTHENTICATE(String::from_utf8_lossy(chunk).into_owned()))
+            .collect();
+        // a full last chunk would make the server wait for more
+        if payload.len() % CHUNK_LEN == 0 {
+            messages.push(Command::AUTHENTICATE(String::from("+")));
+        }
+
+        messages
+    }
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::with_capacity((bytes.len() + 2) / 3 * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | u32::from(b) << (16 - 8 * i));
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(char::from(ALPHABET[((n >> (18 - 6 * i)) & 63) as usize]));
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{base64, Login, CHUNK_LEN};
+    use irc::proto::Command;
+
+    fn payloads(login: &Login) -> Vec<String> {
+        login
+            .sasl_plain()
+            .into_iter()
+            .map(|command| match command {
+                Command::AUTHENTICATE(payload) => payload,
+                _ => panic!("not an AUTHENTICATE"),
+            })
+            .collect()
+    }
+
+    /// A login whose SASL PLAIN payload is `len` bytes before encoding.
+    fn login_of_len(len: usize) -> Login {
+        let password = "p".repeat(len - "u\0u\0".len());
+        Login::new(Some(String::from("u")), Some(password), None).unwrap()
+    }
+
+    #[test]
+    fn base64_padding() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foob"), "Zm9vYg==");
+        assert_eq!(base64(b"fooba"), "Zm9vYmE=");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64(b"\0\xff\xfe"), "AP/+");
+    }
+
+    #[test]
+    fn sasl_plain_payload() {
+        let login = Login::new(
+            Some(String::from("bot")),
+            Some(String::from("hunter2")),
+            None,
+        );
+        assert_eq!(payloads(&login.unwrap()), [base64(b"bot\0bot\0hunter2")]);
+        assert!(Login::default().sasl_plain().is_empty());
+    }
+
+    #[test]
+    fn sasl_plain_chunks() {
+        // 300 bytes are exactly one chunk once encoded
+        let full = CHUNK_LEN / 4 * 3;
+
+        let payloads_short = payloads(&login_of_len(full - 3));
+        assert_eq!(payloads_short.len(), 1);
+        assert_eq!(payloads_short[0].len(), CHUNK_LEN - 4);
+
+        // padding makes this a full chunk too
+        let payloads_padded = payloads(&login_of_len(full - 1));
+        assert_eq!(payloads_padded.len(), 2);
+        assert_eq!(payloads_padded[1], "+");
+
+        let payloads_full = payloads(&login_of_len(full));
+        assert_eq!(payloads_full.len(), 2);
+        assert_eq!(payloads_full[0].len(), CHUNK_LEN);
+        assert_eq!(payloads_full[1], "+");
+
+        let payloads_over = payloads(&login_of_len(full + 1));
+        assert_eq!(payloads_over.len(), 2);
+        assert_eq!(payloads_over[0].len(), CHUNK_LEN);
+        assert_eq!(payloads_over[1].len(), 4);
+
+        let payloads_two = payloads(&login_of_len(full * 2));
+        assert_eq!(payloads_two.len(), 3);
+        assert_eq!(payloads_two[2], "+");
+    }
+}
@@ -0,0 +1,63 @@
+use anyhow::{anyhow, Result};
+use encoding_rs::{Encoding, UTF_8, WINDOWS_1252};
+use std::borrow::Cow;
+
+/// The encoding we tell the irc crate to use when transcoding. Every byte maps to a char and back,
+/// so the raw bytes off the wire survive decoding and we can pick the real encoding ourselves.
+pub const WIRE_ENCODING: &str = "windows-1252";
+
+#[derive(Clone, Copy)]
+pub struct Transcoder {
+    fallback: Option<&'static Encoding>,
+    outgoing: &'static Encoding,
+}
+
+impl Transcoder {
+    pub fn new(fallback: Option<&str>, outgoing: Option<&str>) -> Result<Self> {
+        let lookup = |label: &str| {
+            Encoding::for_label(label.as_bytes()).ok_or_else(|| anyhow!("Unknown encoding {label}"))
+        };
+
+        Ok(Self {
+            fallback: fallback.map(lookup).transpose()?,
+            outgoing: outgoing.map(lookup).transpose()?.unwrap_or(UTF_8),
+        })
+    }
+
+    /// Whether the irc crate needs to be put in [`WIRE_ENCODING`] mode.
+    pub fn enabled(&self) -> bool {
+        self.fallback.is_some() || self.outgoing != UTF_8
+    }
+
+    /// Turns text read off IRC back into what the sender meant. Valid UTF-8 is kept as is, anything
+    /// else goes through the fallback encoding.
+    pub fn decode<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        if !self.enabled() {
+            return Cow::Borrowed(text);
+        }
+
+        let (bytes, _, _) = WINDOWS_1252.encode(text);
+
+        match String::from_utf8(bytes.into_owned()) {
+            Ok(s) => Cow::Owned(s),
+            Err(e) => {
+                let (decoded, _) = self
+                    .fallback
+                    .unwrap_or(UTF_8)
+                    .decode_without_bom_handling(e.as_bytes());
+                Cow::Owned(decoded.into_owned())
+            }
+        }
+    }
+
+    /// Prepares text for sending to IRC in the outgoing encoding.
+    pub fn encode<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        if !self.enabled() {
+            return Cow::Borrowed(text);
+        }
+
+        let (bytes, _, _) = self.outgoing.encode(text);
+        let (wire, _) = WINDOWS_1252.decode_without_bom_handling(&bytes);
+        Cow::Owned(wire.into_owned())
+    }
+}
@@ -0,0 +1,279 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+use serenity::model::{
+    guild::Role,
+    id::{RoleId, UserId},
+};
+
+/// How many of the mIRC colours nicks are spread over.
+const COLOURS: u8 = 12;
+
+/// What the mIRC colours look like in most clients, for matching role colours to.
+const MIRC_PALETTE: [[u8; 3]; 16] = [
+    [0xff, 0xff, 0xff],
+    [0x00, 0x00, 0x00],
+    [0x00, 0x00, 0x7f],
+    [0x00, 0x93, 0x00],
+    [0xff, 0x00, 0x00],
+    [0x7f, 0x00, 0x00],
+    [0x9c, 0x00, 0x9c],
+    [0xfc, 0x7f, 0x00],
+    [0xff, 0xff, 0x00],
+    [0x00, 0xfc, 0x00],
+    [0x00, 0x93, 0x93],
+    [0x00, 0xff, 0xff],
+    [0x00, 0x00, 0xfc],
+    [0xff, 0x00, 0xff],
+    [0x7f, 0x7f, 0x7f],
+    [0xd2, 0xd2, 0xd2],
+];
+
+/// The IRC colour of each Discord user's nick, remembered by user ID so it survives renames.
+pub struct NickColours {
+    /// From the config: user IDs or display names to colours
+    overrides: HashMap<String, u8>,
+    assigned: Arc<Mutex<HashMap<u64, u8>>>,
+    /// Where assigned colours are kept between restarts, if anywhere
+    path: Option<Arc<PathBuf>>,
+    /// Held while the file is written, so two saves don't share the temporary file
+    saving: Arc<tokio::sync::Mutex<()>>,
+    /// What each mIRC colour looks like, if nicks should get their top role's colour
+    palette: Option<Vec<[u8; 3]>>,
+}
+
+impl NickColours {
+    pub fn load(path: Option<String>, overrides: HashMap<String, u8>) -> anyhow::Result<Self> {
+        if let Some((nick, colour)) = overrides.iter().find(|(_, &colour)| colour > 15) {
+            anyhow::bail!("nick_colours has {colour} for {nick}, mIRC colours go from 0 to 15");
+        }
+
+        let assigned = match &path {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(data) => serde_json::from_str(&data)?,
+                Err(e) if e.kind() == ErrorKind::NotFound => HashMap::new(),
+                Err(e) => return Err(e.into()),
+            },
+            None => HashMap::new(),
+        };
+
+        Ok(Self {
+            overrides,
+            assigned: Arc::new(Mutex::new(assigned)),
+            path: path.map(|path| Arc::new(PathBuf::from(path))),
+            saving: Arc::default(),
+            palette: None,
+        })
+    }
+
+    /// Colours nicks like their top coloured role. `palette` is what the mIRC colours look like,
+    /// as `#rrggbb`, in order from 0. Leaving out the end of it means those never get picked.
+    pub fn with_role_colours(mut self, palette: Option<&[String]>) -> anyhow::Result<Self> {
+        let palette = match palette {
+            Some(palette) => {
+                anyhow::ensure!(
+                    !palette.is_empty() && palette.len() <= MIRC_PALETTE.len(),
+                    "irc_palette needs between 1 and 16 colours"
+                );
+                palette
+                    .iter()
+                    .map(|hex| parse_hex(hex).with_context(|| format!("Invalid colour {hex}")))
+                    .collect::<anyhow::Result<_>>()?
+            }
+            None => MIRC_PALETTE.to_vec(),
+        };
+        self.palette = Some(palette);
+        Ok(self)
+    }
+
+    /// The mIRC colour closest to the colour of the highest coloured role in `member_roles`, if
+    /// role colours are on and they have one.
+    pub fn role_colour(
+        &self,
+        member_roles: &[RoleId],
+        roles: &HashMap<RoleId, Role>,
+    ) -> Option<u8> {
+        let palette = self.palette.as_ref()?;
+        let top = member_roles
+            .iter()
+            .filter_map(|id| roles.get(id))
+            .filter(|role| role.colour.0 != 0)
+            .max_by_key(|role| role.position)?;
+        let [r, g, b] = [top.colour.r(), top.colour.g(), top.colour.b()].map(i32::from);
+
+        let closest = palette.iter().enumerate().min_by_key(|(_, colour)| {
+            let [pr, pg, pb] = colour.map(i32::from);
+            (r - pr).pow(2) + (g - pg).pow(2) + (b - pb).pow(2)
+        })?;
+        u8::try_from(closest.0).ok()
+    }
+
+    /// The colour for someone's nick. Colours from the config win over `role_colour`, and nicks
+    /// without either get one picked for them.
+    pub fn colour(&self, user_id: UserId, nick: &str, role_colour: Option<u8>) -> u8 {
+        let id = user_id.0.get();
+        if let Some(&colour) = self
+            .overrides
+            .get(&id.to_string())
+            .or_else(|| self.overrides.get(nick))
+        {
+            return colour;
+        }
+        if let Some(colour) = role_colour {
+            return colour;
+        }
+
+        let mut assigned = self.assigned.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(&colour) = assigned.get(&id) {
+            return colour;
+        }
+
+        // same pick as before colours were remembered, so nobody changes colour on upgrade
+        let first_char = nick.chars().next().unwrap_or_default();
+        let sum = usize::try_from(u32::from(first_char)).unwrap_or_default() + nick.len();
+        let colour = u8::try_from(sum % usize::from(COLOURS)).unwrap();
+        assigned.insert(id, colour);
+        drop(assigned);
+
+        self.save();
+        colour
+    }
+
+    /// Writes the assigned colours out in the background, so sending a message doesn't wait on
+    /// the disk.
+    fn save(&self) {
+        let Some(path) = self.path.clone() else {
+            return;
+        };
+        let (assigned, saving) = (self.assigned.clone(), self.saving.clone());
+
+        tokio::spawn(async move {
+            let _saving = saving.lock().await;
+            // taken now rather than when spawned, so an older save can't land after a newer one
+            let data = serde_json::to_string(&*assigned.lock().unwrap_or_else(|e| e.into_inner()));
+            if let Err(e) = write(&path, data).await {
+                eprintln!("Failed to save nick colours: {e}");
+            }
+        });
+    }
+}
+
+async fn write(path: &Path, data: serde_json::Result<String>) -> io::Result<()> {
+    // written next to the real file first so a crash halfway doesn't lose every colour
+    let tmp = path.with_extension("tmp");
+    tokio::fs::write(&tmp, data?).await?;
+    tokio::fs::rename(tmp, path).await
+}
+
+fn parse_hex(hex: &str) -> anyhow::Result<[u8; 3]> {
+    let digits = hex.strip_prefix('#').unwrap_or(hex);
+    anyhow::ensure!(digits.len() == 6 && digits.is_ascii(), "expected #rrggbb");
+    let channel = |at: usize| u8::from_str_radix(&digits[at..at + 2], 16);
+    Ok([channel(0)?, channel(2)?, channel(4)?])
+}
+
+/// The closest of Discord's eight ANSI colours to each mIRC colour, as an offset from 30 (text)
+/// or 40 (background).
+const ANSI_COLOURS: [u8; 16] = [7, 0, 4, 2, 1, 1, 5, 3, 3, 2, 6, 6, 4, 5, 0, 7];
+
+#[derive(Clone, Copy, Default, PartialEq)]
+struct Style {
+    bold: bool,
+    underline: bool,
+    fg: Option<u8>,
+    bg: Option<u8>,
+}
+
+impl Style {
+    fn escape(self) -> String {
+        let mut codes = vec![String::from("0")];
+        if self.bold {
+            codes.push(String::from("1"));
+        }
+        if self.underline {
+            codes.push(String::from("4"));
+        }
+        if let Some(fg) = self.fg {
+            codes.push((30 + ANSI_COLOURS[usize::from(fg % 16)]).to_string());
+        }
+        if let Some(bg) = self.bg {
+            codes.push((40 + ANSI_COLOURS[usize::from(bg % 16)]).to_string());
+        }
+        format!("\x1b[{}m", codes.join(";"))
+    }
+}
+
+/// Up to two digits of a colour code at the start of `chars`.
+fn colour_number(chars: &mut std::iter::Peekable<std::str::Chars<'_>>) -> Option<u8> {
+    let mut number = None;
+    for _ in 0..2 {
+        match chars.peek().and_then(|c| c.to_digit(10)) {
+            Some(digit) => {
+                number = Some(number.unwrap_or(0) * 10 + u8::try_from(digit).unwrap());
+                chars.next();
+            }
+            None => break,
+        }
+    }
+    number
+}
+
+/// An IRC message with colours in it as an ```ansi code block, which is the only way Discord shows
+/// coloured text. `None` if there are no colours, the usual markdown conversion is better then.
+/// Mentions and emoji don't work in code blocks, so this is opt-in per channel.
+pub fn irc_to_ansi(message: &str) -> Option<String> {
+    if !message.contains('\x03') {
+        return None;
+    }
+
+    let message = message
+        .strip_prefix("\x01ACTION ")
+        .and_then(|s| s.strip_suffix('\x01'))
+        .map_or_else(|| message.to_owned(), |s| format!("* {s}"));
+
+    let mut text = String::with_capacity(message.len() + 16);
+    let mut style = Style::default();
+    let mut chars = message.chars().peekable();
+    while let Some(c) = chars.next() {
+        let before = style;
+        match c {
+            '\x02' => style.bold = !style.bold,
+            '\x1f' => style.underline = !style.underline,
+            '\x0f' => style = Style::default(),
+            '\x03' => match colour_number(&mut chars) {
+                Some(fg) => {
+                    style.fg = Some(fg);
+                    if chars.peek() == Some(&',') {
+                        let mut after_comma = chars.clone();
+                        after_comma.next();
+                        if let Some(bg) = colour_number(&mut after_comma) {
+                            style.bg = Some(bg);
+                            chars = after_comma;
+                        }
+                    }
+                }
+                None => {
+                    style.fg = None;
+                    style.bg = None;
+                }
+            },
+            // nothing to show these as
+            '\x1d' | '\x11' | '\x16' | '\x1e' => {}
+            c => text.push(c),
+        }
+        if style != before {
+            text.push_str(&style.escape());
+        }
+    }
+
+    // a ``` in the message would end the block early, and ones split up by colour codes still
+    // count, so no backtick gets to sit next to another
+    let text = text.replace('`', "`\u{200b}");
+    Some(format!("```ansi\n{text}\n```"))
+}
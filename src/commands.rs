@@ -0,0 +1,901 @@
+use crate::{
+    irc_discord::IrcQuery,
+    locale::Locale,
+    pages::Paged,
+    permissions::{Caller, PermissionMatrix},
+    private::thread_for,
+    ChannelMappingKey, Direction, IrcQueryKey, LatencyKey, LinksKey, LocaleKey, PagedKey,
+    PausesKey, PendingRepliesKey, PermissionsKey, QueryThreadsKey, SendersKey, TranscoderKey,
+};
+use irc::proto::{message::Tag, Command, Message};
+use serenity::{
+    builder::{
+        CreateCommand, CreateCommandOption, CreateInteractionResponse,
+        CreateInteractionResponseMessage,
+    },
+    client::Context,
+    model::{
+        application::{
+            CommandDataOption, CommandDataOptionValue, CommandInteraction, CommandOptionType,
+            CommandType, ComponentInteraction, ResolvedTarget,
+        },
+        id::UserId,
+        Permissions,
+    },
+    prelude::TypeMap,
+};
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+use tokio::{
+    sync::{oneshot, RwLock},
+    time::timeout,
+};
+
+/// How long to wait for IRC to answer before responding anyway. Discord wants a response to an
+/// interaction within 3 seconds.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Commands waiting for IRC to answer something they sent. With labeled-response the server tells
+/// us which command a reply is for, otherwise we go by the channel it mentions.
+#[derive(Default)]
+pub struct PendingReplies {
+    by_channel: HashMap<String, oneshot::Sender<String>>,
+    by_label: HashMap<String, oneshot::Sender<String>>,
+    labels: bool,
+    next_label: u64,
+}
+
+impl PendingReplies {
+    /// Set on every connection, depending on whether the server acked labeled-response.
+    pub fn set_labels(&mut self, enabled: bool) {
+        self.labels = enabled;
+        self.by_channel.clear();
+        self.by_label.clear();
+    }
+
+    /// A fresh label for a command, if the server supports them.
+    pub fn label(&mut self) -> Option<String> {
+        if !self.labels {
+            return None;
+        }
+
+        self.next_label += 1;
+        Some(format!("dircord{}", self.next_label))
+    }
+
+    /// Registers a command that wants an answer. Send it with the label, if there is one.
+    pub fn wait(&mut self, channel: &str) -> (Option<String>, oneshot::Receiver<String>) {
+        let (tx, rx) = oneshot::channel();
+        let label = self.label();
+
+        match label {
+            Some(ref label) => {
+                // whatever timed out isn't getting its answer anymore
+                self.by_label.retain(|_, tx| !tx.is_closed());
+                self.by_label.insert(label.clone(), tx);
+            }
+            None => {
+                self.by_channel.insert(channel.to_lowercase(), tx);
+            }
+        }
+
+        (label, rx)
+    }
+
+    /// Hands a reply to whoever is waiting on it. Returns false if nobody was.
+    pub fn resolve(&mut self, label: Option<&str>, channel: &str, reply: String) -> bool {
+        let tx = if self.labels {
+            // unlabeled replies aren't answers to anything we asked
+            label.and_then(|label| self.by_label.remove(label))
+        } else {
+            self.by_channel.remove(&channel.to_lowercase())
+        };
+
+        match tx {
+            Some(tx) => tx.send(reply).is_ok(),
+            None => false,
+        }
+    }
+}
+
+/// A command with a labeled-response label attached, if it has one.
+pub fn labeled(command: Command, label: Option<String>) -> Message {
+    Message {
+        tags: label.map(|label| vec![Tag(String::from("label"), Some(label))]),
+        prefix: None,
+        command,
+    }
+}
+
+/// Channels relaying has been paused in at runtime, and which way.
+#[derive(Default)]
+pub struct Pauses(HashMap<String, Direction>);
+
+impl Pauses {
+    pub fn pause(&mut self, channel: &str, direction: Direction) {
+        let merged = match self.0.get(channel) {
+            Some(&paused) if paused != direction => Direction::Both,
+            _ => direction,
+        };
+        self.0.insert(channel.to_owned(), merged);
+    }
+
+    pub fn resume(&mut self, channel: &str, direction: Direction) {
+        let Some(&paused) = self.0.get(channel) else {
+            return;
+        };
+
+        let remaining = match (paused, direction) {
+            (_, Direction::Both) => None,
+            (Direction::Both, Direction::IrcToDiscord) => Some(Direction::DiscordToIrc),
+            (Direction::Both, Direction::DiscordToIrc) => Some(Direction::IrcToDiscord),
+            (paused, resumed) if paused == resumed => None,
+            (paused, _) => Some(paused),
+        };
+
+        match remaining {
+            Some(direction) => self.0.insert(channel.to_owned(), direction),
+            None => self.0.remove(channel),
+        };
+    }
+
+    pub fn to_irc(&self, channel: &str) -> bool {
+        self.0.get(channel).map_or(true, |d| !d.to_irc())
+    }
+
+    pub fn to_discord(&self, channel: &str) -> bool {
+        self.0.get(channel).map_or(true, |d| !d.to_discord())
+    }
+}
+
+/// Slash commands registered in every guild the bot is in.
+pub fn definitions() -> Vec<CreateCommand> {
+    vec![
+        CreateCommand::new("kick")
+            .description("Kick someone from the bridged IRC channel")
+            .default_member_permissions(Permissions::KICK_MEMBERS)
+            .dm_permission(false)
+            .add_option(
+                CreateCommandOption::new(CommandOptionType::String, "nick", "Their IRC nick")
+                    .required(true),
+            )
+            .add_option(CreateCommandOption::new(
+                CommandOptionType::String,
+                "reason",
+                "Shown on IRC",
+            )),
+        CreateCommand::new("mode")
+            .description("Set modes on the bridged IRC channel")
+            .default_member_permissions(Permissions::MANAGE_CHANNELS)
+            .dm_permission(false)
+            .add_option(
+                CreateCommandOption::new(CommandOptionType::String, "flags", "e.g. +b or -m")
+                    .required(true),
+            )
+            .add_option(CreateCommandOption::new(
+                CommandOptionType::String,
+                "args",
+                "Arguments for the flags, separated by spaces",
+            )),
+        CreateCommand::new("bridge")
+            .description("Control the bridge")
+            .default_member_permissions(Permissions::MANAGE_GUILD)
+            .dm_permission(false)
+            .add_option(pause_option("pause", "Stop relaying for a while"))
+            .add_option(pause_option("resume", "Start relaying again")),
+        CreateCommand::new("lag")
+            .description("Show how fast IRC and Discord are answering the bridge"),
+        CreateCommand::new("oplist")
+            .description("Show who's an operator or voiced in the bridged IRC channel")
+            .dm_permission(false),
+        CreateCommand::new("IRC info")
+            .kind(CommandType::Message)
+            .dm_permission(false),
+        CreateCommand::new("IRC info")
+            .kind(CommandType::User)
+            .dm_permission(false),
+        CreateCommand::new("debug")
+            .description("Look at the bridge's insides")
+            .default_member_permissions(Permissions::ADMINISTRATOR)
+            .dm_permission(false)
+            .add_option(CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "caches",
+                "Show what's cached and how much is waiting to go to Discord",
+            )),
+        CreateCommand::new("query")
+            .description("Open the private thread for talking to a nick in query_threads")
+            .default_member_permissions(Permissions::MANAGE_THREADS)
+            .dm_permission(false)
+            .add_option(
+                CreateCommandOption::new(CommandOptionType::String, "nick", "Their IRC nick")
+                    .required(true),
+            ),
+        CreateCommand::new("reload")
+            .description("Read the config again, for changes to bridged channels and webhooks")
+            .default_member_permissions(Permissions::ADMINISTRATOR)
+            .dm_permission(false),
+    ]
+}
+
+fn pause_option(name: &str, description: &str) -> CreateCommandOption {
+    CreateCommandOption::new(CommandOptionType::SubCommand, name, description)
+        .add_sub_option(CreateCommandOption::new(
+            CommandOptionType::String,
+            "channel",
+            "IRC channel, defaults to the one bridged here",
+        ))
+        .add_sub_option(
+            CreateCommandOption::new(CommandOptionType::String, "direction", "Defaults to both")
+                .add_string_choice("both", "both")
+                .add_string_choice("irc_to_discord", "irc_to_discord")
+                .add_string_choice("discord_to_irc", "discord_to_irc"),
+        )
+}
+
+/// What a command answers with.
+enum Reply {
+    Text(String),
+    /// Too long for one message, only the caller sees these
+    Pages(Paged),
+}
+
+impl From<String> for Reply {
+    fn from(text: String) -> Self {
+        Self::Text(text)
+    }
+}
+
+pub async fn handle(ctx: &Context, command: &CommandInteraction) {
+    let result = match command.data.name.as_str() {
+        "kick" => kick(ctx, command).await.map(Reply::from),
+        "mode" => mode(ctx, command).await.map(Reply::from),
+        "bridge" => bridge(ctx, command).await.map(Reply::from),
+        "lag" => lag(ctx, command).await.map(Reply::from),
+        "debug" => debug(ctx, command).await,
+        "oplist" => oplist(ctx, command).await,
+        "IRC info" => irc_info(ctx, command).await.map(Reply::from),
+        "query" => query(ctx, command).await.map(Reply::from),
+        "reload" => reload(ctx, command).await.map(Reply::from),
+        _ => return,
+    };
+
+    // errors only go to whoever ran the command, and so does anything from a context menu
+    let message = match result {
+        Ok(Reply::Text(content)) => CreateInteractionResponseMessage::new()
+            .content(content)
+            .ephemeral(command.data.kind != CommandType::ChatInput),
+        Ok(Reply::Pages(paged)) => {
+            let id = command.id.0.get();
+            let message = paged.response(id, 0).ephemeral(true);
+            let data = ctx.data.read().await;
+            data.get::<PagedKey>()
+                .unwrap()
+                .lock()
+                .unwrap()
+                .insert(id, paged);
+            message
+        }
+        Err(content) => CreateInteractionResponseMessage::new()
+            .content(content)
+            .ephemeral(true),
+    };
+
+    if let Err(e) = command
+        .create_response(ctx, CreateInteractionResponse::Message(message))
+        .await
+    {
+        eprintln!("Failed to respond to /{}: {e}", command.data.name);
+    }
+}
+
+/// The next/previous buttons on paged responses.
+pub async fn handle_component(ctx: &Context, component: &ComponentInteraction) {
+    let data = ctx.data.read().await;
+    let page = data
+        .get::<PagedKey>()
+        .unwrap()
+        .lock()
+        .unwrap()
+        .turn(&component.data.custom_id);
+
+    let response = match page {
+        Some(page) => CreateInteractionResponse::UpdateMessage(page),
+        None if component.data.custom_id.starts_with("page:") => {
+            CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new()
+                    .content(locale(&data).text(
+                        "pages_gone",
+                        "These pages are gone, run the command again",
+                        &[],
+                    ))
+                    .ephemeral(true),
+            )
+        }
+        None => return,
+    };
+    drop(data);
+
+    if let Err(e) = component.create_response(ctx, response).await {
+        eprintln!("Failed to turn the page: {e}");
+    }
+}
+
+async fn kick(ctx: &Context, command: &CommandInteraction) -> Result<String, String> {
+    let data = ctx.data.read().await;
+    require(&data, "kick", command, Permissions::KICK_MEMBERS)?;
+
+    let channel = irc_channel(&data, command)?;
+    let sender = data.get::<SendersKey>().unwrap().get(channel);
+    let transcoder = data.get::<TranscoderKey>().unwrap();
+
+    let nick = option(&command.data.options, "nick").unwrap_or_default();
+    let reason = format!(
+        "{} (by {} on Discord)",
+        option(&command.data.options, "reason").unwrap_or("Kicked"),
+        command.user.name
+    );
+
+    sender
+        .send_kick(channel, transcoder.encode(nick), transcoder.encode(&reason))
+        .map_err(|e| {
+            locale(&data).text(
+                "kick_failed",
+                "Couldn't kick {nick}: {error}",
+                &[("nick", &nick), ("error", &e)],
+            )
+        })?;
+
+    Ok(locale(&data).text(
+        "kicking",
+        "Kicking **{nick}** from {channel}",
+        &[("nick", &nick), ("channel", &channel)],
+    ))
+}
+
+async fn mode(ctx: &Context, command: &CommandInteraction) -> Result<String, String> {
+    let data = ctx.data.read().await;
+    require(&data, "mode", command, Permissions::MANAGE_CHANNELS)?;
+
+    let channel = irc_channel(&data, command)?;
+    let senders = data.get::<SendersKey>().unwrap();
+    let sender = senders.get(channel);
+
+    let transcoder = data.get::<TranscoderKey>().unwrap();
+    let mut args = vec![
+        channel.to_owned(),
+        option(&command.data.options, "flags")
+            .unwrap_or_default()
+            .to_owned(),
+    ];
+    args.extend(
+        option(&command.data.options, "args")
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(|arg| transcoder.encode(arg).into_owned()),
+    );
+
+    let (label, reply) = data.get::<PendingRepliesKey>().unwrap()[senders.network(channel)]
+        .lock()
+        .await
+        .wait(channel);
+
+    sender
+        .send(labeled(Command::Raw("MODE".into(), args), label))
+        .map_err(|e| {
+            locale(&data).text(
+                "mode_failed",
+                "Couldn't set modes: {error}",
+                &[("error", &e)],
+            )
+        })?;
+
+    let unanswered = locale(&data).text(
+        "no_answer",
+        "Sent to {channel}, but IRC hasn't answered yet",
+        &[("channel", &channel)],
+    );
+    drop(data); // don't hold the lock while waiting on IRC
+
+    match timeout(REPLY_TIMEOUT, reply).await {
+        Ok(Ok(reply)) => Ok(reply),
+        _ => Ok(unanswered),
+    }
+}
+
+async fn bridge(ctx: &Context, command: &CommandInteraction) -> Result<String, String> {
+    let data = ctx.data.read().await;
+    let Some((name, options)) = subcommand(command) else {
+        return Err(unknown_subcommand(&data));
+    };
+    require(&data, name, command, Permissions::MANAGE_GUILD)?;
+
+    let channel = match option(options, "channel") {
+        Some(channel) => channel,
+        None => irc_channel(&data, command)?,
+    };
+
+    set_paused(
+        data.get::<PausesKey>().unwrap(),
+        data.get::<ChannelMappingKey>().unwrap(),
+        locale(&data),
+        name == "pause",
+        channel,
+        option(options, "direction"),
+    )
+}
+
+async fn lag(ctx: &Context, command: &CommandInteraction) -> Result<String, String> {
+    let data = ctx.data.read().await;
+    require(&data, "lag", command, Permissions::empty())?;
+
+    let latencies = data.get::<LatencyKey>().unwrap();
+    let senders = data.get::<SendersKey>().unwrap();
+    let locale = locale(&data);
+    if let [latency] = &latencies[..] {
+        return Ok(latency.lock().unwrap().summary(locale));
+    }
+
+    let summaries = latencies
+        .iter()
+        .enumerate()
+        .map(|(network, latency)| {
+            let summary = latency.lock().unwrap().summary(locale);
+            format!("**{}**\n{summary}", senders.name(network))
+        })
+        .collect::<Vec<_>>();
+    Ok(summaries.join("\n\n"))
+}
+
+async fn debug(ctx: &Context, command: &CommandInteraction) -> Result<Reply, String> {
+    let data = ctx.data.read().await;
+    if !matches!(subcommand(command), Some(("caches", _))) {
+        return Err(unknown_subcommand(&data));
+    }
+    require(&data, "debug", command, Permissions::ADMINISTRATOR)?;
+    let title = locale(&data).text("debug_caches", "Caches", &[]);
+    drop(data); // the report needs the lock too
+
+    let report = cache_report(&ctx.data).await?;
+    Ok(Reply::Pages(Paged::new(title, &report, true)))
+}
+
+async fn oplist(ctx: &Context, command: &CommandInteraction) -> Result<Reply, String> {
+    let data = ctx.data.read().await;
+    require(&data, "oplist", command, Permissions::empty())?;
+
+    let channel = irc_channel(&data, command)?.to_owned();
+    let network = data.get::<SendersKey>().unwrap().network(&channel);
+    let title = locale(&data).text(
+        "oplist_title",
+        "Status in {channel}",
+        &[("channel", &channel)],
+    );
+    drop(data);
+
+    let list = query_irc(&ctx.data, network, |tx| IrcQuery::Ops(channel.clone(), tx)).await?;
+    // big channels have more ops than fit in a message
+    Ok(Reply::Pages(Paged::new(title, &list, false)))
+}
+
+async fn reload(ctx: &Context, command: &CommandInteraction) -> Result<String, String> {
+    let data = ctx.data.read().await;
+    require(&data, "reload", command, Permissions::ADMINISTRATOR)?;
+    let locale = data.get::<LocaleKey>().unwrap().clone();
+    drop(data); // reloading needs the lock too
+
+    let reloaded = crate::reload_config(&ctx.http, &ctx.data)
+        .await
+        .map_err(|e| {
+            locale.text(
+                "reload_failed",
+                "Couldn't reload the config: {error}",
+                &[("error", &format!("{e:#}"))],
+            )
+        })?;
+
+    let list = |channels: &[String]| {
+        if channels.is_empty() {
+            String::from("-")
+        } else {
+            channels.join(", ")
+        }
+    };
+    Ok(locale.text(
+        "reloaded",
+        "Reloaded the config. Now bridging: {added}. No longer bridging: {removed}",
+        &[
+            ("added", &list(&reloaded.added)),
+            ("removed", &list(&reloaded.removed)),
+        ],
+    ))
+}
+
+/// Staff starting a conversation with services before they've said anything.
+async fn query(ctx: &Context, command: &CommandInteraction) -> Result<String, String> {
+    let data = ctx.data.read().await;
+    require(&data, "query", command, Permissions::MANAGE_THREADS)?;
+    let locale = data.get::<LocaleKey>().unwrap().clone();
+
+    let Some(query_threads) = data.get::<QueryThreadsKey>().unwrap().clone() else {
+        return Err(locale.text("query_off", "The bridge doesn't have query_threads", &[]));
+    };
+    drop(data); // finding the thread can take a few requests
+
+    let nick = option(&command.data.options, "nick").unwrap_or_default();
+    let (watched, known, admin_channel) = {
+        let query_threads = query_threads.lock().unwrap();
+        (
+            query_threads.watches(nick),
+            query_threads.thread(nick),
+            query_threads.admin_channel(),
+        )
+    };
+    if !watched {
+        return Err(locale.text(
+            "query_not_watched",
+            "{nick} isn't in query_threads",
+            &[("nick", &nick)],
+        ));
+    }
+
+    let thread = match known {
+        Some(thread) => thread,
+        None => {
+            let thread = thread_for(&ctx.http, admin_channel, nick)
+                .await
+                .map_err(|e| {
+                    locale.text(
+                        "query_failed",
+                        "Couldn't open a thread for {nick}: {error}",
+                        &[("nick", &nick), ("error", &format!("{e:#}"))],
+                    )
+                })?;
+            query_threads.lock().unwrap().remember(nick, thread);
+            thread
+        }
+    };
+    Ok(locale.text(
+        "query_opened",
+        "Talk to {nick} in <#{thread}>",
+        &[("nick", &nick), ("thread", &thread)],
+    ))
+}
+
+/// For a message relayed from IRC or a Discord member linked to an IRC nick.
+async fn irc_info(ctx: &Context, command: &CommandInteraction) -> Result<String, String> {
+    let data = ctx.data.read().await;
+    require(&data, "irc_info", command, Permissions::empty())?;
+
+    let name = match command.data.target() {
+        Some(ResolvedTarget::Message(message)) if message.webhook_id.is_some() => {
+            message.author.name.clone()
+        }
+        Some(ResolvedTarget::Message(message)) => linked_nick(&data, message.author.id)?,
+        Some(ResolvedTarget::User(user, _)) => linked_nick(&data, user.id)?,
+        _ => {
+            return Err(locale(&data).text(
+                "irc_info_not_irc",
+                "That isn't something from IRC",
+                &[],
+            ))
+        }
+    };
+    // whoever it is, they're most likely on the network this channel is bridged to
+    let network = irc_channel(&data, command).map_or(0, |channel| {
+        data.get::<SendersKey>().unwrap().network(channel)
+    });
+    drop(data);
+
+    query_irc(&ctx.data, network, |tx| IrcQuery::Info(name, tx)).await
+}
+
+fn linked_nick(data: &TypeMap, user_id: UserId) -> Result<String, String> {
+    data.get::<LinksKey>()
+        .unwrap()
+        .lock()
+        .unwrap()
+        .nick(user_id)
+        .map(ToOwned::to_owned)
+        .ok_or_else(|| {
+            locale(data).text(
+                "irc_info_not_linked",
+                "They aren't linked to anyone on IRC",
+                &[],
+            )
+        })
+}
+
+/// Asks every IRC loop what's in its caches. Also used for the SIGUSR2 dump.
+pub async fn cache_report(data: &RwLock<TypeMap>) -> Result<String, String> {
+    let senders = data.read().await.get::<SendersKey>().unwrap().clone();
+    if senders.count() == 1 {
+        return query_irc(data, 0, IrcQuery::Caches).await;
+    }
+
+    let mut reports = Vec::new();
+    for network in 0..senders.count() {
+        let report = query_irc(data, network, IrcQuery::Caches)
+            .await
+            .unwrap_or_else(|e| e);
+        reports.push(format!("== {}\n{report}", senders.name(network)));
+    }
+    Ok(reports.join("\n\n"))
+}
+
+async fn query_irc(
+    data: &RwLock<TypeMap>,
+    network: usize,
+    query: impl FnOnce(oneshot::Sender<String>) -> IrcQuery,
+) -> Result<String, String> {
+    let (tx, rx) = oneshot::channel();
+    let locale = {
+        let data = data.read().await;
+        let locale = data.get::<LocaleKey>().unwrap().clone();
+        data.get::<IrcQueryKey>()
+            .unwrap()
+            .get(&network)
+            .ok_or_else(|| locale.text("irc_not_connected", "IRC isn't connected yet", &[]))?
+            .send(query(tx))
+            .map_err(|_| locale.text("irc_not_running", "The IRC loop isn't running", &[]))?;
+        locale
+    };
+
+    match timeout(REPLY_TIMEOUT, rx).await {
+        Ok(Ok(report)) => Ok(report),
+        _ => Err(locale.text("irc_no_answer", "The IRC loop didn't answer in time", &[])),
+    }
+}
+
+/// Handles `!pause <channel> [direction]` and `!resume` sent to the bridge by an IRC admin.
+/// Returns the reply, or None if the message isn't a command or the caller isn't allowed to use it.
+pub fn irc_command(
+    permissions: &PermissionMatrix,
+    caller: &Caller<'_>,
+    pauses: &Mutex<Pauses>,
+    mapping: &HashMap<String, u64>,
+    locale: &Locale,
+    text: &str,
+) -> Option<String> {
+    let mut words = text.split_whitespace();
+    let pause = match words.next()? {
+        "!pause" => true,
+        "!resume" => false,
+        _ => return None,
+    };
+
+    let name = if pause { "pause" } else { "resume" };
+    if !permissions.check(name, caller).unwrap_or(false) {
+        return None;
+    }
+
+    let Some(channel) = words.next() else {
+        return Some(locale.text(
+            "pause_usage",
+            "Usage: !pause/!resume <channel> [direction]",
+            &[],
+        ));
+    };
+
+    Some(set_paused(pauses, mapping, locale, pause, channel, words.next()).unwrap_or_else(|e| e))
+}
+
+fn set_paused(
+    pauses: &Mutex<Pauses>,
+    mapping: &HashMap<String, u64>,
+    locale: &Locale,
+    pause: bool,
+    channel: &str,
+    direction: Option<&str>,
+) -> Result<String, String> {
+    if !mapping.contains_key(channel) {
+        return Err(locale.text(
+            "not_bridged",
+            "{channel} isn't bridged",
+            &[("channel", &channel)],
+        ));
+    }
+
+    let direction = match direction.unwrap_or("both") {
+        "both" => Direction::Both,
+        "irc_to_discord" => Direction::IrcToDiscord,
+        "discord_to_irc" => Direction::DiscordToIrc,
+        other => {
+            return Err(locale.text(
+                "unknown_direction",
+                "Unknown direction {direction}",
+                &[("direction", &other)],
+            ))
+        }
+    };
+
+    let mut pauses = pauses.lock().unwrap();
+    if pause {
+        pauses.pause(channel, direction);
+        Ok(locale.text(
+            "paused",
+            "Paused relaying in {channel}",
+            &[("channel", &channel)],
+        ))
+    } else {
+        pauses.resume(channel, direction);
+        Ok(locale.text(
+            "resumed",
+            "Resumed relaying in {channel}",
+            &[("channel", &channel)],
+        ))
+    }
+}
+
+/// Glob match for IRC hostmasks like `*!*@staff.example.org`.
+pub fn mask_matches(mask: &str, target: &str) -> bool {
+    fn matches(mask: &[u8], target: &[u8]) -> bool {
+        match (mask.split_first(), target.split_first()) {
+            (None, None) => true,
+            (Some((b'*', rest)), _) => {
+                matches(rest, target) || (!target.is_empty() && matches(mask, &target[1..]))
+            }
+            (Some((b'?', rest)), Some((_, target))) => matches(rest, target),
+            (Some((m, rest)), Some((t, target))) if m.eq_ignore_ascii_case(t) => {
+                matches(rest, target)
+            }
+            _ => false,
+        }
+    }
+
+    matches(mask.as_bytes(), target.as_bytes())
+}
+
+/// Goes by the command's `[permissions]` entry if it has one, otherwise by the Discord permissions
+/// it needs. Discord lets server admins loosen `default_member_permissions`, so check again here.
+fn require(
+    data: &TypeMap,
+    name: &str,
+    command: &CommandInteraction,
+    permissions: Permissions,
+) -> Result<(), String> {
+    let caller = Caller::Discord {
+        user_id: command.user.id,
+        roles: command
+            .member
+            .as_ref()
+            .map_or(&[][..], |m| m.roles.as_slice()),
+    };
+    if let Some(allowed) = data.get::<PermissionsKey>().unwrap().check(name, &caller) {
+        return if allowed {
+            Ok(())
+        } else {
+            Err(locale(data).text(
+                "not_allowed",
+                "You aren't allowed to use {command}",
+                &[("command", &name)],
+            ))
+        };
+    }
+
+    let allowed = command
+        .member
+        .as_ref()
+        .and_then(|m| m.permissions)
+        .map_or(false, |p| p.contains(permissions));
+
+    if allowed {
+        Ok(())
+    } else {
+        Err(locale(data).text(
+            "needs_permission",
+            "You need the {permission} permission to do that",
+            &[("permission", &permissions)],
+        ))
+    }
+}
+
+fn locale(data: &TypeMap) -> &Locale {
+    data.get::<LocaleKey>().unwrap()
+}
+
+fn unknown_subcommand(data: &TypeMap) -> String {
+    locale(data).text("unknown_subcommand", "Unknown subcommand", &[])
+}
+
+/// The IRC channel bridged to the channel a command was run in.
+fn irc_channel<'a>(data: &'a TypeMap, command: &CommandInteraction) -> Result<&'a str, String> {
+    data.get::<ChannelMappingKey>()
+        .unwrap()
+        .iter()
+        .find(|(_, &id)| id == command.channel_id.0.get())
+        .map(|(channel, _)| channel.as_str())
+        .ok_or_else(|| {
+            locale(data).text("not_bridged_here", "This channel isn't bridged to IRC", &[])
+        })
+}
+
+fn option<'a>(options: &'a [CommandDataOption], name: &str) -> Option<&'a str> {
+    options
+        .iter()
+        .find(|o| o.name == name)
+        .and_then(|o| o.value.as_str())
+}
+
+fn subcommand(command: &CommandInteraction) -> Option<(&str, &[CommandDataOption])> {
+    let sub = command.data.options.first()?;
+    match sub.value {
+        CommandDataOptionValue::SubCommand(ref options) => Some((sub.name.as_str(), options)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{mask_matches, Pauses};
+    use crate::Direction;
+
+    const DIRECTIONS: [Direction; 3] = [
+        Direction::Both,
+        Direction::IrcToDiscord,
+        Direction::DiscordToIrc,
+    ];
+
+    /// Whether the channel relays (to IRC, to Discord).
+    fn relaying(pauses: &Pauses) -> (bool, bool) {
+        (pauses.to_irc("#chan"), pauses.to_discord("#chan"))
+    }
+
+    #[test]
+    fn pause_merges_directions() {
+        let mut pauses = Pauses::default();
+        pauses.pause("#chan", Direction::IrcToDiscord);
+        assert_eq!(relaying(&pauses), (true, false));
+        pauses.pause("#chan", Direction::IrcToDiscord);
+        assert_eq!(relaying(&pauses), (true, false));
+        pauses.pause("#chan", Direction::DiscordToIrc);
+        assert_eq!(relaying(&pauses), (false, false));
+        assert!(pauses.to_irc("#other"));
+    }
+
+    #[test]
+    fn resume_matrix() {
+        for paused in DIRECTIONS {
+            for resumed in DIRECTIONS {
+                let mut pauses = Pauses::default();
+                pauses.pause("#chan", paused);
+                pauses.resume("#chan", resumed);
+
+                let expected = match (paused, resumed) {
+                    (_, Direction::Both) => (true, true),
+                    (Direction::Both, Direction::IrcToDiscord) => (false, true),
+                    (Direction::Both, Direction::DiscordToIrc) => (true, false),
+                    (paused, resumed) if paused == resumed => (true, true),
+                    (Direction::IrcToDiscord, _) => (true, false),
+                    (Direction::DiscordToIrc, _) => (false, true),
+                };
+                assert_eq!(relaying(&pauses), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn resume_without_pause() {
+        let mut pauses = Pauses::default();
+        pauses.resume("#chan", Direction::IrcToDiscord);
+        assert_eq!(relaying(&pauses), (true, true));
+    }
+
+    #[test]
+    fn masks() {
+        assert!(mask_matches(
+            "*!*@staff.example.org",
+            "nick!user@staff.example.org"
+        ));
+        assert!(mask_matches("Nick!*@*", "nick!user@host"));
+        assert!(mask_matches("n?ck!*@*", "neck!user@host"));
+        assert!(mask_matches("*", ""));
+        assert!(mask_matches("a**b", "ab"));
+        assert!(!mask_matches(
+            "*!*@staff.example.org",
+            "nick!user@example.org"
+        ));
+        assert!(!mask_matches("n?ck!*@*", "nck!user@host"));
+        assert!(!mask_matches("nick", "nick!user@host"));
+        assert!(!mask_matches("", "nick"));
+    }
+}
@@ -1,26 +1,51 @@
 use crate::{
-    regex, ChannelMappingKey, MembersKey, OptionReplacer, OptionStringKey, RefContentLimitKey,
-    SenderKey, UserIdKey,
+    admin, audit::AuditEntry, colours::NickColours, commands, emoji::EmojiOptions, full_time,
+    irc_discord::strip_user_count, locale::Locale, members, message_cache::CachedMessage, puppets,
+    regex, rehost::Rehost, replace_all_owned, report, short_time, spoilers::Spoilers,
+    AnnounceConfig, AttachmentDimensionsKey, AttachmentPolicy, AttachmentPolicyKey,
+    AttachmentStyle, AuditLogKey, ChannelMappingKey, ChannelOptions, ChannelOptionsKey, ChatLogKey,
+    DmSessionsKey, EmojiOptionsKey, FloodKey, HealthKey, IgnoresKey, IrcStatus, IrcStatusKey,
+    LinksKey, LocaleKey, MembersKey, MessageCacheKey, ModerationKey, NickColoursKey,
+    OptionReplacer, OptionStringKey, PausesKey, PendingRepliesKey, PmInboxKey, PuppetsKey,
+    QueryThreadsKey, RefContentLimitKey, RehostKey, RewrittenNicks, RewrittenNicksKey,
+    ScheduledEventsKey, SendAs, SendersKey, ShardMessengerKey, ShortenerKey, SpoilersKey,
+    StagesKey, TopicInfo, TopicKey, TranscoderKey, TranslatorKey, UrlCleanerKey, UserIdKey,
 };
 use ellipse::Ellipse;
 use fancy_regex::{Captures, Replacer};
 use pulldown_cmark::Parser;
 use serenity::{
     async_trait,
+    cache::Cache,
     client::Context,
-    http::CacheHttp,
+    futures::future::join_all,
+    gateway::{ActivityData, ShardMessenger},
+    http::{CacheHttp, Http},
     model::{
-        channel::{Channel, Message, MessageReference, MessageType},
-        guild::Member,
-        id::GuildId,
-        prelude::{ChannelId, GuildMemberUpdateEvent, Ready, Role, RoleId},
+        application::Interaction,
+        channel::{
+            Attachment, Channel, ChannelType, GuildChannel, Message, MessageReference, MessageType,
+            ReactionType, StageInstance,
+        },
+        event::MessageUpdateEvent,
+        guild::{
+            audit_log::{Action, AuditLogEntry, ChannelAction, MemberAction},
+            Member, ScheduledEvent, ScheduledEventStatus,
+        },
+        id::{GuildId, MessageId},
+        prelude::{ChannelId, GuildMemberUpdateEvent, OnlineStatus, Ready, Role, RoleId},
         user::User,
+        Timestamp,
     },
     prelude::*,
+    utils::{content_safe, ContentSafeOptions},
 };
 use std::borrow::Cow;
 use std::collections::HashMap;
-use std::fmt::Write;
+use std::fmt::{Display, Write};
+use std::num::NonZeroU64;
+use std::time::Duration;
+use tokio::time::timeout;
 
 struct StrChunks<'a> {
     v: &'a str,
@@ -63,15 +88,42 @@ impl<'a> StrChunks<'a> {
     }
 }
 
-async fn create_prefix(msg: &Message, is_reply: bool, http: impl CacheHttp) -> (String, usize) {
-    // it's okay to unwrap here since we know we're in a guild
-    let Ok(nick) = msg.member(http).await.map(|m| m.display_name().to_owned()) else { return ("(reply) ".into(), 400 - "(reply) ".len()) };
+/// Every line of `text` cut to fit in `size`, without the resets Discord formatting leaves at
+/// the ends.
+fn chunk_lines(text: &str, size: usize) -> Vec<String> {
+    text.lines()
+        .flat_map(|line| StrChunks::new(line, size))
+        .map(|chunk| chunk.trim_matches('\u{f}').to_owned())
+        .collect()
+}
+
+async fn display_name(msg: &Message, http: impl CacheHttp) -> Option<String> {
+    msg.member(http)
+        .await
+        .map(|m| m.display_name().to_owned())
+        .ok()
+}
 
+fn format_prefix(
+    nick: Option<&str>,
+    author_id: UserId,
+    colours: &NickColours,
+    role_colour: Option<u8>,
+    is_reply: bool,
+) -> (String, usize) {
+    let Some(nick) = nick else {
+        return ("(reply) ".into(), 400 - "(reply) ".len());
+    };
+
+    // one-letter names have no second letter, the zero-width space goes after the whole name
     let mut chars = nick.char_indices();
-    let first_char = chars.next().unwrap().1;
-    let second_char_offset = chars.next().unwrap().0;
+    chars.next();
+    let mut second_char_offset = nick.len();
+    if let Some((offset, _)) = chars.next() {
+        second_char_offset = offset;
+    }
 
-    let colour_index = (first_char as usize + nick.len()) % 12;
+    let colour_index = colours.colour(author_id, nick, role_colour);
 
     let prefix = format!(
         "{}<\x03{:02}{}\u{200B}{}\x0F> ",
@@ -87,6 +139,653 @@ async fn create_prefix(msg: &Message, is_reply: bool, http: impl CacheHttp) -> (
     (prefix, content_limit)
 }
 
+/// How long fetching a message a link points to can take, they're all fetched at once.
+const PREVIEW_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Quotes for Discord message links in `text`, since the links are useless to IRC users. Only
+/// messages in bridged channels are previewed, everything else might not be meant for IRC.
+pub async fn message_link_previews(
+    http: &Http,
+    cache: &Cache,
+    mapping: &HashMap<String, u64>,
+    text: &str,
+) -> Vec<String> {
+    regex! {
+        static MESSAGE_LINK_RE = r"https://(?:\w+\.)?discord(?:app)?\.com/channels/\d+/(\d+)/(\d+)";
+    }
+
+    let links = MESSAGE_LINK_RE
+        .captures_iter(text)
+        .flatten()
+        .filter_map(|caps| Some((caps[1].parse::<u64>().ok()?, caps[2].parse::<u64>().ok()?)))
+        .filter(|(channel_id, _)| mapping.values().any(|v| v == channel_id))
+        .take(3)
+        .collect::<Vec<_>>();
+
+    let fetches = links.into_iter().map(|(channel_id, message_id)| {
+        let fetch = ChannelId::from(channel_id).message(http, MessageId::from(message_id));
+        timeout(PREVIEW_TIMEOUT, fetch)
+    });
+
+    // ones that don't arrive in time are left out, like ones that are gone
+    join_all(fetches)
+        .await
+        .into_iter()
+        .filter_map(|fetched| fetched.ok()?.ok())
+        .map(|message| {
+            let content = message.content_safe(cache).replace('\n', " ");
+            format!(
+                "> {}: {}",
+                message.author.name,
+                (&*content).truncate_ellipse(300)
+            )
+        })
+        .collect()
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn fetch_reply_context(
+    channel_id: ChannelId,
+    message_id: MessageId,
+    guild_id: Option<GuildId>,
+    members: &[Member],
+    ctx: &Context,
+    roles: &HashMap<RoleId, Role>,
+    emoji: &EmojiOptions,
+    spoilers: &Spoilers,
+    channel: &str,
+    dimensions: bool,
+) -> Option<CachedMessage> {
+    let mut reply = channel_id.message(ctx, message_id).await.ok()?;
+    reply.guild_id = guild_id; // lmao
+
+    let mut content = reply.content.clone();
+    content = content.replace("\r\n", " "); // just in case
+    content = content.replace('\n', " ");
+    let atts: Vec<String> = reply
+        .attachments
+        .iter()
+        .map(|a| describe_attachment(a, &a.url, dimensions))
+        .collect();
+    content = format!("{} {}", content, atts.join(" "));
+
+    content = discord_to_irc_processing(&content, members, ctx, roles, emoji).await;
+    content = spoilers.hide(channel, &content);
+    if let Some(text) = as_action(&content) {
+        content = text.to_owned();
+    }
+
+    Some(CachedMessage {
+        channel_id: reply.channel_id,
+        author_id: reply.author.id,
+        nick: display_name(&reply, ctx).await,
+        content,
+    })
+}
+
+/// Renders an attachment like `[image.png, 1.2 MB] <url>` so IRC users know what they're clicking.
+fn describe_attachment(attachment: &Attachment, url: &str, dimensions: bool) -> String {
+    let mut info = attachment.filename.clone();
+
+    // the type is only worth showing when the filename doesn't give it away
+    if !attachment.filename.contains('.') {
+        if let Some(content_type) = &attachment.content_type {
+            write!(info, ", {content_type}").unwrap();
+        }
+    }
+
+    write!(info, ", {}", human_size(attachment.size)).unwrap();
+
+    if dimensions {
+        if let (Some(width), Some(height)) = (attachment.width, attachment.height) {
+            write!(info, ", {width}x{height}").unwrap();
+        }
+    }
+
+    format!("[{info}] <{url}>")
+}
+
+/// Every attachment described for IRC, with links to wherever they were rehosted if that's set up.
+async fn describe_attachments(
+    attachments: &[Attachment],
+    rehost: Option<&Rehost>,
+    dimensions: bool,
+) -> Vec<String> {
+    let urls = match rehost {
+        Some(rehost) => rehost.urls(attachments).await,
+        None => attachments.iter().map(|a| a.url.clone()).collect(),
+    };
+    attachments
+        .iter()
+        .zip(urls)
+        .map(|(attachment, url)| describe_attachment(attachment, &url, dimensions))
+        .collect()
+}
+
+/// The lines a message's attachments go to IRC as, so ten attachments aren't ten lines unless
+/// that's what the config asks for.
+fn attachment_lines(
+    attachments: &[String],
+    policy: AttachmentPolicy,
+    locale: &Locale,
+    line_limit: usize,
+) -> Vec<String> {
+    let listed = policy
+        .limit
+        .map_or(attachments.len(), |limit| limit.min(attachments.len()));
+    let mut items = attachments[..listed].to_vec();
+    let more = attachments.len() - listed;
+    if more > 0 {
+        items.push(locale.text(
+            "more_attachments",
+            "…and {count} more attachments",
+            &[("count", &more)],
+        ));
+    }
+
+    match policy.style {
+        AttachmentStyle::Separate => items,
+        AttachmentStyle::Inline => {
+            let mut lines: Vec<String> = Vec::new();
+            for item in items {
+                // splitting URLs would break them, so one that doesn't fit gets a line of its own
+                match lines.last_mut() {
+                    Some(line) if line.len() + 1 + item.len() <= line_limit => {
+                        line.push(' ');
+                        line.push_str(&item);
+                    }
+                    _ => lines.push(item),
+                }
+            }
+            lines
+        }
+    }
+}
+
+fn human_size(bytes: u32) -> String {
+    const UNITS: [&str; 3] = ["KB", "MB", "GB"];
+
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+
+    let mut size = f64::from(bytes) / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+/// Looks up who last updated the channel in the audit log. This needs the View Audit Log
+/// permission, so it's fine for it to fail.
+async fn topic_setter(
+    ctx: &Context,
+    members: &Mutex<Vec<Member>>,
+    channel: &GuildChannel,
+) -> Option<String> {
+    let entry = audit_log_entry(
+        ctx,
+        channel.guild_id,
+        Action::Channel(ChannelAction::Update),
+        channel.id.0,
+    )
+    .await?;
+
+    member_name(members, entry.user_id).await
+}
+
+/// Finds the most recent audit log entry for `action` that targets `target`, if it's recent
+/// enough to be about the event being handled and not some older one.
+async fn audit_log_entry(
+    ctx: &Context,
+    guild_id: GuildId,
+    action: Action,
+    target: NonZeroU64,
+) -> Option<AuditLogEntry> {
+    let logs = guild_id
+        .audit_logs(ctx, Some(action), None, None, Some(5))
+        .await
+        .ok()?;
+
+    logs.entries
+        .into_iter()
+        .find(|e| e.target_id.map(|t| t.0) == Some(target))
+        .filter(is_recent)
+}
+
+async fn member_name(members: &Mutex<Vec<Member>>, id: UserId) -> Option<String> {
+    members
+        .lock()
+        .await
+        .iter()
+        .find(|m| m.user.id == id)
+        .map(|m| m.display_name().to_owned())
+}
+
+/// Posts a moderation notice to the moderation channel, or every bridged channel if there isn't
+/// one. Does nothing unless moderation announcements are enabled.
+async fn announce_moderation(
+    ctx_data: &TypeMap,
+    target: &str,
+    what: &str,
+    entry: Option<AuditLogEntry>,
+) {
+    let Some(moderation) = ctx_data.get::<ModerationKey>().unwrap() else {
+        return;
+    };
+    let members = ctx_data.get::<MembersKey>().unwrap();
+    let locale = ctx_data.get::<LocaleKey>().unwrap();
+
+    let mut text = locale.text(
+        "moderation",
+        "[moderation] {target} was {what}",
+        &[("target", &target), ("what", &what)],
+    );
+    if let Some(entry) = entry {
+        if let Some(name) = member_name(members, entry.user_id).await {
+            text = locale.text(
+                "moderation_by",
+                "[moderation] {target} was {what} by {name}",
+                &[("target", &target), ("what", &what), ("name", &name)],
+            );
+        }
+        if let Some(reason) = entry.reason {
+            text = locale.text(
+                "moderation_reason",
+                "{text} ({reason})",
+                &[("text", &text), ("reason", &reason)],
+            );
+        }
+    }
+
+    announce(ctx_data, moderation, &text);
+}
+
+/// Posts an event message to the configured IRC channel, or every bridged channel if there isn't
+/// one.
+fn announce(ctx_data: &TypeMap, config: &AnnounceConfig, text: &str) {
+    let senders = ctx_data.get::<SendersKey>().unwrap();
+    let mapping = ctx_data.get::<ChannelMappingKey>().unwrap();
+    let channel_options = ctx_data.get::<ChannelOptionsKey>().unwrap();
+    let transcoder = ctx_data.get::<TranscoderKey>().unwrap();
+
+    let text = transcoder.encode(text);
+    let send = |channel: &str| {
+        let sent = channel_options
+            .get(channel)
+            .and_then(|o| o.events_as)
+            .unwrap_or(SendAs::Notice)
+            .send(senders.get(channel), channel, &text);
+        if let Err(e) = sent {
+            eprintln!("Failed to announce in {channel}: {e}");
+        }
+    };
+
+    match config.channel {
+        Some(ref channel) => send(channel),
+        None => mapping.keys().for_each(|channel| send(channel)),
+    }
+}
+
+/// Posts a Discord server join or leave to the bridged channels with `relay_joins` on.
+fn announce_member(ctx_data: &TypeMap, text: &str) {
+    let senders = ctx_data.get::<SendersKey>().unwrap();
+    let channel_options = ctx_data.get::<ChannelOptionsKey>().unwrap();
+    let pauses = ctx_data.get::<PausesKey>().unwrap().lock().unwrap();
+    let text = ctx_data.get::<TranscoderKey>().unwrap().encode(text);
+
+    for channel in ctx_data.get::<ChannelMappingKey>().unwrap().keys() {
+        let Some(options) = channel_options.get(channel) else {
+            continue;
+        };
+        if !options.relay_joins.unwrap_or(false)
+            || !options.relays_to_irc()
+            || !pauses.to_irc(channel)
+        {
+            continue;
+        }
+
+        let sent =
+            options
+                .events_as
+                .unwrap_or(SendAs::Notice)
+                .send(senders.get(channel), channel, &text);
+        if let Err(e) = sent {
+            eprintln!("Failed to announce in {channel}: {e}");
+        }
+    }
+}
+
+/// The bridged channel a public thread was started in, with its channel ID and the thread's name.
+async fn thread_parent<'a>(
+    ctx: &Context,
+    mapping: &'a HashMap<String, u64>,
+    thread_id: ChannelId,
+) -> Option<(&'a str, ChannelId, String)> {
+    let thread = thread_id.to_channel(ctx).await.ok()?.guild()?;
+    // private threads are for the people in them, not for IRC
+    if !matches!(
+        thread.kind,
+        ChannelType::PublicThread | ChannelType::NewsThread
+    ) {
+        return None;
+    }
+
+    let parent = thread.parent_id?;
+    let (channel, &id) = mapping.iter().find(|(_, &v)| v == parent.0.get())?;
+    Some((channel.as_str(), ChannelId::from(id), thread.name))
+}
+
+/// Whether a guild has any bridged channels in it.
+fn is_bridged_guild(ctx: &Context, ctx_data: &TypeMap, guild_id: GuildId) -> bool {
+    let mapping = ctx_data.get::<ChannelMappingKey>().unwrap();
+
+    ctx.cache.guild(guild_id).map_or(false, |guild| {
+        mapping
+            .values()
+            .any(|&id| guild.channels.contains_key(&ChannelId::from(id)))
+    })
+}
+
+async fn announce_scheduled_event(ctx: &Context, event: &ScheduledEvent) {
+    let ctx_data = ctx.data.read().await;
+    let Some(config) = ctx_data.get::<ScheduledEventsKey>().unwrap() else {
+        return;
+    };
+    if !is_bridged_guild(ctx, &ctx_data, event.guild_id) {
+        return;
+    }
+
+    let name = &event.name;
+    let link = format!("https://discord.com/events/{}/{}", event.guild_id, event.id);
+    let start = full_time(event.start_time.unix_timestamp());
+    let args: &[(&str, &dyn Display)] = &[("name", name), ("start", &start), ("link", &link)];
+
+    let locale = ctx_data.get::<LocaleKey>().unwrap();
+    let text = match event.status {
+        ScheduledEventStatus::Scheduled => locale.text(
+            "event_scheduled",
+            "[event] {name} is on {start}: {link}",
+            args,
+        ),
+        ScheduledEventStatus::Active => locale.text(
+            "event_started",
+            "[event] {name} is starting now: {link}",
+            args,
+        ),
+        ScheduledEventStatus::Canceled => locale.text(
+            "event_cancelled",
+            "[event] {name} ({start}) was cancelled",
+            args,
+        ),
+        _ => return,
+    };
+
+    announce(&ctx_data, config, &text);
+}
+
+/// Stages going live and ending, with who's speaking in them.
+fn announce_stage(ctx: &Context, ctx_data: &TypeMap, stage: &StageInstance, live: bool) {
+    let Some(config) = ctx_data.get::<StagesKey>().unwrap() else {
+        return;
+    };
+    if !is_bridged_guild(ctx, ctx_data, stage.guild_id) {
+        return;
+    }
+
+    let topic = &stage.topic;
+    let locale = ctx_data.get::<LocaleKey>().unwrap();
+    let text = if live {
+        // everyone on the stage who isn't in the audience
+        let speakers = ctx.cache.guild(stage.guild_id).map_or(0, |guild| {
+            guild
+                .voice_states
+                .values()
+                .filter(|v| v.channel_id == Some(stage.channel_id) && !v.suppress)
+                .count()
+        });
+        let link = format!(
+            "https://discord.com/channels/{}/{}",
+            stage.guild_id, stage.channel_id
+        );
+        if speakers == 1 {
+            locale.text(
+                "stage_live_one",
+                "[stage] {topic} is live with 1 speaker: {link}",
+                &[("topic", topic), ("link", &link)],
+            )
+        } else {
+            locale.text(
+                "stage_live",
+                "[stage] {topic} is live with {speakers} speakers: {link}",
+                &[("topic", topic), ("speakers", &speakers), ("link", &link)],
+            )
+        }
+    } else {
+        locale.text(
+            "stage_ended",
+            "[stage] {topic} has ended",
+            &[("topic", topic)],
+        )
+    };
+
+    announce(ctx_data, config, &text);
+}
+
+/// Sends a DM back as a PM to whoever last messaged this user from IRC. DMs from users that aren't
+/// in the link table, paired with `!pair` or the `pm_inbox` are ignored.
+async fn relay_dm(ctx: &Context, ctx_data: &TypeMap, msg: &Message) {
+    // links are by nick, so they're for the first network only
+    let sender = ctx_data.get::<SendersKey>().unwrap().primary();
+    let transcoder = ctx_data.get::<TranscoderKey>().unwrap();
+    let locale = ctx_data.get::<LocaleKey>().unwrap();
+    let dm_sessions = ctx_data.get::<DmSessionsKey>().unwrap();
+    let links = ctx_data.get::<LinksKey>().unwrap();
+
+    let reply = if msg.content.trim() == "!accept" {
+        // answering a !pair from IRC
+        let accepted = dm_sessions.lock().await.accept(msg.author.id);
+        Some(match accepted {
+            Some(nick) => {
+                let text = locale.text(
+                    "pm_accepted",
+                    "{name} accepted, you can message them now",
+                    &[("name", &msg.author.name)],
+                );
+                if let Err(e) = sender.send_notice(&nick, transcoder.encode(&text)) {
+                    eprintln!("Failed to tell {nick} about the accept: {e}");
+                }
+                locale.text(
+                    "pm_paired",
+                    "You're talking to {nick} on IRC now, anything you say here goes to them",
+                    &[("nick", &nick)],
+                )
+            }
+            None => locale.text(
+                "pm_no_request",
+                "Nobody on IRC is waiting for you to accept",
+                &[],
+            ),
+        })
+    } else {
+        links
+            .lock()
+            .unwrap()
+            .discord_command(msg.author.id, &msg.content, locale)
+    };
+    if let Some(reply) = reply {
+        if let Err(e) = msg.channel_id.say(ctx, reply).await {
+            eprintln!("Failed to answer DM: {e}");
+        }
+        return;
+    }
+
+    // someone PMing a puppet gets the answer from it, links don't matter then
+    if let Some(pool) = ctx_data.get::<PuppetsKey>().unwrap() {
+        let nick = dm_sessions
+            .lock()
+            .await
+            .nick(msg.author.id)
+            .map(ToOwned::to_owned);
+        if let (Some(nick), Some(puppet)) = (nick, pool.existing(0, msg.author.id)) {
+            let dimensions = *ctx_data.get::<AttachmentDimensionsKey>().unwrap();
+            let rehost = ctx_data.get::<RehostKey>().unwrap().as_deref();
+            let attachments = describe_attachments(&msg.attachments, rehost, dimensions).await;
+            // a line starting with \x01 would go out as CTCP
+            let content = msg.content.replace('\x01', "");
+            for line in content
+                .lines()
+                .chain(attachments.iter().map(String::as_str))
+            {
+                for chunk in StrChunks::new(line, puppets::LINE_LIMIT) {
+                    puppet.say(&nick, chunk, false);
+                }
+            }
+            return;
+        }
+    }
+
+    let linked = links
+        .lock()
+        .unwrap()
+        .nick(msg.author.id)
+        .map(ToOwned::to_owned);
+    // the inbox hears from anyone, so its owner says who each reply is for
+    let inbox = *ctx_data.get::<PmInboxKey>().unwrap() == Some(msg.author.id);
+    let (nick, content) = if inbox {
+        regex! {
+            static ADDRESSED_RE = r"(?s)^([^\s:,]+)[:,]\s+(.+)$";
+        }
+        match ADDRESSED_RE.captures(&msg.content) {
+            Ok(Some(caps)) => (
+                Some(caps[1].to_owned()),
+                caps.get(2).unwrap().as_str().to_owned(),
+            ),
+            _ => {
+                let usage = locale.text(
+                    "pm_inbox_usage",
+                    "Start with \"nick: \" to say who on IRC that's for",
+                    &[],
+                );
+                if let Err(e) = msg.channel_id.say(ctx, usage).await {
+                    eprintln!("Failed to answer DM: {e}");
+                }
+                return;
+            }
+        }
+    } else {
+        let nick = dm_sessions
+            .lock()
+            .await
+            .nick(msg.author.id)
+            .map(ToOwned::to_owned);
+        (nick, msg.content.clone())
+    };
+
+    let Some(nick) = nick else {
+        // people nobody asked to talk to are ignored
+        if linked.is_none() {
+            return;
+        }
+        let text = ctx_data.get::<LocaleKey>().unwrap().text(
+            "pm_nobody",
+            "Nobody on IRC has messaged you yet, so there's no one to reply to",
+            &[],
+        );
+        if let Err(e) = msg.channel_id.say(ctx, text).await {
+            eprintln!("Failed to answer DM: {e}");
+        }
+        return;
+    };
+    // paired people, and whoever gets the pm_inbox, go by their Discord name
+    let name = linked.as_deref().unwrap_or(&msg.author.name);
+
+    let prefix = format!("<{name}> ");
+    let dimensions = *ctx_data.get::<AttachmentDimensionsKey>().unwrap();
+    let rehost = ctx_data.get::<RehostKey>().unwrap().as_deref();
+    let attachments = describe_attachments(&msg.attachments, rehost, dimensions).await;
+    let attachments = attachment_lines(
+        &attachments,
+        *ctx_data.get::<AttachmentPolicyKey>().unwrap(),
+        locale,
+        400 - prefix.len(),
+    );
+
+    let flood = ctx_data.get::<FloodKey>().unwrap();
+    let chunks = content
+        .lines()
+        .flat_map(|line| StrChunks::new(line, 400 - prefix.len()))
+        .map(ToOwned::to_owned)
+        .collect();
+    let chunks = flood.fit(&content, chunks, locale).await;
+
+    let mut outbox = flood.outbox(0).await;
+    let mut sent = Ok(());
+    for chunk in chunks.iter().chain(&attachments) {
+        outbox.take().await;
+        sent = sent.and(sender.send_privmsg(&nick, transcoder.encode(&format!("{prefix}{chunk}"))));
+    }
+    drop(outbox);
+
+    if let Err(e) = sent {
+        relay_failed(ctx, (msg.channel_id, msg.id), &nick, &content, &e.into()).await;
+    }
+}
+
+/// Logs and reports something from Discord that didn't make it to IRC, and reacts to it so
+/// whoever sent it can tell.
+async fn relay_failed(
+    ctx: &Context,
+    (channel_id, message_id): (ChannelId, MessageId),
+    channel: &str,
+    content: &str,
+    e: &anyhow::Error,
+) {
+    eprintln!("Failed to relay to {channel}: {e:#}");
+    report::relay_error(e, channel, "discord_to_irc", content.len());
+
+    let reaction = ReactionType::Unicode(String::from("⚠️"));
+    if let Err(e) = channel_id.create_reaction(ctx, message_id, reaction).await {
+        eprintln!("Failed to react to {message_id}: {e}");
+    }
+}
+
+/// Whether an audit log entry is recent enough to belong to an event we just received.
+fn is_recent(entry: &AuditLogEntry) -> bool {
+    Timestamp::now().unix_timestamp() - entry.id.created_at().unix_timestamp() < 30
+}
+
+fn apply_presence(shard: &ShardMessenger, status: &IrcStatus) {
+    match status {
+        IrcStatus::Connected { server, channels } => shard.set_presence(
+            Some(ActivityData::playing(format!(
+                "Bridging {server} · {channels} channel{}",
+                if *channels == 1 { "" } else { "s" }
+            ))),
+            OnlineStatus::Online,
+        ),
+        IrcStatus::Disconnected => shard.set_presence(
+            Some(ActivityData::playing("IRC disconnected")),
+            OnlineStatus::DoNotDisturb,
+        ),
+    }
+}
+
+/// Records the IRC connection state and reflects it in the bot's presence, if the gateway is
+/// already up. Otherwise it's applied once we get `ready`.
+pub async fn set_irc_status(data: &RwLock<TypeMap>, status: IrcStatus) {
+    let mut data = data.write().await;
+
+    if let Some(shard) = data.get::<ShardMessengerKey>() {
+        apply_presence(shard, &status);
+    }
+
+    data.insert::<IrcStatusKey>(status);
+}
+
 pub struct Handler;
 
 #[async_trait]
@@ -97,10 +796,14 @@ impl EventHandler for Handler {
             _ => return,
         }
 
+        if !msg.author.bot && admin::handle_discord(&ctx, &msg).await {
+            return;
+        }
+
         let ctx_data = ctx.data.read().await;
 
         let user_id = ctx_data.get::<UserIdKey>().copied().unwrap();
-        let sender = ctx_data.get::<SenderKey>().unwrap();
+        let senders = ctx_data.get::<SendersKey>().unwrap();
         let members = ctx_data.get::<MembersKey>().unwrap();
         let raw_prefix = ctx_data
             .get::<OptionStringKey>()
@@ -109,36 +812,224 @@ impl EventHandler for Handler {
             .unwrap_or("++");
         let mapping = ctx_data.get::<ChannelMappingKey>().unwrap().clone();
         let ref_content_limit = ctx_data.get::<RefContentLimitKey>().unwrap();
+        let message_cache = ctx_data.get::<MessageCacheKey>().unwrap();
+        let emoji = ctx_data.get::<EmojiOptionsKey>().unwrap();
+        let spoilers = ctx_data.get::<SpoilersKey>().unwrap();
+        let transcoder = ctx_data.get::<TranscoderKey>().unwrap();
+        let colours = ctx_data.get::<NickColoursKey>().unwrap();
 
         if user_id == msg.author.id || msg.author.bot {
             return;
         }
 
-        let (prefix, content_limit) = create_prefix(&msg, false, &ctx).await;
+        let ignored = {
+            let ignores = ctx_data.get::<IgnoresKey>().unwrap().lock().unwrap();
+            ignores.ignores_discord(msg.author.id) || ignores.ignores_text(&msg.content)
+        };
+        if ignored {
+            return;
+        }
 
-        let (channel, channel_id) = match mapping.iter().find(|(_, &v)| v == msg.channel_id.0.get())
+        if msg.guild_id.is_none() {
+            relay_dm(&ctx, &ctx_data, &msg).await;
+            return;
+        }
+
+        // staff talking to services from their query thread, that goes out as-is
+        if let Some(query_threads) = ctx_data.get::<QueryThreadsKey>().unwrap() {
+            let nick = query_threads
+                .lock()
+                .unwrap()
+                .nick(msg.channel_id)
+                .map(ToOwned::to_owned);
+            if let Some(nick) = nick {
+                let mut outbox = ctx_data.get::<FloodKey>().unwrap().outbox(0).await;
+                let mut sent = Ok(());
+                for line in msg.content.lines() {
+                    for chunk in StrChunks::new(line, 400) {
+                        outbox.take().await;
+                        sent = sent.and(
+                            senders
+                                .primary()
+                                .send_privmsg(&nick, transcoder.encode(chunk)),
+                        );
+                    }
+                }
+                drop(outbox);
+
+                if let Err(e) = sent {
+                    relay_failed(
+                        &ctx,
+                        (msg.channel_id, msg.id),
+                        &nick,
+                        &msg.content,
+                        &e.into(),
+                    )
+                    .await;
+                }
+                return;
+            }
+        }
+
+        let mut nick = display_name(&msg, &ctx).await;
+        // two people showing up with the same name on IRC would be confusing
+        if let Some(name) = &mut nick {
+            *name = members::unambiguous_name(
+                &members.lock().await,
+                msg.author.id,
+                name,
+                &msg.author.name,
+            );
+        }
+
+        let (channel, _, thread) = match mapping.iter().find(|(_, &v)| v == msg.channel_id.0.get())
         {
-            Some((k, v)) => (k.as_str(), ChannelId::from(*v)),
-            None => return,
+            Some((k, v)) => (k.as_str(), ChannelId::from(*v), None),
+            None => match thread_parent(&ctx, &mapping, msg.channel_id).await {
+                Some((k, v, name)) => (k, v, Some(name)),
+                None => return,
+            },
         };
 
-        let attachments: Vec<&str> = msg.attachments.iter().map(|a| a.url.as_str()).collect();
+        let options = ctx_data.get::<ChannelOptionsKey>().unwrap().get(channel);
+        let member_roles = msg.member.as_ref().map_or(&[][..], |m| m.roles.as_slice());
+        if !options.map_or(true, ChannelOptions::relays_to_irc)
+            || !options.map_or(true, |o| o.allows(&msg.content))
+            || !options.map_or(true, |o| o.relays_member(member_roles))
+            || !ctx_data
+                .get::<PausesKey>()
+                .unwrap()
+                .lock()
+                .unwrap()
+                .to_irc(channel)
+        {
+            return;
+        }
 
-        let roles = channel_id
-            .to_channel(&ctx)
-            .await
-            .unwrap()
-            .guild()
+        let roles = match msg.guild_id {
+            Some(guild_id) => guild_id.roles(&ctx).await.unwrap_or_default(),
+            None => HashMap::new(),
+        };
+
+        let (mut prefix, mut content_limit) = format_prefix(
+            nick.as_deref(),
+            msg.author.id,
+            colours,
+            colours.role_colour(member_roles, &roles),
+            false,
+        );
+        // timestamps and such go in front of this later, puppets keep those and not the name
+        let nick_prefix_len = prefix.len();
+
+        // threads under the channel go with it, or to their own IRC channel
+        let channel = match thread {
+            Some(ref name) => {
+                let Some(options) = options.filter(|o| o.relays_threads()) else {
+                    return;
+                };
+                let tag = format!("[thread: {name}] ");
+                content_limit = content_limit.saturating_sub(tag.len());
+                prefix.insert_str(0, &tag);
+                options.thread_channel.as_deref().unwrap_or(channel)
+            }
+            None => channel,
+        };
+        let sender = senders.get(channel);
+        // people get their own connection, unless the network is out of room for more. Until
+        // it's in the channel the bridge relays for them
+        let puppet = ctx_data
+            .get::<PuppetsKey>()
             .unwrap()
-            .guild_id
-            .roles(&ctx)
-            .await
-            .unwrap();
+            .as_ref()
+            .and_then(|p| {
+                p.get(
+                    senders.network(channel),
+                    msg.author.id,
+                    nick.as_deref().unwrap_or(&msg.author.name),
+                    channel,
+                )
+            });
+
+        let relay_as = options.and_then(|o| o.relay_as).unwrap_or(SendAs::Privmsg);
+
+        if options.and_then(|o| o.short_ids).unwrap_or(false) {
+            let short = format!("[{}] ", message_cache.lock().await.assign_short_id(msg.id));
+            content_limit = content_limit.saturating_sub(short.len());
+            prefix.insert_str(0, &short);
+        }
+
+        if options.and_then(|o| o.timestamps).unwrap_or(false) {
+            let ts = format!("[{}] ", short_time(msg.timestamp.unix_timestamp()));
+            content_limit = content_limit.saturating_sub(ts.len());
+            prefix.insert_str(0, &ts);
+        }
+
+        let dimensions = *ctx_data.get::<AttachmentDimensionsKey>().unwrap();
+        let rehost = ctx_data.get::<RehostKey>().unwrap().as_deref();
+        let mut attachments = describe_attachments(&msg.attachments, rehost, dimensions).await;
+
+        if let Some(guild_id) = msg.guild_id {
+            let mentioned = msg.mentions.iter().map(|u| u.id);
+            members::fetch_missing(&ctx.http, guild_id, members, mentioned).await;
+        }
 
         let members_lock = members.lock().await;
 
-        let computed = discord_to_irc_processing(&msg.content, &members_lock, &ctx, &roles).await;
+        // plain channels only get mentions turned into names, for bot output that formatting mangles
+        let computed = if options.and_then(|o| o.plain).unwrap_or(false) {
+            msg.content_safe(&ctx.cache)
+        } else {
+            discord_to_irc_processing(&msg.content, &members_lock, &ctx, &roles, emoji).await
+        };
+        let computed = spoilers.hide(channel, &computed);
+        // actions go out as CTCP ACTION, everything else only cares about their text
+        let (computed, action) = match as_action(&computed) {
+            Some(text) => (text.to_owned(), true),
+            None => (computed, false),
+        };
+        let computed = unrewrite_mentions(
+            computed,
+            &ctx_data.get::<RewrittenNicksKey>().unwrap().lock().unwrap(),
+            senders.network(channel),
+        );
+        let computed = ctx_data
+            .get::<UrlCleanerKey>()
+            .unwrap()
+            .clean(&computed)
+            .into_owned();
+
+        // translating can take a while, the members are needed elsewhere meanwhile
+        drop(members_lock);
+        let translator = ctx_data.get::<TranslatorKey>().unwrap();
+        let computed = match (translator, options.and_then(|o| o.translate.as_ref())) {
+            (Some(translator), Some(languages)) => {
+                translator
+                    .translate_with_original(&computed, &languages.discord, &languages.irc)
+                    .await
+            }
+            _ => computed,
+        };
 
+        let shortener = ctx_data.get::<ShortenerKey>().unwrap();
+        let computed = match shortener {
+            Some(shortener) => {
+                let (shortened, attachment_links) = tokio::join!(
+                    shortener.shorten_all(&computed),
+                    join_all(attachments.iter().map(|a| shortener.shorten_all(a))),
+                );
+                attachments = attachment_links;
+                shortened
+            }
+            None => computed,
+        };
+
+        let flood = ctx_data.get::<FloodKey>().unwrap();
+        let locale = ctx_data.get::<LocaleKey>().unwrap();
+
+        let members_lock = members.lock().await;
+        // set when replying to something relayed from IRC, for clients that understand reply tags
+        let mut reply_msgid = None;
+        let mut reply_line = None;
         if let Some(MessageReference {
             guild_id,
             channel_id,
@@ -146,54 +1037,375 @@ impl EventHandler for Handler {
             ..
         }) = msg.message_reference
         {
-            if let Ok(mut reply) = channel_id.message(&ctx, message_id).await {
-                reply.guild_id = guild_id; // lmao
-                let (reply_prefix, reply_content_limit) = create_prefix(&reply, true, &ctx).await;
+            let message_cache = message_cache.lock().await;
+            let cached = message_cache.get(message_id);
+            reply_msgid = message_cache.msgid(message_id);
+            drop(message_cache);
 
-                let mut content = reply.content;
-                content = content.replace("\r\n", " "); // just in case
-                content = content.replace('\n', " ");
-                let atts: Vec<&str> = reply.attachments.iter().map(|a| &*a.url).collect();
-                content = format!("{} {}", content, atts.join(" "));
+            let reply = match cached {
+                Some(reply) => Some(reply),
+                None => {
+                    fetch_reply_context(
+                        channel_id,
+                        message_id,
+                        guild_id,
+                        &members_lock,
+                        &ctx,
+                        &roles,
+                        emoji,
+                        spoilers,
+                        channel,
+                        dimensions,
+                    )
+                    .await
+                }
+            };
 
-                content = discord_to_irc_processing(&content, &members_lock, &ctx, &roles).await;
+            if let Some(reply) = reply {
+                let reply_roles = members_lock
+                    .iter()
+                    .find(|m| m.user.id == reply.author_id)
+                    .map_or(&[][..], |m| m.roles.as_slice());
+                let (reply_prefix, reply_content_limit) = format_prefix(
+                    reply.nick.as_deref(),
+                    reply.author_id,
+                    colours,
+                    colours.role_colour(reply_roles, &roles),
+                    true,
+                );
 
-                let to_send = (&*content).truncate_ellipse(
+                let to_send = (&*reply.content).truncate_ellipse(
                     ref_content_limit
                         .map(|l| l as usize)
                         .unwrap_or(reply_content_limit),
                 );
-
-                sender
-                    .send_privmsg(channel, format!("{reply_prefix}{to_send}"))
-                    .unwrap();
+                reply_line = Some(format!("{reply_prefix}{to_send}"));
             }
         }
+        drop(members_lock);
 
-        if let Some((stripped, false)) = computed
+        message_cache.lock().await.insert(
+            msg.id,
+            CachedMessage {
+                channel_id: msg.channel_id,
+                author_id: msg.author.id,
+                nick: nick.clone(),
+                content: format!("{} {}", computed.replace('\n', " "), attachments.join(" "))
+                    .trim()
+                    .to_owned(),
+            },
+        );
+
+        // everything's worked out before taking the outbox, pastes and previews can take a while
+        let raw = computed
             .strip_prefix(raw_prefix)
             .map(str::trim)
-            .map(|v| (v, v.is_empty()))
-        {
-            let to_send = stripped.trim_matches('\u{f}');
-            sender.send_privmsg(channel, &prefix).unwrap();
-            sender.send_privmsg(channel, to_send).unwrap();
+            .filter(|v| !v.is_empty())
+            // raw messages are meant for other bots, so these always go out as a PRIVMSG
+            .map(|stripped| stripped.trim_matches('\u{f}'));
+        let lines = match (raw, &puppet) {
+            (Some(_), _) => Vec::new(),
+            (None, Some(_)) => {
+                let extra = &prefix[..prefix.len() - nick_prefix_len];
+                let chunks = chunk_lines(&computed, puppets::LINE_LIMIT - extra.len());
+                flood
+                    .fit(&computed, chunks, locale)
+                    .await
+                    .into_iter()
+                    .map(|chunk| format!("{extra}{chunk}"))
+                    .collect()
+            }
+            (None, None) => {
+                let chunk_limit = if action {
+                    content_limit.saturating_sub("\x01ACTION \x01".len())
+                } else {
+                    content_limit
+                };
+                let chunks = chunk_lines(&computed, chunk_limit);
+                flood
+                    .fit(&computed, chunks, locale)
+                    .await
+                    .into_iter()
+                    .map(|chunk| format!("{prefix}{chunk}"))
+                    .collect()
+            }
+        };
+        let attachment_lines = attachment_lines(
+            &attachments,
+            *ctx_data.get::<AttachmentPolicyKey>().unwrap(),
+            locale,
+            content_limit,
+        );
+        let previews = message_link_previews(&ctx.http, &ctx.cache, &mapping, &msg.content).await;
+
+        // held until everything below is sent, so other messages don't get in between
+        let mut outbox = flood.outbox(senders.network(channel)).await;
+        // the first thing that didn't go out, the rest is still tried
+        let mut sent = Ok(());
+
+        if let Some(reply_line) = reply_line {
+            outbox.take().await;
+            sent = sent.and(relay_as.send(sender, channel, transcoder.encode(&reply_line)));
+        }
+
+        if let Some(to_send) = raw {
+            outbox.take().await;
+            outbox.take().await;
+            sent = sent.and(sender.send_privmsg(channel, transcoder.encode(&prefix)));
+            sent = sent.and(sender.send_privmsg(channel, transcoder.encode(to_send)));
+        } else if let Some(ref puppet) = puppet {
+            // puppets are their own connections, with their own burst limit
+            for line in &lines {
+                puppet.say(channel, line, action);
+            }
         } else {
-            for line in computed.lines() {
-                for chunk in StrChunks::new(line, content_limit) {
-                    let to_send = chunk.trim_matches('\u{f}');
-                    sender
-                        .send_privmsg(channel, &format!("{prefix}{to_send}"))
-                        .unwrap();
+            for line in &lines {
+                let to_send = transcoder.encode(line);
+                outbox.take().await;
+                sent = sent.and(if action {
+                    sender.send_action(channel, to_send)
+                } else {
+                    relay_as.send_reply(sender, channel, to_send, reply_msgid.take())
+                });
+            }
+        }
+
+        for attachment in &attachment_lines {
+            match puppet {
+                Some(ref puppet) => puppet.say(channel, attachment, false),
+                None => {
+                    outbox.take().await;
+                    sent = sent.and(relay_as.send(
+                        sender,
+                        channel,
+                        transcoder.encode(&format!("{prefix}{attachment}")),
+                    ));
                 }
             }
         }
 
-        for attachment in attachments {
-            sender
-                .send_privmsg(channel, &format!("{prefix}{attachment}"))
-                .unwrap();
+        let events_as = options.and_then(|o| o.events_as).unwrap_or(SendAs::Notice);
+        for preview in previews {
+            outbox.take().await;
+            sent = sent.and(events_as.send(sender, channel, transcoder.encode(&preview)));
+        }
+        drop(outbox);
+
+        let error = sent.err().map(anyhow::Error::from);
+        if let Some(ref e) = error {
+            relay_failed(&ctx, (msg.channel_id, msg.id), channel, &computed, e).await;
+        }
+
+        if let Some(chat_log) = ctx_data.get::<ChatLogKey>().unwrap() {
+            let content = format!("{} {}", msg.content_safe(&ctx.cache), attachments.join(" "));
+            chat_log.record(
+                channel,
+                "discord",
+                nick.as_deref().unwrap_or(&msg.author.name),
+                content.trim(),
+            );
+        }
+
+        if error.is_none() {
+            ctx_data.get::<HealthKey>().unwrap().relayed();
+        }
+
+        if let Some(audit_log) = ctx_data.get::<AuditLogKey>().unwrap() {
+            let content = format!("{computed} {}", attachments.join(" "));
+            audit_log.record(&AuditEntry {
+                direction: "discord_to_irc",
+                source: &msg.channel_id.to_string(),
+                destination: channel,
+                author: Some(&msg.author.name),
+                content: content.trim(),
+                error: error.map(|e| e.to_string()),
+            });
+        }
+    }
+
+    async fn message_update(
+        &self,
+        ctx: Context,
+        _: Option<Message>,
+        _: Option<Message>,
+        event: MessageUpdateEvent,
+    ) {
+        // embeds being filled in for links count as updates too, only changed text matters here
+        let Some(content) = event.content else {
+            return;
+        };
+
+        let ctx_data = ctx.data.read().await;
+        let message_cache = ctx_data.get::<MessageCacheKey>().unwrap();
+
+        // only messages that made it to IRC in the first place
+        let Some(cached) = message_cache.lock().await.get(event.id) else {
+            return;
+        };
+
+        let mapping = ctx_data.get::<ChannelMappingKey>().unwrap();
+        let (channel, thread) = match mapping
+            .iter()
+            .find(|(_, &v)| v == cached.channel_id.0.get())
+        {
+            Some((k, _)) => (k.as_str(), None),
+            None => match thread_parent(&ctx, mapping, cached.channel_id).await {
+                Some((k, _, name)) => (k, Some(name)),
+                None => return,
+            },
+        };
+
+        // an edit can turn a message into something that's ignored, and its author may have been
+        // ignored since
+        let ignored = {
+            let ignores = ctx_data.get::<IgnoresKey>().unwrap().lock().unwrap();
+            ignores.ignores_discord(cached.author_id) || ignores.ignores_text(&content)
+        };
+        if ignored {
+            return;
+        }
+
+        let options = ctx_data.get::<ChannelOptionsKey>().unwrap().get(channel);
+        if !options.and_then(|o| o.edits).unwrap_or(true)
+            || !options.map_or(true, ChannelOptions::relays_to_irc)
+            || !options.map_or(true, |o| o.allows(&content))
+            || !ctx_data
+                .get::<PausesKey>()
+                .unwrap()
+                .lock()
+                .unwrap()
+                .to_irc(channel)
+        {
+            return;
+        }
+
+        // relayed to the same place as the message was
+        let channel = match thread {
+            Some(_) => {
+                let Some(options) = options.filter(|o| o.relays_threads()) else {
+                    return;
+                };
+                options.thread_channel.as_deref().unwrap_or(channel)
+            }
+            None => channel,
+        };
+
+        let roles = match event.guild_id {
+            Some(guild_id) => guild_id.roles(&ctx).await.unwrap_or_default(),
+            None => HashMap::new(),
+        };
+        let members = ctx_data.get::<MembersKey>().unwrap();
+        let emoji = ctx_data.get::<EmojiOptionsKey>().unwrap();
+        let spoilers = ctx_data.get::<SpoilersKey>().unwrap();
+
+        let computed = if options.and_then(|o| o.plain).unwrap_or(false) {
+            content_safe(
+                &ctx.cache,
+                &content,
+                &ContentSafeOptions::default(),
+                event.mentions.as_deref().unwrap_or_default(),
+            )
+        } else {
+            let members_lock = members.lock().await;
+            let computed =
+                discord_to_irc_processing(&content, &members_lock, &ctx, &roles, emoji).await;
+            as_action(&computed).map_or(computed.clone(), ToOwned::to_owned)
+        };
+        let computed = spoilers.hide(channel, &computed);
+        let computed = unrewrite_mentions(
+            computed,
+            &ctx_data.get::<RewrittenNicksKey>().unwrap().lock().unwrap(),
+            ctx_data.get::<SendersKey>().unwrap().network(channel),
+        );
+        let computed = ctx_data
+            .get::<UrlCleanerKey>()
+            .unwrap()
+            .clean(&computed)
+            .into_owned();
+
+        // the cached content ends with the attachments, which can't be edited
+        let text = computed.replace('\n', " ");
+        let unchanged = cached
+            .content
+            .strip_prefix(text.trim())
+            .map_or(false, |rest| rest.is_empty() || rest.starts_with(" ["));
+        if unchanged {
+            return;
+        }
+
+        let sender = ctx_data.get::<SendersKey>().unwrap().get(channel);
+        let transcoder = ctx_data.get::<TranscoderKey>().unwrap();
+        let colours = ctx_data.get::<NickColoursKey>().unwrap();
+        let locale = ctx_data.get::<LocaleKey>().unwrap();
+        let relay_as = options.and_then(|o| o.relay_as).unwrap_or(SendAs::Privmsg);
+
+        let role_colour = members
+            .lock()
+            .await
+            .iter()
+            .find(|m| m.user.id == cached.author_id)
+            .and_then(|m| colours.role_colour(&m.roles, &roles));
+        let (mut prefix, mut content_limit) = format_prefix(
+            cached.nick.as_deref(),
+            cached.author_id,
+            colours,
+            role_colour,
+            false,
+        );
+        if let Some(name) = thread {
+            let tag = format!("[thread: {name}] ");
+            content_limit = content_limit.saturating_sub(tag.len());
+            prefix.insert_str(0, &tag);
+        }
+        let edited = locale.text("edited", "(edit) {content}", &[("content", &computed)]);
+        let flood = ctx_data.get::<FloodKey>().unwrap();
+        let chunks = chunk_lines(&edited, content_limit);
+        let chunks = flood.fit(&edited, chunks, locale).await;
+
+        let network = ctx_data.get::<SendersKey>().unwrap().network(channel);
+        let mut outbox = flood.outbox(network).await;
+        let mut sent = Ok(());
+        for chunk in chunks {
+            outbox.take().await;
+            sent = sent.and(relay_as.send(
+                sender,
+                channel,
+                transcoder.encode(&format!("{prefix}{chunk}")),
+            ));
+        }
+        drop(outbox);
+
+        let error = sent.err().map(anyhow::Error::from);
+        if let Some(ref e) = error {
+            relay_failed(&ctx, (event.channel_id, event.id), channel, &edited, e).await;
         }
+
+        if let Some(audit_log) = ctx_data.get::<AuditLogKey>().unwrap() {
+            audit_log.record(&AuditEntry {
+                direction: "discord_to_irc",
+                source: &cached.channel_id.to_string(),
+                destination: channel,
+                author: event.author.as_ref().map(|a| a.name.as_str()),
+                content: &edited,
+                error: error.map(|e| e.to_string()),
+            });
+        }
+
+        // so replies quote what it says now
+        let dimensions = *ctx_data.get::<AttachmentDimensionsKey>().unwrap();
+        let rehost = ctx_data.get::<RehostKey>().unwrap().as_deref();
+        let attachments = describe_attachments(
+            event.attachments.as_deref().unwrap_or_default(),
+            rehost,
+            dimensions,
+        )
+        .await;
+        message_cache.lock().await.update_content(
+            event.id,
+            format!("{text} {}", attachments.join(" "))
+                .trim()
+                .to_owned(),
+        );
     }
 
     async fn ready(&self, ctx: Context, info: Ready) {
@@ -201,12 +1413,149 @@ impl EventHandler for Handler {
 
         let mut data = ctx.data.write().await;
         data.insert::<UserIdKey>(id);
+        data.insert::<ShardMessengerKey>(ctx.shard.clone());
+
+        if let Some(status) = data.get::<IrcStatusKey>() {
+            apply_presence(&ctx.shard, status);
+        }
+        drop(data);
+
+        for guild in &info.guilds {
+            if let Err(e) = guild.id.set_commands(&ctx, commands::definitions()).await {
+                eprintln!("Failed to register slash commands in {}: {e}", guild.id);
+            }
+        }
+    }
+
+    async fn guild_scheduled_event_create(&self, ctx: Context, event: ScheduledEvent) {
+        announce_scheduled_event(&ctx, &event).await;
+    }
+
+    async fn guild_scheduled_event_update(&self, ctx: Context, event: ScheduledEvent) {
+        // edits to the details aren't worth a message, only starting and cancelling are
+        if matches!(
+            event.status,
+            ScheduledEventStatus::Active | ScheduledEventStatus::Canceled
+        ) {
+            announce_scheduled_event(&ctx, &event).await;
+        }
+    }
+
+    async fn guild_scheduled_event_delete(&self, ctx: Context, mut event: ScheduledEvent) {
+        // deleting an event that hasn't happened yet is how most people cancel it
+        if event.status == ScheduledEventStatus::Scheduled {
+            event.status = ScheduledEventStatus::Canceled;
+            announce_scheduled_event(&ctx, &event).await;
+        }
+    }
+
+    async fn stage_instance_create(&self, ctx: Context, stage: StageInstance) {
+        let ctx_data = ctx.data.read().await;
+        announce_stage(&ctx, &ctx_data, &stage, true);
+    }
+
+    async fn stage_instance_delete(&self, ctx: Context, stage: StageInstance) {
+        let ctx_data = ctx.data.read().await;
+        announce_stage(&ctx, &ctx_data, &stage, false);
+    }
+
+    async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
+        match interaction {
+            Interaction::Command(ref command) => commands::handle(&ctx, command).await,
+            Interaction::Component(ref component) => {
+                commands::handle_component(&ctx, component).await
+            }
+            _ => {}
+        }
+    }
+
+    async fn channel_update(&self, ctx: Context, _: Option<GuildChannel>, new: GuildChannel) {
+        let ctx_data = ctx.data.read().await;
+        let mapping = ctx_data.get::<ChannelMappingKey>().unwrap();
+        let channel_options = ctx_data.get::<ChannelOptionsKey>().unwrap();
+        let topics = ctx_data.get::<TopicKey>().unwrap();
+        let members = ctx_data.get::<MembersKey>().unwrap();
+        let transcoder = ctx_data.get::<TranscoderKey>().unwrap();
+
+        let Some((channel, _)) = mapping.iter().find(|(_, &v)| v == new.id.0.get()) else {
+            return;
+        };
+        let senders = ctx_data.get::<SendersKey>().unwrap();
+        let sender = senders.get(channel);
+        let pending_replies =
+            &ctx_data.get::<PendingRepliesKey>().unwrap()[senders.network(channel)];
+
+        let topic_sync = channel_options
+            .get(channel)
+            .and_then(|o| o.topic_sync)
+            .unwrap_or_default();
+
+        if !topic_sync.to_irc() {
+            return;
+        }
+
+        // an empty TOPIC would just query the topic instead of clearing it
+        let Some(topic) = new
+            .topic
+            .map(|t| strip_user_count(&t).to_owned())
+            .filter(|t| !t.is_empty())
+        else {
+            return;
+        };
+
+        if topics
+            .lock()
+            .await
+            .get(channel)
+            .map_or(false, |t| t.topic == topic)
+        {
+            return;
+        }
+
+        let set_by = topic_setter(&ctx, members, &new).await;
+
+        let irc_topic = match set_by {
+            Some(ref name) => format!("{topic} (set by {name} on Discord)"),
+            None => topic.clone(),
+        };
+
+        topics.lock().await.insert(
+            channel.clone(),
+            TopicInfo {
+                topic,
+                irc_topic: irc_topic.clone(),
+                set_by: set_by.map(|name| format!("{name} on Discord")),
+            },
+        );
+
+        // labeled, so an error about it can't be taken for the answer to a /mode
+        let label = pending_replies.lock().await.label();
+        let sent = sender.send(commands::labeled(
+            irc::proto::Command::TOPIC(
+                channel.clone(),
+                Some(transcoder.encode(&irc_topic).into_owned()),
+            ),
+            label,
+        ));
+        if let Err(e) = sent {
+            eprintln!("Failed to set the topic of {channel}: {e}");
+        }
     }
 
     async fn guild_member_addition(&self, ctx: Context, new_member: Member) {
         let ctx_data = ctx.data.read().await;
+
+        if is_bridged_guild(&ctx, &ctx_data, new_member.guild_id) {
+            let text = ctx_data.get::<LocaleKey>().unwrap().text(
+                "member_joined",
+                "{name} joined the Discord server",
+                &[("name", &new_member.display_name())],
+            );
+            announce_member(&ctx_data, &text);
+        }
+
         let mut members = ctx_data.get::<MembersKey>().unwrap().lock().await;
-        members.push(new_member);
+        members::remember(&mut members, new_member);
     }
 
     async fn guild_member_update(
@@ -217,30 +1566,126 @@ impl EventHandler for Handler {
         _: GuildMemberUpdateEvent,
     ) {
         let ctx_data = ctx.data.read().await;
-        let mut members = ctx_data.get::<MembersKey>().unwrap().lock().await;
+        let now = Timestamp::now().unix_timestamp();
 
-        if let Some(new) = new {
-            let x = members
+        let Some(new) = new else {
+            return;
+        };
+
+        let (was_timed_out, is_timed_out) = {
+            let mut members = ctx_data.get::<MembersKey>().unwrap().lock().await;
+
+            // members are fetched lazily, so this might be the first we hear of them
+            let old = members
                 .iter()
                 .position(|m| m.user.id == new.user.id)
-                .unwrap();
-            members.remove(x);
-            members.push(new);
+                .map(|x| members.remove(x));
+            let is_timed_out = new
+                .communication_disabled_until
+                .filter(|t| t.unix_timestamp() > now);
+            let was_timed_out = old
+                .and_then(|old| old.communication_disabled_until)
+                .filter(|t| t.unix_timestamp() > now);
+            members.push(new.clone());
+
+            (was_timed_out, is_timed_out)
+        };
+
+        if was_timed_out == is_timed_out {
+            return;
         }
+
+        let locale = ctx_data.get::<LocaleKey>().unwrap();
+        let what = match is_timed_out {
+            Some(until) => locale.text(
+                "moderation_timed_out",
+                "timed out until {until}",
+                &[("until", &until)],
+            ),
+            None => locale.text("moderation_untimed", "released from timeout", &[]),
+        };
+        let entry = audit_log_entry(
+            &ctx,
+            new.guild_id,
+            Action::Member(MemberAction::Update),
+            new.user.id.0,
+        )
+        .await;
+
+        announce_moderation(&ctx_data, new.display_name(), &what, entry).await;
     }
 
     async fn guild_member_removal(
         &self,
         ctx: Context,
-        _guild_id: GuildId,
+        guild_id: GuildId,
         user: User,
         _member: Option<Member>,
     ) {
         let ctx_data = ctx.data.read().await;
-        let mut members = ctx_data.get::<MembersKey>().unwrap().lock().await;
 
-        let pos = members.iter().position(|m| m.user.id == user.id).unwrap();
-        members.remove(pos);
+        let name = {
+            let mut members = ctx_data.get::<MembersKey>().unwrap().lock().await;
+            let name = members
+                .iter()
+                .find(|m| m.user.id == user.id)
+                .map_or_else(|| user.name.clone(), |m| m.display_name().to_owned());
+            members.retain(|m| m.user.id != user.id);
+            name
+        };
+
+        if is_bridged_guild(&ctx, &ctx_data, guild_id) {
+            let text = ctx_data.get::<LocaleKey>().unwrap().text(
+                "member_left",
+                "{name} left the Discord server",
+                &[("name", &name)],
+            );
+            announce_member(&ctx_data, &text);
+        }
+
+        if ctx_data.get::<ModerationKey>().unwrap().is_none() {
+            return;
+        }
+
+        // there's no kick event, so the audit log is the only way to tell a kick from a leave
+        let entry = audit_log_entry(
+            &ctx,
+            guild_id,
+            Action::Member(MemberAction::Kick),
+            user.id.0,
+        )
+        .await;
+
+        if entry.is_some() {
+            let what =
+                ctx_data
+                    .get::<LocaleKey>()
+                    .unwrap()
+                    .text("moderation_kicked", "kicked", &[]);
+            announce_moderation(&ctx_data, &user.name, &what, entry).await;
+        }
+    }
+
+    async fn guild_ban_addition(&self, ctx: Context, guild_id: GuildId, banned_user: User) {
+        let ctx_data = ctx.data.read().await;
+
+        if ctx_data.get::<ModerationKey>().unwrap().is_none() {
+            return;
+        }
+
+        let entry = audit_log_entry(
+            &ctx,
+            guild_id,
+            Action::Member(MemberAction::BanAdd),
+            banned_user.id.0,
+        )
+        .await;
+
+        let what = ctx_data
+            .get::<LocaleKey>()
+            .unwrap()
+            .text("moderation_banned", "banned", &[]);
+        announce_moderation(&ctx_data, &banned_user.name, &what, entry).await;
     }
 }
 
@@ -249,6 +1694,7 @@ async fn discord_to_irc_processing(
     members: &[Member],
     ctx: &Context,
     roles: &HashMap<RoleId, Role>,
+    emoji: &EmojiOptions,
 ) -> String {
     struct MemberReplacer<'a> {
         members: &'a [Member],
@@ -259,7 +1705,14 @@ async fn discord_to_irc_processing(
             let id = caps[1].parse::<u64>().unwrap();
 
             let display_name = self.members.iter().find_map(|member| {
-                (id == member.user.id.0.get()).then(|| member.display_name().to_owned())
+                (id == member.user.id.0.get()).then(|| {
+                    members::unambiguous_name(
+                        self.members,
+                        member.user.id,
+                        member.display_name(),
+                        &member.user.name,
+                    )
+                })
             });
 
             if let Some(display_name) = display_name {
@@ -278,28 +1731,36 @@ async fn discord_to_irc_processing(
         static CHANNEL_RE = r"<#([0-9]+)>";
         static ROLE_RE = r"<@&([0-9]+)>";
         static URL_ESCAPE_RE = r"<(https?://[^\s/$.?#].\S*)>";
+        static ACTION_RE = r"^(?:/me\s+(.+)|_([^_\n]+)_|\*([^*\n]+)\*)$";
     }
 
-    let mut computed = message.to_owned();
+    // Discord's /me sends the whole message in italics, typing it out works too
+    let action = ACTION_RE
+        .captures(message.trim())
+        .ok()
+        .flatten()
+        .and_then(|caps| caps.get(1).or_else(|| caps.get(2)).or_else(|| caps.get(3)))
+        .map(|text| text.as_str());
+
+    let mut computed = action.unwrap_or(message).to_owned();
+
+    computed = replace_all_owned(&URL_ESCAPE_RE, computed, "$1");
 
-    computed = URL_ESCAPE_RE.replace_all(&computed, "$1").into_owned();
+    computed = replace_all_owned(&PING_RE_1, computed, MemberReplacer { members });
 
-    computed = PING_RE_1
-        .replace_all(&computed, MemberReplacer { members })
-        .into_owned();
+    computed = replace_all_owned(&PING_RE_2, computed, MemberReplacer { members });
 
-    computed = PING_RE_2
-        .replace_all(&computed, MemberReplacer { members })
-        .into_owned();
+    computed = replace_all_owned(&EMOJI_RE, computed, ":$1:");
 
-    computed = EMOJI_RE.replace_all(&computed, ":$1:").into_owned();
+    computed = emoji.downgrade(&computed);
 
     // FIXME: the await makes it impossible to use `replace_all`, idk how to fix this
-    for caps in CHANNEL_RE.captures_iter(&computed.clone()) {
-        let replacement = match ChannelId(caps.unwrap()[1].parse().unwrap())
-            .to_channel(&ctx)
-            .await
-        {
+    let mentioned_channels = CHANNEL_RE
+        .captures_iter(&computed)
+        .map(|caps| caps.unwrap()[1].parse().unwrap())
+        .collect::<Vec<NonZeroU64>>();
+    for id in mentioned_channels {
+        let replacement = match ChannelId(id).to_channel(&ctx).await {
             Ok(Channel::Guild(gc)) => Cow::Owned(format!("#{}", gc.name)),
             _ => Cow::Borrowed("#deleted-channel"),
         };
@@ -307,19 +1768,18 @@ async fn discord_to_irc_processing(
         computed = CHANNEL_RE.replace(&computed, replacement).to_string();
     }
 
-    computed = ROLE_RE
-        .replace_all(
-            &computed,
-            OptionReplacer(|caps: &Captures| {
-                roles
-                    .get(&RoleId(caps[1].parse().unwrap()))
-                    .map(|role| format!("@{}", role.name))
-            }),
-        )
-        .into_owned();
+    computed = replace_all_owned(
+        &ROLE_RE,
+        computed,
+        OptionReplacer(|caps: &Captures| {
+            roles
+                .get(&RoleId(caps[1].parse().unwrap()))
+                .map(|role| format!("@{}", role.name))
+        }),
+    );
 
     // switch brackets of unknown pings
-    computed = PING_RE_1.replace_all(&computed, "{@$1}").into_owned();
+    computed = replace_all_owned(&PING_RE_1, computed, "{@$1}");
 
     computed = {
         #[allow(clippy::enum_glob_use)]
@@ -385,7 +1845,90 @@ async fn discord_to_irc_processing(
     };
 
     // switch them back
-    computed = PING_RE_3.replace_all(&computed, "<@$1>").into_owned();
+    computed = replace_all_owned(&PING_RE_3, computed, "<@$1>");
+
+    if action.is_some() {
+        format!("\x01ACTION {}\x01", computed.trim_end())
+    } else {
+        computed
+    }
+}
 
-    computed
+/// The text of a CTCP ACTION, as `discord_to_irc_processing` marks them.
+fn as_action(text: &str) -> Option<&str> {
+    text.strip_prefix("\x01ACTION ")?.strip_suffix('\x01')
+}
+
+/// Turns "name:" and "@name" for someone shown on Discord under a rewritten nick back into their
+/// IRC nick, so their client highlights it.
+fn unrewrite_mentions(text: String, rewritten: &RewrittenNicks, network: usize) -> String {
+    regex! {
+        static ADDRESSED_RE = r"^([\w+]+)(?=[:,])";
+        static AT_RE = r"(?<=\s|^)@(\w+)";
+    }
+
+    let text = replace_all_owned(
+        &ADDRESSED_RE,
+        text,
+        OptionReplacer(|caps: &Captures| rewritten.nick(network, &caps[1])),
+    );
+    replace_all_owned(
+        &AT_RE,
+        text,
+        OptionReplacer(|caps: &Captures| {
+            rewritten.nick(network, &caps[1]).map(|n| format!("@{n}"))
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::unrewrite_mentions;
+    use crate::{rewrite_nick, NickRule, RewrittenNicks};
+    use fancy_regex::Regex;
+
+    /// What the IRC side does for everyone in a channel.
+    fn rewritten(nicks: &[&str]) -> RewrittenNicks {
+        let rules = [NickRule {
+            pattern: Regex::new(r"\|.*$").unwrap(),
+            replace: String::new(),
+        }];
+        let mut rewritten = RewrittenNicks::default();
+        for nick in nicks {
+            let shown = rewrite_nick(&rules, nick);
+            if shown != *nick {
+                rewritten.insert(0, &shown, nick);
+            }
+        }
+        rewritten
+    }
+
+    #[test]
+    fn mentions_round_trip() {
+        let rewritten = rewritten(&["alice|away", "bob"]);
+        let unrewrite = |text: &str| unrewrite_mentions(text.to_owned(), &rewritten, 0);
+
+        assert_eq!(unrewrite("alice: hi"), "alice|away: hi");
+        assert_eq!(unrewrite("Alice, hi"), "alice|away, hi");
+        assert_eq!(unrewrite("hi @alice and @bob"), "hi @alice|away and @bob");
+        assert_eq!(unrewrite("bob: hi"), "bob: hi");
+        assert_eq!(unrewrite("malice: hi"), "malice: hi");
+        assert_eq!(unrewrite("mail me@alice"), "mail me@alice");
+        assert_eq!(unrewrite("hi alice"), "hi alice");
+    }
+
+    #[test]
+    fn mentions_per_network() {
+        let mut rewritten = rewritten(&["alice|away"]);
+        assert_eq!(
+            unrewrite_mentions("alice: hi".to_owned(), &rewritten, 1),
+            "alice: hi"
+        );
+
+        rewritten.forget(0, "alice|away");
+        assert_eq!(
+            unrewrite_mentions("alice: hi".to_owned(), &rewritten, 0),
+            "alice: hi"
+        );
+    }
 }
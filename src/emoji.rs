@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+
+/// The longest emoji sequence we try to match, in chars. ZWJ sequences like family emoji can
+/// get quite long.
+const MAX_EMOJI_CHARS: usize = 10;
+
+/// Replaces unicode emoji with their `:shortcode:`, for IRC clients that can't render them.
+/// Emoji without a known shortcode are left alone.
+pub fn unicode_to_shortcodes(message: &str) -> String {
+    let mut new = String::with_capacity(message.len());
+    let mut rest = message;
+
+    'outer: while let Some(c) = rest.chars().next() {
+        if !c.is_ascii() {
+            let ends = rest
+                .char_indices()
+                .map(|(i, c)| i + c.len_utf8())
+                .take(MAX_EMOJI_CHARS)
+                .collect::<Vec<_>>();
+
+            // prefer the longest match so sequences aren't split into their parts
+            for &end in ends.iter().rev() {
+                if let Some(shortcode) =
+                    emojis::get(&rest[..end]).and_then(emojis::Emoji::shortcode)
+                {
+                    new.push(':');
+                    new.push_str(shortcode);
+                    new.push(':');
+                    rest = &rest[end..];
+                    continue 'outer;
+                }
+            }
+        }
+
+        new.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+
+    new
+}
+
+/// How emoji are rendered for IRC.
+#[derive(Default)]
+pub struct EmojiOptions {
+    shortcodes: bool,
+    /// Emoji to ASCII emoticon replacements, longest first so sequences win over their parts
+    emoticons: Vec<(String, String)>,
+}
+
+impl EmojiOptions {
+    pub fn new(shortcodes: bool, emoticons: HashMap<String, String>) -> Self {
+        let mut emoticons = emoticons.into_iter().collect::<Vec<_>>();
+        emoticons.sort_unstable_by(|(a, _), (b, _)| b.len().cmp(&a.len()));
+
+        Self {
+            shortcodes,
+            emoticons,
+        }
+    }
+
+    /// Applies the emoticon map, then turns whatever emoji are left into shortcodes if enabled.
+    pub fn downgrade(&self, message: &str) -> String {
+        let mut message = message.to_owned();
+        for (emoji, emoticon) in &self.emoticons {
+            if message.contains(emoji.as_str()) {
+                message = message.replace(emoji.as_str(), emoticon);
+            }
+        }
+
+        if self.shortcodes {
+            message = unicode_to_shortcodes(&message);
+        }
+
+        message
+    }
+}
+
+/// Looks up the unicode emoji for a shortcode like `thumbsup`.
+pub fn shortcode_to_unicode(shortcode: &str) -> Option<&'static str> {
+    emojis::get_by_shortcode(shortcode).map(emojis::Emoji::as_str)
+}
@@ -0,0 +1,242 @@
+use std::time::{Duration, Instant};
+
+use irc::client::data::Config;
+use reqwest::multipart::{Form, Part};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use tokio::{
+    sync::{Mutex, MutexGuard},
+    time::sleep,
+};
+
+use crate::locale::Locale;
+
+#[derive(Deserialize, JsonSchema, Default)]
+pub struct FloodConfig {
+    /// Lines per second once a burst is used up, defaults to 1
+    rate: Option<f64>,
+    /// Lines that can go out at once after a quiet spell, defaults to 5
+    burst: Option<u32>,
+    /// Longest message that's sent line by line, defaults to 10
+    max_lines: Option<usize>,
+    /// What happens to longer ones, defaults to truncate
+    overflow: Option<Overflow>,
+    /// Where `paste` uploads them, anything that takes a file like 0x0.st does. Defaults to
+    /// https://0x0.st
+    paste_url: Option<String>,
+}
+
+#[derive(Deserialize, JsonSchema, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+enum Overflow {
+    /// Send the first lines and say how many were left out
+    #[default]
+    Truncate,
+    /// Upload the whole message and send the link
+    Paste,
+}
+
+struct Bucket {
+    tokens: f64,
+    at: Instant,
+}
+
+/// Keeps the bridge from flooding itself off IRC. Every network's connection gets a token
+/// bucket, and messages too long to send line by line are cut short or pasted.
+pub struct Flood {
+    rate: f64,
+    burst: f64,
+    burst_lines: u32,
+    max_lines: usize,
+    overflow: Overflow,
+    paste_url: String,
+    client: reqwest::Client,
+    buckets: Vec<Mutex<Bucket>>,
+}
+
+impl Flood {
+    pub fn new(config: FloodConfig, networks: usize) -> anyhow::Result<Self> {
+        let rate = config.rate.unwrap_or(1.0);
+        anyhow::ensure!(rate > 0.0, "[flood] rate has to be more than 0");
+        let burst_lines = config.burst.unwrap_or(5).max(1);
+        let burst = f64::from(burst_lines);
+
+        Ok(Self {
+            rate,
+            burst,
+            burst_lines,
+            // one line always goes to saying what happened to the rest
+            max_lines: config.max_lines.unwrap_or(10).max(2),
+            overflow: config.overflow.unwrap_or_default(),
+            paste_url: config
+                .paste_url
+                .unwrap_or_else(|| String::from("https://0x0.st")),
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .unwrap(),
+            buckets: (0..networks)
+                .map(|_| {
+                    Mutex::new(Bucket {
+                        tokens: burst,
+                        at: Instant::now(),
+                    })
+                })
+                .collect(),
+        })
+    }
+
+    /// Has the IRC library hold back everything else the bridge sends at about the same pace,
+    /// like command replies and announcements that don't go through an outbox.
+    pub fn limit(&self, config: &mut Config) {
+        let window = Duration::from_secs_f64(self.burst / self.rate)
+            .as_secs()
+            .max(1);
+        config.burst_window_length = Some(u32::try_from(window).unwrap_or(u32::MAX));
+        config.max_messages_in_burst = Some(self.burst_lines);
+    }
+
+    /// The bucket for `network`, waiting for whoever is sending there to finish first.
+    pub async fn outbox(&self, network: usize) -> Outbox<'_> {
+        Outbox {
+            flood: self,
+            bucket: self.buckets[network].lock().await,
+        }
+    }
+
+    /// The lines `text` was split into, or fewer of them if there are more than `max_lines`.
+    pub async fn fit(&self, text: &str, mut lines: Vec<String>, locale: &Locale) -> Vec<String> {
+        if lines.len() <= self.max_lines {
+            return lines;
+        }
+
+        if let Overflow::Paste = self.overflow {
+            match self.paste(text).await {
+                Ok(url) => {
+                    return vec![locale.text(
+                        "flood_pasted",
+                        "{count} lines: {url}",
+                        &[("count", &lines.len()), ("url", &url)],
+                    )]
+                }
+                Err(e) => eprintln!("Failed to paste a long message, truncating it: {e:#}"),
+            }
+        }
+
+        let left_out = lines.len() - (self.max_lines - 1);
+        lines.truncate(self.max_lines - 1);
+        lines.push(locale.text(
+            "flood_truncated",
+            "…and {count} more lines",
+            &[("count", &left_out)],
+        ));
+        lines
+    }
+
+    /// Uploads `text` to the paste service, returning its link.
+    pub async fn paste(&self, text: &str) -> anyhow::Result<String> {
+        let part = Part::text(text.to_owned())
+            .file_name("message.txt")
+            .mime_str("text/plain; charset=utf-8")?;
+        let response = self
+            .client
+            .post(&self.paste_url)
+            .multipart(Form::new().part("file", part))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(response.text().await?.trim().to_owned())
+    }
+}
+
+/// A network's bucket, held while a message goes out so its lines stay together.
+pub struct Outbox<'a> {
+    flood: &'a Flood,
+    bucket: MutexGuard<'a, Bucket>,
+}
+
+impl Outbox<'_> {
+    /// Waits until another line can be sent.
+    pub async fn take(&mut self) {
+        let (rate, burst) = (self.flood.rate, self.flood.burst);
+        let bucket = &mut *self.bucket;
+
+        bucket.tokens = (bucket.tokens + bucket.at.elapsed().as_secs_f64() * rate).min(burst);
+        bucket.at = Instant::now();
+        if bucket.tokens < 1.0 {
+            sleep(Duration::from_secs_f64((1.0 - bucket.tokens) / rate)).await;
+            bucket.tokens = 1.0;
+            bucket.at = Instant::now();
+        }
+        bucket.tokens -= 1.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Flood, FloodConfig};
+    use crate::locale::Locale;
+
+    fn flood(config: FloodConfig) -> Flood {
+        Flood::new(config, 1).unwrap()
+    }
+
+    fn lines(count: usize) -> Vec<String> {
+        (1..=count).map(|i| format!("line {i}")).collect()
+    }
+
+    #[tokio::test]
+    async fn fit_max_lines() {
+        let flood = flood(FloodConfig::default());
+        let locale = Locale::default();
+
+        assert_eq!(flood.fit("", lines(10), &locale).await, lines(10));
+
+        let fitted = flood.fit("", lines(11), &locale).await;
+        assert_eq!(fitted.len(), 10);
+        assert_eq!(fitted[..9], lines(9));
+        assert_eq!(fitted[9], "…and 2 more lines");
+    }
+
+    #[tokio::test]
+    async fn fit_keeps_a_line() {
+        let flood = flood(FloodConfig {
+            max_lines: Some(1),
+            ..FloodConfig::default()
+        });
+        let fitted = flood.fit("", lines(3), &Locale::default()).await;
+        assert_eq!(fitted, ["line 1", "…and 2 more lines"]);
+    }
+
+    #[tokio::test]
+    async fn take_burst_and_refill() {
+        // a line every 20ms after the first 3
+        let flood = flood(FloodConfig {
+            rate: Some(50.0),
+            burst: Some(3),
+            ..FloodConfig::default()
+        });
+        let mut outbox = flood.outbox(0).await;
+
+        let start = Instant::now();
+        for _ in 0..3 {
+            outbox.take().await;
+        }
+        assert!(start.elapsed() < Duration::from_millis(15));
+
+        outbox.take().await;
+        assert!(start.elapsed() >= Duration::from_millis(15));
+
+        // back to a full burst, and no more than that
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let start = Instant::now();
+        for _ in 0..3 {
+            outbox.take().await;
+        }
+        assert!(start.elapsed() < Duration::from_millis(15));
+        outbox.take().await;
+        assert!(start.elapsed() >= Duration::from_millis(15));
+    }
+}
@@ -0,0 +1,288 @@
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use serenity::gateway::{ConnectionStage, ShardManager};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::{interval, timeout},
+};
+
+use crate::{latency::Latency, unix_now};
+
+/// Whether the bridge is still doing its job, for `/healthz`, `/metrics` and the systemd
+/// watchdog.
+pub struct Health {
+    networks: Vec<(String, AtomicBool)>,
+    /// Same order as `networks`
+    latencies: Vec<Arc<Mutex<Latency>>>,
+    /// Unix timestamp, 0 until something gets relayed
+    last_relayed: AtomicU64,
+}
+
+impl Health {
+    pub fn new(
+        networks: impl IntoIterator<Item = String>,
+        latencies: Vec<Arc<Mutex<Latency>>>,
+    ) -> Self {
+        Self {
+            networks: networks
+                .into_iter()
+                .map(|name| (name, AtomicBool::new(false)))
+                .collect(),
+            latencies,
+            last_relayed: AtomicU64::new(0),
+        }
+    }
+
+    /// Set by the IRC loops, when they're welcomed and when the connection is gone.
+    pub fn set_irc(&self, network: usize, connected: bool) {
+        self.networks[network].1.store(connected, Ordering::Relaxed);
+    }
+
+    /// For every message that made it to the other side, either way.
+    pub fn relayed(&self) {
+        self.last_relayed.store(unix_now(), Ordering::Relaxed);
+    }
+
+    /// Whether every network is welcomed right now.
+    pub fn irc_connected(&self) -> bool {
+        self.networks
+            .iter()
+            .all(|(_, connected)| connected.load(Ordering::Relaxed))
+    }
+
+    /// Average round trips for each network's IRC server and the Discord API, in milliseconds.
+    fn latencies(&self) -> Vec<(Option<u64>, Option<u64>)> {
+        let millis = |rtt: Duration| u64::try_from(rtt.as_millis()).unwrap_or(u64::MAX);
+        self.latencies
+            .iter()
+            .map(|latency| {
+                let (irc, discord) = latency.lock().unwrap().averages();
+                (irc.map(millis), discord.map(millis))
+            })
+            .collect()
+    }
+
+    async fn shards(shard_manager: &ShardManager) -> Vec<(String, bool)> {
+        shard_manager
+            .runners
+            .lock()
+            .await
+            .iter()
+            .map(|(shard_id, runner)| {
+                (
+                    shard_id.to_string(),
+                    runner.stage == ConnectionStage::Connected,
+                )
+            })
+            .collect()
+    }
+
+    /// Healthy means every IRC network and every shard is connected. How long ago something was
+    /// relayed and the latencies are only reported, quiet channels and slow links are fine.
+    async fn check(&self, shard_manager: &ShardManager) -> (bool, serde_json::Value) {
+        let shards = Self::shards(shard_manager).await;
+        let discord = !shards.is_empty() && shards.iter().all(|(_, connected)| *connected);
+        let healthy = discord && self.irc_connected();
+
+        let last_relayed = match self.last_relayed.load(Ordering::Relaxed) {
+            0 => serde_json::Value::Null,
+            at => at.into(),
+        };
+        let latency = self
+            .networks
+            .iter()
+            .zip(self.latencies())
+            .map(|((name, _), (irc, discord))| {
+                let rtts = serde_json::json!({ "irc": irc, "discord": discord });
+                (name.clone(), rtts)
+            })
+            .collect::<serde_json::Map<_, _>>();
+        let report = serde_json::json!({
+            "healthy": healthy,
+            "irc": self.networks.iter().map(|(name, connected)| {
+                (name.clone(), connected.load(Ordering::Relaxed).into())
+            }).collect::<serde_json::Map<_, _>>(),
+            "discord": shards.into_iter().map(|(id, connected)| {
+                (id, connected.into())
+            }).collect::<serde_json::Map<_, _>>(),
+            "last_relayed": last_relayed,
+            "latency_ms": latency,
+        });
+
+        (healthy, report)
+    }
+
+    /// The same numbers in Prometheus' text format.
+    async fn metrics(&self, shard_manager: &ShardManager) -> String {
+        let mut metrics = String::new();
+
+        metrics.push_str("# TYPE dircord_irc_connected gauge\n");
+        for (name, connected) in &self.networks {
+            let connected = u8::from(connected.load(Ordering::Relaxed));
+            writeln!(
+                metrics,
+                "dircord_irc_connected{{network=\"{name}\"}} {connected}"
+            )
+            .unwrap();
+        }
+
+        metrics.push_str("# TYPE dircord_discord_shard_connected gauge\n");
+        for (shard, connected) in Self::shards(shard_manager).await {
+            let connected = u8::from(connected);
+            writeln!(
+                metrics,
+                "dircord_discord_shard_connected{{shard=\"{shard}\"}} {connected}"
+            )
+            .unwrap();
+        }
+
+        metrics.push_str("# TYPE dircord_last_relayed_timestamp_seconds gauge\n");
+        writeln!(
+            metrics,
+            "dircord_last_relayed_timestamp_seconds {}",
+            self.last_relayed.load(Ordering::Relaxed)
+        )
+        .unwrap();
+
+        // left out until there's a measurement, rather than pretending it's 0
+        metrics.push_str("# TYPE dircord_latency_milliseconds gauge\n");
+        for ((name, _), (irc, discord)) in self.networks.iter().zip(self.latencies()) {
+            for (side, rtt) in [("irc", irc), ("discord", discord)] {
+                if let Some(rtt) = rtt {
+                    writeln!(
+                        metrics,
+                        "dircord_latency_milliseconds{{network=\"{name}\",side=\"{side}\"}} {rtt}"
+                    )
+                    .unwrap();
+                }
+            }
+        }
+
+        metrics
+    }
+}
+
+/// Answers `GET /healthz` on `listener` with the report as JSON, 200 if healthy and 503 if not,
+/// and `GET /metrics` with the same for Prometheus.
+pub async fn serve(listener: TcpListener, health: Arc<Health>, shard_manager: Arc<ShardManager>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                eprintln!("Failed to accept health check connection: {e}");
+                continue;
+            }
+        };
+
+        let health = health.clone();
+        let shard_manager = shard_manager.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, &health, &shard_manager).await {
+                eprintln!("Failed to answer health check: {e}");
+            }
+        });
+    }
+}
+
+async fn respond(
+    mut stream: TcpStream,
+    health: &Health,
+    shard_manager: &ShardManager,
+) -> anyhow::Result<()> {
+    // only the request line matters, and it fits in the first read
+    let mut request = [0; 1024];
+    let read = timeout(Duration::from_secs(5), stream.read(&mut request)).await??;
+    let request = String::from_utf8_lossy(&request[..read]);
+    let mut words = request.split_whitespace();
+
+    let (status, content_type, body) = match (words.next(), words.next()) {
+        (Some("GET" | "HEAD"), Some("/healthz")) => {
+            let (healthy, report) = health.check(shard_manager).await;
+            let status = if healthy {
+                "200 OK"
+            } else {
+                "503 Service Unavailable"
+            };
+            (status, "application/json", report.to_string())
+        }
+        (Some("GET" | "HEAD"), Some("/metrics")) => (
+            "200 OK",
+            "text/plain; version=0.0.4",
+            health.metrics(shard_manager).await,
+        ),
+        _ => ("404 Not Found", "application/json", String::from("{}")),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        if request.starts_with("HEAD") { "" } else { &body },
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Tells systemd we're up once everything is connected, then pings its watchdog for as long as
+/// things stay healthy. A hung bridge stops pinging and gets restarted, with `WatchdogSec=` set.
+#[cfg(unix)]
+pub async fn notify_systemd(health: Arc<Health>, shard_manager: Arc<ShardManager>) {
+    let Some(socket) = std::env::var_os("NOTIFY_SOCKET") else {
+        eprintln!("systemd_watchdog is on, but NOTIFY_SOCKET isn't set. Is the unit Type=notify?");
+        return;
+    };
+    // systemd wants pings at least twice per timeout
+    let every = std::env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse().ok())
+        .map_or(Duration::from_secs(15), |usec| {
+            Duration::from_micros(usec) / 2
+        });
+
+    let mut timer = interval(every);
+    let mut ready = false;
+    loop {
+        timer.tick().await;
+
+        if !health.check(&shard_manager).await.0 {
+            continue;
+        }
+        let state = if ready {
+            "WATCHDOG=1"
+        } else {
+            "READY=1\nWATCHDOG=1"
+        };
+        ready = true;
+        if let Err(e) = sd_notify(&socket, state) {
+            eprintln!("Failed to notify systemd: {e}");
+        }
+    }
+}
+
+#[cfg(unix)]
+fn sd_notify(socket: &std::ffi::OsStr, state: &str) -> std::io::Result<()> {
+    use std::os::unix::{ffi::OsStrExt, net::UnixDatagram};
+
+    let sender = UnixDatagram::unbound()?;
+    match socket.as_bytes().strip_prefix(b"@") {
+        // an abstract socket, rather than a path
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+            let addr = SocketAddr::from_abstract_name(name)?;
+            sender.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        _ => {
+            sender.send_to(state.as_bytes(), socket)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,180 @@
+use std::collections::BTreeSet;
+
+use fancy_regex::Regex;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serenity::model::id::UserId;
+
+use crate::commands::mask_matches;
+
+#[derive(Deserialize, JsonSchema)]
+pub struct IgnoreConfig {
+    /// IRC nicks, or hostmasks like `*!*@spam.example.org`
+    irc: Option<Vec<String>>,
+    /// Discord user IDs
+    discord: Option<Vec<u64>>,
+    /// Messages matching any of these aren't relayed, either way
+    patterns: Option<Vec<String>>,
+}
+
+/// Who and what isn't relayed, from `[ignore]` in the config and `!dircord ignore` at runtime.
+/// Runtime additions last until the next restart, a reload keeps them.
+#[derive(Default)]
+pub struct Ignores {
+    /// Lowercase, IRC nicks don't care about case
+    nicks: BTreeSet<String>,
+    masks: BTreeSet<String>,
+    users: BTreeSet<UserId>,
+    patterns: Vec<Regex>,
+    /// What `!dircord ignore` and `ignore-pattern` added
+    added: BTreeSet<String>,
+    added_patterns: Vec<String>,
+}
+
+/// A Discord user ID on its own or as a mention, anything with `!` or `@` in it is a hostmask and
+/// the rest are nicks.
+enum Entry {
+    Nick(String),
+    Mask(String),
+    User(UserId),
+}
+
+impl Entry {
+    fn parse(entry: &str) -> Self {
+        let id = entry
+            .strip_prefix("<@")
+            .and_then(|e| e.strip_suffix('>'))
+            .map(|e| e.trim_start_matches('!'))
+            .unwrap_or(entry);
+        // IRC nicks can't start with a digit, so this can't be one
+        if let Ok(id) = id.parse::<u64>() {
+            if id != 0 {
+                return Self::User(UserId::from(id));
+            }
+        }
+        if entry.contains(['!', '@']) {
+            Self::Mask(entry.to_lowercase())
+        } else {
+            Self::Nick(entry.to_lowercase())
+        }
+    }
+}
+
+impl Ignores {
+    pub fn new(config: Option<IgnoreConfig>) -> anyhow::Result<Self> {
+        let mut ignores = Self::default();
+        let Some(config) = config else {
+            return Ok(ignores);
+        };
+
+        for entry in config.irc.unwrap_or_default() {
+            ignores.insert(&entry);
+        }
+        ignores.users.extend(
+            config
+                .discord
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|&id| id != 0)
+                .map(UserId::from),
+        );
+        for pattern in config.patterns.unwrap_or_default() {
+            ignores
+                .insert_pattern(&pattern)
+                .map_err(|e| anyhow::anyhow!("Bad pattern in [ignore]: {e}"))?;
+        }
+        Ok(ignores)
+    }
+
+    /// `[ignore]` from a reloaded config, with what was added at runtime still there.
+    pub fn reloaded(&self, config: Option<IgnoreConfig>) -> anyhow::Result<Self> {
+        let mut ignores = Self::new(config)?;
+        for entry in &self.added {
+            ignores.ignore(entry);
+        }
+        for pattern in &self.added_patterns {
+            // these were valid regexes when they were added
+            let _ = ignores.ignore_pattern(pattern);
+        }
+        Ok(ignores)
+    }
+
+    /// A nick, hostmask or Discord user ID. Whether it wasn't ignored already.
+    pub fn ignore(&mut self, entry: &str) -> bool {
+        self.added.insert(entry.to_lowercase());
+        self.insert(entry)
+    }
+
+    fn insert(&mut self, entry: &str) -> bool {
+        match Entry::parse(entry) {
+            Entry::Nick(nick) => self.nicks.insert(nick),
+            Entry::Mask(mask) => self.masks.insert(mask),
+            Entry::User(user_id) => self.users.insert(user_id),
+        }
+    }
+
+    /// Whether it was ignored. Ones from the config come back on a reload.
+    pub fn unignore(&mut self, entry: &str) -> bool {
+        self.added.remove(&entry.to_lowercase());
+        match Entry::parse(entry) {
+            Entry::Nick(nick) => self.nicks.remove(&nick),
+            Entry::Mask(mask) => self.masks.remove(&mask),
+            Entry::User(user_id) => self.users.remove(&user_id),
+        }
+    }
+
+    /// Whether the pattern wasn't there already, or why it isn't a regex.
+    pub fn ignore_pattern(&mut self, pattern: &str) -> Result<bool, String> {
+        let added = self.insert_pattern(pattern)?;
+        if !self.added_patterns.iter().any(|p| p == pattern) {
+            self.added_patterns.push(pattern.to_owned());
+        }
+        Ok(added)
+    }
+
+    fn insert_pattern(&mut self, pattern: &str) -> Result<bool, String> {
+        if self.patterns.iter().any(|re| re.as_str() == pattern) {
+            return Ok(false);
+        }
+        let re = Regex::new(pattern).map_err(|e| format!("{pattern} isn't a valid regex: {e}"))?;
+        self.patterns.push(re);
+        Ok(true)
+    }
+
+    /// Whether the pattern was there.
+    pub fn unignore_pattern(&mut self, pattern: &str) -> bool {
+        self.added_patterns.retain(|p| p != pattern);
+        let before = self.patterns.len();
+        self.patterns.retain(|re| re.as_str() != pattern);
+        self.patterns.len() != before
+    }
+
+    /// `hostmask` is `nick!user@host`, when the server sent one.
+    pub fn ignores_irc(&self, nick: &str, hostmask: Option<&str>) -> bool {
+        self.nicks.contains(&nick.to_lowercase())
+            || hostmask.map_or(false, |hostmask| {
+                self.masks.iter().any(|mask| mask_matches(mask, hostmask))
+            })
+    }
+
+    pub fn ignores_discord(&self, user_id: UserId) -> bool {
+        self.users.contains(&user_id)
+    }
+
+    pub fn ignores_text(&self, text: &str) -> bool {
+        self.patterns
+            .iter()
+            .any(|re| re.is_match(text).unwrap_or(false))
+    }
+
+    /// Everything ignored, for `!dircord status`.
+    pub fn entries(&self) -> Vec<String> {
+        self.nicks
+            .iter()
+            .chain(&self.masks)
+            .cloned()
+            .chain(self.users.iter().map(|id| id.0.get().to_string()))
+            .chain(self.patterns.iter().map(|re| format!("/{}/", re.as_str())))
+            .collect()
+    }
+}
@@ -1,28 +1,77 @@
-use irc::{client::Client as IrcClient, proto::Command};
-
-use std::{collections::HashMap, num::NonZeroU64, sync::Arc, time::Instant};
+use anyhow::{anyhow, Context};
+use chrono::DateTime;
+use irc::{
+    client::Client as IrcClient,
+    proto::{message::Tag, CapSubCommand, Capability, ChannelMode, Command, Message, Mode, Prefix},
+};
 
-use tokio::sync::{mpsc::unbounded_channel, Mutex};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::{self, Write},
+    num::NonZeroU64,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
-use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio::{
+    select,
+    sync::{
+        mpsc::{error::SendError, unbounded_channel, UnboundedReceiver, UnboundedSender},
+        oneshot, Mutex, RwLock,
+    },
+    time::{interval, timeout},
+};
 
 use serenity::{
-    builder::{EditChannel, ExecuteWebhook},
+    builder::{
+        CreateAttachment, CreateEmbed, CreateEmbedAuthor, CreateMessage, EditChannel,
+        ExecuteWebhook,
+    },
     cache::Cache,
     futures::StreamExt,
     http::Http,
     model::{
+        channel::ReactionType,
         guild::Emoji,
-        id::ChannelId,
-        prelude::{GuildChannel, Member, UserId},
+        id::{ChannelId, MessageId},
+        prelude::{GuildChannel, Member, OnlineStatus, UserId},
         webhook::Webhook,
     },
-    prelude::Mentionable,
+    prelude::{Mentionable, TypeMap},
     utils::{content_safe, ContentSafeOptions},
 };
 
-use crate::{regex, OptionReplacer};
+use crate::{
+    admin::{self, AdminCommand},
+    audit::{AuditEntry, AuditLog},
+    auth::Login,
+    colours::irc_to_ansi,
+    commands::{irc_command, labeled},
+    discord_irc::{message_link_previews, set_irc_status},
+    emoji::shortcode_to_unicode,
+    flood::Flood,
+    health::Health,
+    links::Links,
+    locale::Locale,
+    members::{find_by_name, search_missing},
+    message_cache::MessageCache,
+    permissions::Caller,
+    private::{thread_for, DmSessions, QueryThreads},
+    regex, replace_all_owned, report, rewrite_nick, short_time, unix_now,
+    uploads::ImageUploader,
+    AdminChannelKey, AuditLogKey, AvatarsKey, ChannelMappingKey, ChannelOptions, ChannelOptionsKey,
+    ChannelPatternsKey, ChatLogKey, DefaultAvatarKey, DmSessionsKey, EventBacklogKey, FloodKey,
+    HealthKey, IgnoresKey, ImageUploaderKey, IrcQueryKey, IrcStatus, LatencyKey, LinksKey,
+    LocaleKey, MembersKey, MessageCacheKey, MirrorModerationKey, NickRulesKey, OptionReplacer,
+    PausesKey, PendingRepliesKey, PermissionsKey, PingTimeoutKey, PmInboxKey, PuppetsKey,
+    QueryThreadsKey, RewrittenNicksKey, SendAs, SendersKey, SpoilersKey, TopicInfo, TopicKey,
+    TranscoderKey, TranslatorKey, UrlCleanerKey, WebhooksKey,
+};
 
+use ellipse::Ellipse;
 use fancy_regex::{Captures, Replacer};
 
 macro_rules! unwrap_or_continue {
@@ -34,76 +83,1069 @@ macro_rules! unwrap_or_continue {
     };
 }
 
+/// How often the IRC server is pinged to check the connection is still alive.
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+/// How long shutting down waits for the Discord queue to empty.
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often an invite can set up a Discord channel for a pattern, per network.
+const PATTERN_INVITE_EVERY: Duration = Duration::from_secs(60);
+/// How long a WHOIS for `!dircord` is trusted before asking again.
+const WHOIS_CACHE_FOR: Duration = Duration::from_secs(300);
+/// How often `!dircord` from someone not known to be an admin can send a WHOIS, per network.
+const ADMIN_WHOIS_EVERY: Duration = Duration::from_secs(5);
+
+/// Returned by [`irc_loop`] when the IRC server stops answering pings, which usually means the
+/// connection died without either side noticing.
+#[derive(Debug)]
+pub struct Stalled(Duration);
+
+impl fmt::Display for Stalled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "IRC hasn't answered a ping in {}s", self.0.as_secs())
+    }
+}
+
+impl std::error::Error for Stalled {}
+
+/// Returned by [`irc_loop`] when the server hangs up on us or the connection breaks.
+#[derive(Debug)]
+pub struct Lost(String);
+
+impl fmt::Display for Lost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "IRC connection lost: {}", self.0)
+    }
+}
+
+impl std::error::Error for Lost {}
+
+regex! {
+    static PING_NICK_1 = r"^([\w+]+)(?::|,)";
+    static PING_RE_2 = r"(?<=\s|^)@(\w+)";
+}
+
 #[allow(clippy::too_many_lines)] // missing, fight me
 pub async fn irc_loop(
+    network: usize,
+    login: &Login,
     mut client: IrcClient,
     http: Arc<Http>,
     cache: Arc<Cache>,
-    mapping: Arc<HashMap<String, u64>>,
-    webhooks: HashMap<String, Webhook>,
-    members: Arc<Mutex<Vec<Member>>>,
+    data: Arc<RwLock<TypeMap>>,
     cache_ttl: Option<u64>,
 ) -> anyhow::Result<()> {
+    regex! {
+        static REPLY_NICK_RE = r"^([\w+]+)[:,]\s+";
+        static SHORT_REPLY_RE = r"^@([0-9a-zA-Z]{3})\s+";
+        static QUOTE_NICK_RE = r"^>>([^\s:,]+)[:,]?\s+";
+        static QUOTE_TEXT_RE = r#"^>\s*"([^"]+)"\s+"#;
+        static REACT_RE = r"^\+(:\w+:|[^\w\s]+)\s+([0-9a-zA-Z]{3})$";
+    }
+
+    let (
+        members,
+        message_cache,
+        channel_options,
+        topics,
+        admin_channel,
+        audit_log,
+        chat_log,
+        transcoder,
+        links,
+        dm_sessions,
+        pending_replies,
+        pauses,
+        permissions,
+        nick_rules,
+        avatars,
+        default_avatar,
+        locale,
+        url_cleaner,
+        translator,
+        latency,
+        ping_timeout,
+        event_backlog,
+        mut mapping,
+        patterns,
+        query_threads,
+        mut webhooks,
+        mirror_moderation,
+        spoilers,
+        image_uploader,
+        ignores,
+        health,
+        puppets,
+        pm_inbox,
+        flood,
+    ) = {
+        let data = data.read().await;
+        (
+            data.get::<MembersKey>().unwrap().clone(),
+            data.get::<MessageCacheKey>().unwrap().clone(),
+            data.get::<ChannelOptionsKey>().unwrap().clone(),
+            data.get::<TopicKey>().unwrap().clone(),
+            data.get::<AdminChannelKey>().copied().unwrap(),
+            data.get::<AuditLogKey>().unwrap().clone(),
+            data.get::<ChatLogKey>().unwrap().clone(),
+            data.get::<TranscoderKey>().copied().unwrap(),
+            data.get::<LinksKey>().unwrap().clone(),
+            data.get::<DmSessionsKey>().unwrap().clone(),
+            data.get::<PendingRepliesKey>().unwrap()[network].clone(),
+            data.get::<PausesKey>().unwrap().clone(),
+            data.get::<PermissionsKey>().unwrap().clone(),
+            data.get::<NickRulesKey>().unwrap().clone(),
+            data.get::<AvatarsKey>().unwrap().clone(),
+            data.get::<DefaultAvatarKey>().unwrap().clone(),
+            data.get::<LocaleKey>().unwrap().clone(),
+            data.get::<UrlCleanerKey>().unwrap().clone(),
+            data.get::<TranslatorKey>().unwrap().clone(),
+            data.get::<LatencyKey>().unwrap()[network].clone(),
+            data.get::<PingTimeoutKey>().copied().unwrap(),
+            data.get::<EventBacklogKey>().copied().unwrap(),
+            // not the config's, it has channels bridged through patterns since then too
+            data.get::<ChannelMappingKey>().unwrap().clone(),
+            data.get::<ChannelPatternsKey>().unwrap().clone(),
+            data.get::<QueryThreadsKey>().unwrap().clone(),
+            data.get::<WebhooksKey>().unwrap().clone(),
+            data.get::<MirrorModerationKey>().copied().unwrap(),
+            data.get::<SpoilersKey>().unwrap().clone(),
+            data.get::<ImageUploaderKey>().unwrap().clone(),
+            data.get::<IgnoresKey>().unwrap().clone(),
+            data.get::<HealthKey>().unwrap().clone(),
+            data.get::<PuppetsKey>().unwrap().clone(),
+            data.get::<PmInboxKey>().copied().unwrap(),
+            data.get::<FloodKey>().unwrap().clone(),
+        )
+    };
+    // the other networks' channels are theirs to relay
+    let senders = data.read().await.get::<SendersKey>().unwrap().clone();
+    mapping.retain(|channel, _| senders.network(channel) == network);
+    let rewritten_nicks = data
+        .read()
+        .await
+        .get::<RewrittenNicksKey>()
+        .unwrap()
+        .clone();
+    rewritten_nicks.lock().unwrap().forget_network(network);
+
     let (send, recv) = unbounded_channel();
-    tokio::spawn(msg_task(UnboundedReceiverStream::new(recv)));
+    let send = RelayQueue {
+        sender: send,
+        depth: Arc::default(),
+    };
+    let mut msg_handle = tokio::spawn(msg_task(
+        recv,
+        send.depth.clone(),
+        http.clone(),
+        message_cache.clone(),
+        event_backlog,
+        audit_log,
+        image_uploader,
+        health.clone(),
+        data.clone(),
+    ));
+
+    // names that member searches in the background looked up, so their cached misses go
+    let (searched_send, mut searched) = unbounded_channel::<Vec<String>>();
+
+    // lets commands look at the state below, which only this loop can touch
+    let (query_send, mut queries) = unbounded_channel();
+    data.write()
+        .await
+        .get_mut::<IrcQueryKey>()
+        .unwrap()
+        .insert(network, query_send);
 
-    let mut avatar_cache: HashMap<String, Option<String>> = HashMap::new();
+    // made once here so relaying doesn't allocate them every line
+    let default_avatars: HashMap<&str, Arc<str>> = channel_options
+        .iter()
+        .filter_map(|(channel, o)| {
+            Some((channel.as_str(), Arc::from(o.default_avatar.as_deref()?)))
+        })
+        .collect();
+
+    // set when we're asked to shut down, the loop stops reading from IRC then
+    let mut quit_reason = None;
+    let mut avatar_cache: HashMap<String, Option<Arc<str>>> = HashMap::new();
     let mut id_cache: HashMap<String, Option<u64>> = HashMap::new();
     let mut emoji_cache: Vec<Emoji> = Vec::new();
     let mut channel_users: HashMap<String, Vec<String>> = HashMap::new();
+    let mut seen: HashMap<String, Seen> = HashMap::new();
+    // commands waiting on a WHOIS to tell who sent them, by lowercase nick
+    let mut awaiting_whois: HashMap<String, Awaiting> = HashMap::new();
+    // when `!dircord` last looked someone up, after which their account is in `seen`
+    let mut whoised: HashMap<String, Instant> = HashMap::new();
+    let mut last_admin_whois: Option<Instant> = None;
+    // whether a missing account tag means someone isn't logged in
+    let mut tags_accounts = false;
+    let mut last_pattern_invite: Option<Instant> = None;
 
     let mut ttl = Instant::now();
 
-    client.identify()?;
+    // lets us tell when bouncer playback was originally sent
+    client.send_cap_req(&[Capability::ServerTime])?;
+    // requested on its own, a server that doesn't know it would refuse both
+    client.send_cap_req(&[Capability::Custom("account-tag")])?;
+    // for msgid, so replies and such can point at exact messages
+    client.send_cap_req(&[Capability::Custom("message-tags")])?;
+    // every prefix in NAMES, not just the highest, so taking op doesn't lose voice
+    client.send_cap_req(&[Capability::MultiPrefix])?;
+    // together though, labels on replies longer than a line need batches
+    pending_replies.lock().await.set_labels(false);
+    client.send_cap_req(&[
+        Capability::Custom("batch"),
+        Capability::Custom("labeled-response"),
+    ])?;
+    if login.uses_sasl() {
+        // identify() ends capability negotiation right away, SASL has to finish before that
+        client.send_cap_req(&[Capability::Sasl])?;
+        register(&client)?;
+    } else {
+        client.identify()?;
+    }
     let mut stream = client.stream()?;
 
-    for k in mapping.keys() {
-        client.send(Command::NAMES(Some(k.clone()), None))?;
-        client.send_topic(k, "")?;
-    }
+    // labels of the batches replies are coming in
+    let mut batch_labels: HashMap<String, String> = HashMap::new();
+    // NAMES replies can take several lines, they're only complete at the end
+    let mut names_replies: HashMap<String, Vec<String>> = HashMap::new();
 
     let mut channels_cache = None;
     let mut guild = None;
+    let mut welcomed = false;
+    // by SASL, or NickServ telling us afterwards
+    let mut logged_in = false;
+
+    let mut user_count_timer = interval(Duration::from_secs(60));
+    let mut ping_timer = interval(PING_INTERVAL);
+    // anything still outstanding was sent on a connection that's gone now
+    latency.lock().unwrap().forget_ping();
+    let mut shown_counts: HashMap<String, usize> = HashMap::new();
+    let mut last_count_edit: HashMap<String, Instant> = HashMap::new();
+
+    let mut churn: HashMap<String, Churn> = HashMap::new();
+
+    // when people last spoke, for smart join/part
+    let mut last_spoke: HashMap<(String, String), Instant> = HashMap::new();
+    let longest_window = channel_options
+        .values()
+        .filter_map(|o| o.smart_join_part)
+        .max()
+        .map_or(Duration::ZERO, |minutes| Duration::from_secs(minutes * 60));
+
+    let safe_opts = ContentSafeOptions::new()
+        .clean_role(false)
+        .clean_user(false)
+        .clean_channel(false)
+        .show_discriminator(false)
+        .clean_here(true) // setting these to true explicitly isn't needed,
+        .clean_everyone(true); // but i did it anyway for readability
+
+    // plain channels don't get mentions made for them, so any that are there were typed out
+    let plain_safe_opts = safe_opts.clone().clean_role(true).clean_user(true);
+
+    let mut pastes: HashMap<(String, String), Paste> = HashMap::new();
+    let mut webhook_picker = WebhookPicker::default();
+    let mut paste_timer = interval(Duration::from_secs(1));
+
+    let to_discord = |channel: &str| {
+        channel_options
+            .get(channel)
+            .map_or(true, ChannelOptions::relays_to_discord)
+            && pauses.lock().unwrap().to_discord(channel)
+    };
+
+    loop {
+        let orig_message = select! {
+            m = stream.next() => match m {
+                Some(Ok(m)) => m,
+                Some(Err(e)) => return Err(Lost(e.to_string()).into()),
+                None => return Err(Lost(String::from("the server closed the connection")).into()),
+            },
+            r = &mut msg_handle => {
+                // the queue only ends if sending to discord failed, so there's no point going on
+                return match r {
+                    Ok(r) => r.context("Relaying to Discord failed"),
+                    Err(e) => Err(anyhow!("Discord message queue panicked: {e}")),
+                };
+            },
+            _ = user_count_timer.tick() => {
+                for (channel, &id) in mapping.iter() {
+                    let count =
+                        unwrap_or_continue!(user_count(&channel_options, &channel_users, channel));
+
+                    // discord only allows two channel edits per 10 minutes
+                    if shown_counts.get(channel) == Some(&count)
+                        || last_count_edit
+                            .get(channel)
+                            .map_or(false, |t| t.elapsed() < Duration::from_secs(300))
+                    {
+                        continue;
+                    }
+
+                    let channel_id = ChannelId::from(id);
+                    let known = topics.lock().await.get(channel).map(|i| i.topic.clone());
+                    let base = match known {
+                        Some(topic) => topic,
+                        None => match channel_id.to_channel(&http).await {
+                            Ok(c) => c
+                                .guild()
+                                .and_then(|c| c.topic)
+                                .map(|t| strip_user_count(&t).to_owned())
+                                .unwrap_or_default(),
+                            Err(e) => {
+                                eprintln!("Failed to get the topic of {channel_id}: {e}");
+                                continue;
+                            }
+                        },
+                    };
+
+                    // one failing channel shouldn't stop the others, or the bridge
+                    last_count_edit.insert(channel.clone(), Instant::now());
+                    let builder = EditChannel::new().topic(with_user_count(&base, Some(count)));
+                    if let Err(e) = channel_id.edit(&http, builder).await {
+                        eprintln!("Failed to show the user count in {channel_id}: {e}");
+                        continue;
+                    }
+                    shown_counts.insert(channel.clone(), count);
+                }
+
+                flush_churn(&mut churn, &channel_options, &mapping, &locale, &send, false)?;
+                continue;
+            }
+            _ = paste_timer.tick() => {
+                flush_pastes(&mut pastes, &send, &flood, &locale, false).await?;
+                continue;
+            }
+            Some(names) = searched.recv() => {
+                for name in names {
+                    id_cache.remove(&name);
+                    avatar_cache.remove(&name);
+                }
+                continue;
+            }
+            Some(query) = queries.recv() => {
+                match query {
+                    IrcQuery::Caches(reply) => {
+                        let _ = reply.send(cache_report(
+                            &avatar_cache,
+                            &id_cache,
+                            &channel_users,
+                            channels_cache.as_ref(),
+                            &emoji_cache,
+                            send.depth(),
+                        ));
+                    }
+                    IrcQuery::Ops(channel, reply) => {
+                        let ops = op_list(&channel_users, &channel, &locale);
+                        let _ = reply.send(transcoder.decode(&ops).into_owned());
+                    }
+                    IrcQuery::Info(name, reply) => {
+                        let info = irc_info(&seen, &channel_users, &name);
+                        let _ = reply.send(transcoder.decode(&info).into_owned());
+                    }
+                    IrcQuery::Reload => {
+                        let (mut reloaded, senders) = {
+                            let data = data.read().await;
+                            webhooks = data.get::<WebhooksKey>().unwrap().clone();
+                            (
+                                data.get::<ChannelMappingKey>().unwrap().clone(),
+                                data.get::<SendersKey>().unwrap().clone(),
+                            )
+                        };
+                        reloaded.retain(|channel, _| senders.network(channel) == network);
+
+                        for channel in mapping.keys().filter(|c| !reloaded.contains_key(*c)) {
+                            client.send_part(channel)?;
+                            channel_users.remove(channel);
+                        }
+                        for channel in reloaded.keys().filter(|c| !mapping.contains_key(*c)) {
+                            client.send_join(channel)?;
+                        }
+                        mapping = reloaded;
+                    }
+                    IrcQuery::Shutdown(reason) => {
+                        quit_reason = Some(reason);
+                        break;
+                    }
+                }
+                continue;
+            }
+            _ = ping_timer.tick() => {
+                let waiting = latency.lock().unwrap().waiting();
+                match waiting {
+                    Some(waited) if waited > ping_timeout => return Err(Stalled(waited).into()),
+                    Some(_) => {}
+                    // servers don't answer pings until we're registered
+                    None if welcomed => {
+                        if let Some(token) = latency.lock().unwrap().ping_token() {
+                            client.send(Command::PING(token, None))?;
+                        }
+                    }
+                    None => {}
+                }
+
+                let start = Instant::now();
+                if http.get_current_user().await.is_ok() {
+                    latency.lock().unwrap().record_discord(start.elapsed());
+                }
+
+                continue;
+            }
+        };
 
-    while let Some(orig_message) = stream.next().await.transpose()? {
         if ttl.elapsed().as_secs() > cache_ttl.unwrap_or(1800) {
             avatar_cache.clear();
             channels_cache = None;
             guild = None;
             emoji_cache.clear();
+            last_spoke.retain(|_, t| t.elapsed() < longest_window);
+            seen.retain(|_, s| unix_now().saturating_sub(s.at) < SEEN_FOR);
+            webhook_picker.speakers.clear();
             ttl = Instant::now();
         }
 
+        // replies to a labeled command carry its label, or come in a batch that does
+        let label = tag(&orig_message, "label")
+            .or_else(|| {
+                tag(&orig_message, "batch")
+                    .and_then(|batch| batch_labels.get(batch))
+                    .map(String::as_str)
+            })
+            .map(ToOwned::to_owned);
+
+        if let Command::BATCH(ref reference, _, _) = orig_message.command {
+            if let Some(reference) = reference.strip_prefix('+') {
+                if let Some(ref label) = label {
+                    batch_labels.insert(reference.to_owned(), label.clone());
+                }
+            } else if let Some(reference) = reference.strip_prefix('-') {
+                batch_labels.remove(reference);
+            }
+            continue;
+        }
+
+        // the services account of someone that sent a command, for servers without account-tag
+        if let Some((nick, account)) = whois_account(&orig_message.command) {
+            if let Some(s) = seen.get_mut(nick) {
+                s.account = Some(account.to_owned());
+            }
+            match awaiting_whois.remove(&nick.to_lowercase()) {
+                Some(Awaiting::Admin(mask, command)) => {
+                    let caller = Caller::Irc {
+                        mask: &mask,
+                        account: Some(account),
+                    };
+                    if admin::allowed(&permissions, &caller) {
+                        let reply = admin::run(&http, &data, command)
+                            .await
+                            .unwrap_or_else(|e| e);
+                        for line in reply.lines() {
+                            client.send_notice(nick, transcoder.encode(line))?;
+                        }
+                    }
+                }
+                Some(Awaiting::Link(text)) => {
+                    let reply =
+                        links
+                            .lock()
+                            .unwrap()
+                            .irc_command(nick, Some(account), &text, &locale);
+                    if let Some(reply) = reply {
+                        client.send_notice(nick, transcoder.encode(&reply))?;
+                    }
+                }
+                None => {}
+            }
+            continue;
+        }
+
         if let Command::Response(response, args) = orig_message.command {
             use irc::client::prelude::Response;
 
-            if response == Response::RPL_NAMREPLY {
+            // not logged in, so not an admin after all and nothing to link
+            if response == Response::RPL_ENDOFWHOIS {
+                if let Some(nick) = args.get(1) {
+                    if let Some(Awaiting::Link(text)) = awaiting_whois.remove(&nick.to_lowercase())
+                    {
+                        let reply = links
+                            .lock()
+                            .unwrap()
+                            .irc_command(nick, None, &text, &locale);
+                        if let Some(reply) = reply {
+                            client.send_notice(nick, transcoder.encode(&reply))?;
+                        }
+                    }
+                }
+            }
+
+            if response == Response::RPL_LOGGEDIN {
+                logged_in = true;
+            }
+            if login.uses_sasl()
+                && matches!(
+                    response,
+                    Response::RPL_SASLSUCCESS
+                        | Response::ERR_SASLFAIL
+                        | Response::ERR_SASLTOOLONG
+                        | Response::ERR_SASLABORT
+                        | Response::ERR_SASLALREADY
+                )
+            {
+                // registration waits for this, failures still reach the admin channel below
+                client.send(Command::CAP(None, CapSubCommand::END, None, None))?;
+            }
+
+            if response == Response::RPL_WELCOME {
+                welcomed = true;
+
+                // too late for channels that need it right away, that's what SASL is for
+                if let (Some(password), false) = (login.nickserv_password(), logged_in) {
+                    client.send_privmsg("NickServ", format!("IDENTIFY {password}"))?;
+                }
+
+                // sent now that caps are settled, so these get labels too and their replies can't
+                // be taken for answers to a /mode
+                let mut pending = pending_replies.lock().await;
+                for channel in mapping.keys() {
+                    client.send(labeled(
+                        Command::NAMES(Some(channel.clone()), None),
+                        pending.label(),
+                    ))?;
+                    client.send(labeled(
+                        Command::TOPIC(channel.clone(), None),
+                        pending.label(),
+                    ))?;
+                }
+                drop(pending);
+
+                // the IRC config only has the channels bridged at startup
+                for channel in mapping.keys() {
+                    if !client.config().channels.contains(channel) {
+                        client.send_join(channel)?;
+                    }
+                }
+
+                let server = match orig_message.prefix {
+                    Some(Prefix::ServerName(ref name)) => name.clone(),
+                    _ => String::from("IRC"),
+                };
+
+                health.set_irc(network, true);
+                // the presence stays on disconnected while another network is still down
+                if health.irc_connected() {
+                    set_irc_status(
+                        &data,
+                        IrcStatus::Connected {
+                            server,
+                            channels: mapping.len(),
+                        },
+                    )
+                    .await;
+                }
+            } else if response == Response::RPL_NAMREPLY {
                 let channel = args[2].to_string();
-                let users = args[3]
-                    .split(' ')
-                    .map(ToOwned::to_owned)
-                    .collect::<Vec<String>>();
+                let users = args[3].split(' ').map(ToOwned::to_owned);
 
-                channel_users.insert(channel, users);
+                names_replies.entry(channel).or_default().extend(users);
+            } else if response == Response::RPL_ENDOFNAMES {
+                if let Some(users) = names_replies.remove(&args[1]) {
+                    channel_users.insert(args[1].clone(), users);
+                }
             } else if response == Response::RPL_TOPIC {
                 let channel = &args[1];
-                let topic = &args[2];
+                let topic = &transcoder.decode(&args[2]);
+
+                let channel_id = ChannelId::from(*unwrap_or_continue!(mapping.get(channel)));
+                let count = user_count(&channel_options, &channel_users, channel);
+                let changed = sync_topic(
+                    &http,
+                    &channel_options,
+                    &topics,
+                    channel,
+                    channel_id,
+                    topic,
+                    None,
+                    count,
+                )
+                .await;
+                // Discord not taking it shouldn't stop the bridge
+                let changed = match changed {
+                    Ok(changed) => changed,
+                    Err(e) => {
+                        eprintln!("Failed to set the topic of {channel_id}: {e:#}");
+                        continue;
+                    }
+                };
+
+                if let (true, Some(count)) = (changed, count) {
+                    shown_counts.insert(channel.clone(), count);
+                    last_count_edit.insert(channel.clone(), Instant::now());
+                }
+            } else if response == Response::RPL_CHANNELMODEIS {
+                let text = format!(
+                    "Modes on {}: {}",
+                    args[1],
+                    transcoder.decode(&args[2..].join(" "))
+                );
+                pending_replies
+                    .lock()
+                    .await
+                    .resolve(label.as_deref(), &args[1], text);
+            } else if let (Some((key, reason)), Some(channel)) =
+                (join_failure(response as u16), args.get(1))
+            {
+                // otherwise the mapping just never works and nobody knows why
+                let text = locale.text(
+                    "join_failed",
+                    "Couldn't join {channel} on IRC: {reason} ({server})",
+                    &[
+                        ("channel", channel),
+                        ("reason", &locale.text(key, reason, &[])),
+                        (
+                            "server",
+                            &transcoder.decode(args.last().map_or("", String::as_str)),
+                        ),
+                    ],
+                );
+                eprintln!("{text}");
+
+                if let Some(&id) = mapping.get(channel) {
+                    send.event(QueuedMessage::Raw {
+                        channel_id: ChannelId::from(id),
+                        message: format!("**{text}**"),
+                        reply_to: None,
+                    })?;
+                }
+                if admin_channel.map(|c| c.0.get()) != mapping.get(channel).copied() {
+                    notify_admin(&send, admin_channel, "Join failed", &text)?;
+                }
+            } else if response as u16 >= 400 {
+                // error numerics, the first argument is always our own nick
+                let answered = match args.get(1) {
+                    Some(target) => pending_replies.lock().await.resolve(
+                        label.as_deref(),
+                        target,
+                        transcoder
+                            .decode(args.last().map_or("", String::as_str))
+                            .into_owned(),
+                    ),
+                    None => false,
+                };
 
-                let channel = ChannelId::from(*unwrap_or_continue!(mapping.get(channel)));
-                let builder = EditChannel::new().topic(topic);
-                channel.edit(&http, builder).await?;
+                if !answered {
+                    let text = args.get(1..).unwrap_or_default().join(" ");
+                    notify_admin(
+                        &send,
+                        admin_channel,
+                        &format!("{response:?}"),
+                        &transcoder.decode(&text),
+                    )?;
+                }
             }
 
             continue;
         };
 
+        match orig_message.command {
+            Command::PONG(ref server, ref token) => {
+                latency
+                    .lock()
+                    .unwrap()
+                    .pong(token.as_deref().unwrap_or(server));
+                continue;
+            }
+            Command::NOTICE(_, ref text) if orig_message.source_nickname().is_none() => {
+                let server = match orig_message.prefix {
+                    Some(Prefix::ServerName(ref name)) => name.as_str(),
+                    _ => "server",
+                };
+                notify_admin(
+                    &send,
+                    admin_channel,
+                    &format!("notice from {server}"),
+                    &transcoder.decode(text),
+                )?;
+                continue;
+            }
+            Command::WALLOPS(ref text) => {
+                let source = orig_message.source_nickname().unwrap_or("server");
+                notify_admin(
+                    &send,
+                    admin_channel,
+                    &format!("wallops from {source}"),
+                    &transcoder.decode(text),
+                )?;
+                continue;
+            }
+            Command::ERROR(ref text) => {
+                notify_admin(&send, admin_channel, "error", &transcoder.decode(text))?;
+                continue;
+            }
+            // from the server, so these never have a nick
+            Command::CAP(
+                _,
+                ref subcommand @ (CapSubCommand::ACK | CapSubCommand::NAK),
+                ref first,
+                ref second,
+            ) => {
+                let caps: Vec<&str> = [first, second]
+                    .into_iter()
+                    .flatten()
+                    .flat_map(|caps| caps.split(' '))
+                    .collect();
+                let acked = *subcommand == CapSubCommand::ACK;
+
+                if acked && caps.contains(&"account-tag") {
+                    tags_accounts = true;
+                }
+                if acked && caps.contains(&"labeled-response") {
+                    pending_replies.lock().await.set_labels(true);
+                }
+                if caps.contains(&"sasl") {
+                    if acked {
+                        client.send(Command::AUTHENTICATE(String::from("PLAIN")))?;
+                    } else {
+                        notify_admin(
+                            &send,
+                            admin_channel,
+                            "SASL",
+                            "The server doesn't support it",
+                        )?;
+                        client.send(Command::CAP(None, CapSubCommand::END, None, None))?;
+                    }
+                }
+                continue;
+            }
+            Command::AUTHENTICATE(ref data) if data == "+" => {
+                for message in login.sasl_plain() {
+                    client.send(message)?;
+                }
+                continue;
+            }
+            _ => {}
+        }
+
         let nickname = unwrap_or_continue!(orig_message.source_nickname());
+        // what puppets say and do already happened on Discord
+        if puppets
+            .as_ref()
+            .map_or(false, |p| p.is_puppet(network, nickname))
+        {
+            continue;
+        }
+        // the nick stays as it came for talking to IRC, this one is for showing on Discord
+        let decoded_nick = transcoder.decode(nickname);
+        let shown = rewrite_nick(&nick_rules, &decoded_nick);
+        if shown != decoded_nick {
+            rewritten_nicks
+                .lock()
+                .unwrap()
+                .insert(network, &shown, &decoded_nick);
+        }
+
+        if let Some(mask) = hostmask(&orig_message) {
+            let spoke_in = match orig_message.command {
+                Command::PRIVMSG(ref target, _) if mapping.contains_key(target) => Some(target),
+                _ => None,
+            };
+            remember_seen(
+                &mut seen,
+                nickname,
+                &shown,
+                mask,
+                account(&orig_message),
+                tags_accounts,
+                spoke_in,
+            );
+        }
+
+        // ignored people's commands still work, their chat just isn't relayed
+        if let Command::PRIVMSG(ref target, ref text) | Command::NOTICE(ref target, ref text) =
+            orig_message.command
+        {
+            let ignored = mapping.contains_key(target) && {
+                let ignores = ignores.lock().unwrap();
+                ignores.ignores_irc(nickname, hostmask(&orig_message).as_deref())
+                    || ignores.ignores_text(&transcoder.decode(text))
+            };
+            if ignored {
+                continue;
+            }
+        }
+
+        // private messages from nicks in query_threads go to their thread instead
+        if let Command::PRIVMSG(ref target, ref message)
+        | Command::NOTICE(ref target, ref message) = orig_message.command
+        {
+            let watched = query_threads.as_ref().filter(|q| {
+                network == 0
+                    && target == client.current_nickname()
+                    && q.lock().unwrap().watches(nickname)
+            });
+            if let Some(query_threads) = watched {
+                let text = transcoder.decode(message);
+                relay_query(&http, query_threads, nickname, &shown, &text).await;
+                continue;
+            }
+        }
 
         match orig_message.command {
+            Command::PRIVMSG(ref target, ref message) if target == client.current_nickname() => {
+                // CTCP is between IRC clients, none of it means anything on Discord
+                if message.starts_with('\x01') {
+                    continue;
+                }
+                let message = transcoder.decode(message);
+
+                // only from channels they're in, where they could have seen the placeholder
+                let in_channel = |channel: &str| {
+                    channel_users.iter().any(|(c, users)| {
+                        c.eq_ignore_ascii_case(channel)
+                            && users
+                                .iter()
+                                .any(|u| split_prefix(u).1.eq_ignore_ascii_case(nickname))
+                    })
+                };
+                if let Some(reply) = spoilers.answer(&message, in_channel, &locale) {
+                    client.send_notice(nickname, transcoder.encode(&reply))?;
+                    continue;
+                }
+
+                if let Some(mask) = hostmask(&orig_message) {
+                    let caller = Caller::Irc {
+                        mask: &mask,
+                        account: account(&orig_message),
+                    };
+                    if let Some(reply) =
+                        irc_command(&permissions, &caller, &pauses, &mapping, &locale, &message)
+                    {
+                        client.send_notice(nickname, transcoder.encode(&reply))?;
+                        continue;
+                    }
+
+                    if let Some(parsed) = AdminCommand::parse(&message, &locale) {
+                        if admin::allowed(&permissions, &caller) {
+                            let reply = match parsed {
+                                Ok(command) => admin::run(&http, &data, command)
+                                    .await
+                                    .unwrap_or_else(|e| e),
+                                Err(usage) => usage,
+                            };
+                            for line in reply.lines() {
+                                client.send_notice(nickname, transcoder.encode(line))?;
+                            }
+                            continue;
+                        }
+                        // they might be logged in on a server that doesn't tag accounts
+                        if let (Ok(command), None) = (parsed, account(&orig_message)) {
+                            let key = nickname.to_lowercase();
+                            if whoised
+                                .get(&key)
+                                .map_or(false, |at| at.elapsed() < WHOIS_CACHE_FOR)
+                            {
+                                let caller = Caller::Irc {
+                                    mask: &mask,
+                                    account: seen_account(&seen, nickname),
+                                };
+                                if admin::allowed(&permissions, &caller) {
+                                    let reply = admin::run(&http, &data, command)
+                                        .await
+                                        .unwrap_or_else(|e| e);
+                                    for line in reply.lines() {
+                                        client.send_notice(nickname, transcoder.encode(line))?;
+                                    }
+                                }
+                                continue;
+                            }
+                            // so anyone can't keep the bridge sending WHOISes
+                            if awaiting_whois.contains_key(&key)
+                                || last_admin_whois
+                                    .map_or(false, |at| at.elapsed() < ADMIN_WHOIS_EVERY)
+                            {
+                                continue;
+                            }
+                            last_admin_whois = Some(Instant::now());
+                            whoised.retain(|_, at| at.elapsed() < WHOIS_CACHE_FOR);
+                            whoised.insert(key.clone(), Instant::now());
+
+                            client.send(Command::WHOIS(None, nickname.to_owned()))?;
+                            awaiting_whois.insert(key, Awaiting::Admin(mask, command));
+                            continue;
+                        }
+                    }
+                }
+
+                // links are by nick, which only means something on one network
+                if network != 0 {
+                    let text = locale.text(
+                        "pm_other_network",
+                        "Private messages only reach Discord from the bridge's first network",
+                        &[],
+                    );
+                    client.send_notice(nickname, transcoder.encode(&text))?;
+                    continue;
+                }
+
+                // links go by account, so without account-tag that has to be looked up first
+                if Links::is_command(&message) && account(&orig_message).is_none() && !tags_accounts
+                {
+                    client.send(Command::WHOIS(None, nickname.to_owned()))?;
+                    awaiting_whois.insert(
+                        nickname.to_lowercase(),
+                        Awaiting::Link(message.into_owned()),
+                    );
+                    continue;
+                }
+                let reply = links.lock().unwrap().irc_command(
+                    nickname,
+                    account(&orig_message),
+                    &message,
+                    &locale,
+                );
+                if let Some(reply) = reply {
+                    client.send_notice(nickname, transcoder.encode(&reply))?;
+                    continue;
+                }
+
+                // anyone else on Discord has to agree to talk first
+                if let Some(name) = message.strip_prefix("!pair ").map(str::trim) {
+                    let reply = pair(
+                        &http,
+                        &members,
+                        &dm_sessions,
+                        &locale,
+                        nickname,
+                        &shown,
+                        name,
+                    )
+                    .await;
+                    client.send_notice(nickname, transcoder.encode(&reply))?;
+                    continue;
+                }
+
+                // "name: message" picks who to talk to, after that replies go to the same person
+                let addressed = match REPLY_NICK_RE.captures(&message) {
+                    Ok(Some(caps)) => match links.lock().unwrap().user(&caps[1]) {
+                        // being linked isn't agreeing to hear from anyone, that's `!link pms on`
+                        Some(link) if !link.prefs.pms => Err(link.nick.clone()),
+                        Some(link) => Ok(Some((link.user_id, caps.get(0).unwrap().end()))),
+                        None => Ok(None),
+                    },
+                    _ => Ok(None),
+                };
+                let addressed = match addressed {
+                    Ok(addressed) => addressed,
+                    Err(name) => {
+                        let reply = locale.text(
+                        "pm_not_taken",
+                        "{name} doesn't take private messages from IRC, say \"!pair <Discord name>\" to ask them",
+                            &[("name", &name)],
+                        );
+                        client.send_notice(nickname, transcoder.encode(&reply))?;
+                        continue;
+                    }
+                };
+                let (user_id, text) = match addressed {
+                    Some((user_id, end)) => (user_id, &message[end..]),
+                    _ => match (dm_sessions.lock().await.user(nickname), pm_inbox) {
+                        (Some(user_id), _) | (None, Some(user_id)) => (user_id, &message[..]),
+                        (None, None) => {
+                            let hint = locale.text(
+                                "pm_hint",
+                                "Start your message with \"name: \" to message someone who linked their Discord account, or say \"!pair <Discord name>\" to ask anyone else",
+                                &[],
+                            );
+                            client.send_notice(nickname, transcoder.encode(&hint))?;
+                            continue;
+                        }
+                    },
+                };
+
+                // the inbox owner answers with "nick: ", so they need the real nick
+                let inbox = pm_inbox == Some(user_id);
+                let from = if inbox { nickname } else { &*shown };
+
+                // sent directly instead of through the queue so private messages stay out of the audit log
+                let dm_text = locale.text(
+                    "pm_from_irc",
+                    "**{nick}** on IRC: {text}",
+                    &[("nick", &from), ("text", &text)],
+                );
+                let sent = match user_id.create_dm_channel(&http).await {
+                    Ok(dm) => dm.id.say(&http, dm_text).await,
+                    Err(e) => Err(e),
+                };
+
+                match sent {
+                    // the inbox hears from everyone, so it doesn't follow whoever spoke last
+                    Ok(_) if inbox => {}
+                    Ok(_) => dm_sessions.lock().await.open(nickname, user_id),
+                    Err(e) => {
+                        eprintln!("Failed to DM {user_id}: {e}");
+                        let text =
+                            locale.text("pm_failed", "Couldn't deliver that message, sorry", &[]);
+                        client.send_notice(nickname, transcoder.encode(&text))?;
+                    }
+                }
+            }
             Command::PRIVMSG(ref channel, ref message)
             | Command::NOTICE(ref channel, ref message) => {
+                last_spoke.insert((channel.clone(), nickname.to_owned()), Instant::now());
+
+                if !to_discord(channel) {
+                    continue;
+                }
+
                 let channel_id = ChannelId::from(*unwrap_or_continue!(mapping.get(channel)));
 
+                // answered privately so asking doesn't spam the channel
+                let command = transcoder.decode(message);
+                let mut words = command.split_whitespace();
+                let mask = hostmask(&orig_message).unwrap_or_default();
+                let caller = Caller::Irc {
+                    mask: &mask,
+                    account: account(&orig_message),
+                };
+                let replies = match words.next() {
+                    Some(name @ ("!topic" | "!online" | "!history"))
+                        if !permissions.check(&name[1..], &caller).unwrap_or(true) =>
+                    {
+                        vec![locale.text(
+                            "not_allowed",
+                            "You aren't allowed to use {command}",
+                            &[("command", &name)],
+                        )]
+                    }
+                    Some("!topic") => {
+                        match discord_topic(&http, &topics, &locale, channel, channel_id).await {
+                            Ok(topic) => vec![topic],
+                            Err(e) => {
+                                eprintln!("Failed to get the topic of {channel_id}: {e:#}");
+                                vec![locale.text(
+                                    "topic_failed",
+                                    "Couldn't get the Discord topic, try again later",
+                                    &[],
+                                )]
+                            }
+                        }
+                    }
+                    Some("!online") => vec![online_members(&cache, &locale, channel_id)],
+                    Some("!history") => history(
+                        &message_cache.lock().await,
+                        &locale,
+                        channel_id,
+                        words.next(),
+                    ),
+                    _ => Vec::new(),
+                };
+                if !replies.is_empty() {
+                    for reply in replies {
+                        client.send_notice(nickname, transcoder.encode(&reply))?;
+                    }
+                    continue;
+                }
+
                 if channels_cache.is_none() || guild.is_none() || emoji_cache.is_empty() {
                     let (cc, g, es) = {
                         let guild = channel_id
@@ -124,50 +1166,306 @@ pub async fn irc_loop(
                 }
                 let channels = channels_cache.as_ref().unwrap();
 
+                let decoded = transcoder.decode(message);
+                // answered privately so it doesn't spoil it for the channel
+                if let Some(reply) =
+                    spoilers.answer(&decoded, |c| c.eq_ignore_ascii_case(channel), &locale)
+                {
+                    client.send_notice(nickname, transcoder.encode(&reply))?;
+                    continue;
+                }
+                if !channel_options
+                    .get(channel)
+                    .map_or(true, |o| o.allows(&decoded))
+                {
+                    continue;
+                }
+                let short_ids = channel_options
+                    .get(channel)
+                    .and_then(|o| o.short_ids)
+                    .unwrap_or(false);
+
+                // "+:emoji: ab3" reacts to the message stamped with that short ID instead of being relayed
+                if short_ids {
+                    if let Ok(Some(caps)) = REACT_RE.captures(&decoded) {
+                        let target = message_cache.lock().await.resolve_short_id(&caps[2]);
+                        let reaction = reaction_type(&caps[1], &emoji_cache);
+
+                        if let (Some(id), Some(reaction)) = (target, reaction) {
+                            if let Err(e) = channel_id.create_reaction(&http, id, reaction).await {
+                                eprintln!("Failed to react to {id}: {e}");
+                            }
+                            continue;
+                        }
+                    }
+                }
+
+                // searched in the background so relaying doesn't wait on it, this message goes
+                // out without those mentions and later ones find them
+                let mentioned = mentioned_names(&decoded)
+                    .into_iter()
+                    .filter(|name| !id_cache.contains_key(*name))
+                    .map(ToOwned::to_owned)
+                    .collect::<Vec<_>>();
+                if !mentioned.is_empty() {
+                    let (http, members, searched) =
+                        (http.clone(), members.clone(), searched_send.clone());
+                    let guild_id = guild.unwrap();
+                    tokio::spawn(async move {
+                        let names = search_missing(&http, guild_id, &members, mentioned).await;
+                        let _ = searched.send(names);
+                    });
+                }
+
                 let members_lock = members.lock().await;
 
-                let mut computed = irc_to_discord_processing(
-                    message,
-                    &members_lock,
-                    &mut id_cache,
-                    channels,
-                    &emoji_cache,
-                );
+                // "@ab3 message" replies to the message with that short ID, ">>nick: message" to
+                // the last thing that nick said here, '>"start of it" message' to the last message
+                // starting like that, and "nick: message" addressed to someone whose last message
+                // we know works too
+                let mut reply_to = None;
+                let mut message = decoded.as_ref();
+                // clients that know about reply tags say exactly which message they mean
+                let reply_tag = tag(&orig_message, "+draft/reply").or(tag(&orig_message, "+reply"));
+                let tagged = match reply_tag {
+                    Some(msgid) => message_cache.lock().await.by_msgid(msgid),
+                    None => None,
+                };
+                // "@bob hello" is a ping for bob, even if some message happens to be [bob]
+                let short_reply = SHORT_REPLY_RE
+                    .captures(message)
+                    .ok()
+                    .flatten()
+                    .filter(|caps| {
+                        short_ids && !is_someone(&caps[1], &channel_users, channel, &members_lock)
+                    });
+                if let Some(id) = tagged {
+                    reply_to = Some((id, None));
+                } else if let Some(caps) = short_reply {
+                    let message_cache = message_cache.lock().await;
+                    if let Some(id) = message_cache.resolve_short_id(&caps[1]) {
+                        reply_to = Some((id, message_cache.get(id).map(|m| m.author_id)));
+                        message = &message[caps.get(0).unwrap().end()..];
+                    }
+                } else if let Ok(Some(caps)) = QUOTE_NICK_RE.captures(message) {
+                    // nicks are relayed with a zero width space in them, which copies along
+                    let wanted = caps[1].replace('\u{200B}', "").to_lowercase();
+                    let message_cache = message_cache.lock().await;
+                    let found = message_cache.latest_matching(channel_id, |m| {
+                        m.nick
+                            .as_ref()
+                            .map_or(false, |n| n.to_lowercase() == wanted)
+                    });
+                    if let Some(id) = found {
+                        reply_to = Some((id, message_cache.get(id).map(|m| m.author_id)));
+                        message = &message[caps.get(0).unwrap().end()..];
+                    }
+                } else if let Ok(Some(caps)) = QUOTE_TEXT_RE.captures(message) {
+                    let wanted = caps[1].to_lowercase();
+                    let message_cache = message_cache.lock().await;
+                    let found = message_cache.latest_matching(channel_id, |m| {
+                        m.content.to_lowercase().starts_with(&wanted)
+                    });
+                    if let Some(id) = found {
+                        reply_to = Some((id, message_cache.get(id).map(|m| m.author_id)));
+                        message = &message[caps.get(0).unwrap().end()..];
+                    }
+                } else if let Ok(Some(caps)) = REPLY_NICK_RE.captures(message) {
+                    let user_id = resolve_member(
+                        &caps[1],
+                        &members_lock,
+                        &links.lock().unwrap(),
+                        &seen,
+                        &mut id_cache,
+                    );
+                    if let Some(user_id) = user_id {
+                        if let Some(id) = message_cache.lock().await.latest(channel_id, user_id) {
+                            reply_to = Some((id, Some(user_id)));
+                            message = &message[caps.get(0).unwrap().end()..];
+                        }
+                    }
+                }
+
+                let plain = channel_options
+                    .get(channel)
+                    .and_then(|o| o.plain)
+                    .unwrap_or(false);
+                let ansi = channel_options
+                    .get(channel)
+                    .and_then(|o| o.ansi_colours)
+                    .unwrap_or(false)
+                    .then(|| irc_to_ansi(message))
+                    .flatten();
+                let mut computed = if plain {
+                    message.to_owned()
+                } else if let Some(ansi) = ansi {
+                    ansi
+                } else {
+                    irc_to_discord_processing(
+                        message,
+                        &members_lock,
+                        &links.lock().unwrap(),
+                        &seen,
+                        &mut id_cache,
+                        channels,
+                        &emoji_cache,
+                    )
+                };
+
+                let opts = if plain { &plain_safe_opts } else { &safe_opts };
+                computed = content_safe(&cache, computed, opts, &[]);
+                computed = url_cleaner.clean(&computed).into_owned();
+
+                let timestamp = channel_options
+                    .get(channel)
+                    .and_then(|o| o.timestamps)
+                    .unwrap_or(false)
+                    .then(|| server_time(&orig_message).unwrap_or_else(|| short_time(now())));
 
-                computed = {
-                    let opts = ContentSafeOptions::new()
-                        .clean_role(false)
-                        .clean_user(false)
-                        .clean_channel(false)
-                        .show_discriminator(false)
-                        .clean_here(true) // setting these to true explicitly isn't needed,
-                        .clean_everyone(true); // but i did it anyway for readability
+                let embeds = channel_options
+                    .get(channel)
+                    .and_then(|o| o.embeds)
+                    .unwrap_or(false);
 
-                    content_safe(&cache, computed, &opts, &[])
+                // linked people get their own avatar, unless they turned that off
+                let linked = links
+                    .lock()
+                    .unwrap()
+                    .get(nickname, seen_account(&seen, nickname))
+                    .filter(|l| l.prefs.avatar)
+                    .map(|l| l.user_id);
+                let linked_avatar = linked.and_then(|user_id| {
+                    members_lock
+                        .iter()
+                        .find(|m| m.user.id == user_id)
+                        .and_then(|m| m.user.avatar_url())
+                        .map(Arc::from)
+                });
+                // looked up before inserting so regulars don't cost a key allocation every line
+                let avatar = match avatar_cache.get(&*shown) {
+                    _ if linked_avatar.is_some() => linked_avatar,
+                    Some(avatar) => avatar.clone(),
+                    None => {
+                        let avatar = find_by_name(&members_lock, &shown)
+                            .and_then(|member| member.user.avatar_url())
+                            .or_else(|| avatars.get(nickname).cloned())
+                            .map(Arc::from);
+                        avatar_cache.insert(shown.to_string(), avatar.clone());
+                        avatar
+                    }
                 };
+                // so IRC people don't all get Discord's grey default
+                let avatar = avatar.or_else(|| {
+                    default_avatars
+                        .get(channel.as_str())
+                        .or(default_avatar.as_ref())
+                        .cloned()
+                });
 
-                if let Some(webhook) = webhooks.get(channel) {
-                    let avatar = &*avatar_cache.entry(nickname.to_owned()).or_insert_with(|| {
-                        members_lock.iter().find_map(|member| {
-                            (member.display_name() == nickname)
-                                .then(|| member.user.avatar_url())
-                                .flatten()
-                        })
-                    });
+                // translating can take a while, Discord events need the members meanwhile
+                drop(members_lock);
+                let languages = channel_options
+                    .get(channel)
+                    .and_then(|o| o.translate.as_ref());
+                if let (Some(translator), Some(languages)) = (&translator, languages) {
+                    computed = translator
+                        .translate_with_original(&computed, &languages.irc, &languages.discord)
+                        .await;
+                }
+
+                let webhook = webhook_picker.pick(&webhooks, channel, nickname);
+                let queued = if let Some(webhook) = webhook {
+                    // webhooks can't send real replies, so point at the message instead
+                    if let Some((id, user_id)) = reply_to {
+                        let mention = user_id.map(|u| format!("{} ", u.mention()));
+                        computed = format!(
+                            "[↪](<{}>) {}{computed}",
+                            id.link(channel_id, guild),
+                            mention.unwrap_or_default()
+                        );
+                    }
+
+                    if let Some(ref ts) = timestamp {
+                        computed = format!("[{ts}] {computed}");
+                    }
 
-                    send.send(QueuedMessage::Webhook {
+                    QueuedMessage::Webhook {
+                        // a channel mapped to a thread uses its parent's webhook
+                        thread_id: (webhook.channel_id != Some(channel_id)).then_some(channel_id),
                         webhook: webhook.clone(),
-                        http: http.clone(),
-                        avatar_url: avatar.clone(),
+                        avatar_url: avatar,
                         content: computed,
-                        nickname: nickname.to_string(),
-                    })?;
+                        nickname: shown.to_string(),
+                        upload_images: channel_options
+                            .get(channel)
+                            .and_then(|o| o.upload_images)
+                            .unwrap_or(false),
+                    }
+                } else if embeds {
+                    if let Some(ts) = timestamp {
+                        computed = format!("[{ts}] {computed}");
+                    }
+
+                    QueuedMessage::Embed {
+                        channel_id,
+                        author: shown.to_string(),
+                        avatar_url: avatar,
+                        content: computed,
+                        reply_to: reply_to.map(|(id, _)| id),
+                    }
                 } else {
-                    send.send(QueuedMessage::Raw {
+                    let message = match timestamp {
+                        Some(ts) => format!("[{ts}] <{shown}>, {computed}"),
+                        None => format!("<{shown}>, {computed}"),
+                    };
+
+                    QueuedMessage::Raw {
                         channel_id,
-                        http: http.clone(),
-                        message: format!("<{nickname}>, {computed}"),
-                    })?;
+                        message,
+                        reply_to: reply_to.map(|(id, _)| id),
+                    }
+                };
+
+                if let Some(chat_log) = &chat_log {
+                    chat_log.record(channel, "irc", nickname, &strip_control_codes(&decoded));
+                }
+
+                // code blocks don't render mentions, but keep them from pinging anyway
+                let line = content_safe(&cache, strip_control_codes(message), &safe_opts, &[]);
+                if let Some(queued) =
+                    hold_paste(&mut pastes, &channel_options, channel, &shown, line, queued)
+                {
+                    send.chat_with_msgid(
+                        queued,
+                        tag(&orig_message, "msgid").map(ToOwned::to_owned),
+                    )?;
+                }
+
+                // fetched on the side so the loop doesn't wait on Discord, most messages have no
+                // links to fetch anyway
+                if decoded.contains("/channels/") {
+                    let events_as = channel_options
+                        .get(channel)
+                        .and_then(|o| o.events_as)
+                        .unwrap_or(SendAs::Notice);
+                    let (http, cache, mapping, sender) = (
+                        http.clone(),
+                        cache.clone(),
+                        mapping.clone(),
+                        client.sender(),
+                    );
+                    let (channel, decoded) = (channel.clone(), decoded.into_owned());
+                    tokio::spawn(async move {
+                        for preview in
+                            message_link_previews(&http, &cache, &mapping, &decoded).await
+                        {
+                            if let Err(e) =
+                                events_as.send(&sender, &channel, transcoder.encode(&preview))
+                            {
+                                eprintln!("Failed to send a link preview to {channel}: {e}");
+                            }
+                        }
+                    });
                 }
             }
             Command::JOIN(ref channel, _, _) => {
@@ -176,82 +1474,1408 @@ pub async fn irc_loop(
 
                 users.push(nickname.to_string());
 
-                send.send(QueuedMessage::Raw {
+                if !to_discord(channel)
+                    || !announce_join_part(&channel_options, &last_spoke, channel, nickname)
+                {
+                    continue;
+                }
+
+                if record_churn(&mut churn, &channel_options, channel, &shown, true) {
+                    continue;
+                }
+
+                send.event(QueuedMessage::Raw {
                     channel_id,
-                    http: http.clone(),
-                    message: format!("*{nickname}* has joined the channel"),
+                    message: locale.text(
+                        "join",
+                        "*{nick}* has joined the channel",
+                        &[("nick", &shown)],
+                    ),
+                    reply_to: None,
                 })?;
             }
             Command::PART(ref channel, ref reason) => {
                 let users = unwrap_or_continue!(channel_users.get_mut(channel));
                 let channel_id = ChannelId::from(*unwrap_or_continue!(mapping.get(channel)));
-                let pos = unwrap_or_continue!(users.iter().position(|u| u == nickname));
+                let pos =
+                    unwrap_or_continue!(users.iter().position(|u| split_prefix(u).1 == nickname));
 
                 users.swap_remove(pos);
 
-                let reason = reason.as_deref().unwrap_or("Connection closed");
+                if !to_discord(channel)
+                    || !announce_join_part(&channel_options, &last_spoke, channel, nickname)
+                {
+                    continue;
+                }
+
+                if record_churn(&mut churn, &channel_options, channel, &shown, false) {
+                    continue;
+                }
+
+                let reason = transcoder.decode(reason.as_deref().unwrap_or("Connection closed"));
 
-                send.send(QueuedMessage::Raw {
+                send.event(QueuedMessage::Raw {
                     channel_id,
-                    http: http.clone(),
-                    message: format!("*{nickname}* has quit ({reason})"),
+                    message: locale.text(
+                        "part",
+                        "*{nick}* has quit ({reason})",
+                        &[("nick", &shown), ("reason", &reason)],
+                    ),
+                    reply_to: None,
                 })?;
             }
             Command::QUIT(ref reason) => {
+                if network == 0 {
+                    dm_sessions.lock().await.close(nickname);
+                }
+                // whoever takes the nick next isn't logged in as them
+                if let Some(s) = seen.get_mut(nickname) {
+                    s.account = None;
+                }
+                whoised.remove(&nickname.to_lowercase());
+                rewritten_nicks
+                    .lock()
+                    .unwrap()
+                    .forget(network, &decoded_nick);
+
                 for (channel, users) in &mut channel_users {
                     let channel_id = ChannelId::from(*unwrap_or_continue!(mapping.get(channel)));
-                    let pos = unwrap_or_continue!(users.iter().position(|u| u == nickname));
+                    let pos = unwrap_or_continue!(users
+                        .iter()
+                        .position(|u| split_prefix(u).1 == nickname));
 
                     users.swap_remove(pos);
 
-                    let reason = reason.as_deref().unwrap_or("Connection closed");
+                    if !to_discord(channel)
+                        || !announce_join_part(&channel_options, &last_spoke, channel, nickname)
+                    {
+                        continue;
+                    }
+
+                    if record_churn(&mut churn, &channel_options, channel, &shown, false) {
+                        continue;
+                    }
 
-                    send.send(QueuedMessage::Raw {
+                    let reason =
+                        transcoder.decode(reason.as_deref().unwrap_or("Connection closed"));
+
+                    send.event(QueuedMessage::Raw {
                         channel_id,
-                        http: http.clone(),
-                        message: format!("*{nickname}* has quit ({reason})"),
+                        message: locale.text(
+                            "quit",
+                            "*{nick}* has quit ({reason})",
+                            &[("nick", &shown), ("reason", &reason)],
+                        ),
+                        reply_to: None,
                     })?;
                 }
             }
             Command::NICK(ref new_nick) => {
+                if network == 0 {
+                    dm_sessions.lock().await.rename(nickname, new_nick);
+                }
+                let decoded_new = transcoder.decode(new_nick);
+                let new_shown = rewrite_nick(&nick_rules, &decoded_new).into_owned();
+                if let Some(mut entry) = seen.remove(nickname) {
+                    entry.shown.clone_from(&new_shown);
+                    seen.insert(new_nick.clone(), entry);
+                }
+                whoised.remove(&nickname.to_lowercase());
+                whoised.remove(&new_nick.to_lowercase());
+                {
+                    let mut rewritten = rewritten_nicks.lock().unwrap();
+                    rewritten.forget(network, &decoded_nick);
+                    if new_shown != *decoded_new {
+                        rewritten.insert(network, &new_shown, &decoded_new);
+                    }
+                }
+
+                let spoke = last_spoke
+                    .keys()
+                    .filter(|(_, nick)| nick == nickname)
+                    .cloned()
+                    .collect::<Vec<_>>();
+                for (channel, nick) in spoke {
+                    if let Some(t) = last_spoke.remove(&(channel.clone(), nick)) {
+                        last_spoke.insert((channel, new_nick.clone()), t);
+                    }
+                }
+
                 for (channel, users) in &mut channel_users {
                     let channel_id = ChannelId::from(*unwrap_or_continue!(mapping.get(channel)));
-                    let pos = unwrap_or_continue!(users.iter().position(|u| u == nickname));
+                    let pos = unwrap_or_continue!(users
+                        .iter()
+                        .position(|u| split_prefix(u).1 == nickname));
+
+                    let (prefix, _) = split_prefix(&users[pos]);
+                    users[pos] = format!("{prefix}{new_nick}");
 
-                    users[pos] = new_nick.to_string();
+                    if !to_discord(channel) {
+                        continue;
+                    }
 
-                    send.send(QueuedMessage::Raw {
+                    send.event(QueuedMessage::Raw {
                         channel_id,
-                        http: http.clone(),
-                        message: format!("*{nickname}* is now known as *{new_nick}*"),
+                        message: locale.text(
+                            "nick",
+                            "*{nick}* is now known as *{new_nick}*",
+                            &[("nick", &shown), ("new_nick", &new_shown)],
+                        ),
+                        reply_to: None,
                     })?;
                 }
             }
             Command::TOPIC(ref channel, ref topic) => {
-                let topic = unwrap_or_continue!(topic.as_ref());
-                let channel_id = ChannelId::from(*unwrap_or_continue!(mapping.get(channel)));
-                let builder = EditChannel::new().topic(topic);
-                channel_id.edit(&http, builder).await?;
-            }
-            Command::KICK(ref channel, ref user, ref reason) => {
+                let topic = &transcoder.decode(unwrap_or_continue!(topic.as_ref()));
                 let channel_id = ChannelId::from(*unwrap_or_continue!(mapping.get(channel)));
-                let reason = reason.as_deref().unwrap_or("None");
-
-                send.send(QueuedMessage::Raw {
+                let count = user_count(&channel_options, &channel_users, channel);
+                let changed = sync_topic(
+                    &http,
+                    &channel_options,
+                    &topics,
+                    channel,
                     channel_id,
-                    http: http.clone(),
-                    message: format!("*{nickname}* has kicked *{user}* ({reason})"),
-                })?;
-            }
+                    topic,
+                    Some(shown.as_ref()),
+                    count,
+                )
+                .await;
+                // Discord not taking it shouldn't stop the bridge
+                let changed = match changed {
+                    Ok(changed) => changed,
+                    Err(e) => {
+                        eprintln!("Failed to set the topic of {channel_id}: {e:#}");
+                        continue;
+                    }
+                };
+
+                if let (true, Some(count)) = (changed, count) {
+                    shown_counts.insert(channel.clone(), count);
+                    last_count_edit.insert(channel.clone(), Instant::now());
+                }
+
+                if changed && to_discord(channel) {
+                    send.event(QueuedMessage::Raw {
+                        channel_id,
+                        message: locale.text(
+                            "topic",
+                            "Topic set by *{nick}* on IRC ({time}): {topic}",
+                            &[
+                                ("nick", &shown),
+                                ("time", &format!("<t:{}:f>", unix_now())),
+                                ("topic", topic),
+                            ],
+                        ),
+                        reply_to: None,
+                    })?;
+                }
+            }
+            Command::ChannelMODE(ref channel, ref modes) => {
+                if let Some(users) = channel_users.get_mut(channel) {
+                    for mode in modes {
+                        let (mode, add, Some(target)) = (match mode {
+                            Mode::Plus(mode, target) => (mode, true, target),
+                            Mode::Minus(mode, target) => (mode, false, target),
+                            Mode::NoPrefix(_) => continue,
+                        }) else {
+                            continue;
+                        };
+                        if let Some(symbol) = status_symbol(mode) {
+                            set_status(users, target, symbol, add);
+                        }
+                    }
+                }
+
+                if nickname == client.current_nickname() {
+                    let modes = modes.iter().map(ToString::to_string).collect::<Vec<_>>();
+                    pending_replies.lock().await.resolve(
+                        label.as_deref(),
+                        channel,
+                        format!("Set {} on {channel}", modes.join(" ")),
+                    );
+                }
+            }
+            // a labeled command the server had nothing to say about
+            Command::Raw(ref command, _) if command == "ACK" => {
+                pending_replies
+                    .lock()
+                    .await
+                    .resolve(label.as_deref(), "", String::from("Done"));
+            }
+            Command::INVITE(ref target, ref channel) if target == client.current_nickname() => {
+                if mapping.contains_key(channel) {
+                    client.send_join(channel)?;
+                    continue;
+                }
+
+                // only channels matching a pattern get bridged just by inviting us
+                let category = unwrap_or_continue!(patterns.category_for(network, channel));
+                // it makes a Discord channel, so that's up to the same people as `!dircord`
+                let mask = hostmask(&orig_message).unwrap_or_default();
+                let caller = Caller::Irc {
+                    mask: &mask,
+                    account: account(&orig_message),
+                };
+                if !admin::allowed(&permissions, &caller) {
+                    eprintln!(
+                        "Ignoring an invite to {channel} from {nickname}, who isn't an admin"
+                    );
+                    continue;
+                }
+                if last_pattern_invite.map_or(false, |at| at.elapsed() < PATTERN_INVITE_EVERY) {
+                    client.send_notice(
+                        nickname,
+                        transcoder.encode(&locale.text(
+                            "invite_wait",
+                            "Wait a bit before inviting the bridge to another new channel",
+                            &[],
+                        )),
+                    )?;
+                    continue;
+                }
+                last_pattern_invite = Some(Instant::now());
+
+                let channel_id = match patterns.channel_for(&http, category, channel).await {
+                    Ok(id) => id,
+                    Err(e) => {
+                        eprintln!("Couldn't set up a Discord channel for {channel}: {e}");
+                        continue;
+                    }
+                };
+
+                {
+                    let mut data = data.write().await;
+                    // names are shared between networks, so one bridged elsewhere stays there
+                    if let Err(e) = data
+                        .get_mut::<SendersKey>()
+                        .unwrap()
+                        .add_channel(channel.clone(), network)
+                    {
+                        eprintln!("Not bridging {channel} after an invite: {e}");
+                        continue;
+                    }
+                    data.get_mut::<ChannelMappingKey>()
+                        .unwrap()
+                        .insert(channel.clone(), channel_id.0.get());
+                }
+                mapping.insert(channel.clone(), channel_id.0.get());
+                client.send_join(channel)?;
+            }
+            Command::KICK(ref channel, ref user, ref reason) => {
+                if let Some(users) = channel_users.get_mut(channel) {
+                    users.retain(|u| split_prefix(u).1 != user);
+                }
+
+                let channel_id = ChannelId::from(*unwrap_or_continue!(mapping.get(channel)));
+                let reason = transcoder.decode(reason.as_deref().unwrap_or("None"));
+                let target = rewrite_nick(&nick_rules, &transcoder.decode(user)).into_owned();
+
+                // someone on Discord just lost their voice on IRC, or the whole channel did
+                if mirror_moderation {
+                    let linked = links
+                        .lock()
+                        .unwrap()
+                        .get(user, seen_account(&seen, user))
+                        .map(|l| l.user_id);
+                    let who = match linked {
+                        Some(user_id) => {
+                            let name = members
+                                .lock()
+                                .await
+                                .iter()
+                                .find(|m| m.user.id == user_id)
+                                .map_or_else(
+                                    || user_id.to_string(),
+                                    |m| m.display_name().to_owned(),
+                                );
+                            Some(format!("{target} (linked to {name} on Discord)"))
+                        }
+                        None if user == client.current_nickname() => {
+                            Some(String::from("The bridge"))
+                        }
+                        None => None,
+                    };
+                    if let Some(who) = who {
+                        let text = format!("{who} was kicked from {channel} by {shown}: {reason}");
+                        notify_admin(&send, admin_channel, "Kick", &text)?;
+                    }
+                }
+
+                if !to_discord(channel) {
+                    continue;
+                }
+
+                send.event(QueuedMessage::Raw {
+                    channel_id,
+                    message: locale.text(
+                        "kick",
+                        "*{nick}* has kicked *{target}* ({reason})",
+                        &[("nick", &shown), ("target", &target), ("reason", &reason)],
+                    ),
+                    reply_to: None,
+                })?;
+            }
             _ => {}
         }
     }
+
+    if let Some(reason) = quit_reason {
+        flush_churn(&mut churn, &channel_options, &mapping, &locale, &send, true)?;
+        flush_pastes(&mut pastes, &send, &flood, &locale, true).await?;
+        // the queue ends once its sender is gone, after delivering everything in it
+        let depth = send.depth.clone();
+        drop(send);
+        match timeout(DRAIN_TIMEOUT, &mut msg_handle).await {
+            Ok(Ok(r)) => r.context("Relaying to Discord failed")?,
+            Ok(Err(e)) => return Err(anyhow!("Discord message queue panicked: {e}")),
+            Err(_) => eprintln!(
+                "Gave up on {} messages still queued for Discord",
+                depth.load(Ordering::Relaxed)
+            ),
+        }
+
+        client.send_quit(reason)?;
+        // the QUIT only goes out while the stream is polled, and the server hangs up after it
+        let _ = timeout(Duration::from_secs(5), async {
+            while let Some(Ok(_)) = stream.next().await {}
+        })
+        .await;
+    }
+    Ok(())
+}
+
+/// Asked of the IRC loop by things outside it, since only the loop has this state.
+pub enum IrcQuery {
+    /// What `/debug caches` and SIGUSR2 show
+    Caches(oneshot::Sender<String>),
+    /// Who has status in a channel, for `/oplist`
+    Ops(String, oneshot::Sender<String>),
+    /// What we know about an IRC user, by nick or the name they're shown as on Discord
+    Info(String, oneshot::Sender<String>),
+    /// The config was reloaded, pick up the new channels and webhooks
+    Reload,
+    /// Stop reading from IRC, deliver what's still queued for Discord and QUIT with this reason
+    Shutdown(String),
+}
+
+/// How long people are remembered for "IRC info" after they were last seen, in seconds.
+const SEEN_FOR: u64 = 7 * 24 * 60 * 60;
+
+/// The last we saw of someone on IRC.
+struct Seen {
+    shown: String,
+    mask: String,
+    account: Option<String>,
+    /// The bridged channel they last spoke in
+    channel: Option<String>,
+    at: u64,
+}
+
+fn remember_seen(
+    seen: &mut HashMap<String, Seen>,
+    nick: &str,
+    shown: &str,
+    mask: String,
+    account: Option<&str>,
+    tags_accounts: bool,
+    spoke_in: Option<&String>,
+) {
+    let entry = seen.entry(nick.to_owned()).or_insert_with(|| Seen {
+        shown: shown.to_owned(),
+        mask: String::new(),
+        account: None,
+        channel: None,
+        at: 0,
+    });
+
+    entry.mask = mask;
+    // without account-tag there's no telling, so keep what we knew
+    if account.is_some() || tags_accounts {
+        entry.account = account.map(ToOwned::to_owned);
+    }
+    if let Some(channel) = spoke_in {
+        entry.channel = Some(channel.clone());
+    }
+    entry.at = unix_now();
+}
+
+/// The account `nick` was last seen logged into.
+fn seen_account<'a>(seen: &'a HashMap<String, Seen>, nick: &str) -> Option<&'a str> {
+    seen.get(nick)
+        .or_else(|| {
+            seen.iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(nick))
+                .map(|(_, s)| s)
+        })
+        .and_then(|s| s.account.as_deref())
+}
+
+/// What's waiting on a WHOIS to find out someone's account.
+enum Awaiting {
+    /// `!dircord`, from this hostmask
+    Admin(String, AdminCommand),
+    /// `!link` or `!unlink`
+    Link(String),
+}
+
+/// Who someone is on IRC, for the "IRC info" context menu.
+fn irc_info(
+    seen: &HashMap<String, Seen>,
+    channel_users: &HashMap<String, Vec<String>>,
+    name: &str,
+) -> String {
+    let found = seen.get_key_value(name).or_else(|| {
+        seen.iter()
+            .find(|(nick, s)| s.shown == name || nick.eq_ignore_ascii_case(name))
+    });
+    let Some((nick, seen)) = found else {
+        return format!("Haven't seen {name} on IRC lately");
+    };
+
+    let mut channels = channel_users
+        .iter()
+        .filter_map(|(channel, users)| {
+            let user = users.iter().find(|u| split_prefix(u).1 == nick)?;
+            Some(format!("{}{channel}", split_prefix(user).0))
+        })
+        .collect::<Vec<_>>();
+    channels.sort_unstable();
+
+    let mut info = format!("**{nick}** (`{}`)", seen.mask);
+    match seen.account {
+        Some(ref account) if account != "*" => write!(info, "\nAccount: {account}"),
+        Some(_) => write!(info, "\nAccount: not logged in"),
+        None => write!(info, "\nAccount: unknown"),
+    }
+    .unwrap();
+    if !channels.is_empty() {
+        write!(info, "\nIn: {}", channels.join(", ")).unwrap();
+    }
+    write!(info, "\nLast seen: <t:{}:R>", seen.at).unwrap();
+    if let Some(ref channel) = seen.channel {
+        write!(info, " (last spoke in {channel})").unwrap();
+    }
+
+    info
+}
+
+/// Status prefixes in NAMES replies, highest first. Servers can announce others, but these are
+/// the ones that are actually out there.
+const STATUS_PREFIXES: &str = "~&@%+";
+
+/// A NAMES entry like `@+nick` split into `@+` and `nick`.
+fn split_prefix(user: &str) -> (&str, &str) {
+    let nick = user.trim_start_matches(|c| STATUS_PREFIXES.contains(c));
+    user.split_at(user.len() - nick.len())
+}
+
+fn status_symbol(mode: &ChannelMode) -> Option<char> {
+    Some(match mode {
+        ChannelMode::Founder => '~',
+        ChannelMode::Admin => '&',
+        ChannelMode::Oper => '@',
+        ChannelMode::Halfop => '%',
+        ChannelMode::Voice => '+',
+        _ => return None,
+    })
+}
+
+/// Gives or takes a status prefix from someone in a channel's user list.
+fn set_status(users: &mut [String], nick: &str, symbol: char, add: bool) {
+    let Some(user) = users.iter_mut().find(|u| split_prefix(u).1 == nick) else {
+        return;
+    };
+
+    let (prefix, nick) = split_prefix(user);
+    let prefix = STATUS_PREFIXES
+        .chars()
+        .filter(|&c| if c == symbol { add } else { prefix.contains(c) })
+        .collect::<String>();
+    *user = format!("{prefix}{nick}");
+}
+
+/// Operators and voiced users of a channel, with their highest prefix.
+fn op_list(channel_users: &HashMap<String, Vec<String>>, channel: &str, locale: &Locale) -> String {
+    let Some(users) = channel_users.get(channel) else {
+        return locale.text(
+            "oplist_not_in",
+            "The bridge isn't in {channel} on IRC",
+            &[("channel", &channel)],
+        );
+    };
+
+    let mut ops = Vec::new();
+    let mut voiced = Vec::new();
+    for user in users {
+        let (prefix, nick) = split_prefix(user);
+        match prefix.chars().next() {
+            Some('+') => voiced.push(format!("`{nick}`")),
+            Some(symbol) => ops.push((STATUS_PREFIXES.find(symbol), format!("`{symbol}{nick}`"))),
+            None => {}
+        }
+    }
+    // founders first, then by name
+    ops.sort_unstable();
+    voiced.sort_unstable();
+
+    let list = |names: Vec<String>| {
+        if names.is_empty() {
+            locale.text("oplist_nobody", "nobody", &[])
+        } else {
+            names.join(", ")
+        }
+    };
+    locale.text(
+        "oplist",
+        "**Operators in {channel}**: {ops}\n**Voiced**: {voiced}",
+        &[
+            ("channel", &channel),
+            (
+                "ops",
+                &list(ops.into_iter().map(|(_, name)| name).collect()),
+            ),
+            ("voiced", &list(voiced)),
+        ],
+    )
+}
+
+/// Why the server won't let the bridge into a channel, for the numerics that mean that. Comes with
+/// its locale key.
+fn join_failure(numeric: u16) -> Option<(&'static str, &'static str)> {
+    Some(match numeric {
+        405 => (
+            "join_failed_too_many_channels",
+            "the bridge is in too many channels",
+        ),
+        471 => ("join_failed_full", "the channel is full"),
+        473 => (
+            "join_failed_invite_only",
+            "the channel is invite-only, invite the bridge or give it an invite exception",
+        ),
+        474 => ("join_failed_banned", "the bridge is banned"),
+        475 => ("join_failed_bad_key", "the channel key is wrong"),
+        477 => (
+            "join_failed_registered_only",
+            "the channel needs a registered nick, log the bridge in with services",
+        ),
+        _ => return None,
+    })
+}
+
+/// Everything `identify` sends except CAP END, for when that has to wait.
+fn register(client: &IrcClient) -> anyhow::Result<()> {
+    let config = client.config();
+    if !config.password().is_empty() {
+        client.send(Command::PASS(config.password().to_owned()))?;
+    }
+    client.send(Command::NICK(config.nickname()?.to_owned()))?;
+    client.send(Command::USER(
+        config.username().to_owned(),
+        String::from("0"),
+        config.real_name().to_owned(),
+    ))?;
+    Ok(())
+}
+
+/// Asks whoever goes by `name` on Discord whether they'll take private messages from `nick`.
+/// Returns what to tell `nick`.
+async fn pair(
+    http: &Http,
+    members: &Mutex<Vec<Member>>,
+    dm_sessions: &Mutex<DmSessions>,
+    locale: &Locale,
+    nick: &str,
+    shown: &str,
+    name: &str,
+) -> String {
+    let wait = || locale.text("pm_pair_wait", "Wait a bit before asking again", &[]);
+    if !dm_sessions.lock().await.may_pair(nick) {
+        return wait();
+    }
+
+    let user_id = find_by_name(&members.lock().await, name).map(|m| m.user.id);
+    let Some(user_id) = user_id else {
+        return locale.text(
+            "pm_pair_unknown",
+            "Nobody on Discord goes by {name}",
+            &[("name", &name)],
+        );
+    };
+    // asking again would just be spam
+    if dm_sessions.lock().await.requested(user_id) == Some(nick) {
+        return locale.text(
+            "pm_pair_sent",
+            "Asked {name}, your messages reach them once they accept",
+            &[("name", &name)],
+        );
+    }
+    if !dm_sessions.lock().await.may_ask(user_id) {
+        return wait();
+    }
+
+    let request = locale.text(
+        "pm_pair_request",
+        "**{nick}** on IRC would like to message you privately. Reply `!accept` to talk to them, or ignore this",
+        &[("nick", &shown)],
+    );
+    let sent = match user_id.create_dm_channel(http).await {
+        Ok(dm) => dm.id.say(http, request).await,
+        Err(e) => Err(e),
+    };
+
+    match sent {
+        Ok(_) => {
+            dm_sessions.lock().await.request(nick, user_id);
+            locale.text(
+                "pm_pair_sent",
+                "Asked {name}, your messages reach them once they accept",
+                &[("name", &name)],
+            )
+        }
+        Err(e) => {
+            eprintln!("Failed to DM {user_id}: {e}");
+            locale.text(
+                "pm_pair_failed",
+                "Couldn't reach {name}, they may not take DMs",
+                &[("name", &name)],
+            )
+        }
+    }
+}
+
+/// Posts a private message from a nick in `query_threads` to its thread, starting one if needed.
+/// Sent directly like DMs, so these stay out of the audit log.
+async fn relay_query(
+    http: &Http,
+    query_threads: &std::sync::Mutex<QueryThreads>,
+    nick: &str,
+    shown: &str,
+    text: &str,
+) {
+    let known = query_threads.lock().unwrap().thread(nick);
+    let thread = match known {
+        Some(thread) => thread,
+        None => {
+            let admin_channel = query_threads.lock().unwrap().admin_channel();
+            match thread_for(http, admin_channel, nick).await {
+                Ok(thread) => {
+                    query_threads.lock().unwrap().remember(nick, thread);
+                    thread
+                }
+                Err(e) => {
+                    eprintln!("Failed to open a thread for {nick}: {e}");
+                    return;
+                }
+            }
+        }
+    };
+
+    let message = format!("**{shown}**: {}", text.replace('@', "@\u{200B}"));
+    if let Err(e) = thread.say(http, message).await {
+        eprintln!("Failed to post {nick}'s message to their thread: {e}");
+        // probably deleted, the next message gets a new one
+        query_threads.lock().unwrap().forget(nick);
+    }
+}
+
+/// Forwards a network event to the admin channel, if one is configured.
+fn notify_admin(
+    send: &RelayQueue,
+    admin_channel: Option<ChannelId>,
+    kind: &str,
+    text: &str,
+) -> anyhow::Result<()> {
+    let Some(channel_id) = admin_channel else {
+        return Ok(());
+    };
+
+    send.event(QueuedMessage::Raw {
+        channel_id,
+        // break up pings, servers have no business mentioning anyone
+        message: format!("**{kind}**: {}", text.replace('@', "@\u{200B}")),
+        reply_to: None,
+    })?;
+
+    Ok(())
+}
+
+/// Mirrors an IRC topic onto the Discord channel if the channel's policy allows it.
+/// Returns whether the Discord topic was actually changed.
+#[allow(clippy::too_many_arguments)]
+async fn sync_topic(
+    http: &Http,
+    channel_options: &HashMap<String, ChannelOptions>,
+    topics: &Mutex<HashMap<String, TopicInfo>>,
+    channel: &str,
+    channel_id: ChannelId,
+    topic: &str,
+    set_by: Option<&str>,
+    user_count: Option<usize>,
+) -> anyhow::Result<bool> {
+    let topic_sync = channel_options
+        .get(channel)
+        .and_then(|o| o.topic_sync)
+        .unwrap_or_default();
+
+    if !topic_sync.to_discord() {
+        return Ok(false);
+    }
+
+    // don't bounce back a topic we just synced ourselves. Held over the edit, so Discord telling
+    // us about it waits until it's recorded
+    let mut topics = topics.lock().await;
+    if topics.get(channel).map(|t| t.irc_topic.as_str()) == Some(topic) {
+        return Ok(false);
+    }
+
+    let mut builder = EditChannel::new().topic(with_user_count(topic, user_count));
+    if let Some(nick) = set_by {
+        builder = builder.audit_log_reason(&format!("Topic set by {nick} on IRC"));
+    }
+    channel_id.edit(http, builder).await?;
+
+    topics.insert(
+        channel.to_owned(),
+        TopicInfo {
+            topic: topic.to_owned(),
+            irc_topic: topic.to_owned(),
+            set_by: set_by.map(|nick| format!("{nick} on IRC")),
+        },
+    );
+    Ok(true)
+}
+
+/// What `!topic` answers with.
+async fn discord_topic(
+    http: &Http,
+    topics: &Mutex<HashMap<String, TopicInfo>>,
+    locale: &Locale,
+    channel: &str,
+    channel_id: ChannelId,
+) -> anyhow::Result<String> {
+    let topic = channel_id
+        .to_channel(http)
+        .await?
+        .guild()
+        .and_then(|c| c.topic)
+        .map(|t| strip_user_count(&t).to_owned())
+        .unwrap_or_default();
+
+    if topic.is_empty() {
+        return Ok(locale.text("topic_none", "The Discord channel has no topic", &[]));
+    }
+
+    let set_by = topics
+        .lock()
+        .await
+        .get(channel)
+        .filter(|t| t.topic == topic)
+        .and_then(|t| t.set_by.clone());
+
+    Ok(match set_by {
+        Some(set_by) => locale.text(
+            "topic_discord_set_by",
+            "Discord topic: {topic} (set by {nick})",
+            &[("topic", &topic), ("nick", &set_by)],
+        ),
+        None => locale.text(
+            "topic_discord",
+            "Discord topic: {topic}",
+            &[("topic", &topic)],
+        ),
+    })
+}
+
+/// What `!history [count]` answers with: the last messages relayed from Discord to the channel.
+fn history(
+    message_cache: &MessageCache,
+    locale: &Locale,
+    channel_id: ChannelId,
+    count: Option<&str>,
+) -> Vec<String> {
+    let count = count
+        .and_then(|c| c.parse().ok())
+        .unwrap_or(10)
+        .clamp(1, 25);
+    let recent = message_cache.recent(channel_id, count);
+
+    if recent.is_empty() {
+        return vec![locale.text("history_none", "No recent Discord messages to show", &[])];
+    }
+
+    let someone = locale.text("history_someone", "someone", &[]);
+    recent
+        .into_iter()
+        .map(|(id, m)| {
+            locale.text(
+                "history",
+                "[{time}] <{nick}> {content}",
+                &[
+                    ("time", &short_time(id.created_at().unix_timestamp())),
+                    ("nick", &m.nick.as_deref().unwrap_or(&someone)),
+                    ("content", &m.content.as_str().truncate_ellipse(350)),
+                ],
+            )
+        })
+        .collect()
+}
+
+/// What `!online` answers with: the Discord members that are online and can see the channel.
+fn online_members(cache: &Cache, locale: &Locale, channel_id: ChannelId) -> String {
+    let guild = cache.guilds().into_iter().find_map(|id| {
+        cache
+            .guild(id)
+            .filter(|g| g.channels.contains_key(&channel_id))
+    });
+    let Some(guild) = guild else {
+        return locale.text(
+            "online_no_channel",
+            "Couldn't find the Discord channel",
+            &[],
+        );
+    };
+    let channel = &guild.channels[&channel_id];
+
+    if guild.presences.is_empty() {
+        return locale.text(
+            "online_no_presences",
+            "The bridge can't see who's online, it needs presences enabled",
+            &[],
+        );
+    }
+
+    let mut names = guild
+        .presences
+        .iter()
+        .filter(|(_, p)| p.status != OnlineStatus::Offline)
+        .filter_map(|(id, _)| guild.members.get(id))
+        .filter(|m| !m.user.bot && guild.user_permissions_in(channel, m).view_channel())
+        .map(|m| m.display_name().to_string())
+        .collect::<Vec<_>>();
+    names.sort_unstable_by_key(|n| n.to_lowercase());
+
+    let count = names.len();
+    let mut list = String::new();
+    for (i, name) in names.iter().enumerate() {
+        if list.len() + name.len() > 350 {
+            let more = locale.text(
+                "online_more",
+                ", and {count} more",
+                &[("count", &(count - i))],
+            );
+            list.push_str(&more);
+            break;
+        }
+        if !list.is_empty() {
+            list.push_str(", ");
+        }
+        list.push_str(name);
+    }
+
+    match count {
+        0 => locale.text("online_none", "Nobody is online on Discord", &[]),
+        1 => locale.text(
+            "online_one",
+            "1 online on Discord: {list}",
+            &[("list", &list)],
+        ),
+        _ => locale.text(
+            "online",
+            "{count} online on Discord: {list}",
+            &[("count", &count), ("list", &list)],
+        ),
+    }
+}
+
+/// Turns `:name:` or a literal emoji into something Discord can react with. Guild emoji win over
+/// unicode ones with the same name.
+fn reaction_type(emoji: &str, guild_emojis: &[Emoji]) -> Option<ReactionType> {
+    let Some(name) = emoji.strip_prefix(':').and_then(|e| e.strip_suffix(':')) else {
+        return Some(ReactionType::Unicode(emoji.to_owned()));
+    };
+
+    guild_emojis
+        .iter()
+        .find(|e| e.name == name)
+        .map(|e| ReactionType::Custom {
+            animated: e.animated,
+            id: e.id,
+            name: Some(e.name.clone()),
+        })
+        .or_else(|| shortcode_to_unicode(name).map(|u| ReactionType::Unicode(u.to_owned())))
+}
+
+/// Spreads IRC speakers over a channel's webhooks, so one webhook isn't renamed for every line
+/// when people take turns. Speakers stick to the webhook they got first, and new ones get the
+/// webhook that has been idle the longest.
+#[derive(Default)]
+struct WebhookPicker {
+    speakers: HashMap<(String, String), usize>,
+    last_used: HashMap<(String, usize), Instant>,
+}
+
+impl WebhookPicker {
+    fn pick<'a>(
+        &mut self,
+        webhooks: &'a HashMap<String, Vec<Arc<Webhook>>>,
+        channel: &str,
+        nick: &str,
+    ) -> Option<&'a Arc<Webhook>> {
+        let pool = webhooks.get(channel).filter(|p| !p.is_empty())?;
+
+        let key = (channel.to_owned(), nick.to_owned());
+        let index = match self.speakers.get(&key) {
+            Some(&i) if i < pool.len() => i,
+            _ => {
+                let i = (0..pool.len())
+                    .min_by_key(|&i| self.last_used.get(&(channel.to_owned(), i)).copied())
+                    .unwrap();
+                self.speakers.insert(key, i);
+                i
+            }
+        };
+
+        self.last_used
+            .insert((channel.to_owned(), index), Instant::now());
+        pool.get(index)
+    }
+}
+
+/// Lines closer together than this count towards a paste.
+const PASTE_WINDOW: Duration = Duration::from_secs(3);
+
+/// Room for lines in one code block, leaving some of Discord's 2000 characters for the fences.
+const PASTE_BLOCK_LEN: usize = 1900;
+
+/// Lines from someone pasting on IRC, collected into code blocks instead of relayed one by one.
+#[derive(Default)]
+struct Paste {
+    recent: VecDeque<Instant>,
+    /// The first held message, reused to send the block the same way
+    held: Option<(QueuedMessage, String)>,
+    lines: Vec<String>,
+}
+
+/// Holds on to the line if its sender has gone over the channel's paste limit. Returns the message
+/// back if it should be sent like normal.
+fn hold_paste(
+    pastes: &mut HashMap<(String, String), Paste>,
+    channel_options: &HashMap<String, ChannelOptions>,
+    channel: &str,
+    nick: &str,
+    line: String,
+    msg: QueuedMessage,
+) -> Option<QueuedMessage> {
+    let Some(limit) = channel_options.get(channel).and_then(|o| o.paste_lines) else {
+        return Some(msg);
+    };
+
+    let paste = pastes
+        .entry((channel.to_owned(), nick.to_owned()))
+        .or_default();
+    paste.recent.retain(|t| t.elapsed() < PASTE_WINDOW);
+    paste.recent.push_back(Instant::now());
+
+    if paste.held.is_none() && paste.recent.len() <= limit {
+        return Some(msg);
+    }
+
+    paste.held.get_or_insert_with(|| (msg, nick.to_owned()));
+    paste.lines.push(line);
+    None
+}
+
+/// Sends the pastes nobody has added to for a while, or all of them with `all`. Ones that don't
+/// fit in one code block get uploaded to `[flood]`'s paste service, if it works.
+async fn flush_pastes(
+    pastes: &mut HashMap<(String, String), Paste>,
+    send: &RelayQueue,
+    flood: &Flood,
+    locale: &Locale,
+    all: bool,
+) -> anyhow::Result<()> {
+    let done = pastes
+        .iter()
+        .filter(|(_, p)| {
+            all || p
+                .recent
+                .back()
+                .map_or(true, |t| t.elapsed() >= PASTE_WINDOW)
+        })
+        .map(|(key, _)| key.clone())
+        .collect::<Vec<_>>();
+
+    for key in done {
+        let Some(Paste {
+            held: Some((msg, nick)),
+            lines,
+            ..
+        }) = pastes.remove(&key)
+        else {
+            continue;
+        };
+
+        let (count, text) = (lines.len(), lines.join("\n"));
+        let mut blocks = vec![String::new()];
+        for line in lines {
+            let line = line.replace("```", "`\u{200b}``");
+            let block = blocks.last_mut().unwrap();
+            if !block.is_empty() && block.len() + line.len() + 1 > PASTE_BLOCK_LEN {
+                blocks.push(String::new());
+            }
+            let block = blocks.last_mut().unwrap();
+            block.push_str(&line);
+            block.push('\n');
+        }
+
+        // waited on here instead of in the background, so the link stays where the paste was
+        if blocks.len() > 1 {
+            match flood.paste(&text).await {
+                Ok(url) => {
+                    let link = locale.text(
+                        "flood_pasted",
+                        "{count} lines: {url}",
+                        &[("count", &count), ("url", &url)],
+                    );
+                    send.chat(msg.with_content(&nick, link))?;
+                    continue;
+                }
+                Err(e) => eprintln!("Failed to upload a paste, sending it in blocks: {e:#}"),
+            }
+        }
+
+        for block in blocks {
+            send.chat(msg.with_content(&nick, format!("```\n{block}```")))?;
+        }
+    }
+
     Ok(())
 }
 
+/// Joins and parts waiting to go out as one digest message.
+struct Churn {
+    since: Instant,
+    joined: Vec<String>,
+    left: Vec<String>,
+}
+
+/// Adds a join or part to the channel's digest. Returns false if the channel doesn't use digests,
+/// so it should be relayed like normal.
+fn record_churn(
+    churn: &mut HashMap<String, Churn>,
+    channel_options: &HashMap<String, ChannelOptions>,
+    channel: &str,
+    nick: &str,
+    joined: bool,
+) -> bool {
+    if channel_options
+        .get(channel)
+        .and_then(|o| o.join_part_digest)
+        .is_none()
+    {
+        return false;
+    }
+
+    let entry = churn.entry(channel.to_owned()).or_insert_with(|| Churn {
+        since: Instant::now(),
+        joined: Vec::new(),
+        left: Vec::new(),
+    });
+
+    if joined {
+        entry.joined.push(nick.to_owned());
+    } else {
+        entry.left.push(nick.to_owned());
+    }
+
+    true
+}
+
+/// Posts the digests that have been collecting for long enough, or all of them with `all`.
+fn flush_churn(
+    churn: &mut HashMap<String, Churn>,
+    channel_options: &HashMap<String, ChannelOptions>,
+    mapping: &HashMap<String, u64>,
+    locale: &Locale,
+    send: &RelayQueue,
+    all: bool,
+) -> anyhow::Result<()> {
+    let due = churn
+        .iter()
+        .filter(|(channel, c)| {
+            all || channel_options
+                .get(*channel)
+                .and_then(|o| o.join_part_digest)
+                .map_or(true, |minutes| {
+                    c.since.elapsed() >= Duration::from_secs(minutes * 60)
+                })
+        })
+        .map(|(channel, _)| channel.clone())
+        .collect::<Vec<_>>();
+
+    for channel in due {
+        let Some(c) = churn.remove(&channel) else {
+            continue;
+        };
+        let Some(&id) = mapping.get(&channel) else {
+            continue;
+        };
+
+        let mut names = c
+            .joined
+            .iter()
+            .chain(&c.left)
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        names.sort_unstable();
+        names.dedup();
+
+        let mut list = names
+            .iter()
+            .take(10)
+            .copied()
+            .collect::<Vec<_>>()
+            .join(", ");
+        if names.len() > 10 {
+            list.push('…');
+        }
+
+        send.event(QueuedMessage::Raw {
+            channel_id: ChannelId::from(id),
+            message: locale.text(
+                "join_part_digest",
+                "last {minutes} min: +{joined} joined, −{left} left: {names}",
+                &[
+                    ("minutes", &(c.since.elapsed().as_secs() / 60)),
+                    ("joined", &c.joined.len()),
+                    ("left", &c.left.len()),
+                    ("names", &list),
+                ],
+            ),
+            reply_to: None,
+        })?;
+    }
+
+    Ok(())
+}
+
+/// With smart join/part on, only joins and parts of people that spoke in the channel recently are
+/// worth relaying.
+fn announce_join_part(
+    channel_options: &HashMap<String, ChannelOptions>,
+    last_spoke: &HashMap<(String, String), Instant>,
+    channel: &str,
+    nick: &str,
+) -> bool {
+    let Some(minutes) = channel_options.get(channel).and_then(|o| o.smart_join_part) else {
+        return true;
+    };
+
+    last_spoke
+        .get(&(channel.to_owned(), nick.to_owned()))
+        .map_or(false, |t| t.elapsed() < Duration::from_secs(minutes * 60))
+}
+
+fn hostmask(message: &Message) -> Option<String> {
+    match &message.prefix {
+        Some(Prefix::Nickname(nick, user, host)) => Some(format!("{nick}!{user}@{host}")),
+        _ => None,
+    }
+}
+
+fn tag<'a>(message: &'a Message, key: &str) -> Option<&'a str> {
+    let tags = message.tags.as_ref()?;
+    tags.iter().find(|Tag(k, _)| k == key)?.1.as_deref()
+}
+
+/// Who RPL_WHOISACCOUNT (330) says a nick is logged in as. Not every version of the irc crate
+/// knows that numeric, so it can come as either.
+fn whois_account(command: &Command) -> Option<(&str, &str)> {
+    let args = match command {
+        Command::Response(response, args) if *response as u16 == 330 => args,
+        Command::Raw(numeric, args) if numeric == "330" => args,
+        _ => return None,
+    };
+    Some((args.get(1)?.as_str(), args.get(2)?.as_str()))
+}
+
+/// The services account the sender is logged into, from the account-tag capability.
+fn account(message: &Message) -> Option<&str> {
+    tag(message, "account")
+}
+
+/// The `time` tag from IRCv3 `server-time`, as `HH:MM`.
+fn server_time(message: &Message) -> Option<String> {
+    let time = tag(message, "time")?;
+    let time = DateTime::parse_from_rfc3339(time).ok()?;
+    Some(short_time(time.timestamp()))
+}
+
+fn now() -> i64 {
+    i64::try_from(unix_now()).unwrap_or_default()
+}
+
+fn user_count(
+    channel_options: &HashMap<String, ChannelOptions>,
+    channel_users: &HashMap<String, Vec<String>>,
+    channel: &str,
+) -> Option<usize> {
+    channel_options
+        .get(channel)
+        .and_then(|o| o.user_count)
+        .unwrap_or(false)
+        .then(|| channel_users.get(channel).map_or(0, Vec::len))
+}
+
+fn with_user_count(topic: &str, count: Option<usize>) -> String {
+    match count {
+        Some(count) if topic.is_empty() => format!("{count} on IRC"),
+        Some(count) => format!("{topic} | {count} on IRC"),
+        None => topic.to_owned(),
+    }
+}
+
+/// Removes the `| N on IRC` suffix added by the user count option.
+pub fn strip_user_count(topic: &str) -> &str {
+    regex! {
+        static USER_COUNT_RE = r"(?:^|\s*\|\s*)\d+ on IRC$";
+    }
+
+    match USER_COUNT_RE.find(topic) {
+        Ok(Some(m)) => &topic[..m.start()],
+        _ => topic,
+    }
+}
+
+/// Who `nick` pings on Discord. Links come first, if whoever has the nick is logged in as who
+/// linked it, and people who turned pings off aren't found by name either.
+fn resolve_member(
+    nick: &str,
+    members: &[Member],
+    links: &Links,
+    seen: &HashMap<String, Seen>,
+    id_cache: &mut HashMap<String, Option<u64>>,
+) -> Option<UserId> {
+    if let Some(link) = links.get(nick, seen_account(seen, nick)) {
+        return link.prefs.pings.then_some(link.user_id);
+    }
+    id_cache
+        .entry(nick.to_owned())
+        .or_insert_with(|| find_by_name(members, nick).map(|member| member.user.id.0.get()))
+        .and_then(NonZeroU64::new)
+        .map(UserId)
+}
+
+/// Sizes and a few entries of the loop's caches, for figuring out cache trouble without a rebuild.
+fn cache_report(
+    avatar_cache: &HashMap<String, Option<Arc<str>>>,
+    id_cache: &HashMap<String, Option<u64>>,
+    channel_users: &HashMap<String, Vec<String>>,
+    channels_cache: Option<&HashMap<ChannelId, GuildChannel>>,
+    emoji_cache: &[Emoji],
+    queue_depth: usize,
+) -> String {
+    fn sample(entries: impl Iterator<Item = String>) -> String {
+        let entries = entries.take(3).collect::<Vec<_>>();
+        if entries.is_empty() {
+            String::new()
+        } else {
+            format!(" ({}, …)", entries.join(", "))
+        }
+    }
+
+    let mut report = String::new();
+    writeln!(
+        report,
+        "avatar_cache: {}{}",
+        avatar_cache.len(),
+        sample(
+            avatar_cache
+                .iter()
+                .map(|(nick, url)| format!("{nick} → {}", url.as_deref().unwrap_or("none")))
+        )
+    )
+    .unwrap();
+    writeln!(
+        report,
+        "id_cache: {}{}",
+        id_cache.len(),
+        sample(id_cache.iter().map(|(nick, id)| match id {
+            Some(id) => format!("{nick} → {id}"),
+            None => format!("{nick} → none"),
+        }))
+    )
+    .unwrap();
+    writeln!(
+        report,
+        "channel_users: {} channels, {} users{}",
+        channel_users.len(),
+        channel_users.values().map(Vec::len).sum::<usize>(),
+        sample(
+            channel_users
+                .iter()
+                .map(|(channel, users)| format!("{channel}: {}", users.len()))
+        )
+    )
+    .unwrap();
+    match channels_cache {
+        Some(channels) => writeln!(
+            report,
+            "channels_cache: {}{}",
+            channels.len(),
+            sample(channels.values().map(|c| format!("#{}", c.name)))
+        ),
+        None => writeln!(report, "channels_cache: not loaded"),
+    }
+    .unwrap();
+    writeln!(
+        report,
+        "emoji_cache: {}{}",
+        emoji_cache.len(),
+        sample(emoji_cache.iter().map(|e| format!(":{}:", e.name)))
+    )
+    .unwrap();
+    write!(report, "queue to Discord: {queue_depth} waiting").unwrap();
+
+    report
+}
+
+/// Whether `name` is a nick in `channel` or the name of someone on Discord.
+fn is_someone(
+    name: &str,
+    channel_users: &HashMap<String, Vec<String>>,
+    channel: &str,
+    members: &[Member],
+) -> bool {
+    let on_irc = channel_users.get(channel).map_or(false, |users| {
+        users
+            .iter()
+            .any(|u| split_prefix(u).1.eq_ignore_ascii_case(name))
+    });
+    on_irc
+        || members.iter().any(|m| {
+            m.display_name().eq_ignore_ascii_case(name) || m.user.name.eq_ignore_ascii_case(name)
+        })
+}
+
+/// Names that `irc_to_discord_processing` would try to turn into mentions.
+fn mentioned_names(message: &str) -> Vec<&str> {
+    let addressed = PING_NICK_1.captures(message).ok().flatten();
+    let pinged = PING_RE_2.captures_iter(message).flatten();
+
+    addressed
+        .into_iter()
+        .chain(pinged)
+        .filter_map(|caps| caps.get(1))
+        .map(|m| m.as_str())
+        .collect()
+}
+
 fn irc_to_discord_processing(
     message: &str,
     members: &[Member],
+    links: &Links,
+    seen: &HashMap<String, Seen>,
     id_cache: &mut HashMap<String, Option<u64>>,
     channels: &HashMap<ChannelId, GuildChannel>,
     emojis: &[Emoji],
@@ -259,25 +2883,13 @@ fn irc_to_discord_processing(
     struct MemberReplacer<'a> {
         id_cache: &'a mut HashMap<String, Option<u64>>,
         members: &'a [Member],
+        links: &'a Links,
+        seen: &'a HashMap<String, Seen>,
     }
 
     impl<'a> Replacer for MemberReplacer<'a> {
         fn replace_append(&mut self, caps: &Captures<'_>, dst: &mut String) {
-            let slice = &caps[1];
-
-            let id = self
-                .id_cache
-                .entry(slice.to_owned())
-                .or_insert_with(|| {
-                    self.members.iter().find_map(|member| {
-                        (slice == member.display_name() || slice == member.user.name.as_str())
-                            .then_some(member.user.id.0.get())
-                    })
-                })
-                .map(NonZeroU64::new)
-                .flatten()
-                .map(UserId);
-
+            let id = resolve_member(&caps[1], self.members, self.links, self.seen, self.id_cache);
             if let Some(id) = id {
                 dst.push_str(&id.mention().to_string());
             } else {
@@ -287,12 +2899,10 @@ fn irc_to_discord_processing(
     }
 
     regex! {
-        static PING_NICK_1 = r"^([\w+]+)(?::|,)";
-        static PING_RE_2 = r"(?<=\s|^)@(\w+)";
-        static CONTROL_CHAR_RE = r"\x1f|\x02|\x12|\x0f|\x16|\x03(?:\d{1,2}(?:,\d{1,2})?)?";
         static WHITESPACE_RE = r"^\s";
         static CHANNEL_RE = r"#([\w-]+)";
         static EMOJI_RE = r":(\w+):";
+        static SPOILER_RE = r"(?i)^\[spoiler\]\s*(.+)$";
     }
 
     if WHITESPACE_RE.is_match(message).unwrap() && !PING_RE_2.is_match(message).unwrap() {
@@ -301,35 +2911,48 @@ fn irc_to_discord_processing(
 
     let mut computed = message.to_owned();
 
-    computed = PING_NICK_1
-        .replace_all(&computed, MemberReplacer { id_cache, members })
-        .into_owned();
-
-    computed = PING_RE_2
-        .replace_all(&computed, MemberReplacer { id_cache, members })
-        .into_owned();
-
-    computed = CHANNEL_RE
-        .replace_all(
-            &computed,
-            OptionReplacer(|caps: &Captures| {
-                channels
-                    .iter()
-                    .find_map(|(id, c)| (c.name == caps[1]).then(|| format!("<#{}>", id.0)))
-            }),
-        )
-        .into_owned();
-
-    computed = EMOJI_RE
-        .replace_all(
-            &computed,
-            OptionReplacer(|caps: &Captures| {
-                emojis
-                    .iter()
-                    .find_map(|e| (e.name == caps[1]).then(|| format!("<:{}:{}>", e.name, e.id.0)))
-            }),
-        )
-        .into_owned();
+    computed = replace_all_owned(
+        &PING_NICK_1,
+        computed,
+        MemberReplacer {
+            id_cache,
+            members,
+            links,
+            seen,
+        },
+    );
+
+    computed = replace_all_owned(
+        &PING_RE_2,
+        computed,
+        MemberReplacer {
+            id_cache,
+            members,
+            links,
+            seen,
+        },
+    );
+
+    computed = replace_all_owned(
+        &CHANNEL_RE,
+        computed,
+        OptionReplacer(|caps: &Captures| {
+            channels
+                .iter()
+                .find_map(|(id, c)| (c.name == caps[1]).then(|| format!("<#{}>", id.0)))
+        }),
+    );
+
+    computed = replace_all_owned(
+        &EMOJI_RE,
+        computed,
+        OptionReplacer(|caps: &Captures| {
+            emojis
+                .iter()
+                .find_map(|e| (e.name == caps[1]).then(|| format!("<:{}:{}>", e.name, e.id.0)))
+                .or_else(|| shortcode_to_unicode(&caps[1]).map(ToOwned::to_owned))
+        }),
+    );
 
     #[allow(clippy::map_unwrap_or)]
     {
@@ -366,61 +2989,380 @@ fn irc_to_discord_processing(
             new.push_str("**");
         }
 
-        CONTROL_CHAR_RE.replace_all(&new, "").into_owned()
+        strip_control_codes(&new)
     };
 
-    computed
+    // "[spoiler] text" is how IRC people can hide things from Discord
+    replace_all_owned(&SPOILER_RE, computed, "||$1||")
+}
+
+fn strip_control_codes(text: &str) -> String {
+    regex! {
+        static CONTROL_CHAR_RE = r"\x1f|\x02|\x12|\x0f|\x16|\x03(?:\d{1,2}(?:,\d{1,2})?)?";
+    }
+
+    CONTROL_CHAR_RE.replace_all(text, "").into_owned()
+}
+
+/// Once this many messages are waiting to go to Discord, chat starts skipping ahead of events.
+const FLOOD_THRESHOLD: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Priority {
+    Chat,
+    /// Joins, parts, topic changes and other things that can wait, or even get dropped
+    Event,
+}
+
+/// A message waiting to go to Discord, with the IRC msgid of what it relays if the server gave one.
+type Queued = (Priority, QueuedMessage, Option<String>);
+
+/// Everything going to Discord goes through here, in the order it happened on IRC.
+struct RelayQueue {
+    sender: UnboundedSender<Queued>,
+    /// How many messages haven't been delivered or dropped yet
+    depth: Arc<AtomicUsize>,
+}
+
+impl RelayQueue {
+    fn chat(&self, msg: QueuedMessage) -> Result<(), SendError<Queued>> {
+        self.send((Priority::Chat, msg, None))
+    }
+
+    /// A chat message that relays a tagged IRC message, so the two get linked once it's sent.
+    fn chat_with_msgid(
+        &self,
+        msg: QueuedMessage,
+        msgid: Option<String>,
+    ) -> Result<(), SendError<Queued>> {
+        self.send((Priority::Chat, msg, msgid))
+    }
+
+    fn event(&self, msg: QueuedMessage) -> Result<(), SendError<Queued>> {
+        self.send((Priority::Event, msg, None))
+    }
+
+    fn send(&self, queued: Queued) -> Result<(), SendError<Queued>> {
+        self.sender.send(queued)?;
+        self.depth.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn depth(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
 }
 
 #[allow(clippy::large_enum_variant)] // lmao
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+/// Everything shared between messages is behind an `Arc`, so queueing one under flood load is
+/// only the text. The `Http` is the queue's.
 enum QueuedMessage {
     Webhook {
-        webhook: Webhook,
-        http: Arc<Http>,
-        avatar_url: Option<String>,
+        webhook: Arc<Webhook>,
+        /// Set when the message should go into a thread of the webhook's channel
+        thread_id: Option<ChannelId>,
+        avatar_url: Option<Arc<str>>,
         content: String,
         nickname: String,
+        /// Whether image links in `content` should be uploaded
+        upload_images: bool,
     },
     Raw {
         channel_id: ChannelId,
-        http: Arc<Http>,
         message: String,
+        reply_to: Option<MessageId>,
+    },
+    /// For channels that want attribution but can't have webhooks
+    Embed {
+        channel_id: ChannelId,
+        author: String,
+        avatar_url: Option<Arc<str>>,
+        content: String,
+        reply_to: Option<MessageId>,
     },
 }
 
-async fn msg_task(mut recv: UnboundedReceiverStream<QueuedMessage>) -> anyhow::Result<()> {
-    while let Some(msg) = recv.next().await {
+impl QueuedMessage {
+    /// Where on Discord it's going.
+    fn channel_id(&self) -> Option<ChannelId> {
+        match self {
+            Self::Webhook {
+                webhook, thread_id, ..
+            } => thread_id.or(webhook.channel_id),
+            Self::Raw { channel_id, .. } | Self::Embed { channel_id, .. } => Some(*channel_id),
+        }
+    }
+
+    /// The same message with different text, for sending more than one like it.
+    fn with_content(&self, nick: &str, text: String) -> Self {
+        let mut msg = self.clone();
         match msg {
-            QueuedMessage::Webhook {
-                webhook,
-                http,
-                avatar_url,
-                content,
-                nickname,
+            Self::Webhook {
+                ref mut content,
+                ref mut upload_images,
+                ..
             } => {
-                if content.is_empty() {
-                    continue;
-                }
-                let mut builder = ExecuteWebhook::new();
+                *content = text;
+                // a paste is its own message, images in it stay links
+                *upload_images = false;
+            }
+            Self::Embed {
+                ref mut content, ..
+            } => *content = text,
+            Self::Raw {
+                ref mut message, ..
+            } => *message = format!("<{nick}>\n{text}"),
+        }
+        msg
+    }
+}
+
+/// Sends queued messages to Discord in order. When they start piling up, because of a flood or
+/// rate limits, chat goes first and the oldest events are dropped past the backlog limit.
+#[allow(clippy::too_many_arguments)]
+async fn msg_task(
+    mut recv: UnboundedReceiver<Queued>,
+    depth: Arc<AtomicUsize>,
+    http: Arc<Http>,
+    message_cache: Arc<Mutex<MessageCache>>,
+    event_backlog: usize,
+    audit_log: Option<Arc<AuditLog>>,
+    image_uploader: Arc<ImageUploader>,
+    health: Arc<Health>,
+    data: Arc<RwLock<TypeMap>>,
+) -> anyhow::Result<()> {
+    let mut pending = VecDeque::new();
+
+    loop {
+        while let Ok(queued) = recv.try_recv() {
+            pending.push_back(queued);
+        }
+
+        if pending.is_empty() {
+            match recv.recv().await {
+                Some(queued) => pending.push_back(queued),
+                None => break,
+            }
+            continue;
+        }
+
+        let next = if pending.len() > FLOOD_THRESHOLD {
+            let mut events = pending
+                .iter()
+                .filter(|(p, _, _)| *p == Priority::Event)
+                .count();
+            while events > event_backlog {
+                let oldest = pending.iter().position(|(p, _, _)| *p == Priority::Event);
+                pending.remove(oldest.unwrap());
+                depth.fetch_sub(1, Ordering::Relaxed);
+                events -= 1;
+            }
+
+            pending
+                .iter()
+                .position(|(p, _, _)| *p == Priority::Chat)
+                .unwrap_or(0)
+        } else {
+            0
+        };
+
+        let (_, msg, msgid) = pending.remove(next).unwrap();
+        depth.fetch_sub(1, Ordering::Relaxed);
+        // for the audit log, the mapping can change with a reload so it's looked up now
+        let source = match (&audit_log, msg.channel_id()) {
+            (Some(_), Some(channel_id)) => data
+                .read()
+                .await
+                .get::<ChannelMappingKey>()
+                .unwrap()
+                .iter()
+                .find(|(_, &id)| id == channel_id.0.get())
+                .map(|(channel, _)| channel.clone()),
+            _ => None,
+        };
+        let source = source.as_deref().unwrap_or("irc");
+        let sent = deliver(msg, &http, audit_log.as_deref(), &image_uploader, source).await;
+        if sent.is_some() {
+            health.relayed();
+        }
+
+        if let (Some(msgid), Some(id)) = (msgid, sent) {
+            message_cache.lock().await.link_msgid(msgid, id);
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the ID of the message on Discord, if something was sent. Messages Discord won't take
+/// are logged and reported, one bad message doesn't stop the ones after it. `source` is the IRC
+/// channel it came from, for the audit log.
+async fn deliver(
+    msg: QueuedMessage,
+    http: &Http,
+    audit_log: Option<&AuditLog>,
+    image_uploader: &ImageUploader,
+    source: &str,
+) -> Option<MessageId> {
+    let sent = match msg {
+        QueuedMessage::Webhook {
+            webhook,
+            thread_id,
+            avatar_url,
+            mut content,
+            nickname,
+            upload_images,
+        } => {
+            let links = content.clone();
+            let files = if upload_images {
+                image_uploader.take_images(&mut content).await
+            } else {
+                Vec::new()
+            };
+            if content.is_empty() && files.is_empty() {
+                return None;
+            }
+            let uploading = !files.is_empty();
+            let builder = |content: String, files: Vec<CreateAttachment>| {
+                let mut builder = ExecuteWebhook::new().add_files(files);
                 if let Some(ref url) = avatar_url {
-                    builder = builder.avatar_url(url);
+                    builder = builder.avatar_url(&**url);
+                }
+                if let Some(thread_id) = thread_id {
+                    builder = builder.in_thread(thread_id);
+                }
+                builder = builder.username(&nickname);
+                if !content.is_empty() {
+                    builder = builder.content(content);
+                }
+                builder
+            };
+
+            let destination = thread_id
+                .or(webhook.channel_id)
+                .map(|c| c.to_string())
+                .unwrap_or_default();
+            let mut result = webhook
+                .execute(http, true, builder(content.clone(), files))
+                .await;
+            // too big or whatever else, the links still get there
+            if uploading {
+                if let Err(e) = &result {
+                    eprintln!("Failed to upload images to {destination}, sending the links: {e}");
+                    content = links;
+                    result = webhook
+                        .execute(http, true, builder(content.clone(), Vec::new()))
+                        .await;
                 }
-                builder = builder.username(nickname).content(content);
+            }
 
-                webhook.execute(&http, true, builder).await?;
+            if let Some(audit_log) = audit_log {
+                audit_log.record(&AuditEntry {
+                    direction: "irc_to_discord",
+                    source,
+                    destination: &destination,
+                    author: Some(&nickname),
+                    content: &content,
+                    error: result.as_ref().err().map(ToString::to_string),
+                });
             }
-            QueuedMessage::Raw {
-                channel_id,
-                http,
-                message,
-            } => {
-                if message.is_empty() {
-                    continue;
+
+            match result {
+                Ok(sent) => sent.map(|m| m.id),
+                Err(e) => {
+                    let e = anyhow::Error::from(e);
+                    report::relay_error(&e, &destination, "irc_to_discord", content.len());
+                    eprintln!("Failed to relay to {destination}: {e:#}");
+                    return None;
                 }
-                channel_id.say(&http, message).await?;
             }
         }
-    }
-    Ok(())
+        QueuedMessage::Raw {
+            channel_id,
+            message,
+            reply_to,
+        } => {
+            if message.is_empty() {
+                return None;
+            }
+            let mut builder = CreateMessage::new().content(&message);
+            if let Some(id) = reply_to {
+                builder = builder.reference_message((channel_id, id));
+            }
+
+            let destination = channel_id.to_string();
+            let result = channel_id.send_message(http, builder).await;
+
+            if let Some(audit_log) = audit_log {
+                audit_log.record(&AuditEntry {
+                    direction: "irc_to_discord",
+                    source,
+                    destination: &destination,
+                    author: None,
+                    content: &message,
+                    error: result.as_ref().err().map(ToString::to_string),
+                });
+            }
+
+            match result {
+                Ok(sent) => Some(sent.id),
+                Err(e) => {
+                    let e = anyhow::Error::from(e);
+                    report::relay_error(&e, &destination, "irc_to_discord", message.len());
+                    eprintln!("Failed to relay to {destination}: {e:#}");
+                    return None;
+                }
+            }
+        }
+        QueuedMessage::Embed {
+            channel_id,
+            author,
+            avatar_url,
+            content,
+            reply_to,
+        } => {
+            if content.is_empty() {
+                return None;
+            }
+            let mut embed_author = CreateEmbedAuthor::new(&author);
+            if let Some(ref url) = avatar_url {
+                embed_author = embed_author.icon_url(&**url);
+            }
+            let embed = CreateEmbed::new()
+                .author(embed_author)
+                .description(&content);
+
+            let mut builder = CreateMessage::new().embed(embed);
+            if let Some(id) = reply_to {
+                builder = builder.reference_message((channel_id, id));
+            }
+
+            let destination = channel_id.to_string();
+            let result = channel_id.send_message(http, builder).await;
+
+            if let Some(audit_log) = audit_log {
+                audit_log.record(&AuditEntry {
+                    direction: "irc_to_discord",
+                    source,
+                    destination: &destination,
+                    author: Some(&author),
+                    content: &content,
+                    error: result.as_ref().err().map(ToString::to_string),
+                });
+            }
+
+            match result {
+                Ok(sent) => Some(sent.id),
+                Err(e) => {
+                    let e = anyhow::Error::from(e);
+                    report::relay_error(&e, &destination, "irc_to_discord", content.len());
+                    eprintln!("Failed to relay to {destination}: {e:#}");
+                    return None;
+                }
+            }
+        }
+    };
+
+    sent
 }
@@ -0,0 +1,117 @@
+use std::{
+    collections::VecDeque,
+    fmt::Write,
+    time::{Duration, Instant},
+};
+
+use crate::locale::Locale;
+
+/// How many measurements the averages are taken over.
+const SAMPLES: usize = 10;
+
+/// Round trip times to the IRC server and the Discord API, so slowness can be pinned on one side.
+#[derive(Default)]
+pub struct Latency {
+    irc: VecDeque<Duration>,
+    discord: VecDeque<Duration>,
+    ping: Option<(String, Instant)>,
+    next_token: u64,
+}
+
+impl Latency {
+    /// Token for the next PING to IRC, or None if the last one still hasn't been answered.
+    pub fn ping_token(&mut self) -> Option<String> {
+        if self.ping.is_some() {
+            return None;
+        }
+
+        self.next_token += 1;
+        let token = format!("dircord-lag-{}", self.next_token);
+        self.ping = Some((token.clone(), Instant::now()));
+        Some(token)
+    }
+
+    /// How long the outstanding PING has gone unanswered.
+    pub fn waiting(&self) -> Option<Duration> {
+        self.ping.as_ref().map(|(_, at)| at.elapsed())
+    }
+
+    /// Drops the outstanding PING, for when the connection it was sent on is gone.
+    pub fn forget_ping(&mut self) {
+        self.ping = None;
+    }
+
+    /// Returns false if the PONG wasn't for us.
+    pub fn pong(&mut self, token: &str) -> bool {
+        match self.ping.take() {
+            Some((sent, at)) if sent == token => {
+                push(&mut self.irc, at.elapsed());
+                true
+            }
+            other => {
+                self.ping = other;
+                false
+            }
+        }
+    }
+
+    pub fn record_discord(&mut self, rtt: Duration) {
+        push(&mut self.discord, rtt);
+    }
+
+    /// The rolling averages for IRC and Discord, None until there's been a measurement.
+    pub fn averages(&self) -> (Option<Duration>, Option<Duration>) {
+        (average(&self.irc), average(&self.discord))
+    }
+
+    pub fn summary(&self, locale: &Locale) -> String {
+        let mut summary = String::new();
+        for (name, samples) in [("IRC", &self.irc), ("Discord", &self.discord)] {
+            let line = match (samples.back(), average(samples)) {
+                (Some(last), Some(average)) => locale.text(
+                    "lag",
+                    "**{name}:** {last} ms (average {average} ms over {count} checks)",
+                    &[
+                        ("name", &name),
+                        ("last", &last.as_millis()),
+                        ("average", &average.as_millis()),
+                        ("count", &samples.len()),
+                    ],
+                ),
+                _ => locale.text(
+                    "lag_unmeasured",
+                    "**{name}:** not measured yet",
+                    &[("name", &name)],
+                ),
+            };
+            writeln!(summary, "{line}").unwrap();
+        }
+
+        if let Some((_, at)) = &self.ping {
+            if at.elapsed() > Duration::from_secs(10) {
+                let line = locale.text(
+                    "lag_unanswered",
+                    "IRC hasn't answered a ping for {seconds} seconds",
+                    &[("seconds", &at.elapsed().as_secs())],
+                );
+                writeln!(summary, "{line}").unwrap();
+            }
+        }
+
+        summary
+    }
+}
+
+fn average(samples: &VecDeque<Duration>) -> Option<Duration> {
+    let count = u32::try_from(samples.len())
+        .ok()
+        .filter(|&count| count > 0)?;
+    Some(samples.iter().sum::<Duration>() / count)
+}
+
+fn push(samples: &mut VecDeque<Duration>, rtt: Duration) {
+    if samples.len() == SAMPLES {
+        samples.pop_front();
+    }
+    samples.push_back(rtt);
+}
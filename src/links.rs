@@ -0,0 +1,395 @@
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::{BuildHasher, Hasher},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use rusqlite::{params, Connection};
+use serenity::model::id::UserId;
+
+use crate::locale::Locale;
+
+/// How long a `!link` code can be confirmed for.
+const CODE_LIFETIME: Duration = Duration::from_secs(10 * 60);
+
+/// What someone linked wants, changed with `!link pings off` and such in a DM.
+#[derive(Clone, Copy)]
+pub struct Prefs {
+    /// IRC saying their nick pings them on Discord
+    pub pings: bool,
+    /// What they say on IRC shows with their Discord avatar
+    pub avatar: bool,
+    /// IRC users can PM them with "nick: ", off until they turn it on themselves
+    pub pms: bool,
+}
+
+impl Default for Prefs {
+    fn default() -> Self {
+        Self {
+            pings: true,
+            avatar: true,
+            pms: false,
+        }
+    }
+}
+
+pub struct Link {
+    pub nick: String,
+    pub user_id: UserId,
+    /// The services account they linked from, which counts for any nick they use. Only links
+    /// from the config go by nick alone
+    pub account: Option<String>,
+    pub prefs: Prefs,
+}
+
+struct Pending {
+    nick: String,
+    account: String,
+    at: Instant,
+}
+
+/// Who is who on IRC and Discord. Some come from the config, by nick. The rest linked their
+/// services account themselves with a code from `!link`, and are kept in the database if there
+/// is one.
+pub struct Links {
+    /// From `[links]`, by lowercase nick
+    configured: HashMap<String, Link>,
+    /// By lowercase account
+    linked: HashMap<String, Link>,
+    /// By code
+    pending: HashMap<String, Pending>,
+    db: Option<Connection>,
+}
+
+fn logged<T>(what: &str, result: rusqlite::Result<T>) {
+    if let Err(e) = result {
+        eprintln!("Failed to {what} in the database: {e}");
+    }
+}
+
+impl Links {
+    pub fn open(
+        configured: HashMap<String, u64>,
+        db_path: Option<&Path>,
+    ) -> rusqlite::Result<Self> {
+        let mut linked = HashMap::new();
+
+        let db = match db_path {
+            Some(path) => {
+                let conn = Connection::open(path)?;
+                conn.execute_batch(
+                    "CREATE TABLE IF NOT EXISTS links (
+                        account TEXT PRIMARY KEY COLLATE NOCASE,
+                        nick TEXT NOT NULL,
+                        user_id INTEGER NOT NULL,
+                        pings INTEGER NOT NULL,
+                        avatar INTEGER NOT NULL,
+                        pms INTEGER NOT NULL DEFAULT 0
+                    );",
+                )?;
+                let mut query = conn.prepare(
+                    "SELECT nick, account, user_id, pings, avatar, pms FROM links
+                        WHERE account IS NOT NULL",
+                )?;
+                let rows = query.query_map([], |row| {
+                    Ok(Link {
+                        nick: row.get(0)?,
+                        account: Some(row.get(1)?),
+                        user_id: UserId::from(row.get::<_, u64>(2)?),
+                        prefs: Prefs {
+                            pings: row.get(3)?,
+                            avatar: row.get(4)?,
+                            pms: row.get(5)?,
+                        },
+                    })
+                })?;
+                for link in rows {
+                    let link = link?;
+                    let account = link.account.as_deref().unwrap_or_default().to_lowercase();
+                    linked.insert(account, link);
+                }
+                drop(query);
+                Some(conn)
+            }
+            None => None,
+        };
+
+        let configured = configured
+            .into_iter()
+            .map(|(nick, id)| {
+                let link = Link {
+                    nick: nick.clone(),
+                    user_id: UserId::from(id),
+                    account: None,
+                    prefs: Prefs::default(),
+                };
+                (nick.to_lowercase(), link)
+            })
+            .collect();
+
+        Ok(Self {
+            configured,
+            linked,
+            pending: HashMap::new(),
+            db,
+        })
+    }
+
+    /// Whoever `nick` is linked to in the config, or who `account` linked to. Without an account
+    /// only the config counts, anyone can take a nick.
+    pub fn get(&self, nick: &str, account: Option<&str>) -> Option<&Link> {
+        self.configured
+            .get(&nick.to_lowercase())
+            .or_else(|| self.linked.get(&account?.to_lowercase()))
+    }
+
+    /// Who "name: " in a PM is for. Only picks who gets the message, so the nick someone linked
+    /// from is good enough here. Whether they take PMs is up to their prefs.
+    pub fn user(&self, name: &str) -> Option<&Link> {
+        let name = name.to_lowercase();
+        self.configured
+            .get(&name)
+            .or_else(|| self.linked.get(&name))
+            .or_else(|| {
+                self.linked
+                    .values()
+                    .find(|l| l.nick.eq_ignore_ascii_case(&name))
+            })
+    }
+
+    fn all(&self) -> impl Iterator<Item = &Link> {
+        self.configured.values().chain(self.linked.values())
+    }
+
+    /// What a Discord user goes by on IRC, if they're linked.
+    pub fn nick(&self, user_id: UserId) -> Option<&str> {
+        self.all()
+            .find(|l| l.user_id == user_id)
+            .map(|l| l.nick.as_str())
+    }
+
+    /// Whether `text` is something `irc_command` answers, so the caller can find out the account
+    /// first on servers without account-tag.
+    pub fn is_command(text: &str) -> bool {
+        matches!(text.trim(), "!link" | "!unlink")
+    }
+
+    /// A code for `account` to confirm from Discord, or `None` if the config already links `nick`.
+    fn start(&mut self, nick: &str, account: &str) -> Option<String> {
+        if self.configured.contains_key(&nick.to_lowercase()) {
+            return None;
+        }
+        self.pending.retain(|_, p| p.at.elapsed() < CODE_LIFETIME);
+
+        // random keys every time, which is all a code needs
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write(account.as_bytes());
+        let code = format!("{:08x}", hasher.finish() & 0xffff_ffff);
+
+        self.pending.insert(
+            code.clone(),
+            Pending {
+                nick: nick.to_owned(),
+                account: account.to_owned(),
+                at: Instant::now(),
+            },
+        );
+        Some(code)
+    }
+
+    /// Links whoever got `code` to `user_id`, returning their nick. The code was given to the
+    /// account, so this only ever replaces that account's own link.
+    fn confirm(&mut self, code: &str, user_id: UserId) -> Option<String> {
+        let pending = self
+            .pending
+            .remove(code)
+            .filter(|p| p.at.elapsed() < CODE_LIFETIME)?;
+
+        let link = Link {
+            nick: pending.nick.clone(),
+            user_id,
+            account: Some(pending.account.clone()),
+            prefs: Prefs::default(),
+        };
+        self.save(&link);
+        self.linked.insert(pending.account.to_lowercase(), link);
+        Some(pending.nick)
+    }
+
+    /// Removes what `user_id` linked themselves, returning the nicks that were linked.
+    fn unlink(&mut self, user_id: UserId) -> Vec<String> {
+        let accounts = self
+            .linked
+            .iter()
+            .filter(|(_, l)| l.user_id == user_id)
+            .map(|(account, _)| account.clone())
+            .collect::<Vec<_>>();
+        accounts
+            .iter()
+            .filter_map(|account| self.remove(account))
+            .collect()
+    }
+
+    fn remove(&mut self, account: &str) -> Option<String> {
+        let link = self.linked.remove(&account.to_lowercase())?;
+        if let Some(db) = &self.db {
+            logged(
+                "remove a link",
+                db.execute("DELETE FROM links WHERE account = ?1", params![account]),
+            );
+        }
+        Some(link.nick)
+    }
+
+    fn save(&self, link: &Link) {
+        if let Some(db) = &self.db {
+            logged(
+                "save a link",
+                db.execute(
+                    "INSERT OR REPLACE INTO links (account, nick, user_id, pings, avatar, pms)
+                        VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    params![
+                        link.account,
+                        link.nick,
+                        link.user_id.0.get(),
+                        link.prefs.pings,
+                        link.prefs.avatar,
+                        link.prefs.pms,
+                    ],
+                ),
+            );
+        }
+    }
+
+    /// Returns false if `user_id` isn't linked to anyone.
+    fn set_prefs(&mut self, user_id: UserId, change: impl Fn(&mut Prefs)) -> bool {
+        let mut changed = false;
+        // configured ones only live in memory, the config is where those are kept
+        for link in self
+            .configured
+            .values_mut()
+            .filter(|l| l.user_id == user_id)
+        {
+            change(&mut link.prefs);
+            changed = true;
+        }
+        let mut saved = Vec::new();
+        for link in self.linked.values_mut().filter(|l| l.user_id == user_id) {
+            change(&mut link.prefs);
+            saved.push(link.account.clone().unwrap_or_default().to_lowercase());
+        }
+        for account in &saved {
+            self.save(&self.linked[account]);
+        }
+        changed || !saved.is_empty()
+    }
+
+    /// `!link` and `!unlink` in a PM to the bridge, from someone logged in as `account`.
+    /// Returns what to answer, if it was one.
+    pub fn irc_command(
+        &mut self,
+        nick: &str,
+        account: Option<&str>,
+        text: &str,
+        locale: &Locale,
+    ) -> Option<String> {
+        if !Self::is_command(text) {
+            return None;
+        }
+        // nicks can be taken by anyone, so links go by account
+        let Some(account) = account else {
+            return Some(locale.text(
+                "link_needs_account",
+                "Log in with services first, links go by your account",
+                &[],
+            ));
+        };
+
+        Some(match text.trim() {
+            "!link" => match self.start(nick, account) {
+                Some(code) => locale.text(
+                    "link_code",
+                    "Send \"!link {code}\" to the bridge in a Discord DM within 10 minutes to link {nick} to your Discord account",
+                    &[("code", &code), ("nick", &nick)],
+                ),
+                None => locale.text(
+                    "link_configured",
+                    "{nick} is linked in the bridge's config, ask its admins to change that",
+                    &[("nick", &nick)],
+                ),
+            },
+            _ => match self.remove(account) {
+                Some(linked) => {
+                    locale.text("link_removed", "Unlinked {nick}", &[("nick", &linked)])
+                }
+                None => locale.text("link_none", "You aren't linked to anyone", &[]),
+            },
+        })
+    }
+
+    /// `!link <code>`, `!link pings on` and such and `!unlink` in a Discord DM. Returns what to
+    /// answer, if it was one.
+    pub fn discord_command(
+        &mut self,
+        user_id: UserId,
+        text: &str,
+        locale: &Locale,
+    ) -> Option<String> {
+        let mut words = text.split_whitespace();
+        match (words.next()?, words.next(), words.next(), words.next()) {
+            ("!unlink", None, None, None) => {
+                let nicks = self.unlink(user_id);
+                Some(if nicks.is_empty() {
+                    locale.text("link_none", "You aren't linked to anyone", &[])
+                } else {
+                    locale.text(
+                        "link_removed",
+                        "Unlinked {nick}",
+                        &[("nick", &nicks.join(", "))],
+                    )
+                })
+            }
+            (
+                "!link",
+                Some(pref @ ("pings" | "avatar" | "pms")),
+                Some(value @ ("on" | "off")),
+                None,
+            ) => {
+                let on = value == "on";
+                let set = self.set_prefs(user_id, |prefs| match pref {
+                    "pings" => prefs.pings = on,
+                    "avatar" => prefs.avatar = on,
+                    _ => prefs.pms = on,
+                });
+                Some(if set {
+                    locale.text(
+                        "link_pref_set",
+                        "Turned {pref} {value}",
+                        &[("pref", &pref), ("value", &value)],
+                    )
+                } else {
+                    locale.text("link_none", "You aren't linked to anyone", &[])
+                })
+            }
+            ("!link", Some(code), None, None) => Some(match self.confirm(code, user_id) {
+                Some(nick) => locale.text(
+                    "link_done",
+                    "Linked you to {nick} on IRC. \"!link pings off\" stops IRC pinging you, \"!link avatar off\" stops your avatar showing on what you say there, \"!link pms on\" lets people on IRC message you here",
+                    &[("nick", &nick)],
+                ),
+                None => locale.text(
+                    "link_bad_code",
+                    "That code isn't right or it's too old, say \"!link\" to the bridge on IRC for a new one",
+                    &[],
+                ),
+            }),
+            ("!link", ..) => Some(locale.text(
+                "link_usage",
+                "Say \"!link\" to the bridge on IRC to get a code, then \"!link <code>\" here",
+                &[],
+            )),
+            _ => None,
+        }
+    }
+}
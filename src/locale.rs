@@ -0,0 +1,52 @@
+use std::{collections::HashMap, fmt::Display, fmt::Write, fs, path::Path};
+
+use anyhow::Context;
+
+/// Text the bridge writes itself, like join lines and command replies, so it can be translated
+/// without patching the source. A locale file is a TOML table of keys to text with `{placeholders}`,
+/// see `sample_locale.toml` for all of them.
+#[derive(Default)]
+pub struct Locale(HashMap<String, String>);
+
+impl Locale {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Couldn't read locale {}", path.display()))?;
+        let strings =
+            toml::from_str(&text).with_context(|| format!("Invalid locale {}", path.display()))?;
+        Ok(Self(strings))
+    }
+
+    /// The locale's text for `key`, or `default` if it doesn't have one, with the placeholders
+    /// filled in. Unknown placeholders are left alone.
+    pub fn text(&self, key: &str, default: &str, args: &[(&str, &dyn Display)]) -> String {
+        let template = self.0.get(key).map_or(default, String::as_str);
+
+        let mut text = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            text.push_str(&rest[..start]);
+            let after = &rest[start + 1..];
+
+            let arg = after.find('}').and_then(|end| {
+                let name = &after[..end];
+                args.iter()
+                    .find(|(n, _)| *n == name)
+                    .map(|(_, value)| (value, end))
+            });
+            match arg {
+                Some((value, end)) => {
+                    write!(text, "{value}").unwrap();
+                    rest = &after[end + 1..];
+                }
+                None => {
+                    text.push('{');
+                    rest = after;
+                }
+            }
+        }
+        text.push_str(rest);
+
+        text
+    }
+}
@@ -0,0 +1,306 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write as _,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::Context;
+use chrono::{TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::unix_now;
+
+/// One line of conversation, as kept in the chat logs.
+#[derive(Serialize, Deserialize)]
+struct LogLine {
+    timestamp: u64,
+    /// "irc" or "discord", whichever side said it
+    side: String,
+    author: String,
+    content: String,
+}
+
+/// Conversations from both sides of the bridge, in one JSON lines file per channel per day.
+pub struct ChatLog {
+    dir: PathBuf,
+    /// Days older than this are deleted as new ones start, `None` keeps them all
+    keep_days: Option<u64>,
+    /// Today's file for each channel, behind one lock so lines from both directions don't
+    /// interleave halfway
+    files: Mutex<HashMap<PathBuf, File>>,
+}
+
+impl ChatLog {
+    pub fn open(dir: impl Into<PathBuf>, keep_days: Option<u64>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            keep_days,
+            files: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Opens every file again on the next line, after logrotate or such moved them away.
+    pub fn reopen(&self) {
+        self.files.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    }
+
+    /// The day's file for the channel in `dir`, opened if it wasn't yet. Starting a new day is
+    /// when old ones go.
+    fn file<'a>(
+        &self,
+        files: &'a mut HashMap<PathBuf, File>,
+        dir: &Path,
+        path: &Path,
+        timestamp: u64,
+    ) -> io::Result<&'a mut File> {
+        if !files.contains_key(path) {
+            fs::create_dir_all(dir)?;
+            files.retain(|open, _| open.parent() != Some(dir));
+            if let Some(keep_days) = self.keep_days {
+                prune(
+                    dir,
+                    &day(timestamp.saturating_sub(keep_days.saturating_mul(24 * 60 * 60))),
+                );
+            }
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            files.insert(path.to_owned(), file);
+        }
+        Ok(files.get_mut(path).unwrap())
+    }
+
+    pub fn record(&self, channel: &str, side: &str, author: &str, content: &str) {
+        let timestamp = unix_now();
+        let line = LogLine {
+            timestamp,
+            side: side.to_owned(),
+            author: author.to_owned(),
+            content: content.to_owned(),
+        };
+
+        let mut line = match serde_json::to_string(&line) {
+            Ok(line) => line,
+            Err(e) => {
+                eprintln!("Failed to serialize chat log line: {e}");
+                return;
+            }
+        };
+        line.push('\n');
+
+        let dir = self.dir.join(dir_name(channel));
+        let path = dir.join(format!("{}.jsonl", day(timestamp)));
+
+        let mut files = self.files.lock().unwrap_or_else(|e| e.into_inner());
+        let result = self
+            .file(&mut files, &dir, &path, timestamp)
+            .and_then(|file| file.write_all(line.as_bytes()));
+        if let Err(e) = result {
+            eprintln!("Failed to write chat log {}: {e}", path.display());
+            // opened again next time, in case the file went away
+            files.remove(&path);
+        }
+    }
+}
+
+/// Deletes the days in `dir` from before `oldest`, which is a day like `2024-01-31`.
+fn prune(dir: &Path, oldest: &str) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("Failed to look for old chat logs in {}: {e}", dir.display());
+            return;
+        }
+    };
+
+    for path in entries.flatten().map(|entry| entry.path()) {
+        let old = path.extension().map_or(false, |e| e == "jsonl")
+            && path
+                .file_stem()
+                .map_or(false, |day| day.to_string_lossy().as_ref() < oldest);
+        if old {
+            if let Err(e) = fs::remove_file(&path) {
+                eprintln!("Failed to delete old chat log {}: {e}", path.display());
+            }
+        }
+    }
+}
+
+/// Channel names as directory names, `#rust-lang` becomes `rust-lang`.
+fn dir_name(channel: &str) -> String {
+    channel
+        .trim_start_matches(&['#', '&'][..])
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn day(unix: u64) -> String {
+    Utc.timestamp_opt(i64::try_from(unix).unwrap_or_default(), 0)
+        .single()
+        .map_or_else(String::new, |t| t.format("%Y-%m-%d").to_string())
+}
+
+fn time(unix: u64) -> String {
+    Utc.timestamp_opt(i64::try_from(unix).unwrap_or_default(), 0)
+        .single()
+        .map_or_else(String::new, |t| t.format("%H:%M:%S").to_string())
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+const STYLE: &str = "body{font-family:sans-serif;max-width:60em;margin:auto;padding:1em}\
+    .line{font-family:monospace;white-space:pre-wrap}.time{color:#888}\
+    .irc .author{color:#2a7}.discord .author{color:#57f}.hidden{display:none}";
+
+// filters the lines of a day as you type into the box
+const FILTER_SCRIPT: &str = "document.getElementById('filter').oninput=function(e){\
+    var q=e.target.value.toLowerCase();\
+    document.querySelectorAll('.line').forEach(function(l){\
+    l.classList.toggle('hidden',l.textContent.toLowerCase().indexOf(q)<0)})}";
+
+// searches every day of a channel, the lines are embedded in the page as `LINES`
+const SEARCH_SCRIPT: &str = "document.getElementById('filter').oninput=function(e){\
+    var q=e.target.value.toLowerCase(),r=document.getElementById('results');r.innerHTML='';\
+    if(q.length<2)return;LINES.filter(function(l){return (l[2]+' '+l[3]).toLowerCase().indexOf(q)>=0})\
+    .slice(0,200).forEach(function(l){var a=document.createElement('a');\
+    a.href=l[0]+'.html#'+l[1];a.textContent=l[0]+' <'+l[2]+'> '+l[3];\
+    var d=document.createElement('div');d.className='line';d.appendChild(a);r.appendChild(d)})}";
+
+fn page(title: &str, body: &str, script: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{title}</title>\
+         <style>{STYLE}</style></head><body>{body}<script>{script}</script></body></html>\n",
+        title = escape(title),
+    )
+}
+
+/// Turns the chat logs in `dir` into HTML pages in `out`: an index of channels, an index of days
+/// for each channel with a search box, and one page per day.
+pub fn render(dir: &Path, out: &Path) -> anyhow::Result<()> {
+    fs::create_dir_all(out)?;
+    let mut channels = Vec::new();
+
+    for entry in fs::read_dir(dir).with_context(|| format!("Couldn't read {}", dir.display()))? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let channel = entry.file_name().to_string_lossy().into_owned();
+
+        // BTreeMap so days come out in order
+        let mut days = BTreeMap::new();
+        for file in fs::read_dir(entry.path())? {
+            let path = file?.path();
+            if path.extension().map_or(true, |e| e != "jsonl") {
+                continue;
+            }
+            let Some(day) = path.file_stem().map(|s| s.to_string_lossy().into_owned()) else {
+                continue;
+            };
+
+            let lines = fs::read_to_string(&path)?
+                .lines()
+                .filter_map(|line| match serde_json::from_str::<LogLine>(line) {
+                    Ok(line) => Some(line),
+                    Err(e) => {
+                        eprintln!("Skipping bad line in {}: {e}", path.display());
+                        None
+                    }
+                })
+                .collect::<Vec<_>>();
+            days.insert(day, lines);
+        }
+
+        let channel_out = out.join(&channel);
+        fs::create_dir_all(&channel_out)?;
+
+        let mut search_lines = Vec::new();
+        for (day, lines) in &days {
+            let mut body = format!(
+                "<p><a href=\"index.html\">#{}</a></p><h1>{day}</h1>\
+                 <input id=\"filter\" placeholder=\"Filter\">",
+                escape(&channel)
+            );
+            for (i, line) in lines.iter().enumerate() {
+                write!(
+                    body,
+                    "<div class=\"line {side}\" id=\"l{i}\"><a class=\"time\" href=\"#l{i}\">{time}</a> \
+                     <span class=\"author\">&lt;{author}&gt;</span> {content}</div>",
+                    side = if line.side == "discord" { "discord" } else { "irc" },
+                    time = time(line.timestamp),
+                    author = escape(&line.author),
+                    content = escape(&line.content),
+                )
+                .unwrap();
+                search_lines.push((day, format!("l{i}"), &line.author, &line.content));
+            }
+
+            fs::write(
+                channel_out.join(format!("{day}.html")),
+                page(&format!("#{channel} {day}"), &body, FILTER_SCRIPT),
+            )?;
+        }
+
+        let mut body = format!(
+            "<p><a href=\"../index.html\">Channels</a></p><h1>#{}</h1>\
+             <input id=\"filter\" placeholder=\"Search all days\"><div id=\"results\"></div><ul>",
+            escape(&channel)
+        );
+        for day in days.keys().rev() {
+            write!(body, "<li><a href=\"{day}.html\">{day}</a></li>").unwrap();
+        }
+        body.push_str("</ul>");
+
+        // `</` can't appear inside the script, or a message could end it early
+        let search_json = serde_json::to_string(&search_lines)?.replace("</", "<\\/");
+        fs::write(
+            channel_out.join("index.html"),
+            page(
+                &format!("#{channel}"),
+                &body,
+                &format!("var LINES={search_json};{SEARCH_SCRIPT}"),
+            ),
+        )?;
+
+        channels.push(channel);
+    }
+
+    channels.sort_unstable();
+    let mut body = String::from("<h1>Channels</h1><ul>");
+    for channel in &channels {
+        let channel = escape(channel);
+        write!(
+            body,
+            "<li><a href=\"{channel}/index.html\">#{channel}</a></li>"
+        )
+        .unwrap();
+    }
+    body.push_str("</ul>");
+    fs::write(out.join("index.html"), page("Channels", &body, ""))?;
+
+    println!("Rendered {} channels to {}", channels.len(), out.display());
+    Ok(())
+}
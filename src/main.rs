@@ -1,44 +1,585 @@
 #![warn(clippy::pedantic)]
 
+mod admin;
+mod audit;
+mod auth;
+mod charset;
+mod colours;
+mod commands;
 mod discord_irc;
+mod emoji;
+mod flood;
+mod health;
+mod ignores;
 mod irc_discord;
+mod latency;
+mod links;
+mod locale;
+mod logs;
+mod members;
+mod message_cache;
+mod networks;
+mod pages;
+mod patterns;
+mod permissions;
+mod private;
+mod puppets;
+mod rehost;
+mod report;
+mod shortener;
+mod spoilers;
+mod store;
+mod translate;
+mod uploads;
+mod urls;
 
-use std::{borrow::Cow, collections::HashMap, env, fs::File, io::Read, sync::Arc};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    env,
+    fmt::Display,
+    fs,
+    future::Future,
+    panic,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use serenity::{
+    builder::CreateWebhook,
+    cache::Cache,
+    futures::future::{join_all, select_all},
+    gateway::{ConnectionStage, ShardManager, ShardMessenger},
     http::Http,
     model::{
         gateway::GatewayIntents,
         guild::Member,
-        id::{ChannelId, UserId},
+        id::{ChannelId, RoleId, UserId},
         webhook::Webhook,
     },
+    prelude::TypeMap,
     Client as DiscordClient,
 };
 
-use tokio::{select, sync::Mutex};
+use tokio::{
+    net::TcpListener,
+    select,
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc::UnboundedSender,
+        Mutex, RwLock,
+    },
+    task::JoinHandle,
+    time::{interval, sleep, timeout, Instant},
+};
 
-use irc::client::{data::Config, Client as IrcClient, Sender};
+use irc::{
+    client::{data::Config, Client as IrcClient, Sender},
+    proto::{message::Tag, Command, Message},
+};
 
-use crate::discord_irc::Handler;
-use crate::irc_discord::irc_loop;
+use crate::audit::{AuditLog, Rotation};
+use crate::auth::Login;
+use crate::charset::{Transcoder, WIRE_ENCODING};
+use crate::colours::NickColours;
+use crate::commands::{Pauses, PendingReplies};
+use crate::discord_irc::{set_irc_status, Handler};
+use crate::emoji::EmojiOptions;
+use crate::flood::{Flood, FloodConfig};
+use crate::health::Health;
+use crate::ignores::{IgnoreConfig, Ignores};
+use crate::irc_discord::{irc_loop, IrcQuery, Lost, Stalled, DRAIN_TIMEOUT};
+use crate::latency::Latency;
+use crate::links::Links;
+use crate::locale::Locale;
+use crate::logs::ChatLog;
+use crate::message_cache::MessageCache;
+use crate::networks::Senders;
+use crate::pages::PagedResponses;
+use crate::patterns::{ChannelPatterns, ChannelTarget};
+use crate::permissions::PermissionMatrix;
+use crate::private::{DmSessions, QueryThreads};
+use crate::puppets::{PuppetConfig, PuppetContext, Puppets};
+use crate::rehost::{Rehost, RehostConfig};
+use crate::shortener::{Shortener, ShortenerConfig};
+use crate::spoilers::Spoilers;
+use crate::store::MessageStore;
+use crate::translate::{LanguagePair, Translator, TranslatorConfig};
+use crate::uploads::ImageUploader;
+use crate::urls::UrlCleaner;
 
-use fancy_regex::{Captures, Replacer};
-use serde::Deserialize;
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use fancy_regex::{Captures, Regex, Replacer};
+use lazy_static::lazy_static;
+use schemars::JsonSchema;
+use serde::{de, Deserialize, Deserializer};
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 struct DircordConfig {
+    name: Option<String>,
     token: String,
     nickname: Option<String>,
     server: String,
     port: Option<u16>,
+    /// Sent as PASS, bouncers like ZNC take `user:pass` here
+    server_password: Option<String>,
+    /// Defaults to the nickname
+    sasl_username: Option<String>,
+    sasl_password: Option<String>,
+    /// Identifies with NickServ after connecting, when SASL isn't set up or didn't work
+    nickserv_password: Option<String>,
     mode: Option<String>,
     tls: Option<bool>,
     raw_prefix: Option<String>,
-    channels: HashMap<String, u64>,
-    webhooks: Option<HashMap<String, String>>,
+    channels: HashMap<String, ChannelTarget>,
+    webhooks: Option<HashMap<String, WebhookUrls>>,
+    /// More IRC networks bridged through the same Discord bot
+    networks: Option<Vec<NetworkConfig>>,
     ref_content_limit: Option<u16>,
     cache_ttl: Option<u64>,
+    message_cache_size: Option<usize>,
+    /// SQLite database that keeps the message cache across restarts
+    db_path: Option<String>,
+    db_keep_days: Option<u64>,
+    channel_options: Option<HashMap<String, ChannelOptions>>,
+    emoji_shortcodes: Option<bool>,
+    emoticons: Option<HashMap<String, String>>,
+    attachment_dimensions: Option<bool>,
+    attachment_style: Option<AttachmentStyle>,
+    attachment_limit: Option<usize>,
+    strip_tracking_params: Option<bool>,
+    tracking_params: Option<Vec<String>>,
+    /// In MB, for `upload_images`
+    upload_limit: Option<u64>,
+    shortener: Option<ShortenerConfig>,
+    rehost: Option<RehostConfig>,
+    /// Gives Discord users their own IRC connections
+    puppets: Option<PuppetConfig>,
+    /// How fast the bridge itself sends to IRC
+    flood: Option<FloodConfig>,
+    translator: Option<TranslatorConfig>,
+    moderation: Option<AnnounceConfig>,
+    admin_channel: Option<u64>,
+    query_threads: Option<Vec<String>>,
+    connection_notices: Option<bool>,
+    mirror_moderation: Option<bool>,
+    startup_attempts: Option<u32>,
+    startup_backoff: Option<u64>,
+    ping_timeout: Option<u64>,
+    gateway_timeout: Option<u64>,
+    /// Address for the `/healthz` endpoint, like `127.0.0.1:8080`
+    health_listen: Option<String>,
+    systemd_watchdog: Option<bool>,
+    event_backlog: Option<usize>,
+    presences: Option<bool>,
+    audit_log: Option<String>,
+    /// In megabytes
+    audit_log_max_size: Option<u64>,
+    audit_log_keep: Option<usize>,
+    chat_logs: Option<String>,
+    chat_logs_keep_days: Option<u64>,
+    encoding: Option<String>,
+    outgoing_encoding: Option<String>,
+    links: Option<HashMap<String, u64>>,
+    /// Who and what isn't relayed
+    ignore: Option<IgnoreConfig>,
+    /// Who gets private messages that aren't for anyone in particular
+    pm_inbox: Option<u64>,
+    scheduled_events: Option<AnnounceConfig>,
+    stages: Option<AnnounceConfig>,
+    irc_admins: Option<Vec<String>>,
+    /// Discord user IDs that can use `!dircord`
+    discord_admins: Option<Vec<u64>>,
+    permissions: Option<PermissionMatrix>,
+    nick_rules: Option<Vec<NickRule>>,
+    avatars: Option<HashMap<String, String>>,
+    avatar_service: Option<String>,
+    /// For IRC users nobody has an avatar for
+    default_avatar: Option<String>,
+    nick_colours: Option<HashMap<String, u8>>,
+    nick_colours_file: Option<String>,
+    /// Colour Discord nicks like their top coloured role
+    role_colours: Option<bool>,
+    /// What the mIRC colours look like, for matching role colours to
+    irc_palette: Option<Vec<String>>,
+    /// Translations of the text the bridge writes itself
+    locale: Option<String>,
+}
+
+/// Another IRC network bridged through the same Discord bot, from `[[networks]]`. Everything not
+/// in here, like channel options, is shared with the network at the top level of the config.
+#[derive(Deserialize, JsonSchema)]
+struct NetworkConfig {
+    /// Used in messages about the connection, defaults to the server
+    name: Option<String>,
+    /// Defaults to the top level nickname
+    nickname: Option<String>,
+    server: String,
+    port: Option<u16>,
+    server_password: Option<String>,
+    sasl_username: Option<String>,
+    sasl_password: Option<String>,
+    nickserv_password: Option<String>,
+    mode: Option<String>,
+    tls: Option<bool>,
+    channels: HashMap<String, ChannelTarget>,
+    webhooks: Option<HashMap<String, WebhookUrls>>,
+}
+
+/// Everything a config file can hold, for `dircord schema`. `main` reads the file in steps
+/// instead of through this.
+#[derive(JsonSchema)]
+#[serde(untagged)]
+#[allow(dead_code)]
+enum ConfigFile {
+    Bridges {
+        include: Option<Vec<String>>,
+        sentry_dsn: Option<String>,
+        bridges: Vec<DircordConfig>,
+    },
+    Single {
+        include: Option<Vec<String>>,
+        sentry_dsn: Option<String>,
+        #[serde(flatten)]
+        bridge: DircordConfig,
+    },
+}
+
+/// Rewrites IRC nicks before they're shown on Discord.
+#[derive(Deserialize, JsonSchema, Clone)]
+struct NickRule {
+    #[serde(deserialize_with = "deserialize_required_regex")]
+    #[schemars(with = "String")]
+    pattern: Regex,
+    replace: String,
+}
+
+/// Applies every matching rule in order.
+fn rewrite_nick<'a>(rules: &[NickRule], nick: &'a str) -> Cow<'a, str> {
+    rules.iter().fold(Cow::Borrowed(nick), |nick, rule| {
+        let replaced = match rule.pattern.replace(&nick, rule.replace.as_str()) {
+            Cow::Borrowed(_) => None,
+            Cow::Owned(replaced) => Some(replaced),
+        };
+        replaced.map_or(nick, Cow::Owned)
+    })
+}
+
+/// IRC nicks the rules changed, by network and what they're shown as on Discord, lowercased. So
+/// someone on Discord addressing the name they see can still highlight the nick on IRC.
+#[derive(Default)]
+struct RewrittenNicks(HashMap<(usize, String), String>);
+
+impl RewrittenNicks {
+    fn insert(&mut self, network: usize, shown: &str, nick: &str) {
+        let key = (network, shown.to_lowercase());
+        if self.0.get(&key).map_or(true, |n| n != nick) {
+            self.0.insert(key, nick.to_owned());
+        }
+    }
+
+    /// For a nick that's gone.
+    fn forget(&mut self, network: usize, nick: &str) {
+        self.0.retain(|(n, _), v| *n != network || v != nick);
+    }
+
+    /// For a new connection, nobody from the last one is known to be there.
+    fn forget_network(&mut self, network: usize) {
+        self.0.retain(|(n, _), _| *n != network);
+    }
+
+    fn nick(&self, network: usize, shown: &str) -> Option<&str> {
+        self.0
+            .get(&(network, shown.to_lowercase()))
+            .map(String::as_str)
+    }
+}
+
+/// One webhook for a channel, or a pool of them to spread speakers over.
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged)]
+enum WebhookUrls {
+    One(WebhookSpec),
+    Pool(Vec<WebhookSpec>),
+}
+
+/// A webhook URL as copied from Discord, or its ID and token on their own.
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged)]
+enum WebhookSpec {
+    Url(String),
+    Parts { id: u64, token: String },
+}
+
+#[derive(Deserialize, JsonSchema, Clone)]
+struct AnnounceConfig {
+    channel: Option<String>,
+}
+
+#[derive(Deserialize, JsonSchema, Clone, Default)]
+struct ChannelOptions {
+    topic_sync: Option<TopicSync>,
+    user_count: Option<bool>,
+    timestamps: Option<bool>,
+    relay_as: Option<SendAs>,
+    events_as: Option<SendAs>,
+    short_ids: Option<bool>,
+    direction: Option<Direction>,
+    #[serde(default, deserialize_with = "deserialize_regex")]
+    #[schemars(with = "Option<String>")]
+    include: Option<Regex>,
+    #[serde(default, deserialize_with = "deserialize_regex")]
+    #[schemars(with = "Option<String>")]
+    exclude: Option<Regex>,
+    quiet_hours: Option<QuietHours>,
+    embeds: Option<bool>,
+    smart_join_part: Option<u64>,
+    join_part_digest: Option<u64>,
+    paste_lines: Option<usize>,
+    /// How many webhooks to spread IRC speakers over, created as needed
+    webhook_pool: Option<usize>,
+    plain: Option<bool>,
+    /// Show IRC colours with an ```ansi code block, for messages that have any
+    ansi_colours: Option<bool>,
+    /// Needs a [translator] to do anything
+    translate: Option<LanguagePair>,
+    /// Only Discord members with this role get relayed to IRC
+    required_role: Option<u64>,
+    default_avatar: Option<String>,
+    edits: Option<bool>,
+    /// Upload direct image links from IRC so Discord shows them, webhooks only
+    upload_images: Option<bool>,
+    /// Relay messages in public threads under the channel, tagged with the thread's name
+    threads: Option<bool>,
+    /// Where thread messages go instead of the channel itself, joined at startup
+    thread_channel: Option<String>,
+    /// Post people joining and leaving the Discord server
+    relay_joins: Option<bool>,
+}
+
+impl ChannelOptions {
+    /// Whether messages from Discord should be relayed right now.
+    fn relays_to_irc(&self) -> bool {
+        let quiet = self.quiet_hours.as_ref().and_then(QuietHours::suspended);
+        self.direction.unwrap_or_default().to_irc() && !quiet.map_or(false, Direction::to_irc)
+    }
+
+    /// Whether messages from IRC should be relayed right now.
+    fn relays_to_discord(&self) -> bool {
+        let quiet = self.quiet_hours.as_ref().and_then(QuietHours::suspended);
+        self.direction.unwrap_or_default().to_discord()
+            && !quiet.map_or(false, Direction::to_discord)
+    }
+
+    /// Whether messages in threads under the channel go to IRC.
+    fn relays_threads(&self) -> bool {
+        self.threads.unwrap_or(self.thread_channel.is_some())
+    }
+
+    /// Whether a Discord member with these roles may be relayed to IRC.
+    fn relays_member(&self, roles: &[RoleId]) -> bool {
+        self.required_role
+            .map_or(true, |role| roles.iter().any(|r| r.0.get() == role))
+    }
+
+    /// Whether a message passes the channel's include and exclude filters.
+    fn allows(&self, content: &str) -> bool {
+        let matches =
+            |re: &Option<Regex>| re.as_ref().map(|r| r.is_match(content).unwrap_or(false));
+
+        matches(&self.include).unwrap_or(true) && !matches(&self.exclude).unwrap_or(false)
+    }
+}
+
+/// A daily time range during which relaying is suspended. `from` can be after `to` for ranges
+/// that go past midnight.
+#[derive(Deserialize, JsonSchema, Clone)]
+struct QuietHours {
+    #[serde(deserialize_with = "deserialize_time")]
+    #[schemars(with = "String")]
+    from: NaiveTime,
+    #[serde(deserialize_with = "deserialize_time")]
+    #[schemars(with = "String")]
+    to: NaiveTime,
+    #[schemars(with = "Option<String>")]
+    timezone: Option<Tz>,
+    direction: Option<Direction>,
+}
+
+impl QuietHours {
+    /// The direction that's suspended, if it's quiet right now.
+    fn suspended(&self) -> Option<Direction> {
+        self.suspended_at(Utc::now())
+    }
+
+    fn suspended_at(&self, now: DateTime<Utc>) -> Option<Direction> {
+        let now = now.with_timezone(&self.timezone.unwrap_or(Tz::UTC)).time();
+
+        let quiet = if self.from <= self.to {
+            self.from <= now && now < self.to
+        } else {
+            self.from <= now || now < self.to
+        };
+
+        quiet.then(|| self.direction.unwrap_or_default())
+    }
+}
+
+fn deserialize_time<'de, D: Deserializer<'de>>(deserializer: D) -> Result<NaiveTime, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    NaiveTime::parse_from_str(&s, "%H:%M").map_err(de::Error::custom)
+}
+
+fn deserialize_required_regex<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Regex, D::Error> {
+    Regex::new(&String::deserialize(deserializer)?).map_err(de::Error::custom)
+}
+
+fn deserialize_regex<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Regex>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|s| Regex::new(&s).map_err(de::Error::custom))
+        .transpose()
+}
+
+/// Which way messages are relayed in a channel.
+#[derive(Deserialize, JsonSchema, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum Direction {
+    #[default]
+    Both,
+    IrcToDiscord,
+    DiscordToIrc,
+}
+
+impl Direction {
+    fn to_discord(self) -> bool {
+        self != Self::DiscordToIrc
+    }
+
+    fn to_irc(self) -> bool {
+        self != Self::IrcToDiscord
+    }
+}
+
+/// How a message's attachments go out to IRC.
+#[derive(Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum AttachmentStyle {
+    /// A line each
+    Separate,
+    /// As few lines as they fit on
+    Inline,
+}
+
+#[derive(Clone, Copy)]
+struct AttachmentPolicy {
+    style: AttachmentStyle,
+    /// How many get listed before the rest are only counted, `None` lists them all
+    limit: Option<usize>,
+}
+
+/// Which command the bridge speaks with on IRC.
+#[derive(Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum SendAs {
+    Privmsg,
+    Notice,
+}
+
+impl SendAs {
+    fn send(self, sender: &Sender, target: &str, text: impl Display) -> irc::error::Result<()> {
+        match self {
+            Self::Privmsg => sender.send_privmsg(target, text),
+            Self::Notice => sender.send_notice(target, text),
+        }
+    }
+
+    /// Like `send`, tagged as a reply to the IRC message with this msgid if there is one.
+    fn send_reply(
+        self,
+        sender: &Sender,
+        target: &str,
+        text: impl Display,
+        msgid: Option<String>,
+    ) -> irc::error::Result<()> {
+        let Some(msgid) = msgid else {
+            return self.send(sender, target, text);
+        };
+
+        let command = match self {
+            Self::Privmsg => Command::PRIVMSG(target.to_owned(), text.to_string()),
+            Self::Notice => Command::NOTICE(target.to_owned(), text.to_string()),
+        };
+        sender.send(Message {
+            tags: Some(vec![Tag(String::from("+draft/reply"), Some(msgid))]),
+            prefix: None,
+            command,
+        })
+    }
+}
+
+#[derive(Deserialize, JsonSchema, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum TopicSync {
+    Off,
+    #[default]
+    IrcToDiscord,
+    DiscordToIrc,
+    Both,
+}
+
+impl TopicSync {
+    fn to_discord(self) -> bool {
+        matches!(self, Self::IrcToDiscord | Self::Both)
+    }
+
+    fn to_irc(self) -> bool {
+        matches!(self, Self::DiscordToIrc | Self::Both)
+    }
+}
+
+/// State of the IRC connection, shown in the bot's presence.
+#[derive(Clone)]
+enum IrcStatus {
+    Connected { server: String, channels: usize },
+    Disconnected,
+}
+
+/// The last topic synced for an IRC channel.
+#[derive(Clone, Default)]
+struct TopicInfo {
+    /// The topic as it appears on Discord
+    topic: String,
+    /// The topic as it appears on IRC, which may carry an attribution suffix
+    irc_topic: String,
+    /// Who set it and where, if we know
+    set_by: Option<String>,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Formats a unix timestamp as `HH:MM`, in UTC.
+fn short_time(unix: i64) -> String {
+    Utc.timestamp_opt(unix, 0)
+        .single()
+        .map_or_else(String::new, |t| t.format("%H:%M").to_string())
+}
+
+/// Formats a unix timestamp with the date, for things further away than today.
+fn full_time(unix: i64) -> String {
+    Utc.timestamp_opt(unix, 0)
+        .single()
+        .map_or_else(String::new, |t| t.format("%Y-%m-%d %H:%M UTC").to_string())
 }
 
 macro_rules! type_map_key {
@@ -57,12 +598,64 @@ type_map_key!(
     HttpKey => Arc<Http>,
     ChannelIdKey => ChannelId,
     UserIdKey => UserId,
-    SenderKey => Sender,
+    SendersKey => Senders,
     MembersKey => Arc<Mutex<Vec<Member>>>,
     StringKey => String,
     OptionStringKey => Option<String>,
     ChannelMappingKey => HashMap<String, u64>,
+    ChannelPatternsKey => Arc<ChannelPatterns>,
     RefContentLimitKey => Option<u16>,
+    MessageCacheKey => Arc<Mutex<MessageCache>>,
+    ChannelOptionsKey => Arc<HashMap<String, ChannelOptions>>,
+    TopicKey => Arc<Mutex<HashMap<String, TopicInfo>>>,
+    ShardMessengerKey => ShardMessenger,
+    ShardManagerKey => Arc<ShardManager>,
+    IrcStatusKey => IrcStatus,
+    PuppetsKey => Option<Arc<Puppets>>,
+    HealthKey => Arc<Health>,
+    EmojiOptionsKey => Arc<EmojiOptions>,
+    SpoilersKey => Arc<Spoilers>,
+    AttachmentDimensionsKey => bool,
+    AttachmentPolicyKey => AttachmentPolicy,
+    UrlCleanerKey => Arc<UrlCleaner>,
+    ShortenerKey => Option<Arc<Shortener>>,
+    RehostKey => Option<Arc<Rehost>>,
+    ImageUploaderKey => Arc<ImageUploader>,
+    TranslatorKey => Option<Arc<Translator>>,
+    ModerationKey => Option<AnnounceConfig>,
+    ScheduledEventsKey => Option<AnnounceConfig>,
+    StagesKey => Option<AnnounceConfig>,
+    AdminChannelKey => Option<ChannelId>,
+    AuditLogKey => Option<Arc<AuditLog>>,
+    ChatLogKey => Option<Arc<ChatLog>>,
+    TranscoderKey => Transcoder,
+    LinksKey => Arc<std::sync::Mutex<Links>>,
+    DmSessionsKey => Arc<Mutex<DmSessions>>,
+    PmInboxKey => Option<UserId>,
+    QueryThreadsKey => Option<Arc<std::sync::Mutex<QueryThreads>>>,
+    /// By network, each connection has its own labels
+    PendingRepliesKey => Vec<Arc<Mutex<PendingReplies>>>,
+    // std mutex so the relay checks don't have to await, it's never held for long
+    PausesKey => Arc<std::sync::Mutex<Pauses>>,
+    IgnoresKey => Arc<std::sync::Mutex<Ignores>>,
+    FloodKey => Arc<Flood>,
+    // one per network
+    LatencyKey => Vec<Arc<std::sync::Mutex<Latency>>>,
+    PagedKey => Arc<std::sync::Mutex<PagedResponses>>,
+    PingTimeoutKey => Duration,
+    EventBacklogKey => usize,
+    PermissionsKey => Arc<PermissionMatrix>,
+    NickRulesKey => Arc<Vec<NickRule>>,
+    RewrittenNicksKey => Arc<std::sync::Mutex<RewrittenNicks>>,
+    AvatarsKey => Arc<HashMap<String, String>>,
+    DefaultAvatarKey => Option<Arc<str>>,
+    NickColoursKey => Arc<NickColours>,
+    LocaleKey => Arc<Locale>,
+    // by network, set by the IRC loops on every connection
+    IrcQueryKey => HashMap<usize, UnboundedSender<IrcQuery>>,
+    WebhooksKey => Arc<HashMap<String, Vec<Arc<Webhook>>>>,
+    MirrorModerationKey => bool,
+    ConfigSourceKey => Arc<ConfigSource>,
 );
 
 #[cfg(unix)]
@@ -84,89 +677,1157 @@ async fn terminate_signal() {
     let _ = ctrlc.recv().await;
 }
 
+/// Prints the same thing as `/debug caches` to the log whenever the process gets SIGUSR2.
+#[cfg(unix)]
+async fn dump_caches_on_signal(data: Arc<RwLock<TypeMap>>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut sigusr2 = signal(SignalKind::user_defined2()).unwrap();
+
+    while sigusr2.recv().await.is_some() {
+        match commands::cache_report(&data).await {
+            Ok(report) => eprintln!("Caches:\n{report}"),
+            Err(e) => eprintln!("Couldn't dump caches: {e}"),
+        }
+    }
+}
+
+#[cfg(windows)]
+async fn dump_caches_on_signal(_data: Arc<RwLock<TypeMap>>) {}
+
+/// Reopens the audit log and chat logs on SIGUSR1, for logrotate's `postrotate`.
+#[cfg(unix)]
+async fn reopen_logs_on_signal(audit_log: Option<Arc<AuditLog>>, chat_log: Option<Arc<ChatLog>>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    if audit_log.is_none() && chat_log.is_none() {
+        return;
+    }
+    let mut sigusr1 = signal(SignalKind::user_defined1()).unwrap();
+
+    while sigusr1.recv().await.is_some() {
+        if let Some(audit_log) = &audit_log {
+            audit_log.reopen();
+        }
+        if let Some(chat_log) = &chat_log {
+            chat_log.reopen();
+        }
+    }
+}
+
+#[cfg(windows)]
+async fn reopen_logs_on_signal(_audit_log: Option<Arc<AuditLog>>, _chat_log: Option<Arc<ChatLog>>) {
+}
+
+/// Re-reads the config on SIGHUP, see [`reload_config`].
+#[cfg(unix)]
+async fn reload_on_signal(http: Arc<Http>, data: Arc<RwLock<TypeMap>>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut sighup = signal(SignalKind::hangup()).unwrap();
+
+    while sighup.recv().await.is_some() {
+        let admin_channel = data.read().await.get::<AdminChannelKey>().copied().unwrap();
+        let text = match reload_config(&http, &data).await {
+            Ok(reloaded) => reloaded.to_string(),
+            Err(e) => format!("**Couldn't reload the config**: {e:#}"),
+        };
+        eprintln!("{text}");
+        if let Some(admin_channel) = admin_channel {
+            let _ = admin_channel.say(&http, &text).await;
+        }
+    }
+}
+
+#[cfg(windows)]
+async fn reload_on_signal(_http: Arc<Http>, _data: Arc<RwLock<TypeMap>>) {}
+
+/// Where a bridge's config came from, so it can be read again.
+struct ConfigSource {
+    path: PathBuf,
+    /// Which of the `[[bridges]]` it is, by position
+    bridge: usize,
+}
+
+impl ConfigSource {
+    fn load(&self) -> anyhow::Result<DircordConfig> {
+        load_bridges(load_config(&self.path, 0)?)?
+            .into_iter()
+            .nth(self.bridge)
+            .context("This bridge isn't in the config anymore")
+    }
+}
+
+/// What reloading the config changed.
+struct Reloaded {
+    added: Vec<String>,
+    removed: Vec<String>,
+}
+
+impl Display for Reloaded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Reloaded the config")?;
+        if !self.added.is_empty() {
+            write!(f, ", now bridging {}", self.added.join(", "))?;
+        }
+        if !self.removed.is_empty() {
+            write!(f, ", no longer bridging {}", self.removed.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the config file again and applies what can change without reconnecting: which
+/// channels are bridged where and their webhooks. Anything else, like new `[[networks]]` or
+/// patterns, still needs a restart.
+async fn reload_config(http: &Arc<Http>, data: &RwLock<TypeMap>) -> anyhow::Result<Reloaded> {
+    let source = data.read().await.get::<ConfigSourceKey>().unwrap().clone();
+    let conf = source.load()?;
+
+    let mut targets = vec![conf.channels];
+    let mut webhook_urls = conf.webhooks.unwrap_or_default();
+    for network_conf in conf.networks.unwrap_or_default() {
+        targets.push(network_conf.channels);
+        webhook_urls.extend(network_conf.webhooks.unwrap_or_default());
+    }
+
+    let (old, mut senders, patterns, channel_options) = {
+        let data = data.read().await;
+        (
+            data.get::<ChannelMappingKey>().unwrap().clone(),
+            data.get::<SendersKey>().unwrap().clone(),
+            data.get::<ChannelPatternsKey>().unwrap().clone(),
+            data.get::<ChannelOptionsKey>().unwrap().clone(),
+        )
+    };
+    if targets.len() != senders.count() {
+        anyhow::bail!("[[networks]] changed, that needs a restart");
+    }
+
+    // invited channels aren't in the config, but their pattern still is
+    let mut mapping: HashMap<String, u64> = old
+        .iter()
+        .filter(|(channel, _)| {
+            patterns
+                .category_for(senders.network(channel), channel)
+                .is_some()
+        })
+        .map(|(channel, &id)| (channel.clone(), id))
+        .collect();
+    let mut removed: Vec<String> = old
+        .keys()
+        .filter(|channel| {
+            !mapping.contains_key(*channel)
+                && !targets
+                    .iter()
+                    .any(|t| matches!(t.get(*channel), Some(ChannelTarget::Id(_))))
+        })
+        .cloned()
+        .collect();
+    removed.sort_unstable();
+    for channel in &removed {
+        senders.remove_channel(channel);
+    }
+
+    for (network, targets) in targets.into_iter().enumerate() {
+        for (channel, target) in targets {
+            if let ChannelTarget::Id(id) = target {
+                senders.add_channel(channel.clone(), network)?;
+                mapping.insert(channel, id);
+            }
+        }
+    }
+    let mut added: Vec<String> = mapping
+        .keys()
+        .filter(|channel| !old.contains_key(*channel))
+        .cloned()
+        .collect();
+    added.sort_unstable();
+
+    // done before anything changes, so a bad webhook or pattern leaves everything like it was
+    let webhooks = load_webhooks(http, webhook_urls, &channel_options, &mapping).await?;
+    let ignores = data.read().await.get::<IgnoresKey>().unwrap().clone();
+    let reloaded_ignores = ignores.lock().unwrap().reloaded(conf.ignore)?;
+
+    *ignores.lock().unwrap() = reloaded_ignores;
+    let mut data = data.write().await;
+    data.insert::<ChannelMappingKey>(mapping);
+    data.insert::<SendersKey>(senders);
+    data.insert::<WebhooksKey>(Arc::new(webhooks));
+    // the IRC loops have their own copies, and join and part as needed
+    for queries in data.get::<IrcQueryKey>().unwrap().values() {
+        let _ = queries.send(IrcQuery::Reload);
+    }
+
+    Ok(Reloaded { added, removed })
+}
+
+/// Either a single bridge at the top level of the config, or several in `[[bridges]]`.
+fn load_bridges(value: toml::Value) -> anyhow::Result<Vec<DircordConfig>> {
+    Ok(match value.get("bridges") {
+        Some(bridges) => bridges.clone().try_into()?,
+        None => vec![value.try_into()?],
+    })
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let filename = env::args()
-        .nth(1)
-        .map_or(Cow::Borrowed("config.toml"), Cow::Owned);
-    let mut data = String::new();
-    File::open(&*filename)?.read_to_string(&mut data)?;
+    install_panic_hook();
+
+    let mut args = env::args().skip(1);
+    let filename = match args.next() {
+        Some(command) if command == "logs" => {
+            return match (args.next().as_deref(), args.next(), args.next()) {
+                (Some("render"), Some(dir), Some(out)) => {
+                    logs::render(Path::new(&dir), Path::new(&out))
+                }
+                _ => Err(anyhow!("Usage: dircord logs render <log dir> <output dir>")),
+            };
+        }
+        Some(command) if command == "schema" => {
+            let schema = schemars::schema_for!(ConfigFile);
+            println!("{}", serde_json::to_string_pretty(&schema)?);
+            return Ok(());
+        }
+        Some(filename) => Cow::Owned(filename),
+        None => Cow::Borrowed("config.toml"),
+    };
+
+    let value = load_config(Path::new(&*filename), 0)?;
+
+    let _sentry = report::init(value.get("sentry_dsn").and_then(toml::Value::as_str));
+
+    let bridges = load_bridges(value)?;
+
+    let handles = bridges
+        .into_iter()
+        .enumerate()
+        .map(|(i, conf)| {
+            let name = conf.name.clone().unwrap_or_else(|| format!("bridge {i}"));
+            let source = ConfigSource {
+                path: PathBuf::from(&*filename),
+                bridge: i,
+            };
+            (name, tokio::spawn(run_bridge(conf, source)))
+        })
+        .collect::<Vec<_>>();
+
+    // bridges are independent, so one failing shouldn't take the others down with it
+    for (name, handle) in handles {
+        match handle.await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                eprintln!("{name} exited with an error: {e:?}");
+                report::bridge_error(&e, &name);
+            }
+            Err(e) => eprintln!("{name} panicked: {e}"),
+        }
+    }
+
+    Ok(())
+}
 
-    let conf: DircordConfig = toml::from_str(&data)?;
+/// Reads a config file along with everything it `include`s. Included files are relative to the
+/// one including them, and anything set in the including file wins.
+fn load_config(path: &Path, depth: usize) -> anyhow::Result<toml::Value> {
+    if depth > 8 {
+        anyhow::bail!(
+            "Config includes nest too deep at {}, is something including itself?",
+            path.display()
+        );
+    }
+
+    let data =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut value: toml::Value =
+        toml::from_str(&data).with_context(|| format!("Failed to parse {}", path.display()))?;
+
+    let includes = match value.as_table_mut().and_then(|t| t.remove("include")) {
+        Some(includes) => includes
+            .try_into::<Vec<String>>()
+            .with_context(|| format!("include in {} should be a list of paths", path.display()))?,
+        None => return Ok(value),
+    };
+
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut merged = toml::Value::Table(toml::value::Table::new());
+    for include in includes {
+        merge_config(&mut merged, load_config(&dir.join(include), depth + 1)?);
+    }
+    merge_config(&mut merged, value);
+
+    Ok(merged)
+}
+
+/// Tables are merged key by key, anything else in `overlay` replaces what's in `base`.
+fn merge_config(base: &mut toml::Value, overlay: toml::Value) {
+    match (base, overlay) {
+        (toml::Value::Table(base), toml::Value::Table(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_config(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
 
-    let intents = GatewayIntents::non_privileged()
+async fn run_bridge(conf: DircordConfig, source: ConfigSource) -> anyhow::Result<()> {
+    let mut intents = GatewayIntents::non_privileged()
         | GatewayIntents::GUILD_MEMBERS
         | GatewayIntents::MESSAGE_CONTENT;
 
+    // privileged, so it has to be turned on in the developer portal first
+    if conf.presences.unwrap_or(false) {
+        intents |= GatewayIntents::GUILD_PRESENCES;
+    }
+
     let mut discord_client = DiscordClient::builder(&conf.token, intents)
         .event_handler(Handler)
         .await?;
 
-    let config = Config {
-        nickname: conf.nickname,
+    let transcoder = Transcoder::new(conf.encoding.as_deref(), conf.outgoing_encoding.as_deref())?;
+    let retry = Retry::new(&conf);
+
+    let mut channels = HashMap::new();
+    let mut patterns = ChannelPatterns::default();
+    let mut senders = Senders::default();
+
+    // the top level is the first network, [[networks]] come after it
+    let primary = senders.add_network(conf.server.clone());
+    let mut join = add_network_channels(
+        primary,
+        conf.channels,
+        &mut channels,
+        &mut patterns,
+        &mut senders,
+    )?;
+    // announcement channels don't have to be bridged, but we still need to be in them
+    let announce = [&conf.moderation, &conf.scheduled_events, &conf.stages]
+        .into_iter()
+        .flatten()
+        .filter_map(|a| a.channel.clone());
+    join.extend(announce);
+    join.sort_unstable();
+    join.dedup();
+
+    let encoding = transcoder.enabled().then(|| WIRE_ENCODING.to_string());
+    let mut logins = vec![Login::new(
+        conf.sasl_username.or_else(|| conf.nickname.clone()),
+        conf.sasl_password,
+        conf.nickserv_password,
+    )?];
+    let mut configs = vec![Config {
+        nickname: conf.nickname.clone(),
         server: Some(conf.server),
         port: conf.port,
-        channels: conf.channels.keys().map(Clone::clone).collect(),
+        password: conf.server_password,
+        channels: join,
         use_tls: conf.tls,
         umodes: conf.mode,
+        encoding: encoding.clone(),
         ..Config::default()
-    };
+    }];
+    let mut webhook_urls = conf.webhooks.unwrap_or_default();
+
+    for network_conf in conf.networks.unwrap_or_default() {
+        let name = network_conf
+            .name
+            .unwrap_or_else(|| network_conf.server.clone());
+        let network = senders.add_network(name);
+        let join = add_network_channels(
+            network,
+            network_conf.channels,
+            &mut channels,
+            &mut patterns,
+            &mut senders,
+        )?;
+        let nickname = network_conf.nickname.or_else(|| conf.nickname.clone());
+
+        logins.push(
+            Login::new(
+                network_conf.sasl_username.or_else(|| nickname.clone()),
+                network_conf.sasl_password,
+                network_conf.nickserv_password,
+            )
+            .with_context(|| format!("In [[networks]] for {}", senders.name(network)))?,
+        );
+        configs.push(Config {
+            nickname,
+            server: Some(network_conf.server),
+            port: network_conf.port,
+            password: network_conf.server_password,
+            channels: join,
+            use_tls: network_conf.tls,
+            umodes: network_conf.mode,
+            encoding: encoding.clone(),
+            ..Config::default()
+        });
+        webhook_urls.extend(network_conf.webhooks.unwrap_or_default());
+    }
+
+    // like announcement channels, thread channels only need joining
+    for (parent, options) in conf.channel_options.iter().flatten() {
+        let Some(ref thread_channel) = options.thread_channel else {
+            continue;
+        };
+        let network = senders.network(parent);
+        senders.add_channel(thread_channel.clone(), network)?;
+        let join = &mut configs[network].channels;
+        if !join.contains(thread_channel) {
+            join.push(thread_channel.clone());
+        }
+    }
 
-    let irc_client = IrcClient::from_config(config).await?;
+    let flood = Arc::new(Flood::new(conf.flood.unwrap_or_default(), senders.count())?);
+    for config in &mut configs {
+        flood.limit(config);
+    }
+
+    let mut irc_clients = Vec::new();
+    for (network, config) in configs.iter().enumerate() {
+        let what = format!("Connecting to {}", senders.name(network));
+        let irc_client = with_retry(&what, retry, || async {
+            Ok(IrcClient::from_config(config.clone()).await?)
+        })
+        .await?;
+        senders.connected(network, irc_client.sender());
+        irc_clients.push(irc_client);
+    }
 
     let http = discord_client.http.clone();
     let cache = discord_client.cache.clone();
 
-    let members = Arc::new(Mutex::new({
-        let channel_id = ChannelId::from(*conf.channels.iter().next().unwrap().1);
-
-        channel_id
-            .to_channel(discord_client.http.clone())
-            .await?
-            .guild()
-            .unwrap() // we can panic here because if it's not a guild channel then the bot shouldn't even work
-            .guild_id
-            .members(&http, None, None)
-            .await?
-    }));
+    // channels from patterns are joined once IRC welcomes us, like ones that get invited to later
+    match patterns.existing(&http).await {
+        Ok(existing) => {
+            for (channel, id, network) in existing {
+                if let Err(e) = senders.add_channel(channel.clone(), network) {
+                    eprintln!("Not bridging {channel} through its pattern: {e}");
+                    continue;
+                }
+                channels.entry(channel).or_insert(id);
+            }
+        }
+        Err(e) => eprintln!("Couldn't look for channels bridged through patterns: {e}"),
+    }
+
+    let guild_id = {
+        let channel_id = channels
+            .values()
+            .next()
+            .map(|&id| ChannelId::from(id))
+            .or_else(|| patterns.categories().next())
+            .context("No channels to bridge")?;
+
+        with_retry("Finding the Discord server", retry, || async {
+            Ok(channel_id
+                .to_channel(&http)
+                .await?
+                .guild()
+                .unwrap() // we can panic here because if it's not a guild channel then the bot shouldn't even work
+                .guild_id)
+        })
+        .await?
+    };
+
+    // members are looked up as they're mentioned until the full list is in
+    let members = Arc::new(Mutex::new(Vec::new()));
+    let member_warmer = tokio::spawn({
+        let (http, members) = (http.clone(), members.clone());
+        async move { members::warm(&http, guild_id, &members).await }
+    });
+
+    let audit_log = match conf.audit_log {
+        Some(ref path) => {
+            let rotation = Rotation {
+                max_size: conf.audit_log_max_size.map(|mb| mb * 1024 * 1024),
+                keep: conf.audit_log_keep.unwrap_or(5),
+            };
+            Some(Arc::new(AuditLog::open(path, rotation).with_context(
+                || format!("Failed to open audit log {path}"),
+            )?))
+        }
+        None => None,
+    };
+
+    let store = match conf.db_path {
+        Some(ref path) => Some(
+            MessageStore::open(path, conf.db_keep_days.unwrap_or(30))
+                .with_context(|| format!("Failed to open database {path}"))?,
+        ),
+        None => None,
+    };
 
-    let channels = Arc::new(conf.channels);
+    let query_threads = match (conf.admin_channel, conf.query_threads) {
+        (_, None) => None,
+        (Some(admin_channel), Some(nicks)) => {
+            let mut query_threads = QueryThreads::new(ChannelId::from(admin_channel), nicks);
+            if let Err(e) = query_threads.find_existing(&http).await {
+                eprintln!("Failed to find the query threads from before: {e:#}");
+            }
+            Some(Arc::new(std::sync::Mutex::new(query_threads)))
+        }
+        (None, Some(_)) => anyhow::bail!("query_threads needs an admin_channel to put them in"),
+    };
+
+    let chat_log = match conf.chat_logs {
+        Some(ref dir) => Some(Arc::new(
+            ChatLog::open(dir, conf.chat_logs_keep_days)
+                .with_context(|| format!("Failed to open chat logs in {dir}"))?,
+        )),
+        None => None,
+    };
+
+    let mut nick_colours = NickColours::load(
+        conf.nick_colours_file.clone(),
+        conf.nick_colours.unwrap_or_default(),
+    )
+    .context("Failed to load nick colours")?;
+    if conf.role_colours.unwrap_or(false) {
+        nick_colours = nick_colours.with_role_colours(conf.irc_palette.as_deref())?;
+    }
+
+    let locale = match conf.locale {
+        Some(ref path) => Locale::load(Path::new(path))?,
+        None => Locale::default(),
+    };
+    let locale = Arc::new(locale);
+
+    let dm_sessions = Arc::new(Mutex::new(DmSessions::default()));
+    let puppets = conf.puppets.map(|puppets| {
+        let context = PuppetContext {
+            http: discord_client.http.clone(),
+            dm_sessions: dm_sessions.clone(),
+            transcoder,
+            locale: locale.clone(),
+        };
+        let idle_reason = locale.text("puppet_idle", "Idle on Discord", &[]);
+        Arc::new(Puppets::new(puppets, &configs, context, idle_reason))
+    });
+
+    let channels = Arc::new(channels);
+    let latencies = (0..senders.count())
+        .map(|_| Arc::new(std::sync::Mutex::new(Latency::default())))
+        .collect::<Vec<_>>();
+    let health = Arc::new(Health::new(
+        (0..senders.count()).map(|network| senders.name(network).to_owned()),
+        latencies.clone(),
+    ));
+    let health_listener = match conf.health_listen {
+        Some(ref addr) => Some(
+            TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("Failed to listen for health checks on {addr}"))?,
+        ),
+        None => None,
+    };
+
+    let discord_data = discord_client.data.clone();
 
     {
         let mut data = discord_client.data.write().await;
-        data.insert::<SenderKey>(irc_client.sender());
-        data.insert::<MembersKey>(members.clone());
+        data.insert::<ShardManagerKey>(discord_client.shard_manager.clone());
+        data.insert::<SendersKey>(senders.clone());
+        data.insert::<ConfigSourceKey>(Arc::new(source));
+        data.insert::<MembersKey>(members);
         data.insert::<OptionStringKey>(conf.raw_prefix);
         data.insert::<ChannelMappingKey>((*channels).clone());
+        data.insert::<ChannelPatternsKey>(Arc::new(patterns));
         data.insert::<RefContentLimitKey>(conf.ref_content_limit);
+        data.insert::<ChannelOptionsKey>(Arc::new(conf.channel_options.unwrap_or_default()));
+        data.insert::<ModerationKey>(conf.moderation);
+        data.insert::<ScheduledEventsKey>(conf.scheduled_events);
+        data.insert::<StagesKey>(conf.stages);
+        data.insert::<AuditLogKey>(audit_log.clone());
+        data.insert::<ChatLogKey>(chat_log.clone());
+        data.insert::<TranscoderKey>(transcoder);
+        data.insert::<LinksKey>(Arc::new(std::sync::Mutex::new(
+            Links::open(
+                conf.links.unwrap_or_default(),
+                conf.db_path.as_deref().map(Path::new),
+            )
+            .context("Failed to load links from the database")?,
+        )));
+        data.insert::<DmSessionsKey>(dm_sessions.clone());
+        data.insert::<PmInboxKey>(conf.pm_inbox.map(UserId::from));
+        data.insert::<PuppetsKey>(puppets.clone());
+        data.insert::<QueryThreadsKey>(query_threads);
+        data.insert::<PendingRepliesKey>(
+            (0..senders.count())
+                .map(|_| Arc::new(Mutex::new(PendingReplies::default())))
+                .collect(),
+        );
+        data.insert::<PausesKey>(Arc::new(std::sync::Mutex::new(Pauses::default())));
+        data.insert::<FloodKey>(flood.clone());
+        data.insert::<IgnoresKey>(Arc::new(std::sync::Mutex::new(Ignores::new(conf.ignore)?)));
+        data.insert::<PagedKey>(Arc::new(std::sync::Mutex::new(PagedResponses::default())));
+        data.insert::<LatencyKey>(latencies);
+        data.insert::<IrcQueryKey>(HashMap::new());
+        data.insert::<HealthKey>(health.clone());
+        data.insert::<EventBacklogKey>(conf.event_backlog.unwrap_or(100).max(1));
+        data.insert::<PingTimeoutKey>(Duration::from_secs(conf.ping_timeout.unwrap_or(90)));
+        data.insert::<PermissionsKey>(Arc::new({
+            let mut permissions = conf.permissions.unwrap_or_default();
+            let irc_admins = conf.irc_admins.unwrap_or_default();
+            permissions.fallback("pause", &irc_admins, &[]);
+            permissions.fallback("resume", &irc_admins, &[]);
+            permissions.fallback(
+                "dircord",
+                &irc_admins,
+                &conf.discord_admins.unwrap_or_default(),
+            );
+            permissions
+        }));
+        data.insert::<NickRulesKey>(Arc::new(conf.nick_rules.unwrap_or_default()));
+        data.insert::<RewrittenNicksKey>(Arc::default());
+        data.insert::<AvatarsKey>(Arc::new(avatar_urls(
+            conf.avatars.unwrap_or_default(),
+            conf.avatar_service.as_deref(),
+        )));
+        data.insert::<DefaultAvatarKey>(conf.default_avatar.map(Arc::from));
+        data.insert::<NickColoursKey>(Arc::new(nick_colours));
+        data.insert::<LocaleKey>(locale.clone());
+        // {id} is left for the spoilers to fill in
+        data.insert::<SpoilersKey>(Arc::new(Spoilers::new(locale.text(
+            "spoiler_hidden",
+            "[spoiler, say \"!spoiler {id}\" to see it]",
+            &[],
+        ))));
+        data.insert::<AdminChannelKey>(conf.admin_channel.map(ChannelId::from));
+        data.insert::<EmojiOptionsKey>(Arc::new(EmojiOptions::new(
+            conf.emoji_shortcodes.unwrap_or(false),
+            conf.emoticons.unwrap_or_default(),
+        )));
+        data.insert::<AttachmentDimensionsKey>(conf.attachment_dimensions.unwrap_or(false));
+        data.insert::<MirrorModerationKey>(conf.mirror_moderation.unwrap_or(false));
+        data.insert::<AttachmentPolicyKey>(AttachmentPolicy {
+            style: conf.attachment_style.unwrap_or(AttachmentStyle::Separate),
+            limit: conf.attachment_limit,
+        });
+        data.insert::<UrlCleanerKey>(Arc::new(UrlCleaner::new(
+            conf.strip_tracking_params.unwrap_or(false),
+            conf.tracking_params,
+        )));
+        data.insert::<ImageUploaderKey>(Arc::new(ImageUploader::new(
+            conf.upload_limit.unwrap_or(8) * 1024 * 1024,
+        )));
+        data.insert::<ShortenerKey>(conf.shortener.map(|c| Arc::new(Shortener::new(c))));
+        data.insert::<RehostKey>(conf.rehost.map(|c| Arc::new(Rehost::new(c))));
+        data.insert::<TranslatorKey>(conf.translator.map(|c| Arc::new(Translator::new(c))));
+        data.insert::<TopicKey>(Arc::new(Mutex::new(HashMap::new())));
+        data.insert::<MessageCacheKey>(Arc::new(Mutex::new(MessageCache::new(
+            conf.message_cache_size.unwrap_or(512),
+            store,
+        ))));
     }
 
-    let mut webhooks_transformed: HashMap<String, Webhook> = HashMap::new();
+    let channel_options = discord_data
+        .read()
+        .await
+        .get::<ChannelOptionsKey>()
+        .unwrap()
+        .clone();
+    let webhooks = load_webhooks(&http, webhook_urls, &channel_options, &channels).await?;
+    discord_data
+        .write()
+        .await
+        .insert::<WebhooksKey>(Arc::new(webhooks));
 
-    if let Some(webhooks) = conf.webhooks {
-        for (channel, wh) in webhooks {
-            let parsed = parse_webhook_url(http.clone(), wh)
-                .await
-                .expect("Invalid webhook URL");
+    let admin_channel = conf.admin_channel.map(ChannelId::from);
+    let panic_notifier = tokio::spawn(notify_panics(http.clone(), admin_channel));
+    let connection_notices = conf.connection_notices.unwrap_or(true);
+    let watchdog = tokio::spawn(gateway_watchdog(
+        discord_client.shard_manager.clone(),
+        discord_data.clone(),
+        Duration::from_secs(conf.gateway_timeout.unwrap_or(120)),
+        connection_notices,
+    ));
+    let health_server = health_listener.map(|listener| {
+        tokio::spawn(health::serve(
+            listener,
+            health.clone(),
+            discord_client.shard_manager.clone(),
+        ))
+    });
+    #[cfg(unix)]
+    let systemd_notifier = conf.systemd_watchdog.unwrap_or(false).then(|| {
+        tokio::spawn(health::notify_systemd(
+            health.clone(),
+            discord_client.shard_manager.clone(),
+        ))
+    });
+    let puppet_expirer = puppets
+        .clone()
+        .map(|puppets| tokio::spawn(puppets.expire()));
+    let cache_dumper = tokio::spawn(dump_caches_on_signal(discord_data.clone()));
+    let reloader = tokio::spawn(reload_on_signal(http.clone(), discord_data.clone()));
+    let log_reopener = tokio::spawn(reopen_logs_on_signal(audit_log.clone(), chat_log.clone()));
+
+    // spawned so a panic in a loop is reported here instead of taking the whole bridge down
+    let mut irc_tasks = irc_clients
+        .into_iter()
+        .zip(configs.into_iter().zip(logins))
+        .enumerate()
+        .map(|(network, (irc_client, (config, login)))| {
+            tokio::spawn(run_network(
+                network,
+                irc_client,
+                config,
+                login,
+                retry,
+                NetworkContext {
+                    http: http.clone(),
+                    cache: cache.clone(),
+                    data: discord_data.clone(),
+                    cache_ttl: conf.cache_ttl,
+                    admin_channel,
+                    connection_notices,
+                    locale: locale.clone(),
+                },
+            ))
+        })
+        .collect::<Vec<_>>();
+
+    // started again with the same backoff as IRC if the gateway gives up, so one outage doesn't
+    // take the whole bridge down
+    let shard_manager = discord_client.shard_manager.clone();
+    let discord_client = Mutex::new(discord_client);
+    let run_discord = with_retry("Connecting to Discord", retry, || async {
+        Ok(discord_client.lock().await.start().await?)
+    });
+
+    let result = select! {
+        (r, network, _) = select_all(irc_tasks.iter_mut()) => {
+            set_irc_status(&discord_data, IrcStatus::Disconnected).await;
+            let name = senders.name(network);
+            match r {
+                Ok(r) => r.with_context(|| format!("IRC loop for {name} exited")),
+                Err(e) => Err(anyhow!("IRC loop for {name} panicked: {e}")),
+            }
+        },
+        r = run_discord => r.context("Discord client exited"),
+        _ = terminate_signal() => {
+            shut_down(&shard_manager, &discord_data, &http, &mut irc_tasks, &locale).await
+        },
+    };
+
+    panic_notifier.abort();
+    watchdog.abort();
+    for irc_task in &irc_tasks {
+        irc_task.abort();
+    }
+    if let Some(health_server) = health_server {
+        health_server.abort();
+    }
+    #[cfg(unix)]
+    if let Some(systemd_notifier) = systemd_notifier {
+        systemd_notifier.abort();
+    }
+    if let Some(puppet_expirer) = puppet_expirer {
+        puppet_expirer.abort();
+    }
+    cache_dumper.abort();
+    reloader.abort();
+    log_reopener.abort();
+    member_warmer.abort();
+
+    if let (Err(e), Some(admin_channel)) = (&result, admin_channel) {
+        let _ = admin_channel
+            .say(&http, format!("**dircord stopped**: {e:#}"))
+            .await;
+    }
+
+    result
+}
+
+/// Forwards panics caught by the panic hook to the admin channel.
+async fn notify_panics(http: Arc<Http>, admin_channel: Option<ChannelId>) {
+    let Some(admin_channel) = admin_channel else {
+        return;
+    };
+    let mut panics = PANICS.subscribe();
 
-            webhooks_transformed.insert(channel.clone(), parsed);
+    loop {
+        match panics.recv().await {
+            Ok(panic) => {
+                let _ = admin_channel
+                    .say(&http, format!("**dircord panicked**: {panic}"))
+                    .await;
+            }
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => break,
         }
     }
+}
 
-    select! {
-        r = irc_loop(irc_client, http.clone(), cache.clone(), channels.clone(), webhooks_transformed, members, conf.cache_ttl) => r.unwrap(),
-        r = discord_client.start() => r.unwrap(),
-        _ = terminate_signal() => {
-            for (_, &v) in channels.iter() {
-                let channel_id = ChannelId::from(v);
-                channel_id.say(&http, format!("dircord shutting down! (dircord {}-{})", env!("VERGEN_GIT_BRANCH"), &env!("VERGEN_GIT_SHA")[..7])).await.unwrap();
+/// Sorts a network's `channels` into the bridge's mapping and patterns, returning the ones to join.
+fn add_network_channels(
+    network: usize,
+    targets: HashMap<String, ChannelTarget>,
+    channels: &mut HashMap<String, u64>,
+    patterns: &mut ChannelPatterns,
+    senders: &mut Senders,
+) -> anyhow::Result<Vec<String>> {
+    let mut join = Vec::new();
+
+    for (channel, target) in targets {
+        match target {
+            ChannelTarget::Id(id) => {
+                senders.add_channel(channel.clone(), network)?;
+                join.push(channel.clone());
+                channels.insert(channel, id);
             }
-        },
+            ChannelTarget::Category { category } => patterns.add(channel, category, network),
+        }
+    }
+
+    join.sort_unstable();
+    Ok(join)
+}
+
+/// What every network's IRC loop gets from the bridge.
+struct NetworkContext {
+    http: Arc<Http>,
+    cache: Arc<Cache>,
+    data: Arc<RwLock<TypeMap>>,
+    cache_ttl: Option<u64>,
+    admin_channel: Option<ChannelId>,
+    connection_notices: bool,
+    locale: Arc<Locale>,
+}
+
+/// Runs the IRC loop for one network, reconnecting when the connection stalls.
+async fn run_network(
+    network: usize,
+    mut irc_client: IrcClient,
+    config: Config,
+    login: Login,
+    retry: Retry,
+    ctx: NetworkContext,
+) -> anyhow::Result<()> {
+    let NetworkContext {
+        http,
+        cache,
+        data,
+        cache_ttl,
+        admin_channel,
+        connection_notices,
+        locale,
+    } = ctx;
+    let name = data
+        .read()
+        .await
+        .get::<SendersKey>()
+        .unwrap()
+        .name(network)
+        .to_owned();
+
+    loop {
+        let result = irc_loop(
+            network,
+            &login,
+            irc_client,
+            http.clone(),
+            cache.clone(),
+            data.clone(),
+            cache_ttl,
+        )
+        .await;
+
+        // a stalled or dropped connection is worth another try, anything else is fatal
+        let e = match result {
+            Err(e) if e.is::<Stalled>() || e.is::<Lost>() => e,
+            r => return r,
+        };
+
+        let down_since = format!("<t:{}:t>", unix_now());
+        set_irc_status(&data, IrcStatus::Disconnected).await;
+        data.read()
+            .await
+            .get::<HealthKey>()
+            .unwrap()
+            .set_irc(network, false);
+        if let Some(admin_channel) = admin_channel {
+            let text = locale.text(
+                "admin_irc_down",
+                "**IRC connection to {name} went down**: {error}, reconnecting",
+                &[("name", &name), ("error", &e)],
+            );
+            let _ = admin_channel.say(&http, text).await;
+        }
+        // so people don't think IRC just went quiet
+        if connection_notices {
+            let text = locale.text(
+                "irc_down",
+                "IRC link down since {time}, messages sent until it's back won't reach IRC",
+                &[("time", &down_since)],
+            );
+            announce_to_discord(&http, &data, network, &text).await;
+        }
+
+        irc_client = with_retry(&format!("Reconnecting to {name}"), retry, || async {
+            Ok(IrcClient::from_config(config.clone()).await?)
+        })
+        .await?;
+        data.write()
+            .await
+            .get_mut::<SendersKey>()
+            .unwrap()
+            .connected(network, irc_client.sender());
+
+        if connection_notices {
+            let text = locale.text(
+                "irc_up",
+                "IRC link is back, it was down since {time}",
+                &[("time", &down_since)],
+            );
+            announce_to_discord(&http, &data, network, &text).await;
+        }
     }
+}
 
+/// Posts to every Discord channel bridged with `network`, for things everyone there should know
+/// about.
+async fn announce_to_discord(http: &Http, data: &RwLock<TypeMap>, network: usize, text: &str) {
+    // read every time, channels can be bridged through patterns while running
+    let channels = {
+        let data = data.read().await;
+        let senders = data.get::<SendersKey>().unwrap();
+        data.get::<ChannelMappingKey>()
+            .unwrap()
+            .iter()
+            .filter(|(channel, _)| senders.network(channel) == network)
+            .map(|(_, &id)| id)
+            .collect::<Vec<_>>()
+    };
+
+    for id in channels {
+        if let Err(e) = ChannelId::from(id).say(http, text).await {
+            eprintln!("Failed to announce to {id}: {e}");
+        }
+    }
+}
+
+/// Stops taking in anything new from Discord, lets every IRC loop deliver what it still has
+/// queued and QUIT, then says goodbye on Discord.
+async fn shut_down(
+    shard_manager: &ShardManager,
+    data: &RwLock<TypeMap>,
+    http: &Http,
+    irc_tasks: &mut [JoinHandle<anyhow::Result<()>>],
+    locale: &Locale,
+) -> anyhow::Result<()> {
+    let version = format!(
+        "{}-{}",
+        env!("VERGEN_GIT_BRANCH"),
+        &env!("VERGEN_GIT_SHA")[..7]
+    );
+    let text = locale.text(
+        "shutdown",
+        "dircord shutting down! (dircord {version})",
+        &[("version", &version)],
+    );
+
+    shard_manager.shutdown_all().await;
+    if let Some(puppets) = data.read().await.get::<PuppetsKey>().unwrap() {
+        puppets.quit_all(&text);
+    }
+    for queries in data.read().await.get::<IrcQueryKey>().unwrap().values() {
+        let _ = queries.send(IrcQuery::Shutdown(text.clone()));
+    }
+    // the loops stop waiting on the queue by themselves, this is for ones stuck reconnecting
+    let drained = timeout(
+        DRAIN_TIMEOUT + Duration::from_secs(10),
+        join_all(irc_tasks.iter_mut()),
+    )
+    .await;
+    match drained {
+        Ok(results) => {
+            for result in results {
+                match result {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => eprintln!("IRC loop failed while shutting down: {e:#}"),
+                    Err(e) => eprintln!("IRC loop panicked while shutting down: {e}"),
+                }
+            }
+        }
+        Err(_) => eprintln!("Not every IRC loop finished in time, shutting down anyway"),
+    }
+
+    // read again, channels can be bridged through patterns while running
+    let mapping = data
+        .read()
+        .await
+        .get::<ChannelMappingKey>()
+        .unwrap()
+        .clone();
+    for &v in mapping.values() {
+        ChannelId::from(v).say(http, &text).await?;
+    }
     Ok(())
 }
 
+/// Restarts shards that have been disconnected or missing heartbeats for too long, and with
+/// `notices` lets IRC know messages from Discord may have been missed in the meantime.
+async fn gateway_watchdog(
+    shard_manager: Arc<ShardManager>,
+    data: Arc<RwLock<TypeMap>>,
+    timeout: Duration,
+    notices: bool,
+) {
+    let mut timer = interval(Duration::from_secs(15));
+    let mut unhealthy_since = HashMap::new();
+    // when the connection was lost, as a unix timestamp
+    let mut interrupted = None;
+
+    loop {
+        timer.tick().await;
+
+        let health = shard_manager
+            .runners
+            .lock()
+            .await
+            .iter()
+            .map(|(&shard_id, runner)| {
+                let healthy = runner.stage == ConnectionStage::Connected
+                    && runner.latency.map_or(false, |l| l < timeout);
+                (shard_id, healthy)
+            })
+            .collect::<Vec<_>>();
+
+        let mut restart = Vec::new();
+        for (shard_id, healthy) in health {
+            if healthy {
+                unhealthy_since.remove(&shard_id);
+                continue;
+            }
+
+            let since = *unhealthy_since.entry(shard_id).or_insert_with(Instant::now);
+            if since.elapsed() > timeout {
+                restart.push(shard_id);
+            }
+        }
+
+        let notice = match interrupted {
+            None if !restart.is_empty() => {
+                let longest = unhealthy_since.values().map(Instant::elapsed).max();
+                let since = unix_now().saturating_sub(longest.unwrap_or_default().as_secs());
+                interrupted = Some(since);
+                Some((
+                    "discord_down",
+                    "Discord link down since {time}, messages from there may have been missed",
+                    since,
+                ))
+            }
+            Some(since) if unhealthy_since.is_empty() => {
+                interrupted = None;
+                Some((
+                    "discord_up",
+                    "Discord link is back, it was down since {time}",
+                    since,
+                ))
+            }
+            _ => None,
+        };
+
+        if let (Some((key, default, since)), true) = (notice, notices) {
+            let data = data.read().await;
+            let senders = data.get::<SendersKey>().unwrap();
+            let transcoder = data.get::<TranscoderKey>().unwrap();
+            let time = format!(
+                "{} UTC",
+                short_time(i64::try_from(since).unwrap_or_default())
+            );
+            let message = data
+                .get::<LocaleKey>()
+                .unwrap()
+                .text(key, default, &[("time", &time)]);
+            for channel in data.get::<ChannelMappingKey>().unwrap().keys() {
+                if let Err(e) = senders
+                    .get(channel)
+                    .send_notice(channel, transcoder.encode(&message))
+                {
+                    eprintln!("Failed to tell {channel} about Discord: {e}");
+                }
+            }
+        }
+
+        for shard_id in restart {
+            eprintln!("Discord shard {shard_id} looks dead, restarting it");
+            unhealthy_since.remove(&shard_id);
+            shard_manager.restart(shard_id).await;
+        }
+    }
+}
+
+lazy_static! {
+    static ref PANICS: broadcast::Sender<String> = broadcast::channel(16).0;
+}
+
+/// Keeps the default panic output, but also lets every bridge report the panic to its admin
+/// channel.
+fn install_panic_hook() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        let _ = PANICS.send(info.to_string());
+    }));
+}
+
+#[derive(Clone, Copy)]
+struct Retry {
+    attempts: u32,
+    backoff: Duration,
+}
+
+impl Retry {
+    fn new(conf: &DircordConfig) -> Self {
+        Self {
+            attempts: conf.startup_attempts.unwrap_or(5).max(1),
+            backoff: Duration::from_secs(conf.startup_backoff.unwrap_or(5)),
+        }
+    }
+}
+
+/// Runs `f` until it succeeds or we run out of attempts, doubling the delay between attempts
+/// each time. Used so a briefly unreachable network at boot doesn't kill the process.
+async fn with_retry<T, F, Fut>(what: &str, retry: Retry, mut f: F) -> anyhow::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = anyhow::Result<T>>,
+{
+    let max_attempts = retry.attempts;
+    let mut delay = retry.backoff;
+    let mut attempt = 1;
+
+    loop {
+        match f().await {
+            Ok(v) => return Ok(v),
+            Err(e) if attempt < max_attempts => {
+                eprintln!(
+                    "{what} failed (attempt {attempt}/{max_attempts}), retrying in {}s: {e:?}",
+                    delay.as_secs()
+                );
+                sleep(delay).await;
+                delay = (delay * 2).min(Duration::from_secs(300));
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// `Regex::replace_all`, but `text` is handed back as is instead of copied when nothing matches.
+fn replace_all_owned(re: &Regex, text: String, rep: impl Replacer) -> String {
+    let replaced = match re.replace_all(&text, rep) {
+        Cow::Borrowed(_) => None,
+        Cow::Owned(replaced) => Some(replaced),
+    };
+    replaced.unwrap_or(text)
+}
+
 struct OptionReplacer<F>(F);
 
 impl<T: AsRef<str>, F: for<'r, 't> FnMut(&'r Captures<'t>) -> Option<T>> Replacer
@@ -191,11 +1852,281 @@ macro_rules! regex {
     };
 }
 
-async fn parse_webhook_url(http: Arc<Http>, url: String) -> anyhow::Result<Webhook> {
-    let url = url.trim_start_matches("https://discord.com/api/webhooks/");
-    let split = url.split('/').collect::<Vec<&str>>();
-    let id = split[0].parse::<u64>()?;
-    let token = split[1].to_string();
-    let webhook = http.get_webhook_with_token(id.into(), &token).await?;
+/// Turns the configured emails (or hashes) of IRC users into Gravatar-style avatar URLs.
+fn avatar_urls(avatars: HashMap<String, String>, service: Option<&str>) -> HashMap<String, String> {
+    let base = match service.unwrap_or("gravatar") {
+        "gravatar" => "https://www.gravatar.com/avatar/",
+        "libravatar" => "https://seccdn.libravatar.org/avatar/",
+        other => other,
+    };
+
+    avatars
+        .into_iter()
+        .map(|(nick, email)| {
+            let is_hash = email.len() == 32 && email.chars().all(|c| c.is_ascii_hexdigit());
+            let hash = if is_hash {
+                email.to_lowercase()
+            } else {
+                format!("{:x}", md5::compute(email.trim().to_lowercase()))
+            };
+
+            (nick, format!("{base}{hash}?d=identicon"))
+        })
+        .collect()
+}
+
+/// What webhooks made for a pool are called, so they can be found again after a restart.
+const WEBHOOK_POOL_NAME: &str = "dircord";
+
+/// Tops a channel's webhook pool up to `size`, reusing the ones made on earlier runs first.
+async fn fill_webhook_pool(
+    http: &Http,
+    channel_id: ChannelId,
+    pool: &mut Vec<Arc<Webhook>>,
+    size: usize,
+) -> anyhow::Result<()> {
+    if pool.len() >= size {
+        return Ok(());
+    }
+
+    // threads can't have webhooks, their parent's get pointed into them instead
+    let channel_id = match channel_id.to_channel(http).await?.guild() {
+        Some(channel) if channel.thread_metadata.is_some() => {
+            channel.parent_id.unwrap_or(channel_id)
+        }
+        _ => channel_id,
+    };
+
+    for webhook in channel_id.webhooks(http).await? {
+        if pool.len() >= size {
+            break;
+        }
+        if webhook.name.as_deref() == Some(WEBHOOK_POOL_NAME)
+            && webhook.token.is_some()
+            && !pool.iter().any(|w| w.id == webhook.id)
+        {
+            pool.push(Arc::new(webhook));
+        }
+    }
+
+    while pool.len() < size {
+        let webhook = channel_id
+            .create_webhook(http, CreateWebhook::new(WEBHOOK_POOL_NAME))
+            .await?;
+        pool.push(Arc::new(webhook));
+    }
+
+    Ok(())
+}
+
+/// The webhooks from the config, with the pools of channels that want one filled up.
+async fn load_webhooks(
+    http: &Arc<Http>,
+    urls: HashMap<String, WebhookUrls>,
+    channel_options: &HashMap<String, ChannelOptions>,
+    channels: &HashMap<String, u64>,
+) -> anyhow::Result<HashMap<String, Vec<Arc<Webhook>>>> {
+    let mut webhooks: HashMap<String, Vec<Arc<Webhook>>> = HashMap::new();
+
+    for (channel, urls) in urls {
+        let urls = match urls {
+            WebhookUrls::One(url) => vec![url],
+            WebhookUrls::Pool(urls) => urls,
+        };
+
+        for (i, wh) in urls.into_iter().enumerate() {
+            let parsed = parse_webhook_url(http.clone(), wh)
+                .await
+                .with_context(|| format!("Invalid webhook #{} for {channel}", i + 1))?;
+
+            webhooks
+                .entry(channel.clone())
+                .or_default()
+                .push(Arc::new(parsed));
+        }
+    }
+
+    for (channel, options) in channel_options {
+        let (Some(size), Some(&id)) = (options.webhook_pool, channels.get(channel)) else {
+            continue;
+        };
+
+        let pool = webhooks.entry(channel.clone()).or_default();
+        if let Err(e) = fill_webhook_pool(http, ChannelId::from(id), pool, size).await {
+            eprintln!("Couldn't set up webhooks for {channel}: {e}");
+        }
+    }
+
+    Ok(webhooks)
+}
+
+/// The ID and token of a webhook, checked as far as they can be without asking Discord.
+fn webhook_parts(spec: WebhookSpec) -> anyhow::Result<(u64, String)> {
+    regex! {
+        static WEBHOOK_RE = r"^https?://(?:(?:ptb|canary)\.)?discord(?:app)?\.com/api(?:/v\d+)?/webhooks/(\d+)/([\w-]+)/?(?:\?.*)?$";
+    }
+
+    let (id, token) = match spec {
+        WebhookSpec::Parts { id, token } => (id, token),
+        WebhookSpec::Url(url) => {
+            let caps = WEBHOOK_RE
+                .captures(url.trim())?
+                .ok_or_else(|| anyhow!("Doesn't look like a Discord webhook URL"))?;
+            // not echoing the URL back, it has the token in it
+            let id = caps[1]
+                .parse::<u64>()
+                .context("Webhook ID is out of range")?;
+            (id, caps[2].to_owned())
+        }
+    };
+
+    if id == 0 {
+        anyhow::bail!("Webhook ID can't be 0");
+    }
+    Ok((id, token))
+}
+
+async fn parse_webhook_url(http: Arc<Http>, spec: WebhookSpec) -> anyhow::Result<Webhook> {
+    let (id, token) = webhook_parts(spec)?;
+    let webhook = http
+        .get_webhook_with_token(id.into(), &token)
+        .await
+        .with_context(|| format!("Couldn't fetch webhook {id}, is the token right?"))?;
     Ok(webhook)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{rewrite_nick, webhook_parts, Direction, NickRule, QuietHours, WebhookSpec};
+    use chrono::{DateTime, NaiveDate, NaiveTime, TimeZone, Utc};
+    use chrono_tz::Tz;
+    use fancy_regex::Regex;
+    use std::borrow::Cow;
+
+    fn nick_rule(pattern: &str, replace: &str) -> NickRule {
+        NickRule {
+            pattern: Regex::new(pattern).unwrap(),
+            replace: replace.to_owned(),
+        }
+    }
+
+    #[test]
+    fn nick_rules_apply_in_order() {
+        let rules = [
+            nick_rule(r"\|.*$", ""),
+            nick_rule(r"^guest(\d+)$", "visitor$1"),
+        ];
+        assert_eq!(rewrite_nick(&rules, "alice|away"), "alice");
+        assert_eq!(rewrite_nick(&rules, "guest12"), "visitor12");
+        assert_eq!(rewrite_nick(&rules, "guest1|afk"), "visitor1");
+        assert!(matches!(rewrite_nick(&rules, "bob"), Cow::Borrowed("bob")));
+    }
+
+    fn quiet_hours(from: (u32, u32), to: (u32, u32), timezone: Option<Tz>) -> QuietHours {
+        QuietHours {
+            from: NaiveTime::from_hms_opt(from.0, from.1, 0).unwrap(),
+            to: NaiveTime::from_hms_opt(to.0, to.1, 0).unwrap(),
+            timezone,
+            direction: None,
+        }
+    }
+
+    /// A winter day, so Europe is on standard time.
+    fn utc_time(hour: u32, minute: u32) -> DateTime<Utc> {
+        let date = NaiveDate::from_ymd_opt(2024, 1, 15).unwrap();
+        Utc.from_utc_datetime(&date.and_hms_opt(hour, minute, 0).unwrap())
+    }
+
+    #[test]
+    fn quiet_hours_within_a_day() {
+        let quiet = quiet_hours((9, 0), (17, 0), None);
+        assert!(quiet.suspended_at(utc_time(8, 59)).is_none());
+        assert!(quiet.suspended_at(utc_time(9, 0)) == Some(Direction::Both));
+        assert!(quiet.suspended_at(utc_time(16, 59)).is_some());
+        assert!(quiet.suspended_at(utc_time(17, 0)).is_none());
+    }
+
+    #[test]
+    fn quiet_hours_past_midnight() {
+        let quiet = quiet_hours((22, 0), (6, 0), None);
+        assert!(quiet.suspended_at(utc_time(21, 59)).is_none());
+        assert!(quiet.suspended_at(utc_time(22, 0)).is_some());
+        assert!(quiet.suspended_at(utc_time(23, 59)).is_some());
+        assert!(quiet.suspended_at(utc_time(0, 0)).is_some());
+        assert!(quiet.suspended_at(utc_time(5, 59)).is_some());
+        assert!(quiet.suspended_at(utc_time(6, 0)).is_none());
+        assert!(quiet.suspended_at(utc_time(12, 0)).is_none());
+    }
+
+    #[test]
+    fn quiet_hours_in_a_timezone() {
+        // UTC+1 in winter
+        let berlin = quiet_hours((22, 0), (6, 0), Some(Tz::Europe__Berlin));
+        assert!(berlin.suspended_at(utc_time(20, 59)).is_none());
+        assert!(berlin.suspended_at(utc_time(21, 0)).is_some());
+        assert!(berlin.suspended_at(utc_time(4, 59)).is_some());
+        assert!(berlin.suspended_at(utc_time(5, 0)).is_none());
+
+        // UTC-5, the window crosses midnight in UTC but not in New York
+        let new_york = quiet_hours((18, 0), (23, 0), Some(Tz::America__New_York));
+        assert!(new_york.suspended_at(utc_time(22, 59)).is_none());
+        assert!(new_york.suspended_at(utc_time(23, 0)).is_some());
+        assert!(new_york.suspended_at(utc_time(3, 59)).is_some());
+        assert!(new_york.suspended_at(utc_time(4, 0)).is_none());
+    }
+
+    #[test]
+    fn quiet_hours_direction() {
+        let quiet = QuietHours {
+            direction: Some(Direction::DiscordToIrc),
+            ..quiet_hours((0, 0), (23, 59), None)
+        };
+        assert!(quiet.suspended_at(utc_time(12, 0)) == Some(Direction::DiscordToIrc));
+    }
+
+    fn url_parts(url: &str) -> anyhow::Result<(u64, String)> {
+        webhook_parts(WebhookSpec::Url(url.to_owned()))
+    }
+
+    #[test]
+    fn webhook_urls() {
+        let parts = (123, String::from("abc-DEF_1"));
+        for url in [
+            "https://discord.com/api/webhooks/123/abc-DEF_1",
+            "https://discordapp.com/api/webhooks/123/abc-DEF_1",
+            "https://canary.discord.com/api/v10/webhooks/123/abc-DEF_1",
+            "https://ptb.discord.com/api/webhooks/123/abc-DEF_1/",
+            "https://discord.com/api/webhooks/123/abc-DEF_1?wait=true",
+            "https://discord.com/api/webhooks/123/abc-DEF_1/?thread_id=456",
+            " https://discord.com/api/webhooks/123/abc-DEF_1\n",
+        ] {
+            assert_eq!(url_parts(url).unwrap(), parts, "{url}");
+        }
+    }
+
+    #[test]
+    fn bad_webhook_urls() {
+        for url in [
+            "https://discord.com/api/webhooks/123/abc/slack",
+            "https://discord.com/api/webhooks/123/abc/github?wait=true",
+            "https://discord.com/api/webhooks/123",
+            "https://discord.com/webhooks/123/abc",
+            "https://example.com/api/webhooks/123/abc",
+            "https://discord.com/api/webhooks/99999999999999999999/abc",
+        ] {
+            assert!(url_parts(url).is_err(), "{url}");
+        }
+    }
+
+    #[test]
+    fn zero_webhook_ids() {
+        let e = url_parts("https://discord.com/api/webhooks/0/abc").unwrap_err();
+        assert_eq!(e.to_string(), "Webhook ID can't be 0");
+
+        let parts = webhook_parts(WebhookSpec::Parts {
+            id: 0,
+            token: String::from("abc"),
+        });
+        assert!(parts.is_err());
+    }
+}
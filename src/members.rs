@@ -0,0 +1,133 @@
+use serenity::{
+    http::Http,
+    model::{
+        guild::Member,
+        id::{GuildId, UserId},
+    },
+};
+use std::collections::HashSet;
+use tokio::sync::Mutex;
+
+/// How many members Discord hands out per request.
+const PAGE_SIZE: u64 = 1000;
+
+/// Adds a member to the known ones, replacing what we had on them.
+pub fn remember(members: &mut Vec<Member>, member: Member) {
+    match members.iter_mut().find(|m| m.user.id == member.user.id) {
+        Some(known) => *known = member,
+        None => members.push(member),
+    }
+}
+
+/// The member going by `name`: whoever has it as their username, otherwise whoever has it as their
+/// display name, as long as that's only one person. Display names aren't unique, so guessing
+/// would ping or show the wrong person.
+pub fn find_by_name<'a>(members: &'a [Member], name: &str) -> Option<&'a Member> {
+    if let Some(member) = members.iter().find(|m| m.user.name == name) {
+        return Some(member);
+    }
+
+    let mut named = members.iter().filter(|m| m.display_name() == name);
+    let member = named.next()?;
+    named.next().is_none().then_some(member)
+}
+
+/// `display_name`, with the username after it if someone else goes by that name too.
+pub fn unambiguous_name(
+    members: &[Member],
+    user_id: UserId,
+    display_name: &str,
+    username: &str,
+) -> String {
+    let shared = display_name != username
+        && members
+            .iter()
+            .any(|m| m.user.id != user_id && m.display_name() == display_name);
+
+    if shared {
+        format!("{display_name} ({username})")
+    } else {
+        display_name.to_owned()
+    }
+}
+
+/// Fetches every member in the background, for the lookups that can't search. Big servers take
+/// minutes, which is why this doesn't hold up startup anymore.
+pub async fn warm(http: &Http, guild_id: GuildId, members: &Mutex<Vec<Member>>) {
+    let mut after = None;
+
+    loop {
+        let page = match guild_id.members(http, Some(PAGE_SIZE), after).await {
+            Ok(page) => page,
+            Err(e) => {
+                eprintln!("Stopped fetching Discord members: {e}");
+                return;
+            }
+        };
+
+        after = page.last().map(|m| m.user.id);
+        let done = (page.len() as u64) < PAGE_SIZE;
+
+        let mut members = members.lock().await;
+        // anything we already know about is as fresh or fresher than this
+        let known = members.iter().map(|m| m.user.id).collect::<HashSet<_>>();
+        members.extend(page.into_iter().filter(|m| !known.contains(&m.user.id)));
+        drop(members);
+
+        if done {
+            return;
+        }
+    }
+}
+
+/// Makes sure the members with these IDs are known, fetching the ones that aren't.
+pub async fn fetch_missing(
+    http: &Http,
+    guild_id: GuildId,
+    members: &Mutex<Vec<Member>>,
+    ids: impl IntoIterator<Item = UserId>,
+) {
+    for id in ids {
+        if members.lock().await.iter().any(|m| m.user.id == id) {
+            continue;
+        }
+
+        // people that left can still be mentioned, that's fine
+        if let Ok(member) = guild_id.member(http, id).await {
+            remember(&mut *members.lock().await, member);
+        }
+    }
+}
+
+/// Makes sure members going by these names are known, searching for the ones that aren't.
+/// Returns the names it searched for.
+pub async fn search_missing(
+    http: &Http,
+    guild_id: GuildId,
+    members: &Mutex<Vec<Member>>,
+    names: Vec<String>,
+) -> Vec<String> {
+    let mut searched = Vec::new();
+    for name in names {
+        let known = members
+            .lock()
+            .await
+            .iter()
+            .any(|m| m.display_name() == name || m.user.name == name);
+        if known {
+            continue;
+        }
+
+        match guild_id.search_members(http, &name, Some(5)).await {
+            Ok(found) => {
+                let mut members = members.lock().await;
+                for member in found {
+                    remember(&mut members, member);
+                }
+                searched.push(name);
+            }
+            Err(e) => eprintln!("Failed to search Discord members for {name}: {e}"),
+        }
+    }
+    searched
+}
@@ -0,0 +1,192 @@
+use std::collections::{HashMap, VecDeque};
+
+use serenity::model::id::{ChannelId, MessageId, UserId};
+
+use crate::store::MessageStore;
+
+/// A message that has already been relayed to IRC, kept around so that replies to it don't
+/// need another REST fetch and processing pass.
+#[derive(Clone, Debug)]
+pub struct CachedMessage {
+    pub channel_id: ChannelId,
+    pub author_id: UserId,
+    pub nick: Option<String>,
+    pub content: String,
+}
+
+/// Bounded cache of recently relayed messages. When full, the oldest entry is evicted.
+///
+/// Also tracks the most recent message of each user per channel, so IRC users addressing
+/// someone can be turned into real Discord replies, the short IDs handed out to IRC, and the IRC
+/// msgids of messages relayed to Discord.
+pub struct MessageCache {
+    entries: HashMap<MessageId, CachedMessage>,
+    order: VecDeque<MessageId>,
+    latest: HashMap<(ChannelId, UserId), MessageId>,
+    capacity: usize,
+    short_ids: HashMap<String, MessageId>,
+    next_short_id: u32,
+    by_msgid: HashMap<String, MessageId>,
+    msgids: HashMap<MessageId, String>,
+    msgid_order: VecDeque<MessageId>,
+    /// Where everything above goes too, when `db_path` is set. Looked in when memory doesn't have
+    /// something anymore.
+    store: Option<MessageStore>,
+}
+
+/// Short IDs are this many base 36 digits, so they wrap around after 36^3 = 46656 messages and
+/// the map can't grow past that.
+const SHORT_ID_LEN: u32 = 3;
+
+impl MessageCache {
+    pub fn new(capacity: usize, store: Option<MessageStore>) -> Self {
+        Self {
+            entries: HashMap::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+            latest: HashMap::new(),
+            capacity,
+            short_ids: HashMap::new(),
+            next_short_id: 0,
+            by_msgid: HashMap::new(),
+            msgids: HashMap::new(),
+            msgid_order: VecDeque::new(),
+            store,
+        }
+    }
+
+    pub fn insert(&mut self, id: MessageId, message: CachedMessage) {
+        if let Some(ref store) = self.store {
+            store.save_message(id, &message);
+        }
+        if self.capacity == 0 {
+            return;
+        }
+
+        self.latest
+            .insert((message.channel_id, message.author_id), id);
+
+        if self.entries.insert(id, message).is_none() {
+            self.order.push_back(id);
+        }
+
+        while self.order.len() > self.capacity {
+            let Some(old) = self.order.pop_front() else {
+                break;
+            };
+            if let Some(evicted) = self.entries.remove(&old) {
+                let key = (evicted.channel_id, evicted.author_id);
+                if self.latest.get(&key) == Some(&old) {
+                    self.latest.remove(&key);
+                }
+            }
+        }
+    }
+
+    pub fn get(&self, id: MessageId) -> Option<CachedMessage> {
+        match self.entries.get(&id) {
+            Some(message) => Some(message.clone()),
+            None => self.store.as_ref()?.message(id),
+        }
+    }
+
+    /// For edits, without making the message anyone's latest again.
+    pub fn update_content(&mut self, id: MessageId, content: String) {
+        if let Some(ref store) = self.store {
+            store.update_content(id, &content);
+        }
+        if let Some(entry) = self.entries.get_mut(&id) {
+            entry.content = content;
+        }
+    }
+
+    /// The last `n` cached messages in a channel, oldest first.
+    pub fn recent(&self, channel_id: ChannelId, n: usize) -> Vec<(MessageId, &CachedMessage)> {
+        let mut recent = self
+            .order
+            .iter()
+            .rev()
+            .filter_map(|id| Some((*id, self.entries.get(id)?)))
+            .filter(|(_, m)| m.channel_id == channel_id)
+            .take(n)
+            .collect::<Vec<_>>();
+        recent.reverse();
+        recent
+    }
+
+    /// The newest cached message in a channel that `matches`.
+    pub fn latest_matching(
+        &self,
+        channel_id: ChannelId,
+        matches: impl Fn(&CachedMessage) -> bool,
+    ) -> Option<MessageId> {
+        self.order.iter().rev().copied().find(|id| {
+            self.entries
+                .get(id)
+                .map_or(false, |m| m.channel_id == channel_id && matches(m))
+        })
+    }
+
+    pub fn latest(&self, channel_id: ChannelId, user_id: UserId) -> Option<MessageId> {
+        self.latest.get(&(channel_id, user_id)).copied()
+    }
+
+    /// Hands out a short ID like `ab3` that IRC users can use to point at a message.
+    pub fn assign_short_id(&mut self, id: MessageId) -> String {
+        let mut n = self.next_short_id;
+        self.next_short_id = (n + 1) % 36u32.pow(SHORT_ID_LEN);
+
+        let mut short = String::with_capacity(SHORT_ID_LEN as usize);
+        for _ in 0..SHORT_ID_LEN {
+            short.insert(0, char::from_digit(n % 36, 36).unwrap());
+            n /= 36;
+        }
+
+        self.short_ids.insert(short.clone(), id);
+        short
+    }
+
+    pub fn resolve_short_id(&self, short: &str) -> Option<MessageId> {
+        self.short_ids.get(&short.to_lowercase()).copied()
+    }
+
+    /// Remembers which Discord message an IRC message with this msgid turned into. Kept to the
+    /// same size as the message cache.
+    pub fn link_msgid(&mut self, msgid: String, id: MessageId) {
+        if let Some(ref store) = self.store {
+            store.save_msgid(&msgid, id);
+        }
+        if self.capacity == 0 {
+            return;
+        }
+
+        self.by_msgid.insert(msgid.clone(), id);
+        if self.msgids.insert(id, msgid).is_none() {
+            self.msgid_order.push_back(id);
+        }
+
+        while self.msgid_order.len() > self.capacity {
+            let Some(old) = self.msgid_order.pop_front() else {
+                break;
+            };
+            if let Some(msgid) = self.msgids.remove(&old) {
+                self.by_msgid.remove(&msgid);
+            }
+        }
+    }
+
+    /// The Discord message an IRC msgid was relayed as.
+    pub fn by_msgid(&self, msgid: &str) -> Option<MessageId> {
+        match self.by_msgid.get(msgid) {
+            Some(&id) => Some(id),
+            None => self.store.as_ref()?.by_msgid(msgid),
+        }
+    }
+
+    /// The IRC msgid of a message relayed to Discord, for pointing back at it on IRC.
+    pub fn msgid(&self, id: MessageId) -> Option<String> {
+        match self.msgids.get(&id) {
+            Some(msgid) => Some(msgid.clone()),
+            None => self.store.as_ref()?.msgid(id),
+        }
+    }
+}
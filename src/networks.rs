@@ -0,0 +1,72 @@
+use irc::client::Sender;
+use std::collections::HashMap;
+
+/// The connection to each IRC network, and which network each bridged channel is on. Network 0
+/// is the one at the top level of the config, `[[networks]]` come after it in order.
+#[derive(Clone, Default)]
+pub struct Senders {
+    names: Vec<String>,
+    senders: Vec<Option<Sender>>,
+    by_channel: HashMap<String, usize>,
+}
+
+impl Senders {
+    /// Adds a network, returning its index.
+    pub fn add_network(&mut self, name: String) -> usize {
+        self.names.push(name);
+        self.senders.push(None);
+        self.names.len() - 1
+    }
+
+    /// Bridges `channel` on `network`. Channels are looked up by name everywhere, so one can
+    /// only be on one network.
+    pub fn add_channel(&mut self, channel: String, network: usize) -> anyhow::Result<()> {
+        match self.by_channel.insert(channel.clone(), network) {
+            Some(other) if other != network => anyhow::bail!(
+                "{channel} is bridged on both {} and {}, use separate [[bridges]] for that",
+                self.names[other],
+                self.names[network]
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// For channels that stopped being bridged.
+    pub fn remove_channel(&mut self, channel: &str) {
+        self.by_channel.remove(channel);
+    }
+
+    /// Set on every connection.
+    pub fn connected(&mut self, network: usize, sender: Sender) {
+        self.senders[network] = Some(sender);
+    }
+
+    pub fn count(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn name(&self, network: usize) -> &str {
+        &self.names[network]
+    }
+
+    /// The network `channel` is on. Channels that aren't bridged, like announcement channels,
+    /// are on the first one.
+    pub fn network(&self, channel: &str) -> usize {
+        self.by_channel.get(channel).copied().unwrap_or(0)
+    }
+
+    /// The connection to send to `channel` on.
+    pub fn get(&self, channel: &str) -> &Sender {
+        self.network_sender(self.network(channel))
+    }
+
+    /// The first network's connection, which private messages go through.
+    pub fn primary(&self) -> &Sender {
+        self.network_sender(0)
+    }
+
+    fn network_sender(&self, network: usize) -> &Sender {
+        // only read after the bridge connected to every network
+        self.senders[network].as_ref().unwrap()
+    }
+}
@@ -0,0 +1,123 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use serenity::{
+    builder::{
+        CreateActionRow, CreateButton, CreateEmbed, CreateEmbedFooter,
+        CreateInteractionResponseMessage,
+    },
+    model::application::ButtonStyle,
+};
+
+/// How much goes on one page, embeds could take more but it gets hard to read.
+const PAGE_LEN: usize = 1800;
+/// Interaction tokens only last this long, after that the buttons can't edit the response anyway.
+const KEEP_FOR: Duration = Duration::from_secs(15 * 60);
+
+/// Long command output, shown a page at a time with buttons to flip through it.
+pub struct Paged {
+    title: String,
+    pages: Vec<String>,
+}
+
+impl Paged {
+    /// Splits `text` into pages, at line breaks where it can. With `code` every page gets its own
+    /// code block, so one doesn't end halfway through a page.
+    pub fn new(title: impl Into<String>, text: &str, code: bool) -> Self {
+        let mut pages = Vec::new();
+        let mut rest = text.trim_end();
+
+        while !rest.is_empty() {
+            let (page, next) = if rest.len() <= PAGE_LEN {
+                (rest, "")
+            } else {
+                let mut end = PAGE_LEN;
+                while !rest.is_char_boundary(end) {
+                    end -= 1;
+                }
+                let end = rest[..end]
+                    .rfind('\n')
+                    .or_else(|| rest[..end].rfind(' '))
+                    .filter(|&at| at > 0)
+                    .unwrap_or(end);
+                (
+                    &rest[..end],
+                    rest[end..].trim_start_matches(&['\n', ' '][..]),
+                )
+            };
+
+            pages.push(if code {
+                format!("```\n{page}\n```")
+            } else {
+                page.to_owned()
+            });
+            rest = next;
+        }
+
+        if pages.is_empty() {
+            pages.push(String::from("Nothing to show"));
+        }
+
+        Self {
+            title: title.into(),
+            pages,
+        }
+    }
+
+    /// Page `page` as a response, with the buttons for the pages around it. `id` is what the
+    /// buttons find these pages again by.
+    pub fn response(&self, id: u64, page: usize) -> CreateInteractionResponseMessage {
+        let page = page.min(self.pages.len() - 1);
+
+        let mut embed = CreateEmbed::new()
+            .title(&self.title)
+            .description(&self.pages[page]);
+        if self.pages.len() == 1 {
+            return CreateInteractionResponseMessage::new()
+                .embed(embed)
+                .components(Vec::new());
+        }
+        embed = embed.footer(CreateEmbedFooter::new(format!(
+            "Page {} of {}",
+            page + 1,
+            self.pages.len()
+        )));
+
+        let buttons = vec![
+            CreateButton::new(format!("page:{id}:{}", page.saturating_sub(1)))
+                .label("Previous")
+                .style(ButtonStyle::Secondary)
+                .disabled(page == 0),
+            CreateButton::new(format!("page:{id}:{}", page + 1))
+                .label("Next")
+                .style(ButtonStyle::Secondary)
+                .disabled(page + 1 == self.pages.len()),
+        ];
+
+        CreateInteractionResponseMessage::new()
+            .embed(embed)
+            .components(vec![CreateActionRow::Buttons(buttons)])
+    }
+}
+
+/// Paged responses that can still be flipped through, by the ID of the command they answered.
+#[derive(Default)]
+pub struct PagedResponses(HashMap<u64, (Instant, Paged)>);
+
+impl PagedResponses {
+    pub fn insert(&mut self, id: u64, paged: Paged) {
+        self.0.retain(|_, (at, _)| at.elapsed() < KEEP_FOR);
+        self.0.insert(id, (Instant::now(), paged));
+    }
+
+    /// What a next/previous button with `custom_id` should show, if it's one of ours and we still
+    /// have the pages.
+    pub fn turn(&self, custom_id: &str) -> Option<CreateInteractionResponseMessage> {
+        let (id, page) = custom_id.strip_prefix("page:")?.split_once(':')?;
+        let id = id.parse().ok()?;
+        let (_, paged) = self.0.get(&id)?;
+        Some(paged.response(id, page.parse().ok()?))
+    }
+}
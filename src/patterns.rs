@@ -0,0 +1,115 @@
+use crate::commands::mask_matches;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serenity::{
+    builder::CreateChannel,
+    http::Http,
+    model::{channel::ChannelType, id::ChannelId},
+};
+
+/// How many channels Discord lets a server have, categories included.
+const MAX_GUILD_CHANNELS: usize = 500;
+
+/// What an entry in `[channels]` bridges to: one Discord channel, or for patterns like
+/// `#project-*`, a category that gets a channel for each matching IRC channel.
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum ChannelTarget {
+    Id(u64),
+    Category { category: u64 },
+}
+
+struct ChannelPattern {
+    pattern: String,
+    category: ChannelId,
+    /// Which IRC network invites count on
+    network: usize,
+}
+
+/// Bridges IRC channels the bot is invited to when they match a pattern, so every channel of a
+/// project doesn't have to be listed.
+#[derive(Default)]
+pub struct ChannelPatterns(Vec<ChannelPattern>);
+
+impl ChannelPatterns {
+    pub fn add(&mut self, pattern: String, category: u64, network: usize) {
+        self.0.push(ChannelPattern {
+            pattern,
+            category: ChannelId::from(category),
+            network,
+        });
+    }
+
+    pub fn categories(&self) -> impl Iterator<Item = ChannelId> + '_ {
+        self.0.iter().map(|p| p.category)
+    }
+
+    /// The category an IRC channel on `network` should get its Discord channel in, if it matches
+    /// a pattern.
+    pub fn category_for(&self, network: usize, channel: &str) -> Option<ChannelId> {
+        self.0
+            .iter()
+            .find(|p| p.network == network && mask_matches(&p.pattern, channel))
+            .map(|p| p.category)
+    }
+
+    /// Channels that were set up through a pattern before, with the network they're on, found by
+    /// their names under the categories. Discord lowercases channel names, which is fine since IRC
+    /// doesn't care.
+    pub async fn existing(&self, http: &Http) -> anyhow::Result<Vec<(String, u64, usize)>> {
+        let mut found = Vec::new();
+
+        for pattern in &self.0 {
+            let category = pattern.category.to_channel(http).await?;
+            let Some(category) = category.guild() else {
+                anyhow::bail!("{} isn't a category in a server", pattern.category);
+            };
+
+            for channel in category.guild_id.channels(http).await?.into_values() {
+                if channel.parent_id != Some(pattern.category) || channel.kind != ChannelType::Text
+                {
+                    continue;
+                }
+
+                let irc_name = format!("#{}", channel.name);
+                if self.category_for(pattern.network, &irc_name) == Some(pattern.category) {
+                    found.push((irc_name, channel.id.0.get(), pattern.network));
+                }
+            }
+        }
+
+        Ok(found)
+    }
+
+    /// The Discord channel for `channel` under `category`, created if there isn't one yet.
+    pub async fn channel_for(
+        &self,
+        http: &Http,
+        category: ChannelId,
+        channel: &str,
+    ) -> anyhow::Result<ChannelId> {
+        let name = channel.trim_start_matches(&['#', '&'][..]).to_lowercase();
+        let Some(category) = category.to_channel(http).await?.guild() else {
+            anyhow::bail!("{category} isn't a category in a server");
+        };
+
+        let channels = category.guild_id.channels(http).await?;
+        let existing = channels
+            .values()
+            .find(|c| c.parent_id == Some(category.id) && c.name == name);
+        if let Some(existing) = existing {
+            return Ok(existing.id);
+        }
+        // it'd fail anyway, this says why
+        anyhow::ensure!(
+            channels.len() < MAX_GUILD_CHANNELS,
+            "the server already has Discord's limit of {MAX_GUILD_CHANNELS} channels"
+        );
+
+        let builder = CreateChannel::new(name)
+            .kind(ChannelType::Text)
+            .category(category.id)
+            .topic(format!("Bridged with {channel} on IRC"));
+        Ok(category.guild_id.create_channel(http, builder).await?.id)
+    }
+}
@@ -0,0 +1,102 @@
+use crate::commands::mask_matches;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serenity::model::id::{RoleId, UserId};
+use std::collections::HashMap;
+
+/// Who may use a command, on either side of the bridge.
+#[derive(Deserialize, JsonSchema, Default)]
+pub struct Principals {
+    discord_roles: Option<Vec<u64>>,
+    discord_users: Option<Vec<u64>>,
+    /// Hostmasks like `*!*@staff.example.org`
+    irc_masks: Option<Vec<String>>,
+    /// Services accounts, needs the server to support account-tag
+    irc_accounts: Option<Vec<String>>,
+}
+
+pub enum Caller<'a> {
+    Discord {
+        user_id: UserId,
+        roles: &'a [RoleId],
+    },
+    Irc {
+        mask: &'a str,
+        account: Option<&'a str>,
+    },
+}
+
+fn listed<T>(list: Option<&[T]>) -> bool {
+    list.map_or(false, |l| !l.is_empty())
+}
+
+/// The `[permissions]` section: command names to who may use them. Commands without an entry keep
+/// their default check.
+#[derive(Deserialize, JsonSchema, Default)]
+#[serde(transparent)]
+pub struct PermissionMatrix(HashMap<String, Principals>);
+
+impl PermissionMatrix {
+    /// Gives a command an entry if it doesn't have one yet, for older per-feature settings.
+    pub fn fallback(&mut self, command: &str, irc_masks: &[String], discord_users: &[u64]) {
+        if irc_masks.is_empty() && discord_users.is_empty() {
+            return;
+        }
+
+        self.0
+            .entry(command.to_owned())
+            .or_insert_with(|| Principals {
+                irc_masks: Some(irc_masks.to_vec()),
+                discord_users: Some(discord_users.to_vec()),
+                ..Principals::default()
+            });
+    }
+
+    /// Whether the caller may use the command, or None if the matrix doesn't say. An entry that
+    /// only lists people on one side doesn't say anything about the other.
+    pub fn check(&self, command: &str, caller: &Caller<'_>) -> Option<bool> {
+        let principals = self.0.get(command)?;
+        let covered = match caller {
+            Caller::Discord { .. } => {
+                listed(principals.discord_users.as_deref())
+                    || listed(principals.discord_roles.as_deref())
+            }
+            Caller::Irc { .. } => {
+                listed(principals.irc_masks.as_deref())
+                    || listed(principals.irc_accounts.as_deref())
+            }
+        };
+        if !covered {
+            return None;
+        }
+
+        Some(match *caller {
+            Caller::Discord { user_id, roles } => {
+                principals
+                    .discord_users
+                    .iter()
+                    .flatten()
+                    .any(|&id| id == user_id.0.get())
+                    || principals
+                        .discord_roles
+                        .iter()
+                        .flatten()
+                        .any(|&id| roles.iter().any(|r| r.0.get() == id))
+            }
+            Caller::Irc { mask, account } => {
+                principals
+                    .irc_masks
+                    .iter()
+                    .flatten()
+                    .any(|m| mask_matches(m, mask))
+                    || account.map_or(false, |account| {
+                        principals
+                            .irc_accounts
+                            .iter()
+                            .flatten()
+                            .any(|a| a.eq_ignore_ascii_case(account))
+                    })
+            }
+        })
+    }
+}
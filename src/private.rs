@@ -0,0 +1,220 @@
+use serenity::{
+    builder::CreateThread,
+    http::Http,
+    model::{
+        channel::{ChannelType, GuildChannel},
+        id::{ChannelId, UserId},
+    },
+};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// How often one nick can `!pair`.
+const PAIR_EVERY: Duration = Duration::from_secs(60);
+/// How often one Discord user can be asked, by anyone.
+const ASKED_EVERY: Duration = Duration::from_secs(5 * 60);
+
+/// Who is talking to whom over IRC PMs and Discord DMs, so replies on either side find their way
+/// back. Each side only has one open conversation at a time, the latest one wins.
+#[derive(Default)]
+pub struct DmSessions {
+    by_nick: HashMap<String, UserId>,
+    by_user: HashMap<UserId, String>,
+    /// `!pair` requests from IRC that the Discord user hasn't accepted yet, by who they're for
+    requests: HashMap<UserId, String>,
+    /// When each nick last used `!pair`
+    paired_at: HashMap<String, Instant>,
+    /// When each Discord user was last asked
+    asked_at: HashMap<UserId, Instant>,
+}
+
+/// Whether `key` was last let through long enough ago, counting this time if so.
+fn allow<K: std::hash::Hash + Eq>(
+    times: &mut HashMap<K, Instant>,
+    key: K,
+    every: Duration,
+) -> bool {
+    times.retain(|_, at| at.elapsed() < every);
+    if times.contains_key(&key) {
+        return false;
+    }
+    times.insert(key, Instant::now());
+    true
+}
+
+impl DmSessions {
+    pub fn open(&mut self, nick: &str, user_id: UserId) {
+        if let Some(old) = self.by_user.insert(user_id, nick.to_owned()) {
+            if old != nick {
+                self.by_nick.remove(&old);
+            }
+        }
+        if let Some(old) = self.by_nick.insert(nick.to_owned(), user_id) {
+            if old != user_id {
+                self.by_user.remove(&old);
+            }
+        }
+    }
+
+    pub fn user(&self, nick: &str) -> Option<UserId> {
+        self.by_nick.get(nick).copied()
+    }
+
+    pub fn nick(&self, user_id: UserId) -> Option<&str> {
+        self.by_user.get(&user_id).map(String::as_str)
+    }
+
+    /// Someone on IRC asking to talk to `user_id`, replacing anyone else that asked before.
+    pub fn request(&mut self, nick: &str, user_id: UserId) {
+        self.requests.insert(user_id, nick.to_owned());
+    }
+
+    /// Whether `nick` can `!pair` again yet.
+    pub fn may_pair(&mut self, nick: &str) -> bool {
+        allow(&mut self.paired_at, nick.to_lowercase(), PAIR_EVERY)
+    }
+
+    /// Whether `user_id` can be sent another `!pair` request yet.
+    pub fn may_ask(&mut self, user_id: UserId) -> bool {
+        allow(&mut self.asked_at, user_id, ASKED_EVERY)
+    }
+
+    /// Who is waiting for `user_id` to accept.
+    pub fn requested(&self, user_id: UserId) -> Option<&str> {
+        self.requests.get(&user_id).map(String::as_str)
+    }
+
+    /// Starts the conversation `user_id` was asked for, returning who with.
+    pub fn accept(&mut self, user_id: UserId) -> Option<String> {
+        let nick = self.requests.remove(&user_id)?;
+        self.open(&nick, user_id);
+        Some(nick)
+    }
+
+    /// Keeps a conversation going when the IRC side changes nick.
+    pub fn rename(&mut self, old: &str, new: &str) {
+        if let Some(user_id) = self.by_nick.remove(old) {
+            self.open(new, user_id);
+        }
+        for nick in self.requests.values_mut().filter(|n| *n == old) {
+            new.clone_into(nick);
+        }
+    }
+
+    /// Ends the conversation with an IRC user, e.g. because they quit.
+    pub fn close(&mut self, nick: &str) {
+        if let Some(user_id) = self.by_nick.remove(nick) {
+            self.by_user.remove(&user_id);
+        }
+        self.requests.retain(|_, n| n != nick);
+    }
+}
+
+/// IRC nicks like services or a helpdesk bot whose private messages with the bridge go to private
+/// threads under the admin channel, so staff can talk to them from Discord.
+pub struct QueryThreads {
+    admin_channel: ChannelId,
+    nicks: Vec<String>,
+    threads: Vec<(String, ChannelId)>,
+}
+
+impl QueryThreads {
+    pub fn new(admin_channel: ChannelId, nicks: Vec<String>) -> Self {
+        Self {
+            admin_channel,
+            nicks,
+            threads: Vec::new(),
+        }
+    }
+
+    pub fn admin_channel(&self) -> ChannelId {
+        self.admin_channel
+    }
+
+    pub fn watches(&self, nick: &str) -> bool {
+        self.nicks.iter().any(|n| n.eq_ignore_ascii_case(nick))
+    }
+
+    pub fn thread(&self, nick: &str) -> Option<ChannelId> {
+        self.threads
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(nick))
+            .map(|&(_, thread)| thread)
+    }
+
+    /// Who a thread talks to, for relaying what's said in it.
+    pub fn nick(&self, thread: ChannelId) -> Option<&str> {
+        self.threads
+            .iter()
+            .find(|&&(_, t)| t == thread)
+            .map(|(nick, _)| nick.as_str())
+    }
+
+    pub fn remember(&mut self, nick: &str, thread: ChannelId) {
+        self.forget(nick);
+        self.threads.push((nick.to_owned(), thread));
+    }
+
+    /// For threads that went away, the next message starts a new one.
+    pub fn forget(&mut self, nick: &str) {
+        self.threads.retain(|(n, _)| !n.eq_ignore_ascii_case(nick));
+    }
+
+    /// Picks up the threads from before a restart, so what staff say in them still goes out
+    /// before the nick has said anything.
+    pub async fn find_existing(&mut self, http: &Http) -> anyhow::Result<()> {
+        let threads = threads_under(http, self.admin_channel).await?;
+        for nick in &self.nicks {
+            let name = thread_name(nick);
+            if let Some(thread) = threads.iter().find(|t| t.name.eq_ignore_ascii_case(&name)) {
+                self.threads.push((nick.clone(), thread.id));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn thread_name(nick: &str) -> String {
+    format!("{nick} on IRC")
+}
+
+/// The threads under the admin channel, archived ones too.
+async fn threads_under(http: &Http, admin_channel: ChannelId) -> anyhow::Result<Vec<GuildChannel>> {
+    let Some(channel) = admin_channel.to_channel(http).await?.guild() else {
+        anyhow::bail!("{admin_channel} isn't a channel in a server");
+    };
+
+    let active = channel.guild_id.get_active_threads(http).await?.threads;
+    let archived = admin_channel
+        .get_archived_private_threads(http, None, None)
+        .await?
+        .threads;
+    Ok(active
+        .into_iter()
+        .chain(archived)
+        .filter(|t| t.parent_id == Some(admin_channel))
+        .collect())
+}
+
+/// The thread for `nick` under the admin channel, created if there isn't one yet. Threads from
+/// before a restart are found by their name.
+pub async fn thread_for(
+    http: &Http,
+    admin_channel: ChannelId,
+    nick: &str,
+) -> anyhow::Result<ChannelId> {
+    let name = thread_name(nick);
+    // posting in an archived thread brings it back
+    let existing = threads_under(http, admin_channel)
+        .await?
+        .into_iter()
+        .find(|t| t.name.eq_ignore_ascii_case(&name));
+    if let Some(existing) = existing {
+        return Ok(existing.id);
+    }
+
+    let builder = CreateThread::new(name).kind(ChannelType::PrivateThread);
+    Ok(admin_channel.create_thread(http, builder).await?.id)
+}
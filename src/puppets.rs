@@ -0,0 +1,500 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use irc::{
+    client::{data::Config, Client as IrcClient},
+    proto::{Command, Response},
+};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serenity::{futures::StreamExt, http::Http, model::id::UserId};
+use tokio::{
+    select,
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    time::{interval, timeout},
+};
+
+use crate::{charset::Transcoder, locale::Locale, private::DmSessions};
+
+/// Room left in a line once the server puts our prefix in front of it.
+pub const LINE_LIMIT: usize = 400;
+/// Libera's limit, most networks allow at least this much.
+const NICK_LEN: usize = 16;
+/// How long a puppet the server turned away waits before it's tried again.
+const RETRY_AFTER: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Deserialize, JsonSchema)]
+pub struct PuppetConfig {
+    /// Goes after the Discord name, defaults to `[d]`
+    suffix: Option<String>,
+    /// Minutes without talking before a puppet disconnects, defaults to 60
+    idle_timeout: Option<u64>,
+    /// Per network, defaults to 30. Anyone past that is relayed by the bridge as usual
+    max_connections: Option<usize>,
+    /// How many lines a puppet can send at once, defaults to 4
+    burst: Option<u32>,
+    /// Seconds the burst is counted over, defaults to 8
+    burst_window: Option<u32>,
+}
+
+enum PuppetCommand {
+    Say {
+        target: String,
+        text: String,
+        action: bool,
+    },
+    Join(String),
+    Nick(String),
+    Quit(String),
+}
+
+/// What a puppet's connection has got to, shared with the pool.
+#[derive(Default)]
+struct State {
+    nick: String,
+    welcomed: bool,
+    /// Lowercase channels the server says we're in and can talk in
+    ready: HashSet<String>,
+    /// When the connection ended because the server wouldn't have it
+    refused_at: Option<Instant>,
+}
+
+/// Someone's own connection to IRC, lines sent here come from their nick.
+#[derive(Clone)]
+pub struct Puppet {
+    commands: UnboundedSender<PuppetCommand>,
+}
+
+impl Puppet {
+    pub fn say(&self, target: &str, text: &str, action: bool) {
+        let _ = self.commands.send(PuppetCommand::Say {
+            target: target.to_owned(),
+            text: text.to_owned(),
+            action,
+        });
+    }
+}
+
+struct Connection {
+    puppet: Puppet,
+    /// What we asked for, the server may have given us something else
+    wanted: String,
+    state: Arc<Mutex<State>>,
+    last_used: Instant,
+}
+
+fn lock_state(state: &Mutex<State>) -> MutexGuard<'_, State> {
+    state.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// What puppets need to pass private messages on to their Discord user.
+pub struct PuppetContext {
+    pub http: Arc<Http>,
+    pub dm_sessions: Arc<tokio::sync::Mutex<DmSessions>>,
+    pub transcoder: Transcoder,
+    pub locale: Arc<Locale>,
+}
+
+/// The puppet connections, by network and Discord user. Connecting and joining happen in the
+/// background, the bridge relays for people as usual until their puppet is in the channel.
+pub struct Puppets {
+    /// Each network's connection settings, which puppets start from
+    configs: Vec<Config>,
+    suffix: String,
+    idle_timeout: Duration,
+    max_connections: usize,
+    context: Arc<PuppetContext>,
+    /// The QUIT reason for puppets that haven't talked in a while
+    idle_reason: String,
+    pool: Mutex<HashMap<(usize, UserId), Connection>>,
+}
+
+impl Puppets {
+    pub fn new(
+        config: PuppetConfig,
+        configs: &[Config],
+        context: PuppetContext,
+        idle_reason: String,
+    ) -> Self {
+        let configs = configs
+            .iter()
+            .map(|network| Config {
+                nickname: None,
+                alt_nicks: Vec::new(),
+                nick_password: None,
+                // a server password is usually a bouncer login, which is the bridge's own
+                password: None,
+                channels: Vec::new(),
+                umodes: None,
+                burst_window_length: Some(config.burst_window.unwrap_or(8)),
+                max_messages_in_burst: Some(config.burst.unwrap_or(4)),
+                ..network.clone()
+            })
+            .collect();
+
+        Self {
+            configs,
+            suffix: config.suffix.unwrap_or_else(|| String::from("[d]")),
+            idle_timeout: Duration::from_secs(config.idle_timeout.unwrap_or(60) * 60),
+            max_connections: config.max_connections.unwrap_or(30),
+            context: Arc::new(context),
+            idle_reason,
+            pool: Mutex::default(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<(usize, UserId), Connection>> {
+        self.pool.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The puppet for `user_id` on `network`, if it's in `channel` and can talk there. Otherwise
+    /// it's connected as `name` and sent to join, and `None` means the bridge relays meanwhile.
+    pub fn get(
+        &self,
+        network: usize,
+        user_id: UserId,
+        name: &str,
+        channel: &str,
+    ) -> Option<Puppet> {
+        let wanted = nick_for(name, &self.suffix);
+        let mut pool = self.lock();
+
+        if let Some(connection) = pool.get_mut(&(network, user_id)) {
+            if !connection.puppet.commands.is_closed() {
+                connection.last_used = Instant::now();
+                if connection.wanted != wanted {
+                    let _ = connection
+                        .puppet
+                        .commands
+                        .send(PuppetCommand::Nick(wanted.clone()));
+                    connection.wanted = wanted;
+                }
+                if lock_state(&connection.state)
+                    .ready
+                    .contains(&channel.to_lowercase())
+                {
+                    return Some(connection.puppet.clone());
+                }
+                // joining twice is sorted out by the connection
+                let _ = connection
+                    .puppet
+                    .commands
+                    .send(PuppetCommand::Join(channel.to_owned()));
+                return None;
+            }
+            // a server that turned us away would just do it again
+            let refused_at = lock_state(&connection.state).refused_at;
+            if refused_at.map_or(false, |at| at.elapsed() < RETRY_AFTER) {
+                return None;
+            }
+            // the connection dropped, a new one takes its place
+            pool.remove(&(network, user_id));
+        }
+
+        if pool.keys().filter(|(n, _)| *n == network).count() >= self.max_connections {
+            return None;
+        }
+
+        let config = Config {
+            nickname: Some(wanted.clone()),
+            alt_nicks: (1..=3)
+                .map(|n| format!("{wanted}{}", "_".repeat(n)))
+                .collect(),
+            username: Some(String::from("discord")),
+            realname: Some(format!("{name} on Discord")),
+            ..self.configs[network].clone()
+        };
+        let (commands, received) = unbounded_channel();
+        // waits in the queue until the server lets us in
+        let _ = commands.send(PuppetCommand::Join(channel.to_owned()));
+        let state = Arc::new(Mutex::new(State {
+            nick: wanted.clone(),
+            ..State::default()
+        }));
+        tokio::spawn(run(
+            config,
+            network,
+            state.clone(),
+            user_id,
+            received,
+            self.context.clone(),
+        ));
+
+        pool.insert(
+            (network, user_id),
+            Connection {
+                puppet: Puppet { commands },
+                wanted,
+                state,
+                last_used: Instant::now(),
+            },
+        );
+        None
+    }
+
+    /// The puppet for `user_id` on `network`, only if it's already connected.
+    pub fn existing(&self, network: usize, user_id: UserId) -> Option<Puppet> {
+        let mut pool = self.lock();
+        let connection = pool
+            .get_mut(&(network, user_id))
+            .filter(|c| !c.puppet.commands.is_closed() && lock_state(&c.state).welcomed)?;
+        connection.last_used = Instant::now();
+        Some(connection.puppet.clone())
+    }
+
+    /// So the bridge doesn't relay what puppets say back to Discord.
+    pub fn is_puppet(&self, network: usize, nick: &str) -> bool {
+        self.lock().iter().any(|((n, _), connection)| {
+            *n == network
+                && lock_state(&connection.state)
+                    .nick
+                    .eq_ignore_ascii_case(nick)
+        })
+    }
+
+    pub fn quit_all(&self, reason: &str) {
+        for (_, connection) in self.lock().drain() {
+            let _ = connection
+                .puppet
+                .commands
+                .send(PuppetCommand::Quit(reason.to_owned()));
+        }
+    }
+
+    /// Disconnects puppets that haven't said anything for `idle_timeout`.
+    pub async fn expire(self: Arc<Self>) {
+        let mut timer = interval(Duration::from_secs(60));
+        loop {
+            timer.tick().await;
+            self.lock().retain(|_, connection| {
+                if connection.last_used.elapsed() < self.idle_timeout {
+                    return true;
+                }
+                let _ = connection
+                    .puppet
+                    .commands
+                    .send(PuppetCommand::Quit(self.idle_reason.clone()));
+                false
+            });
+        }
+    }
+}
+
+/// `name` made into something IRC takes as a nick, with `suffix` after it.
+fn nick_for(name: &str, suffix: &str) -> String {
+    let mut nick = name
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || "_-[]\\^{}|`".contains(*c))
+        .take(NICK_LEN.saturating_sub(suffix.len()))
+        .collect::<String>();
+    if nick.is_empty() {
+        nick.push_str("discord");
+    }
+    // nicks can't start with these
+    if nick.starts_with(|c: char| c.is_ascii_digit() || c == '-') {
+        nick.insert(0, '_');
+        nick.truncate(NICK_LEN.saturating_sub(suffix.len()));
+    }
+    nick + suffix
+}
+
+async fn run(
+    config: Config,
+    network: usize,
+    state: Arc<Mutex<State>>,
+    user_id: UserId,
+    commands: UnboundedReceiver<PuppetCommand>,
+    context: Arc<PuppetContext>,
+) {
+    let wanted = config.nickname.clone().unwrap_or_default();
+    if let Err(e) = relay(config, network, &state, user_id, commands, &context).await {
+        eprintln!("Puppet {wanted} disconnected: {e:#}");
+    }
+    let mut state = lock_state(&state);
+    state.ready.clear();
+    if !state.welcomed {
+        state.refused_at = Some(Instant::now());
+    }
+}
+
+async fn relay(
+    config: Config,
+    network: usize,
+    state: &Mutex<State>,
+    user_id: UserId,
+    mut commands: UnboundedReceiver<PuppetCommand>,
+    context: &PuppetContext,
+) -> anyhow::Result<()> {
+    let current = || lock_state(state).nick.clone();
+
+    let mut client = IrcClient::from_config(config).await?;
+    client.identify()?;
+    let mut stream = client.stream()?;
+
+    let transcoder = context.transcoder;
+    let mut welcomed = false;
+    let mut joined = HashSet::new();
+
+    loop {
+        select! {
+            message = stream.next() => {
+                let Some(message) = message.transpose()? else {
+                    return Ok(());
+                };
+                let source = message.source_nickname().map(ToOwned::to_owned);
+
+                match message.command {
+                    Command::Response(Response::RPL_WELCOME, ref args) => {
+                        welcomed = true;
+                        let mut state = lock_state(state);
+                        state.welcomed = true;
+                        if let Some(given) = args.first() {
+                            state.nick.clone_from(given);
+                        }
+                    }
+                    // with no nick to fall back on, or a ban, there's no getting in
+                    Command::Response(
+                        ref response @ (Response::ERR_ERRONEOUSNICKNAME
+                        | Response::ERR_PASSWDMISMATCH
+                        | Response::ERR_YOUREBANNEDCREEP),
+                        ref args,
+                    ) if !welcomed => {
+                        anyhow::bail!("Refused with {response:?}: {}", args.join(" "));
+                    }
+                    Command::ERROR(ref text) if !welcomed => {
+                        anyhow::bail!("Refused: {text}");
+                    }
+                    Command::NICK(ref new) if source.as_deref() == Some(current().as_str()) => {
+                        lock_state(state).nick.clone_from(new);
+                    }
+                    // only now is it safe to talk there instead of the bridge
+                    Command::JOIN(ref channel, ..) if source.as_deref() == Some(current().as_str()) => {
+                        lock_state(state).ready.insert(channel.to_lowercase());
+                    }
+                    Command::PART(ref channel, _) if source.as_deref() == Some(current().as_str()) => {
+                        joined.remove(channel);
+                        lock_state(state).ready.remove(&channel.to_lowercase());
+                    }
+                    Command::KICK(ref channel, ref kicked, _) if *kicked == current() => {
+                        joined.remove(channel);
+                        lock_state(state).ready.remove(&channel.to_lowercase());
+                    }
+                    // stays in joined so it isn't tried again, the bridge relays for them there
+                    Command::Response(
+                        ref response @ (Response::ERR_BANNEDFROMCHAN
+                        | Response::ERR_INVITEONLYCHAN
+                        | Response::ERR_CHANNELISFULL
+                        | Response::ERR_BADCHANNELKEY),
+                        ref args,
+                    ) => {
+                        let channel = args.get(1).map_or("", String::as_str);
+                        eprintln!("Puppet {} can't join {channel}: {response:?}", current());
+                    }
+                    // +m or a quiet, so what they said has to go through the bridge after all
+                    Command::Response(Response::ERR_CANNOTSENDTOCHAN, ref args) => {
+                        let Some(channel) = args.get(1) else {
+                            continue;
+                        };
+                        let was_ready = lock_state(state).ready.remove(&channel.to_lowercase());
+                        if was_ready {
+                            let text = context.locale.text(
+                                "puppet_not_sent",
+                                "What you just said didn't reach {channel} on IRC, the bridge will relay for you there from now on",
+                                &[("channel", channel)],
+                            );
+                            tell(context, user_id, &text).await;
+                        }
+                    }
+                    // CTCP is the client's business, there's nobody on Discord to answer it
+                    Command::PRIVMSG(ref target, ref text)
+                        if *target == current() && !text.starts_with('\x01') =>
+                    {
+                        let Some(from) = source else {
+                            continue;
+                        };
+                        // DMs are answered from the first network's puppet, like with links
+                        if network != 0 {
+                            let text = context.locale.text(
+                                "pm_other_network",
+                                "Private messages only reach Discord from the bridge's first network",
+                                &[],
+                            );
+                            client.send_notice(&from, transcoder.encode(&text))?;
+                            continue;
+                        }
+                        let text = transcoder.decode(text);
+                        forward_private(context, user_id, &from, &text).await;
+                    }
+                    _ => {}
+                }
+            }
+            command = commands.recv(), if welcomed => match command {
+                Some(PuppetCommand::Join(channel)) => {
+                    if joined.insert(channel.clone()) {
+                        client.send_join(&channel)?;
+                    }
+                }
+                Some(PuppetCommand::Say { target, text, action }) => {
+                    if target.starts_with(['#', '&']) && joined.insert(target.clone()) {
+                        client.send_join(&target)?;
+                    }
+                    let text = transcoder.encode(&text);
+                    if action {
+                        client.send_action(&target, text)?;
+                    } else {
+                        client.send_privmsg(&target, text)?;
+                    }
+                }
+                Some(PuppetCommand::Nick(new)) => client.send(Command::NICK(new))?,
+                Some(PuppetCommand::Quit(reason)) => return quit(&client, &mut stream, &reason).await,
+                None => return quit(&client, &mut stream, "").await,
+            },
+        }
+    }
+}
+
+async fn quit(
+    client: &IrcClient,
+    stream: &mut irc::client::ClientStream,
+    reason: &str,
+) -> anyhow::Result<()> {
+    client.send_quit(reason)?;
+    // the QUIT only goes out while the stream is polled, and the server hangs up after it
+    let _ = timeout(Duration::from_secs(5), async {
+        while let Some(Ok(_)) = stream.next().await {}
+    })
+    .await;
+    Ok(())
+}
+
+/// Lets a puppet's Discord user know something happened to it.
+async fn tell(context: &PuppetContext, user_id: UserId, text: &str) {
+    let sent = match user_id.create_dm_channel(&context.http).await {
+        Ok(dm) => dm.id.say(&context.http, text).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = sent {
+        eprintln!("Failed to DM {user_id}: {e}");
+    }
+}
+
+/// A PM to a puppet goes to its Discord user, and their DM replies come back from the puppet.
+async fn forward_private(context: &PuppetContext, user_id: UserId, from: &str, text: &str) {
+    let dm_text = context.locale.text(
+        "pm_from_irc",
+        "**{nick}** on IRC: {text}",
+        &[("nick", &from), ("text", &text)],
+    );
+    let sent = match user_id.create_dm_channel(&context.http).await {
+        Ok(dm) => dm.id.say(&context.http, dm_text).await,
+        Err(e) => Err(e),
+    };
+
+    match sent {
+        Ok(_) => context.dm_sessions.lock().await.open(from, user_id),
+        Err(e) => eprintln!("Failed to DM {user_id}: {e}"),
+    }
+}
@@ -0,0 +1,277 @@
+use std::{collections::HashMap, fmt::Write, path::PathBuf, sync::Mutex, time::Duration};
+
+use anyhow::Context;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use reqwest::multipart::{Form, Part};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serenity::{
+    futures::future::join_all,
+    model::{channel::Attachment, id::AttachmentId},
+};
+use sha2::{Digest, Sha256};
+use tokio::time::timeout;
+
+/// How many rehosted links are remembered, so edits and replies don't upload again.
+const REMEMBERED: usize = 1000;
+/// For all of a message's attachments together, the ones that aren't done by then keep their
+/// Discord link so the message isn't held up.
+const DEADLINE: Duration = Duration::from_secs(15);
+
+#[derive(Deserialize, JsonSchema)]
+pub struct RehostConfig {
+    #[serde(flatten)]
+    service: Service,
+    /// In MB, bigger attachments keep their Discord link
+    max_size: Option<u64>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(tag = "service", rename_all = "snake_case")]
+enum Service {
+    #[serde(rename = "0x0")]
+    NullPointer {
+        /// For other instances, defaults to https://0x0.st
+        url: Option<String>,
+    },
+    Imgur {
+        client_id: String,
+    },
+    /// Anything that speaks the S3 API, like AWS, MinIO or R2
+    S3 {
+        endpoint: String,
+        bucket: String,
+        region: Option<String>,
+        access_key: String,
+        secret_key: String,
+        /// Where the bucket can be read from, if not at `endpoint/bucket`
+        public_url: Option<String>,
+    },
+    /// Files written to a directory that a web server serves at `public_url`
+    Directory {
+        path: PathBuf,
+        public_url: String,
+    },
+}
+
+/// Puts Discord attachments somewhere else for IRC, since CDN links are long and expire.
+pub struct Rehost {
+    client: reqwest::Client,
+    service: Service,
+    max_size: u64,
+    rehosted: Mutex<HashMap<AttachmentId, String>>,
+}
+
+impl Rehost {
+    pub fn new(config: RehostConfig) -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(30))
+                .build()
+                .unwrap(),
+            service: config.service,
+            max_size: config.max_size.unwrap_or(25) * 1024 * 1024,
+            rehosted: Mutex::default(),
+        }
+    }
+
+    /// The links IRC gets for a message's attachments, uploaded all at once.
+    pub async fn urls(&self, attachments: &[Attachment]) -> Vec<String> {
+        join_all(attachments.iter().map(|attachment| async {
+            match timeout(DEADLINE, self.url(attachment)).await {
+                Ok(url) => url,
+                Err(_) => {
+                    eprintln!("Rehosting {} took too long", attachment.filename);
+                    attachment.url.clone()
+                }
+            }
+        }))
+        .await
+    }
+
+    /// The link IRC gets for `attachment`. It's the Discord one if rehosting didn't work.
+    async fn url(&self, attachment: &Attachment) -> String {
+        if let Some(url) = self.lock().get(&attachment.id) {
+            return url.clone();
+        }
+        if u64::from(attachment.size) > self.max_size {
+            return attachment.url.clone();
+        }
+
+        match self.upload(attachment).await {
+            Ok(url) => {
+                let mut rehosted = self.lock();
+                if rehosted.len() >= REMEMBERED {
+                    rehosted.clear();
+                }
+                rehosted.insert(attachment.id, url.clone());
+                url
+            }
+            Err(e) => {
+                eprintln!("Failed to rehost {}: {e:#}", attachment.filename);
+                attachment.url.clone()
+            }
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<AttachmentId, String>> {
+        self.rehosted.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    async fn upload(&self, attachment: &Attachment) -> anyhow::Result<String> {
+        let data = self
+            .client
+            .get(&attachment.url)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?
+            .to_vec();
+        let content_type = attachment
+            .content_type
+            .clone()
+            .unwrap_or_else(|| String::from("application/octet-stream"));
+        // the ID keeps names from clashing, and nothing in the name needs escaping
+        let name = format!("{}-{}", attachment.id, safe_name(&attachment.filename));
+
+        match &self.service {
+            Service::NullPointer { url } => {
+                let part = Part::bytes(data).file_name(name).mime_str(&content_type)?;
+                let response = self
+                    .client
+                    .post(url.as_deref().unwrap_or("https://0x0.st"))
+                    .multipart(Form::new().part("file", part))
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                Ok(response.text().await?.trim().to_owned())
+            }
+            Service::Imgur { client_id } => {
+                let part = Part::bytes(data).file_name(name);
+                let response = self
+                    .client
+                    .post("https://api.imgur.com/3/image")
+                    .header("Authorization", format!("Client-ID {client_id}"))
+                    .multipart(Form::new().part("image", part))
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json::<serde_json::Value>()
+                    .await?;
+                response["data"]["link"]
+                    .as_str()
+                    .map(ToOwned::to_owned)
+                    .context("Imgur didn't say where it went")
+            }
+            Service::S3 {
+                endpoint,
+                bucket,
+                region,
+                access_key,
+                secret_key,
+                public_url,
+            } => {
+                let endpoint = endpoint.trim_end_matches('/');
+                let url = reqwest::Url::parse(&format!("{endpoint}/{bucket}/{name}"))?;
+                let region = region.as_deref().unwrap_or("us-east-1");
+                let headers = sign_s3(&url, region, access_key, secret_key, &data)?;
+
+                let mut request = self
+                    .client
+                    .put(url.clone())
+                    .header("Content-Type", content_type)
+                    .body(data);
+                for (name, value) in headers {
+                    request = request.header(name, value);
+                }
+                request.send().await?.error_for_status()?;
+
+                Ok(match public_url {
+                    Some(public_url) => format!("{}/{name}", public_url.trim_end_matches('/')),
+                    None => url.to_string(),
+                })
+            }
+            Service::Directory { path, public_url } => {
+                tokio::fs::write(path.join(&name), data).await?;
+                Ok(format!("{}/{name}", public_url.trim_end_matches('/')))
+            }
+        }
+    }
+}
+
+/// Only the characters that are fine in any URL and filesystem.
+fn safe_name(filename: &str) -> String {
+    let name = filename
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    name.trim_start_matches('.').to_owned()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut out, b| {
+        write!(out, "{b:02x}").unwrap();
+        out
+    })
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// The headers that sign a PUT of `body` to `url`, AWS signature version 4.
+fn sign_s3(
+    url: &reqwest::Url,
+    region: &str,
+    access_key: &str,
+    secret_key: &str,
+    body: &[u8],
+) -> anyhow::Result<Vec<(&'static str, String)>> {
+    let host = match (url.host_str(), url.port()) {
+        (Some(host), Some(port)) => format!("{host}:{port}"),
+        (Some(host), None) => host.to_owned(),
+        (None, _) => anyhow::bail!("No host in the S3 endpoint"),
+    };
+    let now = Utc::now();
+    let date = now.format("%Y%m%d").to_string();
+    let timestamp = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let payload_hash = hex(&Sha256::digest(body));
+
+    let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+    let canonical_request = format!(
+        "PUT\n{}\n\nhost:{host}\nx-amz-content-sha256:{payload_hash}\nx-amz-date:{timestamp}\n\n{signed_headers}\n{payload_hash}",
+        url.path()
+    );
+    let scope = format!("{date}/{region}/s3/aws4_request");
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{timestamp}\n{scope}\n{}",
+        hex(&Sha256::digest(canonical_request.as_bytes()))
+    );
+
+    let mut key = hmac(format!("AWS4{secret_key}").as_bytes(), &date);
+    for part in [region, "s3", "aws4_request"] {
+        key = hmac(&key, part);
+    }
+    let signature = hex(&hmac(&key, &string_to_sign));
+
+    Ok(vec![
+        ("x-amz-content-sha256", payload_hash),
+        ("x-amz-date", timestamp),
+        (
+            "Authorization",
+            format!(
+                "AWS4-HMAC-SHA256 Credential={access_key}/{scope}, SignedHeaders={signed_headers}, Signature={signature}"
+            ),
+        ),
+    ])
+}
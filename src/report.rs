@@ -0,0 +1,49 @@
+//! Optional error reporting to Sentry. Everything here is a no-op unless dircord is built with
+//! the `sentry` feature.
+
+/// Sets up Sentry for the whole process. The returned guard flushes pending events when dropped,
+/// so it has to live until `main` returns.
+#[cfg(feature = "sentry")]
+pub fn init(dsn: Option<&str>) -> Option<sentry::ClientInitGuard> {
+    let dsn = dsn?;
+    Some(sentry::init((
+        dsn,
+        sentry::ClientOptions {
+            release: sentry::release_name!(),
+            ..Default::default()
+        },
+    )))
+}
+
+#[cfg(not(feature = "sentry"))]
+pub fn init(_dsn: Option<&str>) -> Option<()> {
+    None
+}
+
+/// Reports a message that couldn't be relayed, tagged with where it was going.
+pub fn relay_error(err: &anyhow::Error, channel: &str, direction: &str, len: usize) {
+    #[cfg(feature = "sentry")]
+    sentry::with_scope(
+        |scope| {
+            scope.set_tag("channel", channel);
+            scope.set_tag("direction", direction);
+            scope.set_extra("message_length", len.into());
+        },
+        || sentry::integrations::anyhow::capture_anyhow(err),
+    );
+
+    #[cfg(not(feature = "sentry"))]
+    let _ = (err, channel, direction, len);
+}
+
+/// Reports an error that stopped a bridge.
+pub fn bridge_error(err: &anyhow::Error, bridge: &str) {
+    #[cfg(feature = "sentry")]
+    sentry::with_scope(
+        |scope| scope.set_tag("bridge", bridge),
+        || sentry::integrations::anyhow::capture_anyhow(err),
+    );
+
+    #[cfg(not(feature = "sentry"))]
+    let _ = (err, bridge);
+}
@@ -0,0 +1,101 @@
+use crate::regex;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serenity::futures::future::join_all;
+use std::time::Duration;
+use tokio::time::timeout;
+
+/// For all the links in a message together, past this they're sent long.
+const DEADLINE: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize, JsonSchema)]
+pub struct ShortenerConfig {
+    /// Endpoint that gets `{"<field>": "<long url>"}` POSTed to it
+    url: String,
+    field: Option<String>,
+    /// Where the short URL is in the JSON response. If unset the whole body is the short URL.
+    response_field: Option<String>,
+    token: Option<String>,
+    min_length: Option<usize>,
+}
+
+/// Shortens long links before they go to IRC, where every byte of a line counts.
+pub struct Shortener {
+    client: reqwest::Client,
+    config: ShortenerConfig,
+}
+
+impl Shortener {
+    pub fn new(config: ShortenerConfig) -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(3))
+                .build()
+                .unwrap(),
+            config,
+        }
+    }
+
+    /// Replaces every link longer than the threshold. Links the shortener chokes on are kept as is.
+    pub async fn shorten_all(&self, text: &str) -> String {
+        regex! {
+            static URL_RE = r"https?://[^\s<>]+";
+        }
+
+        let min_length = self.config.min_length.unwrap_or(80);
+        let long = URL_RE
+            .find_iter(text)
+            .flatten()
+            .map(|m| m.as_str())
+            .filter(|url| url.len() >= min_length)
+            .collect::<Vec<_>>();
+
+        let results =
+            match timeout(DEADLINE, join_all(long.iter().map(|url| self.shorten(url)))).await {
+                Ok(results) => results,
+                Err(_) => {
+                    eprintln!("Shortening took too long, sending the links as they are");
+                    return text.to_owned();
+                }
+            };
+
+        let mut shortened = text.to_owned();
+        for (url, result) in long.iter().zip(results) {
+            match result {
+                Ok(short) => shortened = shortened.replacen(url, &short, 1),
+                Err(e) => eprintln!("Failed to shorten {url}: {e}"),
+            }
+        }
+
+        shortened
+    }
+
+    async fn shorten(&self, url: &str) -> anyhow::Result<String> {
+        let field = self.config.field.as_deref().unwrap_or("url");
+        let mut request = self
+            .client
+            .post(&self.config.url)
+            .json(&serde_json::json!({ field: url }));
+        if let Some(token) = &self.config.token {
+            request = request.bearer_auth(token);
+        }
+
+        let response = request.send().await?.error_for_status()?;
+        let short = match &self.config.response_field {
+            Some(name) => response
+                .json::<serde_json::Value>()
+                .await?
+                .get(name)
+                .and_then(serde_json::Value::as_str)
+                .ok_or_else(|| anyhow::anyhow!("Response has no {name} field"))?
+                .to_owned(),
+            None => response.text().await?.trim().to_owned(),
+        };
+
+        if short.is_empty() {
+            anyhow::bail!("Shortener returned nothing");
+        }
+
+        Ok(short)
+    }
+}
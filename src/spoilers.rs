@@ -0,0 +1,126 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+    hash::{Hash, Hasher},
+    sync::Mutex,
+};
+
+use crate::locale::Locale;
+
+/// How many hidden spoilers can still be revealed, older ones are forgotten.
+const KEEP: usize = 500;
+
+/// A spoiler's IRC channel, lowercased, and its ID.
+type Key = (String, String);
+
+#[derive(Default)]
+struct Hidden {
+    texts: HashMap<Key, String>,
+    order: VecDeque<Key>,
+}
+
+/// Discord `||spoilers||` hidden from IRC behind a placeholder, until someone asks for them with
+/// `!spoiler <id>`. IDs only mean something in the channel the spoiler was hidden in.
+pub struct Spoilers {
+    /// What the spoiler turns into on IRC, with `{id}` in it
+    placeholder: String,
+    hidden: Mutex<Hidden>,
+}
+
+impl Spoilers {
+    pub fn new(placeholder: String) -> Self {
+        Self {
+            placeholder,
+            hidden: Mutex::default(),
+        }
+    }
+
+    /// Replaces every `||spoiler||` in `text`, going to IRC `channel`, with the placeholder. The
+    /// same spoiler always gets the same ID, so an edit doesn't look changed just because it was
+    /// hidden again.
+    pub fn hide(&self, channel: &str, text: &str) -> String {
+        let mut hidden = self.hidden.lock().unwrap_or_else(|e| e.into_inner());
+        let mut out = String::with_capacity(text.len());
+        let mut rest = text;
+
+        while let Some(start) = rest.find("||") {
+            let Some(len) = rest[start + 2..].find("||") else {
+                break;
+            };
+            let spoiler = &rest[start + 2..start + 2 + len];
+            // "||||" isn't a spoiler of nothing
+            if spoiler.trim().is_empty() {
+                out.push_str(&rest[..start + 4 + len]);
+                rest = &rest[start + 4 + len..];
+                continue;
+            }
+
+            let id = spoiler_id(spoiler);
+            let key = (channel.to_lowercase(), id.clone());
+            if !hidden.texts.contains_key(&key) {
+                if hidden.order.len() == KEEP {
+                    if let Some(oldest) = hidden.order.pop_front() {
+                        hidden.texts.remove(&oldest);
+                    }
+                }
+                hidden.order.push_back(key.clone());
+                hidden.texts.insert(key, spoiler.trim().to_owned());
+            }
+
+            out.push_str(&rest[..start]);
+            out.push_str(&self.placeholder.replace("{id}", &id));
+            rest = &rest[start + 4 + len..];
+        }
+        out.push_str(rest);
+
+        out
+    }
+
+    /// What to tell someone saying `text`, if it's a `!spoiler <id>` command. Only spoilers from
+    /// channels `can_see` is true for are revealed.
+    pub fn answer(
+        &self,
+        text: &str,
+        can_see: impl Fn(&str) -> bool,
+        locale: &Locale,
+    ) -> Option<String> {
+        let mut words = text.split_whitespace();
+        if words.next()? != "!spoiler" {
+            return None;
+        }
+        let id = words.next()?.to_lowercase();
+        if words.next().is_some() {
+            return None;
+        }
+
+        let hidden = self.hidden.lock().unwrap_or_else(|e| e.into_inner());
+        let text = hidden
+            .texts
+            .iter()
+            .find(|((channel, key), _)| *key == id && can_see(channel))
+            .map(|(_, text)| text.clone());
+        Some(text.unwrap_or_else(|| {
+            locale.text(
+                "spoiler_unknown",
+                "No spoiler {id} in a channel you're in, it may be too old",
+                &[("id", &id)],
+            )
+        }))
+    }
+}
+
+/// Four base 36 digits from a hash of the text.
+fn spoiler_id(spoiler: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    spoiler.trim().hash(&mut hasher);
+    let mut n = hasher.finish() % 36u64.pow(4);
+
+    let mut id = String::with_capacity(4);
+    for _ in 0..4 {
+        id.insert(
+            0,
+            char::from_digit(u32::try_from(n % 36).unwrap(), 36).unwrap(),
+        );
+        n /= 36;
+    }
+    id
+}
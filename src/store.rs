@@ -0,0 +1,207 @@
+use std::{
+    path::Path,
+    sync::mpsc::{channel, sync_channel, RecvTimeoutError, Sender},
+    thread,
+    time::{Duration, Instant},
+};
+
+use rusqlite::{params, Connection, OptionalExtension};
+use serenity::model::id::{ChannelId, MessageId, UserId};
+use tokio::task::block_in_place;
+
+use crate::{message_cache::CachedMessage, unix_now};
+
+/// How often messages past `db_keep_days` are forgotten.
+const PRUNE_EVERY: Duration = Duration::from_secs(60 * 60);
+
+type Job = Box<dyn FnOnce(&Connection) + Send>;
+
+/// What the message cache knows, on disk so edits and replies still work for messages from
+/// before a restart or that fell out of memory. The database gets a thread of its own so slow
+/// disks don't hold up the runtime.
+pub struct MessageStore {
+    jobs: Sender<Job>,
+}
+
+fn logged<T>(what: &str, result: rusqlite::Result<T>) -> Option<T> {
+    match result {
+        Ok(v) => Some(v),
+        Err(e) => {
+            eprintln!("Failed to {what} in the database: {e}");
+            None
+        }
+    }
+}
+
+fn prune(conn: &Connection, keep_days: u64) -> rusqlite::Result<()> {
+    let cutoff = unix_now().saturating_sub(keep_days * 24 * 60 * 60);
+    conn.execute(
+        "DELETE FROM discord_messages WHERE relayed_at < ?1",
+        params![cutoff],
+    )?;
+    conn.execute(
+        "DELETE FROM irc_messages WHERE relayed_at < ?1",
+        params![cutoff],
+    )?;
+    Ok(())
+}
+
+impl MessageStore {
+    /// Opens or creates the database, forgetting anything older than `keep_days` now and every
+    /// hour after.
+    pub fn open(path: impl AsRef<Path>, keep_days: u64) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS discord_messages (
+                id INTEGER PRIMARY KEY,
+                channel_id INTEGER NOT NULL,
+                author_id INTEGER NOT NULL,
+                nick TEXT,
+                content TEXT NOT NULL,
+                relayed_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS irc_messages (
+                msgid TEXT PRIMARY KEY,
+                discord_id INTEGER NOT NULL,
+                relayed_at INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS irc_messages_by_discord_id ON irc_messages (discord_id);",
+        )?;
+        prune(&conn, keep_days)?;
+
+        let (jobs, received) = channel::<Job>();
+        thread::spawn(move || {
+            let mut pruned_at = Instant::now();
+            loop {
+                match received.recv_timeout(PRUNE_EVERY) {
+                    Ok(job) => job(&conn),
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+                if pruned_at.elapsed() >= PRUNE_EVERY {
+                    logged("forget old messages", prune(&conn, keep_days));
+                    pruned_at = Instant::now();
+                }
+            }
+        });
+
+        Ok(Self { jobs })
+    }
+
+    fn write(&self, job: impl FnOnce(&Connection) + Send + 'static) {
+        let _ = self.jobs.send(Box::new(job));
+    }
+
+    /// Waits for the database thread to answer, letting other tasks run meanwhile.
+    fn read<T: Send + 'static>(
+        &self,
+        query: impl FnOnce(&Connection) -> Option<T> + Send + 'static,
+    ) -> Option<T> {
+        let (reply, answer) = sync_channel(1);
+        self.jobs
+            .send(Box::new(move |conn| {
+                let _ = reply.send(query(conn));
+            }))
+            .ok()?;
+        block_in_place(|| answer.recv().ok().flatten())
+    }
+
+    pub fn save_message(&self, id: MessageId, message: &CachedMessage) {
+        let message = message.clone();
+        let relayed_at = unix_now();
+        self.write(move |conn| {
+            logged(
+                "save a message",
+                conn.execute(
+                    "INSERT OR REPLACE INTO discord_messages
+                        (id, channel_id, author_id, nick, content, relayed_at)
+                        VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    params![
+                        id.0.get(),
+                        message.channel_id.0.get(),
+                        message.author_id.0.get(),
+                        message.nick,
+                        message.content,
+                        relayed_at,
+                    ],
+                ),
+            );
+        });
+    }
+
+    pub fn message(&self, id: MessageId) -> Option<CachedMessage> {
+        self.read(move |conn| {
+            let found = conn
+                .query_row(
+                    "SELECT channel_id, author_id, nick, content FROM discord_messages WHERE id = ?1",
+                    params![id.0.get()],
+                    |row| {
+                        Ok(CachedMessage {
+                            channel_id: ChannelId::from(row.get::<_, u64>(0)?),
+                            author_id: UserId::from(row.get::<_, u64>(1)?),
+                            nick: row.get(2)?,
+                            content: row.get(3)?,
+                        })
+                    },
+                )
+                .optional();
+            logged("look up a message", found).flatten()
+        })
+    }
+
+    pub fn update_content(&self, id: MessageId, content: &str) {
+        let content = content.to_owned();
+        self.write(move |conn| {
+            logged(
+                "update a message",
+                conn.execute(
+                    "UPDATE discord_messages SET content = ?2 WHERE id = ?1",
+                    params![id.0.get(), content],
+                ),
+            );
+        });
+    }
+
+    pub fn save_msgid(&self, msgid: &str, id: MessageId) {
+        let msgid = msgid.to_owned();
+        let relayed_at = unix_now();
+        self.write(move |conn| {
+            logged(
+                "save a msgid",
+                conn.execute(
+                    "INSERT OR REPLACE INTO irc_messages (msgid, discord_id, relayed_at)
+                        VALUES (?1, ?2, ?3)",
+                    params![msgid, id.0.get(), relayed_at],
+                ),
+            );
+        });
+    }
+
+    pub fn by_msgid(&self, msgid: &str) -> Option<MessageId> {
+        let msgid = msgid.to_owned();
+        self.read(move |conn| {
+            let found = conn
+                .query_row(
+                    "SELECT discord_id FROM irc_messages WHERE msgid = ?1",
+                    params![msgid],
+                    |row| row.get::<_, u64>(0),
+                )
+                .optional();
+            logged("look up a msgid", found).flatten()
+        })
+        .map(MessageId::from)
+    }
+
+    pub fn msgid(&self, id: MessageId) -> Option<String> {
+        self.read(move |conn| {
+            let found = conn
+                .query_row(
+                    "SELECT msgid FROM irc_messages WHERE discord_id = ?1",
+                    params![id.0.get()],
+                    |row| row.get(0),
+                )
+                .optional();
+            logged("look up a msgid", found).flatten()
+        })
+    }
+}
@@ -0,0 +1,80 @@
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::time::Duration;
+
+#[derive(Deserialize, JsonSchema)]
+pub struct TranslatorConfig {
+    /// A LibreTranslate compatible `/translate` endpoint
+    url: String,
+    api_key: Option<String>,
+}
+
+/// Languages spoken on each side of a channel mapping, as codes like `en` or `es`.
+#[derive(Deserialize, JsonSchema, Clone)]
+pub struct LanguagePair {
+    pub irc: String,
+    pub discord: String,
+}
+
+/// Translates relayed messages for channels bridging two languages.
+pub struct Translator {
+    client: reqwest::Client,
+    config: TranslatorConfig,
+}
+
+impl Translator {
+    pub fn new(config: TranslatorConfig) -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(5))
+                .build()
+                .unwrap(),
+            config,
+        }
+    }
+
+    /// The translation followed by the original, or just the original if translating failed or
+    /// didn't change anything.
+    pub async fn translate_with_original(&self, text: &str, source: &str, target: &str) -> String {
+        if text.trim().is_empty() {
+            return text.to_owned();
+        }
+
+        match self.translate(text, source, target).await {
+            Ok(translated) if translated.trim() != text.trim() => {
+                format!("{translated} (original: {text})")
+            }
+            Ok(_) => text.to_owned(),
+            Err(e) => {
+                eprintln!("Failed to translate from {source} to {target}: {e}");
+                text.to_owned()
+            }
+        }
+    }
+
+    async fn translate(&self, text: &str, source: &str, target: &str) -> anyhow::Result<String> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Response {
+            translated_text: String,
+        }
+
+        let response = self
+            .client
+            .post(&self.config.url)
+            .json(&serde_json::json!({
+                "q": text,
+                "source": source,
+                "target": target,
+                "format": "text",
+                "api_key": self.config.api_key,
+            }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Response>()
+            .await?;
+
+        Ok(response.translated_text)
+    }
+}
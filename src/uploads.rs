@@ -0,0 +1,198 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use anyhow::Context;
+use reqwest::{header::LOCATION, redirect::Policy, Url};
+use serenity::{builder::CreateAttachment, futures::future::join_all};
+use tokio::{net::lookup_host, time::timeout};
+
+use crate::regex;
+
+/// Discord doesn't take more than this many files on one message.
+const MAX_FILES: usize = 10;
+/// Discord's limit for a whole message without boosts, files together.
+const MAX_TOTAL: u64 = 25 * 1024 * 1024;
+/// For all the downloads of a message together, so the queue to Discord doesn't stall on them.
+const FETCH_DEADLINE: Duration = Duration::from_secs(5);
+const MAX_REDIRECTS: usize = 3;
+
+/// Downloads images IRC people link to, so they can go to Discord as attachments and show up
+/// inline instead of as a bare link.
+pub struct ImageUploader {
+    /// In bytes, anything bigger stays a link
+    max_size: u64,
+}
+
+impl ImageUploader {
+    pub fn new(max_size: u64) -> Self {
+        Self { max_size }
+    }
+
+    /// Uploads the direct image links in `content`, taking them out of it. Links that can't be
+    /// downloaded, are too big or don't fit in the message with the others are left in.
+    pub async fn take_images(&self, content: &mut String) -> Vec<CreateAttachment> {
+        regex! {
+            static IMAGE_URL_RE = r"(?i)\bhttps?://[^\s<>]+?\.(?:png|jpe?g|gif|webp)(?:\?[^\s<>]*)?(?=\s|$)";
+        }
+
+        let urls = IMAGE_URL_RE
+            .find_iter(content)
+            .flatten()
+            .map(|m| m.as_str().to_owned())
+            .take(MAX_FILES)
+            .collect::<Vec<_>>();
+
+        // shared, so the downloads stop once they're past what the message can take together
+        let total = AtomicU64::new(0);
+        let fetched = join_all(urls.iter().map(|url| async {
+            match timeout(FETCH_DEADLINE, self.fetch(url, &total)).await {
+                Ok(result) => result,
+                Err(_) => Err(anyhow::anyhow!("Took too long")),
+            }
+        }))
+        .await;
+
+        let mut files = Vec::new();
+        for (url, file) in urls.iter().zip(fetched) {
+            match file {
+                Ok(file) => {
+                    files.push(file);
+                    *content = content.replacen(url, "", 1);
+                }
+                Err(e) => eprintln!("Failed to upload {url}: {e:#}"),
+            }
+        }
+
+        if !files.is_empty() {
+            *content = content.trim().to_owned();
+        }
+        files
+    }
+
+    async fn fetch(&self, url: &str, total: &AtomicU64) -> anyhow::Result<CreateAttachment> {
+        let mut url = Url::parse(url)?;
+        let mut hops = 0;
+        let mut response = loop {
+            let response = pinned_client(&url).await?.get(url.clone()).send().await?;
+            if !response.status().is_redirection() {
+                break response.error_for_status()?;
+            }
+            // every hop gets checked, a public host can redirect to a private one
+            hops += 1;
+            if hops > MAX_REDIRECTS {
+                anyhow::bail!("Too many redirects");
+            }
+            let location = response
+                .headers()
+                .get(LOCATION)
+                .and_then(|l| l.to_str().ok())
+                .context("Redirect without a location")?;
+            url = url.join(location)?;
+        };
+
+        let is_image = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|t| t.to_str().ok())
+            .map_or(false, |t| t.starts_with("image/"));
+        if !is_image {
+            anyhow::bail!("Not an image");
+        }
+        if response
+            .content_length()
+            .map_or(false, |len| len > self.max_size)
+        {
+            anyhow::bail!("Bigger than the upload limit");
+        }
+
+        // servers can leave the length out or lie about it
+        let mut data = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            data.extend_from_slice(&chunk);
+            let len = chunk.len() as u64;
+            if data.len() as u64 > self.max_size {
+                anyhow::bail!("Bigger than the upload limit");
+            }
+            if total.fetch_add(len, Ordering::Relaxed) + len > MAX_TOTAL {
+                anyhow::bail!("Too much to upload with the other images");
+            }
+        }
+
+        let name = url
+            .path_segments()
+            .and_then(Iterator::last)
+            .filter(|name| !name.is_empty())
+            .unwrap_or("image.png")
+            .to_owned();
+        Ok(CreateAttachment::bytes(data, name))
+    }
+}
+
+/// A client that can only reach where `url` resolves to right now, and only if that's on the
+/// internet. Pinning the address keeps a second lookup from pointing somewhere else.
+async fn pinned_client(url: &Url) -> anyhow::Result<reqwest::Client> {
+    if !matches!(url.scheme(), "http" | "https") {
+        anyhow::bail!("Not a web link");
+    }
+    let host = url.host_str().context("No host")?;
+    let port = url.port_or_known_default().context("No port")?;
+
+    let builder = reqwest::Client::builder()
+        .timeout(FETCH_DEADLINE)
+        .redirect(Policy::none());
+    let builder = match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+        Ok(ip) => {
+            if !is_public(ip) {
+                anyhow::bail!("{ip} isn't a public address");
+            }
+            builder
+        }
+        Err(_) => {
+            let addrs = lookup_host((host, port)).await?.collect::<Vec<_>>();
+            if let Some(addr) = addrs.iter().find(|a| !is_public(a.ip())) {
+                anyhow::bail!("{host} resolves to {}, which isn't public", addr.ip());
+            }
+            let addr: SocketAddr = *addrs.first().context("No addresses")?;
+            builder.resolve(host, addr)
+        }
+    };
+    Ok(builder.build()?)
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        // 0.0.0.0/8, carrier-grade NAT in 100.64.0.0/10 and reserved 240.0.0.0/4
+        || a == 0
+        || (a == 100 && (64..128).contains(&b))
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // unique local fc00::/7 and link-local fe80::/10
+        || (first & 0xfe00) == 0xfc00
+        || (first & 0xffc0) == 0xfe80)
+}
@@ -0,0 +1,101 @@
+use crate::regex;
+use std::borrow::Cow;
+
+/// Query parameters that only exist to track who clicked what. A trailing `*` matches any
+/// parameter starting with the rest.
+const DEFAULT_TRACKING_PARAMS: &[&str] = &[
+    "utm_*", "fbclid", "gclid", "dclid", "msclkid", "mc_cid", "mc_eid", "igshid", "yclid",
+    "_hsenc", "_hsmi", "si",
+];
+
+/// Removes tracking parameters from links in relayed messages.
+#[derive(Default)]
+pub struct UrlCleaner {
+    params: Vec<String>,
+}
+
+impl UrlCleaner {
+    pub fn new(enabled: bool, params: Option<Vec<String>>) -> Self {
+        if !enabled {
+            return Self::default();
+        }
+
+        Self {
+            params: params.unwrap_or_else(|| {
+                DEFAULT_TRACKING_PARAMS
+                    .iter()
+                    .map(|&p| p.to_owned())
+                    .collect()
+            }),
+        }
+    }
+
+    pub fn clean<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        regex! {
+            static URL_RE = r"https?://[^\s<>]+\?[^\s<>]+";
+        }
+
+        if self.params.is_empty() {
+            return Cow::Borrowed(text);
+        }
+
+        let mut cleaned = String::with_capacity(text.len());
+        let mut last = 0;
+        for m in URL_RE.find_iter(text).flatten() {
+            // punctuation right after a link is almost always part of the sentence
+            let url = m
+                .as_str()
+                .trim_end_matches(&['.', ',', ')', '!', '?', ';', ':', '\'', '"'][..]);
+
+            cleaned.push_str(&text[last..m.start()]);
+            cleaned.push_str(&self.clean_url(url));
+            last = m.start() + url.len();
+        }
+
+        if last == 0 {
+            return Cow::Borrowed(text);
+        }
+
+        cleaned.push_str(&text[last..]);
+        Cow::Owned(cleaned)
+    }
+
+    fn clean_url(&self, url: &str) -> String {
+        let (url, fragment) = match url.split_once('#') {
+            Some((url, fragment)) => (url, Some(fragment)),
+            None => (url, None),
+        };
+
+        let (base, query) = url.split_once('?').unwrap_or((url, ""));
+
+        let kept = query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .filter(|pair| {
+                let key = pair.split('=').next().unwrap_or_default();
+                !self.is_tracking(key)
+            })
+            .collect::<Vec<_>>();
+
+        let mut cleaned = base.to_owned();
+        if !kept.is_empty() {
+            cleaned.push('?');
+            cleaned.push_str(&kept.join("&"));
+        }
+        if let Some(fragment) = fragment {
+            cleaned.push('#');
+            cleaned.push_str(fragment);
+        }
+
+        cleaned
+    }
+
+    fn is_tracking(&self, key: &str) -> bool {
+        self.params
+            .iter()
+            .any(|param| match param.strip_suffix('*') {
+                Some(prefix) => key.starts_with(prefix),
+                None => key == param,
+            })
+    }
+}